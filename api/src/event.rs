@@ -0,0 +1,127 @@
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+pub type EventId = i64;
+
+/// Stage of event record without `stage` field
+pub const DEFAULT_STAGE: i64 = 1;
+
+fn default_stage() -> i64 { DEFAULT_STAGE }
+
+/// Sport of the event, it determines default event DB config and enumz entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Sport {
+    #[default]
+    #[serde(rename = "foot-o")]
+    FootO,
+    #[serde(rename = "mtbo")]
+    Mtbo,
+    #[serde(rename = "ski-o")]
+    SkiO,
+}
+
+impl Sport {
+    pub const ALL: [Sport; 3] = [Sport::FootO, Sport::Mtbo, Sport::SkiO];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sport::FootO => "foot-o",
+            Sport::Mtbo => "mtbo",
+            Sport::SkiO => "ski-o",
+        }
+    }
+    /// QuickEvent `event.sportId`
+    pub fn quickevent_id(&self) -> i64 {
+        match self {
+            Sport::FootO => 1,
+            Sport::SkiO => 2,
+            Sport::Mtbo => 3,
+        }
+    }
+    pub fn from_quickevent_id(id: i64) -> Option<Self> {
+        Self::ALL.into_iter().find(|sport| sport.quickevent_id() == id)
+    }
+}
+
+impl std::str::FromStr for Sport {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|sport| sport.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("Invalid sport: '{s}', expected one of foot-o, mtbo, ski-o"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventStatus {
+    pub current_stage: i64,
+    pub is_local: bool,
+    pub open_at: DateTime<chrono::FixedOffset>,
+    pub expires_at: DateTime<chrono::FixedOffset>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub name: String,
+    pub date: DateTime<chrono::FixedOffset>,
+    #[serde(default = "default_stage")]
+    pub stage: i64,
+    pub owner: String,
+    pub api_token: String,
+    pub is_local: bool,
+    /// Mount point of already running remote event DB service, default mount is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_mount: Option<String>,
+    #[serde(default)]
+    pub sport: Sport,
+    /// Organization the event belongs to, event is available in organization subtree `eventctl/<org>/<id>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization_id: Option<i64>,
+    /// Reason why the event cannot be opened, it is set by consistency check of event directories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broken: Option<String>,
+}
+
+impl_rpcvalue_conversions!(EventStatus);
+impl_rpcvalue_conversions!(EventRecord);
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct EventRecordChange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub date: Option<DateTime<chrono::FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub api_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub is_local: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub remote_mount: Option<String>,
+}
+
+impl EventRecordChange {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.date.is_none()
+            && self.stage.is_none()
+            && self.owner.is_none()
+            && self.api_token.is_none()
+            && self.is_local.is_none()
+            && self.remote_mount.is_none()
+    }
+}
+
+impl_rpcvalue_conversions!(EventRecordChange);
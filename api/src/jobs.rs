@@ -0,0 +1,92 @@
+use anyhow::anyhow;
+use shvproto::{RpcValue, make_map};
+
+use crate::EventId;
+
+pub type JobId = i64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub const ALL: [JobState; 5] = [JobState::Queued, JobState::Running, JobState::Finished, JobState::Failed, JobState::Cancelled];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Finished => "finished",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+impl std::str::FromStr for JobState {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|state| state.as_str() == s)
+            .ok_or_else(|| anyhow!("Invalid job state: '{s}'"))
+    }
+}
+
+/// Result of `status` method and param of `progress` signal of job node
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+    pub id: JobId,
+    pub name: String,
+    pub event_id: Option<EventId>,
+    pub state: JobState,
+    pub done: i64,
+    pub total: i64,
+    pub error: Option<String>,
+    /// Return value of finished job
+    pub result: Option<RpcValue>,
+}
+
+impl From<&JobStatus> for RpcValue {
+    fn from(status: &JobStatus) -> Self {
+        make_map!(
+            "id" => status.id,
+            "name" => status.name.clone(),
+            "eventId" => status.event_id.map(RpcValue::from).unwrap_or_else(RpcValue::null),
+            "state" => status.state.as_str(),
+            "done" => status.done,
+            "total" => status.total,
+            "error" => status.error.clone().map(RpcValue::from).unwrap_or_else(RpcValue::null),
+            "result" => status.result.clone().unwrap_or_else(RpcValue::null),
+        ).into()
+    }
+}
+
+impl TryFrom<&RpcValue> for JobStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &RpcValue) -> Result<Self, Self::Error> {
+        if !value.is_map() {
+            return Err(anyhow!("Job status must be a map"));
+        }
+        let map = value.as_map();
+        let field = |name: &str| map.get(name).filter(|v| !v.is_null());
+        Ok(Self {
+            id: field("id").ok_or_else(|| anyhow!("Job status id is missing"))?.as_int(),
+            name: field("name").map(|v| v.as_str().to_string()).unwrap_or_default(),
+            event_id: field("eventId").map(RpcValue::as_int),
+            state: field("state").ok_or_else(|| anyhow!("Job status state is missing"))?.as_str().parse()?,
+            done: field("done").map(RpcValue::as_int).unwrap_or_default(),
+            total: field("total").map(RpcValue::as_int).unwrap_or_default(),
+            error: field("error").map(|v| v.as_str().to_string()),
+            result: field("result").cloned(),
+        })
+    }
+}
@@ -0,0 +1,12 @@
+//! Param and result types of qxeventd SHV API. Rust clients can depend on this crate instead of building
+//! and parsing `RpcValue` maps by hand, the daemon uses the same types, so they cannot get out of sync.
+
+#[macro_use]
+mod macros;
+mod event;
+mod jobs;
+mod results;
+
+pub use event::{DEFAULT_STAGE, EventId, EventRecord, EventRecordChange, EventStatus, Sport};
+pub use jobs::{JobId, JobState, JobStatus};
+pub use results::{ClassDelta, ResultDelta, ResultRow, TopRow};
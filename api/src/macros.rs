@@ -1,14 +1,17 @@
 /// Macro to generate bidirectional RpcValue conversion implementations for types with Serialize/Deserialize
 ///
-/// This macro generates three `From` trait implementations:
+/// This macro generates these conversions:
 /// - `From<&Type> for RpcValue` - Convert a reference to RpcValue
 /// - `From<Type> for RpcValue` - Convert an owned value to RpcValue
-/// - `From<&RpcValue> for Type` - Convert RpcValue back to Type
+/// - `TryFrom<&RpcValue> for Type` - Convert RpcValue back to Type
+/// - `TryFrom<Option<&RpcValue>> for Type` - Convert optional method param to Type
 ///
 /// # Example
 ///
 /// ```
+/// use qxeventd_api::impl_rpcvalue_conversions;
 /// use serde::{Deserialize, Serialize};
+/// use shvproto::RpcValue;
 ///
 /// #[derive(Debug, Clone, Serialize, Deserialize)]
 /// struct MyData {
@@ -18,10 +21,10 @@
 ///
 /// impl_rpcvalue_conversions!(MyData);
 ///
-/// // Now you can use:
 /// let data = MyData { name: "test".to_string(), value: 42 };
 /// let rpc_value: RpcValue = data.into();
-/// let data_back: MyData = (&rpc_value).into();
+/// let data_back = MyData::try_from(&rpc_value).unwrap();
+/// assert_eq!(data_back.value, 42);
 /// ```
 #[macro_export]
 macro_rules! impl_rpcvalue_conversions {
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+/// Result row of results endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResultRow {
+    pub run_id: i64,
+    pub class_id: i64,
+    pub class_name: String,
+    pub start_number: Option<i64>,
+    pub name: String,
+    pub registration: String,
+    pub club: String,
+    pub start_time_ms: Option<i64>,
+    pub finish_time_ms: Option<i64>,
+    pub time_ms: Option<i64>,
+    /// IOF XML competitor status
+    pub status: String,
+    pub place: Option<usize>,
+    /// Time adjustment of upheld protests included in `time_ms`
+    pub adjustment_ms: i64,
+    pub reinstated: bool,
+}
+
+/// Leaderboard row with minimal payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopRow {
+    pub place: usize,
+    pub name: String,
+    pub club: String,
+    pub time_ms: i64,
+    /// Result has changed within `results_top.recent_change`
+    pub recent: bool,
+}
+
+/// Changed result of run, removed run has moved to other class or it does not run anymore
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResultDelta {
+    pub run_id: i64,
+    pub place: Option<usize>,
+    pub time_ms: Option<i64>,
+    /// IOF XML competitor status
+    pub status: String,
+    pub removed: bool,
+}
+
+/// Param of `resultdelta` signal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClassDelta {
+    pub class_id: i64,
+    pub changes: Vec<ResultDelta>,
+}
+
+impl_rpcvalue_conversions!(ResultRow);
+impl_rpcvalue_conversions!(TopRow);
+impl_rpcvalue_conversions!(ClassDelta);
@@ -0,0 +1,206 @@
+use anyhow::{anyhow, bail};
+use clap::Subcommand;
+use log::info;
+use qxsql::DbValue;
+use qxsql::sql::{QxSqlApi, record_from_slice};
+
+use crate::appsqlapi::AppSqlApi;
+use crate::eventdb::{migrate_event_db_to, migrate_existing_db};
+use crate::jobs::JobProgress;
+use crate::migrate::create_db_connection;
+use crate::state::{EventId, event_db_file};
+use crate::{generate_api_token, global_config};
+
+/// Offline administration commands, they work with master and event DB files directly
+/// and do not connect to the broker.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Connect to the broker and serve events, this is the default
+    Run,
+    /// Apply pending migrations to the master DB and all existing event DBs
+    Migrate {
+        /// Migrate single event DB only, master DB is not migrated
+        #[arg(long)]
+        event: Option<EventId>,
+        /// Migrate the event DB up or down to this schema version instead of the latest one,
+        /// the event DB is backed up first
+        #[arg(long, requires = "event")]
+        to: Option<usize>,
+    },
+    /// Copy the master DB and all existing event DBs to a directory
+    Backup {
+        /// Destination directory, it is created if it does not exist
+        dest_dir: String,
+    },
+    /// Event management
+    Events {
+        #[command(subcommand)]
+        command: EventsCommand,
+    },
+    /// Event API token management
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum EventsCommand {
+    /// List all events in the master DB
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokenCommand {
+    /// Generate new API token for event and print it. Broker mount of remote event is registered with its token,
+    /// the running daemon must rotate it by `eventctl:updateEventRecord`, which unregisters the old mount.
+    Rotate {
+        event_id: EventId,
+        /// Rotate token of remote event while the daemon is stopped, the old broker mount is pruned when it connects
+        #[arg(long)]
+        offline: bool,
+    },
+}
+
+pub async fn run_command(command: Command) -> anyhow::Result<()> {
+    if global_config().data_dir.is_empty() {
+        bail!("Offline administration requires data directory to be set");
+    }
+    match command {
+        Command::Run => unreachable!("Run command is handled by main"),
+        Command::Migrate { event: None, .. } => migrate().await,
+        Command::Migrate { event: Some(event_id), to } => migrate_event(event_id, to).await,
+        Command::Backup { dest_dir } => backup(&dest_dir).await,
+        Command::Events { command: EventsCommand::List } => list_events().await,
+        Command::Token { command: TokenCommand::Rotate { event_id, offline } } => rotate_token(event_id, offline).await,
+    }
+}
+
+async fn event_ids(qxsql: &AppSqlApi) -> anyhow::Result<Vec<EventId>> {
+    let result = qxsql.query("SELECT id FROM events ORDER BY id", None).await?;
+    Ok(result.rows.iter()
+        .filter_map(|row| row.first().and_then(|cell| cell.to_int()))
+        .collect())
+}
+
+/// Event DB files existing on disk, remote events without local file are skipped
+async fn existing_event_db_files(qxsql: &AppSqlApi) -> anyhow::Result<Vec<(EventId, String)>> {
+    Ok(event_ids(qxsql).await?.into_iter()
+        .map(|event_id| (event_id, event_db_file(event_id)))
+        .filter(|(_, db_file)| std::path::Path::new(db_file).is_file())
+        .collect())
+}
+
+async fn migrate() -> anyhow::Result<()> {
+    // master DB is migrated on connection
+    let db_pool = create_db_connection().await?;
+    let qxsql = AppSqlApi::new_without_recchng(db_pool);
+    for (event_id, db_file) in existing_event_db_files(&qxsql).await? {
+        info!("Migrating event {event_id} DB");
+        migrate_existing_db(&db_file).await?;
+    }
+    Ok(())
+}
+
+async fn migrate_event(event_id: EventId, to: Option<usize>) -> anyhow::Result<()> {
+    let db_file = event_db_file(event_id);
+    let Some(version) = to else {
+        info!("Migrating event {event_id} DB");
+        return migrate_existing_db(&db_file).await;
+    };
+    let result = migrate_event_db_to(&db_file, version).await?;
+    println!("Event {event_id} DB migrated from version {} to {}, backup: {}", result.from_version, result.to_version, result.backup_file);
+    Ok(())
+}
+
+async fn vacuum_into(db_pool: &async_sqlite::Pool, dest_file: String) -> anyhow::Result<()> {
+    db_pool.conn(move |conn| conn.execute("VACUUM INTO ?1", [dest_file])).await?;
+    Ok(())
+}
+
+/// Copy the master DB and all existing event DBs to `dest_dir`, returns written files
+pub(crate) async fn backup_databases(db_pool: &async_sqlite::Pool, dest_dir: &str, progress: Option<&JobProgress>) -> anyhow::Result<Vec<String>> {
+    std::fs::create_dir_all(dest_dir)?;
+    let master_dest = format!("{dest_dir}/qxevent.sqlite");
+    if std::path::Path::new(&master_dest).exists() {
+        bail!("Backup destination {master_dest} already exists");
+    }
+    let qxsql = AppSqlApi::new_without_recchng(db_pool.clone());
+    let event_db_files = existing_event_db_files(&qxsql).await?;
+    let total = event_db_files.len() as i64 + 1;
+    vacuum_into(db_pool, master_dest.clone()).await?;
+    let mut files = vec![master_dest];
+    if let Some(progress) = progress {
+        progress.report(1, total);
+    }
+    for (event_id, db_file) in event_db_files {
+        let event_dest_dir = format!("{dest_dir}/{event_id}");
+        std::fs::create_dir_all(&event_dest_dir)?;
+        let event_dest = format!("{event_dest_dir}/event.qbe");
+        let event_pool = crate::eventdb::open_event_db_pool(&db_file, Some(1)).await?;
+        vacuum_into(&event_pool, event_dest.clone()).await?;
+        files.push(event_dest);
+        if let Some(progress) = progress {
+            progress.report(files.len() as i64, total);
+        }
+    }
+    Ok(files)
+}
+
+async fn backup(dest_dir: &str) -> anyhow::Result<()> {
+    let db_pool = create_db_connection().await?;
+    for file in backup_databases(&db_pool, dest_dir, None).await? {
+        println!("{file}");
+    }
+    Ok(())
+}
+
+fn db_value_to_string(value: &DbValue) -> String {
+    match value {
+        DbValue::Null => String::new(),
+        DbValue::String(s) => s.clone(),
+        DbValue::Int(i) => i.to_string(),
+        DbValue::Bool(b) => b.to_string(),
+        DbValue::DateTime(dt) => dt.to_rfc3339(),
+        value => format!("{value:?}"),
+    }
+}
+
+async fn list_events() -> anyhow::Result<()> {
+    let db_pool = create_db_connection().await?;
+    let qxsql = AppSqlApi::new_without_recchng(db_pool);
+    let result = qxsql.query("SELECT id, name, date, owner, is_local, was_open, remote_mount FROM events ORDER BY id", None).await?;
+    println!("{}", result.fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>().join("\t"));
+    for row in &result.rows {
+        println!("{}", row.iter().map(db_value_to_string).collect::<Vec<_>>().join("\t"));
+    }
+    Ok(())
+}
+
+async fn rotate_token(event_id: EventId, offline: bool) -> anyhow::Result<()> {
+    let db_pool = create_db_connection().await?;
+    let qxsql = AppSqlApi::new_without_recchng(db_pool);
+    let result = qxsql.query("SELECT is_local FROM events WHERE id = :id", Some(&record_from_slice(&[
+        ("id", event_id.into()),
+    ]))).await?;
+    let is_local = result.value(0, 0).ok_or_else(|| anyhow!("Event id: {event_id} not found"))?.to_bool();
+    if !is_local && !offline {
+        bail!("Event id: {event_id} is remote, its broker mount is registered with the API token, \
+            rotate the token by eventctl:updateEventRecord of the running daemon, or stop the daemon and use --offline");
+    }
+    let api_token = generate_api_token();
+    let result = qxsql.exec("UPDATE events SET api_token = :api_token WHERE id = :id", Some(&record_from_slice(&[
+        ("api_token", api_token.clone().into()),
+        ("id", event_id.into()),
+    ]))).await?;
+    if result.rows_affected == 0 {
+        return Err(anyhow!("Event id: {event_id} not found"));
+    }
+    if is_local {
+        info!("Event {event_id} API token rotated");
+    } else {
+        info!("Event {event_id} API token rotated, old broker mount is pruned when the daemon connects, the new one is registered when the event is opened");
+    }
+    println!("{api_token}");
+    Ok(())
+}
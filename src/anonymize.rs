@@ -0,0 +1,71 @@
+//! Irreversible anonymization of past event. Personal identifiers are removed from competitors, registrations
+//! and entries, names are reduced to initials, so the results statistics (classes, clubs, times) stay valid.
+
+use anyhow::bail;
+use log::info;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+
+use crate::eventsqlapi::EventSqlApi;
+use crate::state::{EventId, EventRecord};
+
+/// Operation of `record_history` audit entry
+const HISTORY_ANONYMIZE: &str = "anonymize";
+
+fn initials(column: &str) -> String {
+    format!("CASE WHEN trim(coalesce({column}, '')) = '' THEN {column} ELSE upper(substr(trim({column}), 1, 1)) || '.' END")
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AnonymizeResult {
+    pub competitors: i64,
+    pub registrations: i64,
+    pub pending_entries: i64,
+    pub runs: i64,
+    pub cards: i64,
+    pub punches: i64,
+    pub notifications: i64,
+    /// Removed change history records, they contain original personal data
+    pub history: i64,
+}
+
+pub(crate) async fn anonymize_event(sql_api: &EventSqlApi, event_id: EventId, event_record: &EventRecord, issuer: Option<String>) -> anyhow::Result<AnonymizeResult> {
+    if event_record.date.date_naive() >= chrono::Local::now().date_naive() {
+        bail!("Event id: {event_id} has not finished yet, only past events can be anonymized");
+    }
+    let (first_name, last_name) = (initials("firstName"), initials("lastName"));
+    // all or nothing for local event, partially anonymized event cannot be anonymized again nor restored
+    let result = sql_api.with_savepoint(async |sql_api| {
+        let exec = async |query: String| -> anyhow::Result<i64> {
+            Ok(sql_api.exec(&query, None).await?.rows_affected)
+        };
+        let result = AnonymizeResult {
+            competitors: exec(format!("UPDATE competitors SET firstName = {first_name}, lastName = {last_name}, \
+                registration = NULL, iofId = NULL, licence = NULL, siId = NULL, note = NULL, importId = NULL, paymentReference = NULL, email = NULL, phone = NULL")).await?,
+            registrations: exec(format!("UPDATE registrations SET firstName = {first_name}, lastName = {last_name}, \
+                registration = NULL, licence = NULL, siId = NULL, importId = NULL")).await?,
+            pending_entries: exec(format!("UPDATE pending_entries SET firstName = {first_name}, lastName = {last_name}, \
+                registration = NULL, siId = NULL, email = NULL, note = NULL, statusKey = NULL, decidedBy = NULL")).await?,
+            runs: exec("UPDATE runs SET siId = NULL WHERE siId IS NOT NULL".to_string()).await?,
+            cards: exec("UPDATE cards SET siId = NULL WHERE siId IS NOT NULL".to_string()).await?,
+            punches: exec("UPDATE punches SET siId = NULL WHERE siId IS NOT NULL".to_string()).await?,
+            notifications: exec("UPDATE notifications SET recipient = NULL WHERE recipient IS NOT NULL".to_string()).await?,
+            history: exec("DELETE FROM record_history".to_string()).await?,
+        };
+        let new_values = shvproto::to_rpcvalue(&result)?.to_cpon();
+        sql_api.exec("INSERT INTO record_history (tableName, recordId, operation, newValues, issuer, changedAt) \
+            VALUES (:tableName, :recordId, :operation, :newValues, :issuer, :changedAt)", Some(&record_from_slice(&[
+            ("tableName", "event".into()),
+            ("recordId", event_id.into()),
+            ("operation", HISTORY_ANONYMIZE.into()),
+            ("newValues", new_values.into()),
+            ("issuer", issuer.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("changedAt", chrono::Local::now().fixed_offset().into()),
+        ]))).await?;
+        Ok(result)
+    }).await?;
+    info!("Event {event_id} anonymized by {}: {result:?}", issuer.unwrap_or_default());
+    Ok(result)
+}
@@ -0,0 +1,210 @@
+//! Machine-readable API description for generated client SDKs. Nodes and methods come from the static
+//! method tables, param and result signatures like `{i:classId,i|n:stageId}` are parsed to shapes.
+//! Signature types are `i` int, `d` and `f` double, `s` string, `b` bool, `x` blob, `n` null and `?` any,
+//! `{...}`, `{?}` and `{}` are maps of unspecified fields. Signature, which cannot be parsed, has no shape.
+
+use serde::Serialize;
+use shvrpc::metamethod::MetaMethod;
+
+use crate::{eventctlnode, jobsnode, league, webhooks};
+use crate::jobs::JOBS_SHV_PATH;
+use crate::league::SERIES_SHV_PATH;
+use crate::webhooks::WEBHOOKS_SHV_PATH;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Shape {
+    Int,
+    Double,
+    String,
+    Bool,
+    Blob,
+    Null,
+    Any,
+    /// Fields are not known if not set
+    Map { fields: Option<Vec<Field>> },
+    List { items: Box<Field> },
+    Tuple { items: Vec<Field> },
+    OneOf { variants: Vec<Field> },
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Field {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(flatten)]
+    shape: Shape,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MethodSchema {
+    name: String,
+    access: String,
+    param: String,
+    param_shape: Option<Field>,
+    result: String,
+    result_shape: Option<Field>,
+    description: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NodeSchema {
+    /// Path with placeholders in braces, like `eventctl/{eventId}/runs`
+    path: String,
+    methods: Vec<MethodSchema>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ApiSchema {
+    version: &'static str,
+    nodes: Vec<NodeSchema>,
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        if self.input[self.pos..].starts_with(s.as_bytes()) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_') {
+            self.pos += 1;
+        }
+        (self.pos > start).then(|| String::from_utf8_lossy(&self.input[start..self.pos]).into_owned())
+    }
+
+    /// Items of list or map up to `close`
+    fn items(&mut self, close: u8) -> Option<Vec<Field>> {
+        let mut items = vec![];
+        if self.eat(close) {
+            return Some(items);
+        }
+        loop {
+            items.push(self.field()?);
+            if self.eat(close) {
+                return Some(items);
+            }
+            if !self.eat(b',') {
+                return None;
+            }
+        }
+    }
+
+    fn shape(&mut self) -> Option<Shape> {
+        if self.eat_str("{...}") || self.eat_str("{?}") || self.eat_str("{}") {
+            return Some(Shape::Map { fields: None });
+        }
+        let shape = match self.peek()? {
+            b'i' => Shape::Int,
+            b'd' | b'f' => Shape::Double,
+            b's' => Shape::String,
+            b'b' => Shape::Bool,
+            b'x' => Shape::Blob,
+            b'n' => Shape::Null,
+            b'?' => Shape::Any,
+            b'{' => {
+                self.pos += 1;
+                return Some(Shape::Map { fields: Some(self.items(b'}')?) });
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = self.items(b']')?;
+                return Some(match items.len() {
+                    1 => Shape::List { items: Box::new(items.remove(0)) },
+                    _ => Shape::Tuple { items },
+                });
+            }
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(shape)
+    }
+
+    /// Alternatives separated by `|`, name of the last alternative names the whole union, like in `i|n:stageId`
+    fn field(&mut self) -> Option<Field> {
+        let mut variants = vec![];
+        loop {
+            let shape = self.shape()?;
+            let name = if self.eat(b':') { Some(self.name()?) } else { None };
+            variants.push(Field { name, shape });
+            if !self.eat(b'|') {
+                break;
+            }
+        }
+        if variants.len() == 1 {
+            return variants.pop();
+        }
+        let unnamed_variants = variants.iter().rev().skip(1).all(|variant| variant.name.is_none());
+        let name = if unnamed_variants { variants.last_mut().and_then(|variant| variant.name.take()) } else { None };
+        Some(Field { name, shape: Shape::OneOf { variants } })
+    }
+}
+
+/// Shape of method signature, `None` if the signature is empty or it cannot be parsed
+fn parse_signature(signature: &str) -> Option<Field> {
+    if signature.is_empty() {
+        return None;
+    }
+    let mut parser = Parser { input: signature.as_bytes(), pos: 0 };
+    parser.field().filter(|_| parser.pos == signature.len())
+}
+
+fn node_schema(path: String, methods: &[MetaMethod]) -> NodeSchema {
+    NodeSchema {
+        path,
+        methods: methods.iter()
+            .map(|mm| MethodSchema {
+                name: mm.name.to_string(),
+                access: format!("{:?}", mm.access).to_lowercase(),
+                param: mm.param.to_string(),
+                param_shape: parse_signature(&mm.param),
+                result: mm.result.to_string(),
+                result_shape: parse_signature(&mm.result),
+                description: mm.description.to_string(),
+            })
+            .collect(),
+    }
+}
+
+/// Schema of all mounted nodes, `.app` and `sql` methods are passed by their static nodes
+pub(crate) fn api_schema(app_methods: &[MetaMethod], sql_methods: &[MetaMethod]) -> ApiSchema {
+    let mut nodes = vec![
+        node_schema(".app".to_string(), app_methods),
+        node_schema("sql".to_string(), sql_methods),
+        node_schema(JOBS_SHV_PATH.to_string(), jobsnode::JOBS_NODE_METHODS),
+        node_schema(format!("{JOBS_SHV_PATH}/{{jobId}}"), jobsnode::JOB_NODE_METHODS),
+        node_schema(SERIES_SHV_PATH.to_string(), league::SERIES_ROOT_METHODS),
+        node_schema(format!("{SERIES_SHV_PATH}/{{seriesId}}"), league::SERIES_NODE_METHODS),
+        node_schema(WEBHOOKS_SHV_PATH.to_string(), webhooks::WEBHOOKS_ROOT_METHODS),
+        node_schema(format!("{WEBHOOKS_SHV_PATH}/{{webhookId}}"), webhooks::WEBHOOK_NODE_METHODS),
+    ];
+    nodes.extend(eventctlnode::schema_nodes().into_iter()
+        .map(|(path, methods)| node_schema(if path.is_empty() { "eventctl".to_string() } else { format!("eventctl/{path}") }, methods)));
+    ApiSchema { version: env!("CARGO_PKG_VERSION"), nodes }
+}
@@ -9,7 +9,7 @@ use shvrpc::metamethod::{AccessLevel, MetaMethod, Flags};
 use shvrpc::{RpcMessageMetaTags, RpcMessage, rpcmessage::RpcError};
 use shvproto::RpcValue;
 
-use crate::{anyhow_to_rpc_error, global_config};
+use crate::{SqlNode, anyhow_to_rpc_error, apischema, global_config, reconcile, selftest, sqlstats};
 use crate::state::SharedAppState;
 
 pub struct AppNode {
@@ -38,6 +38,14 @@ fn get_methods() -> &'static [MetaMethod] {
 
 const METH_CONFIG: &str = "config";
 const METH_QUIT: &str = "quit";
+const METH_SET_LOG_LEVEL: &str = "setLogLevel";
+const METH_SQL_STATS: &str = "sqlStats";
+const METH_RESET_SQL_STATS: &str = "resetSqlStats";
+const METH_DB_MAINTENANCE: &str = "dbMaintenance";
+const METH_RECONCILE_EVENTS: &str = "reconcileEvents";
+const METH_DISK_SPACE: &str = "diskSpace";
+const METH_SELF_TEST: &str = "selfTest";
+const METH_API_SCHEMA: &str = "apiSchema";
 
 pub const APP_METHODS: &[MetaMethod] = &[
     MetaMethod::new_static(
@@ -46,6 +54,30 @@ pub const APP_METHODS: &[MetaMethod] = &[
     MetaMethod::new_static(
         METH_QUIT, Flags::None, AccessLevel::Write, "", "", &[], "",
     ),
+    MetaMethod::new_static(
+        METH_SET_LOG_LEVEL, Flags::None, AccessLevel::Service, "s:spec", "s:log_spec", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_STATS, Flags::None, AccessLevel::Read, "", "[{s:statement,i:count,i:errors,i:slow,d:totalMs,d:maxMs}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_RESET_SQL_STATS, Flags::None, AccessLevel::Service, "", "", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DB_MAINTENANCE, Flags::None, AccessLevel::Read, "", "[{i:eventId,i:runs,i:reclaimedBytes,i:totalReclaimedBytes,i:dbSizeBytes,s|n:lastRun,s|n:lastError}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DISK_SPACE, Flags::None, AccessLevel::Read, "", "{s:level,i|n:freeMb,s|n:checkedAt}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SELF_TEST, Flags::None, AccessLevel::Service, "", "{b:ok,[{s:name,b:ok,b:skipped,i:durationMs,s|n:message}]:checks}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_API_SCHEMA, Flags::None, AccessLevel::Browse, "", "{s:version,[{s:path,[{...}]:methods}]:nodes}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_RECONCILE_EVENTS, Flags::None, AccessLevel::Service, "s|n:repair", "{[i]:missingDirs,[s]:orphanedDirs,[i]:recreated,[i]:markedBroken,[i]:unmarkedBroken}", &[], "",
+    ),
 ];
 
 #[async_trait]
@@ -57,18 +89,75 @@ impl StaticNode for AppNode {
     async fn process_request(&self, request: RpcMessage, client_command_sender: ClientCommandSender) -> Option<Result<RpcValue, RpcError>> {
         match request.method() {
             Some(METH_CONFIG) => {
-                match serde_yaml::to_string(global_config()) {
+                match global_config().to_redacted_value().and_then(|config| Ok(serde_yaml::to_string(&config)?)) {
                     Ok(s) => Some(Ok(shvproto::RpcValue::from(s))),
                     Err(e) => Some(Err(anyhow_to_rpc_error(anyhow!("Failed to serialize configuration: {}", e)))),
                 }
             }
             Some(METH_QUIT) => {
                 log::info!("Exit method called, initiating graceful shutdown");
-                match self.app_state.write().await.quit_app(client_command_sender).await {
+                match self.app_state.quit_app(client_command_sender).await {
                     Ok(_) => Some(Ok(shvproto::RpcValue::from(()))),
                     Err(e) => Some(Err(anyhow_to_rpc_error(e))),
                 }
             }
+            Some(METH_SET_LOG_LEVEL) => {
+                let spec = request.param().unwrap_or_default().as_str();
+                log::info!("Setting log level to: {spec}");
+                match crate::logger::set_log_level(spec) {
+                    Ok(log_spec) => Some(Ok(shvproto::RpcValue::from(log_spec))),
+                    Err(e) => Some(Err(anyhow_to_rpc_error(e))),
+                }
+            }
+            Some(METH_SQL_STATS) => {
+                match shvproto::to_rpcvalue(&sqlstats::statement_stats()) {
+                    Ok(stats) => Some(Ok(stats)),
+                    Err(e) => Some(Err(anyhow_to_rpc_error(anyhow!("Failed to serialize SQL statistics: {}", e)))),
+                }
+            }
+            Some(METH_RESET_SQL_STATS) => {
+                sqlstats::reset();
+                Some(Ok(shvproto::RpcValue::from(())))
+            }
+            Some(METH_DB_MAINTENANCE) => {
+                match shvproto::to_rpcvalue(&self.app_state.maintenance.stats()) {
+                    Ok(stats) => Some(Ok(stats)),
+                    Err(e) => Some(Err(anyhow_to_rpc_error(anyhow!("Failed to serialize DB maintenance statistics: {}", e)))),
+                }
+            }
+            Some(METH_DISK_SPACE) => {
+                match shvproto::to_rpcvalue(&self.app_state.disk_space.status()) {
+                    Ok(status) => Some(Ok(status)),
+                    Err(e) => Some(Err(anyhow_to_rpc_error(anyhow!("Failed to serialize disk space status: {}", e)))),
+                }
+            }
+            Some(METH_SELF_TEST) => {
+                let report = selftest::self_test(self.app_state.clone(), client_command_sender).await;
+                match shvproto::to_rpcvalue(&report) {
+                    Ok(report) => Some(Ok(report)),
+                    Err(e) => Some(Err(anyhow_to_rpc_error(anyhow!("Failed to serialize self test report: {}", e)))),
+                }
+            }
+            Some(METH_RECONCILE_EVENTS) => {
+                let repair = request.param().unwrap_or_default().as_str();
+                let report = async {
+                    let repair = reconcile::parse_repair(repair)?;
+                    reconcile::reconcile_events(&self.app_state, repair).await
+                }.await;
+                match report {
+                    Ok(report) => Some(shvproto::to_rpcvalue(&report)
+                        .map_err(|e| anyhow_to_rpc_error(anyhow!("Failed to serialize consistency report: {}", e)))),
+                    Err(e) => Some(Err(anyhow_to_rpc_error(e))),
+                }
+            }
+            Some(METH_API_SCHEMA) => {
+                let sql_node = SqlNode { app_state: self.app_state.clone() };
+                let schema = apischema::api_schema(get_methods(), sql_node.methods());
+                match shvproto::to_rpcvalue(&schema) {
+                    Ok(schema) => Some(Ok(schema)),
+                    Err(e) => Some(Err(anyhow_to_rpc_error(anyhow!("Failed to serialize API schema: {}", e)))),
+                }
+            }
             _ => self.dot_app_node.process_request(request, client_command_sender).await,
         }
     }
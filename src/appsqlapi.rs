@@ -1,8 +1,188 @@
+use std::ops::Range;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail};
+use async_sqlite::rusqlite::{Batch, OptionalExtension};
 use async_sqlite::rusqlite::types::ValueRef;
 use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use log::warn;
 use qxsql::{DbValue, RecChng, sql::{DbField, ExecResult, QueryResult}};
 use qxsql::sql::Record;
+use serde::{Deserialize, Serialize};
 use shvclient::ClientCommandSender;
+use shvproto::RpcValue;
+
+use crate::sqlstats;
+
+pub(crate) const UPSERT_PARAMS: &str = "[s:table,s:keyField,[{}]:records]";
+pub(crate) const UPSERT_RESULT: &str = "[{i:id,s:status}]";
+
+/// `[table, key_field, records]`, records are matched to existing rows by `key_field`, typically `importId`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct UpsertParams(pub String, pub String, pub Vec<Record>);
+impl_rpcvalue_conversions!(UpsertParams);
+
+pub(crate) fn query_result_to_records(result: &QueryResult) -> Vec<Record> {
+    (0..result.row_count())
+        .map(|row| {
+            result.fields.iter().enumerate()
+                .map(|(col, field)| (field.name.clone(), result.value(row, col).cloned().unwrap_or(DbValue::Null)))
+                .collect()
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UpsertStatus {
+    Created,
+    Updated,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct UpsertResult {
+    pub id: i64,
+    pub status: UpsertStatus,
+}
+
+pub(crate) const SQL_QUERY_PARAMS: &str = "[s:query,{}|[?]|n:params]";
+
+/// Param of `query` and `exec` methods, `params` are record of named params, or list of positional params
+/// bound to `?` and `?NNN` placeholders. Positional params are bound as named params `:pNNN`.
+#[derive(Debug, Clone)]
+pub(crate) struct SqlQueryParams {
+    pub query: String,
+    pub params: Option<Record>,
+}
+
+impl TryFrom<&RpcValue> for SqlQueryParams {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &RpcValue) -> Result<Self, Self::Error> {
+        let (query, params) = if value.is_list() {
+            let list = value.as_list();
+            (list.first(), list.get(1))
+        } else if value.is_map() {
+            let map = value.as_map();
+            (map.get("query"), map.get("params"))
+        } else {
+            (None, None)
+        };
+        let query = query.filter(|query| query.is_string()).ok_or_else(|| anyhow!("Query string is required"))?.as_str();
+        match params {
+            Some(params) if params.is_list() => {
+                let values = params.as_list().iter()
+                    .map(rpcvalue_to_dbvalue)
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .map_err(|e| anyhow!("Invalid positional params: {e}"))?;
+                let (query, params) = bind_positional_params(query, values)?;
+                Ok(Self { query, params: Some(params) })
+            }
+            Some(params) if params.is_map() => {
+                if !scan_placeholders(query).positional.is_empty() {
+                    bail!("Query with positional placeholders requires list of params");
+                }
+                let params = params.as_map().iter()
+                    .map(|(key, value)| Ok((key.clone(), rpcvalue_to_dbvalue(value).map_err(|e| anyhow!("Invalid param {key}: {e}"))?)))
+                    .collect::<anyhow::Result<Record>>()?;
+                Ok(Self { query: query.to_string(), params: Some(params) })
+            }
+            Some(params) if !params.is_null() => bail!("Params must be map, list or null"),
+            _ => {
+                if !scan_placeholders(query).positional.is_empty() {
+                    bail!("Query with positional placeholders requires list of params");
+                }
+                Ok(Self { query: query.to_string(), params: None })
+            }
+        }
+    }
+}
+
+/// SQL param value of RPC value, every value is converted to `DbValue` it is stored as:
+/// - `UInt` is `Int`, value above `i64::MAX` is rejected instead of wrapping to negative
+/// - `DateTime` keeps its UTC offset
+/// - `Decimal` is `Double` stored as REAL
+/// - `List` is CPON `String` stored as TEXT, it is decoded on read from column declared as `list`
+/// - maps have no SQL representation and they are rejected
+pub(crate) fn rpcvalue_to_dbvalue(value: &RpcValue) -> anyhow::Result<DbValue> {
+    use shvproto::rpcvalue::Value;
+    Ok(match &value.value {
+        Value::Null => DbValue::Null,
+        Value::Bool(b) => DbValue::Bool(*b),
+        Value::Int(n) => DbValue::Int(*n),
+        Value::UInt(n) => DbValue::Int(i64::try_from(*n).map_err(|_| anyhow!("Unsigned value {n} is out of SQL integer range"))?),
+        Value::Double(d) => DbValue::Double(*d),
+        Value::String(s) => s.as_str().into(),
+        Value::Blob(b) => b.as_slice().into(),
+        Value::DateTime(dt) => DbValue::DateTime(dt.to_chrono_datetime()),
+        Value::Decimal(d) => DbValue::Double(d.to_f64()),
+        Value::List(_) => value.to_cpon().into(),
+        Value::Map(_) | Value::IMap(_) => bail!("Value {} cannot be SQL param", value.to_cpon()),
+    })
+}
+
+impl TryFrom<Option<&RpcValue>> for SqlQueryParams {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Option<&RpcValue>) -> Result<Self, Self::Error> {
+        value.ok_or_else(|| anyhow!("RpcValue is None")).and_then(Self::try_from)
+    }
+}
+
+pub(crate) const EXPLAIN_PARAMS: &str = "{s:query,{}|n:params,b|n:analyze}";
+pub(crate) const EXPLAIN_RESULT: &str = "{[{i:id,i:parent,s:detail}]:plan,i|n:rowCount,d|n:durationMs}";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExplainParams {
+    pub query: String,
+    #[serde(default)]
+    pub params: Option<Record>,
+    /// Execute read only statement to get its row count and duration
+    #[serde(default)]
+    pub analyze: bool,
+}
+impl_rpcvalue_conversions!(ExplainParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QueryPlanStep {
+    pub id: i64,
+    pub parent: i64,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExplainResult {
+    pub plan: Vec<QueryPlanStep>,
+    pub row_count: Option<i64>,
+    pub duration_ms: Option<f64>,
+}
+
+pub(crate) const EXEC_SCRIPT_PARAMS: &str = "{s:script}";
+pub(crate) const EXEC_SCRIPT_RESULT: &str = "{[i]:rowsAffected,{i:index,s:message}|n:error}";
+
+/// Semicolon separated SQL statements, like pasted fixture data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExecScriptParams {
+    pub script: String,
+}
+impl_rpcvalue_conversions!(ExecScriptParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ScriptError {
+    /// 0-based index of failed statement
+    pub index: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExecScriptResult {
+    /// Affected rows of statements executed before the error, they are rolled back if error is set
+    pub rows_affected: Vec<i64>,
+    pub error: Option<ScriptError>,
+}
 
 pub struct AppSqlApi(async_sqlite::Pool, Option<ClientCommandSender>);
 
@@ -13,6 +193,167 @@ impl AppSqlApi {
     pub fn new_without_recchng(pool: async_sqlite::Pool) -> Self {
         Self(pool, None)
     }
+    /// Execute statement in a transaction, which is always rolled back, to find out number of affected rows
+    pub async fn exec_dry_run(&self, query: &str, params: Option<&Record>) -> anyhow::Result<ExecResult> {
+        let query = query.to_string();
+        let params = process_record_params(params.unwrap_or(&Record::default()))?;
+        let result = self.0
+            .conn_mut(move |conn| {
+                let tx = conn.transaction()?;
+                let rows_affected = {
+                    let param_refs = create_param_refs(&params);
+                    let mut stmt = tx.prepare(&query)?;
+                    stmt.execute(&param_refs[..])?
+                };
+                tx.rollback()?;
+                Ok(ExecResult { rows_affected: rows_affected as i64, insert_id: None })
+            })
+            .await?;
+        Ok(result)
+    }
+    /// Execute INSERT, UPDATE or DELETE statement with RETURNING clause and return its rows,
+    /// so generated ids and default values do not need a follow-up query
+    pub async fn exec_returning(&self, query: &str, params: Option<&Record>) -> anyhow::Result<QueryResult> {
+        let query = query.to_string();
+        let params = process_record_params(params.unwrap_or(&Record::default()))?;
+        let result = self.0
+            .conn(move |conn| {
+                let returning = conn.prepare(&query).map(|stmt| !stmt.readonly() && stmt.column_count() > 0)?;
+                if !returning {
+                    return Ok(None);
+                }
+                let started = Instant::now();
+                let result = conn_query(conn, &query, &params);
+                sqlstats::record(&query, &params, started.elapsed(), result.is_ok());
+                result.map(Some)
+            })
+            .await?;
+        result.ok_or_else(|| anyhow!("Only writing statement with RETURNING clause can be executed"))
+    }
+    /// Execute statements of `script` one by one in a transaction, which is committed only if all of them succeed.
+    /// Rows returned by a statement are dropped.
+    pub async fn exec_script(&self, script: &str) -> anyhow::Result<ExecScriptResult> {
+        let script = script.to_string();
+        let result = self.0
+            .conn_mut(move |conn| {
+                let started = Instant::now();
+                let tx = conn.transaction()?;
+                let result = conn_exec_script(&tx, &script);
+                if result.error.is_none() {
+                    tx.commit()?;
+                }
+                sqlstats::record(&script, &[], started.elapsed(), result.error.is_none());
+                Ok(result)
+            })
+            .await?;
+        Ok(result)
+    }
+    /// SQLite query plan of statement, read only statement is executed with `analyze` too,
+    /// to get its actual row count and duration. Other statements cannot be analyzed.
+    pub async fn explain(&self, query: &str, params: Option<&Record>, analyze: bool) -> anyhow::Result<ExplainResult> {
+        let query = query.to_string();
+        let params = process_record_params(params.unwrap_or(&Record::default()))?;
+        let (plan, readonly, analysis) = self.0
+            .conn(move |conn| {
+                let param_refs = create_param_refs(&params);
+                let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {query}"))?;
+                let plan = stmt
+                    .query_map(&param_refs[..], |row| Ok(QueryPlanStep { id: row.get(0)?, parent: row.get(1)?, detail: row.get(3)? }))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut stmt = conn.prepare(&query)?;
+                let readonly = stmt.readonly();
+                if !analyze || !readonly {
+                    return Ok((plan, readonly, None));
+                }
+                let started = Instant::now();
+                let mut rows = stmt.query(&param_refs[..])?;
+                let mut row_count = 0;
+                while rows.next()?.is_some() {
+                    row_count += 1;
+                }
+                Ok((plan, readonly, Some((row_count, started.elapsed().as_secs_f64() * 1000.))))
+            })
+            .await?;
+        if analyze && !readonly {
+            bail!("Only read only statement can be analyzed");
+        }
+        Ok(ExplainResult {
+            plan,
+            row_count: analysis.map(|(row_count, _)| row_count),
+            duration_ms: analysis.map(|(_, duration_ms)| duration_ms),
+        })
+    }
+    /// Insert or update every record matched by `key_field` value, whole batch is one transaction.
+    /// Results are in the order of `records`.
+    ///
+    /// Update increments `row_version` column, if it is set. Record carrying the version is updated only
+    /// if it was not changed meanwhile, the whole batch fails otherwise.
+    pub async fn upsert_records(&self, table: &str, key_field: &str, records: &[Record], row_version: Option<&str>) -> anyhow::Result<Vec<UpsertResult>> {
+        if !is_valid_sql_identifier(table) || !is_valid_sql_identifier(key_field) || !row_version.is_none_or(is_valid_sql_identifier) {
+            bail!("Invalid table, key field or row version name");
+        }
+        let key_param = format!(":{key_field}");
+        let mut batch = Vec::with_capacity(records.len());
+        for (ix, record) in records.iter().enumerate() {
+            if let Some((field, _)) = record.iter().find(|(field, _)| !is_valid_sql_identifier(field)) {
+                bail!("Invalid field name '{field}' in record {ix}");
+            }
+            let mut params = process_record_params(record)?;
+            let expected_version = match row_version.and_then(|row_version| params.iter().position(|(name, _)| name[1..] == *row_version)) {
+                Some(version_ix) => match params.remove(version_ix).1 {
+                    async_sqlite::rusqlite::types::Value::Integer(version) => Some(version),
+                    _ => bail!("Record {ix} has invalid {} value", row_version.unwrap_or_default()),
+                },
+                None => None,
+            };
+            let key_ix = params.iter()
+                .position(|(name, value)| *name == key_param && *value != async_sqlite::rusqlite::types::Value::Null)
+                .ok_or_else(|| anyhow!("Record {ix} has no {key_field} value"))?;
+            batch.push((key_ix, params, expected_version));
+        }
+        let select_query = format!("SELECT id FROM {table} WHERE {key_field} = ?1");
+        let table_name = table.to_string();
+        let version_column = row_version.map(str::to_string);
+        let results = self.0
+            .conn_mut(move |conn| {
+                let tx = conn.transaction()?;
+                let mut results = Vec::with_capacity(batch.len());
+                for (key_ix, params, expected_version) in &batch {
+                    let id: Option<i64> = tx.query_row(&select_query, [&params[*key_ix].1], |row| row.get(0)).optional()?;
+                    let mut param_refs = create_param_refs(params);
+                    let fields = params.iter().map(|(name, _)| &name[1..]);
+                    match id {
+                        Some(id) => {
+                            let mut assignments = fields.map(|field| format!("{field} = :{field}")).collect::<Vec<_>>();
+                            let mut condition = "id = :upsert_row_id".to_string();
+                            if let Some(row_version) = &version_column {
+                                assignments.push(format!("{row_version} = {row_version} + 1"));
+                                if let Some(expected_version) = expected_version {
+                                    condition.push_str(&format!(" AND {row_version} = :upsert_row_version"));
+                                    param_refs.push((":upsert_row_version", expected_version));
+                                }
+                            }
+                            param_refs.push((":upsert_row_id", &id));
+                            let updated = tx.execute(&format!("UPDATE {table_name} SET {} WHERE {condition}", assignments.join(", ")), &param_refs[..])?;
+                            if updated == 0 {
+                                // dropped transaction is rolled back
+                                return Ok(Err((id, expected_version.unwrap_or_default())));
+                            }
+                            results.push(UpsertResult { id, status: UpsertStatus::Updated });
+                        }
+                        None => {
+                            let (fields, values): (Vec<_>, Vec<_>) = fields.map(|field| (field.to_string(), format!(":{field}"))).unzip();
+                            tx.execute(&format!("INSERT INTO {table_name} ({}) VALUES ({})", fields.join(", "), values.join(", ")), &param_refs[..])?;
+                            results.push(UpsertResult { id: tx.last_insert_rowid(), status: UpsertStatus::Created });
+                        }
+                    }
+                }
+                tx.commit()?;
+                Ok(Ok(results))
+            })
+            .await?;
+        results.map_err(|(id, expected_version)| anyhow!("Conflict: {table} id: {id} was changed, expected {}: {expected_version}", row_version.unwrap_or_default()))
+    }
 }
 
 #[async_trait]
@@ -44,17 +385,282 @@ impl qxsql::QxSqlApiRecChng for AppSqlApi {
 
 }
 
+/// Savepoint open on a connection dedicated to its caller, statements executed through it are part of the savepoint.
+/// Record change signals are not sent for them.
+pub(crate) struct Savepoint {
+    pool: async_sqlite::Pool,
+    depth: usize,
+}
+
+impl Savepoint {
+    /// Nested savepoint, only its own changes are rolled back when `f` fails
+    pub async fn with_savepoint<T>(&self, f: impl AsyncFnOnce(&Savepoint) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let nested = Savepoint { pool: self.pool.clone(), depth: self.depth + 1 };
+        run_in_savepoint(&nested.pool, nested.depth, async || f(&nested).await).await
+    }
+}
+
+#[async_trait]
+impl qxsql::QxSqlApi for Savepoint {
+    async fn query(&self, query: &str, params: Option<&Record>) -> anyhow::Result<QueryResult> {
+        sql_query(&self.pool, query, params.unwrap_or(&Record::default())).await
+    }
+    async fn exec(&self, query: &str, params: Option<&Record>) -> anyhow::Result<ExecResult> {
+        sql_exec(&self.pool, query, params.unwrap_or(&Record::default())).await
+    }
+}
+
+/// Run `f` in savepoint, which is released when `f` succeeds and rolled back when it fails.
+/// Pool must have single connection not shared with other callers, to keep the savepoint across awaits.
+pub(crate) async fn with_savepoint<T>(pool: &async_sqlite::Pool, f: impl AsyncFnOnce(&Savepoint) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let savepoint = Savepoint { pool: pool.clone(), depth: 0 };
+    run_in_savepoint(pool, 0, async || f(&savepoint).await).await
+}
+
+/// Run `f` in savepoint of nesting `depth`. The outermost savepoint is wrapped in immediate transaction,
+/// so the write lock is taken before `f` reads anything and the transaction cannot fail on upgrade to write.
+pub(crate) async fn run_in_savepoint<T>(pool: &async_sqlite::Pool, depth: usize, f: impl AsyncFnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let name = format!("qxeventd_sp{depth}");
+    let begin = if depth == 0 { format!("BEGIN IMMEDIATE; SAVEPOINT {name}") } else { format!("SAVEPOINT {name}") };
+    pool.conn(move |conn| conn.execute_batch(&begin)).await?;
+    let result = f().await;
+    let end = match (&result, depth) {
+        (Ok(_), 0) => format!("RELEASE {name}; COMMIT"),
+        (Ok(_), _) => format!("RELEASE {name}"),
+        (Err(_), 0) => "ROLLBACK".to_string(),
+        (Err(_), _) => format!("ROLLBACK TO {name}; RELEASE {name}"),
+    };
+    let ended = pool.conn(move |conn| conn.execute_batch(&end)).await;
+    match (result, ended) {
+        (result, Ok(())) => result,
+        (Ok(_), Err(e)) => {
+            if depth == 0 {
+                // failed commit leaves the transaction open on the connection
+                let _ = pool.conn(|conn| conn.execute_batch("ROLLBACK")).await;
+            }
+            Err(e.into())
+        }
+        (Err(e), Err(rollback_error)) => {
+            warn!("Rollback to savepoint {name} failed: {rollback_error}");
+            Err(e)
+        }
+    }
+}
+
+/// Placeholders of SQL statement, string literals, quoted identifiers and comments are skipped
+#[derive(Debug, Default)]
+struct Placeholders {
+    /// Byte range and 1-based index of `?` and `?NNN` placeholders
+    positional: Vec<(Range<usize>, usize)>,
+    /// `:name`, `@name` or `$name` placeholder is used
+    named: bool,
+}
+
+fn scan_placeholders(query: &str) -> Placeholders {
+    let bytes = query.as_bytes();
+    let mut placeholders = Placeholders::default();
+    let mut next_index = 1;
+    let mut pos = 0;
+    let skip_to = |pos: usize, end: &str| query[pos..].find(end).map_or(bytes.len(), |ix| pos + ix + end.len());
+    while pos < bytes.len() {
+        match bytes[pos] {
+            // doubled quote inside of literal is scanned as two adjacent literals
+            b'\'' => pos = skip_to(pos + 1, "'"),
+            b'"' => pos = skip_to(pos + 1, "\""),
+            b'`' => pos = skip_to(pos + 1, "`"),
+            b'[' => pos = skip_to(pos + 1, "]"),
+            b'-' if bytes.get(pos + 1) == Some(&b'-') => pos = skip_to(pos + 2, "\n"),
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => pos = skip_to(pos + 2, "*/"),
+            b'?' => {
+                let start = pos;
+                pos += 1;
+                while bytes.get(pos).is_some_and(u8::is_ascii_digit) {
+                    pos += 1;
+                }
+                // SQLite gives bare `?` the index following the largest index used so far
+                let index = if pos > start + 1 { query[start + 1..pos].parse().unwrap_or(0) } else { next_index };
+                next_index = next_index.max(index + 1);
+                placeholders.positional.push((start..pos, index));
+            }
+            b':' | b'@' | b'$' if bytes.get(pos + 1).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_') => {
+                placeholders.named = true;
+                pos += 1;
+            }
+            _ => pos += 1,
+        }
+    }
+    placeholders
+}
+
+/// Rewrite positional placeholders to named params `:pNNN` and make record of `values` for them
+fn bind_positional_params(query: &str, values: Vec<DbValue>) -> anyhow::Result<(String, Record)> {
+    let placeholders = scan_placeholders(query);
+    if placeholders.named {
+        bail!("Query cannot mix named and positional params");
+    }
+    if placeholders.positional.iter().any(|(_, index)| *index == 0) {
+        bail!("Positional param index must start from 1");
+    }
+    let count = placeholders.positional.iter().map(|(_, index)| *index).max().unwrap_or_default();
+    if count != values.len() {
+        bail!("Query has {count} positional params, but {} values are given", values.len());
+    }
+    let mut rewritten = String::with_capacity(query.len());
+    let mut last = 0;
+    for (range, index) in &placeholders.positional {
+        rewritten.push_str(&query[last..range.start]);
+        rewritten.push_str(":p");
+        rewritten.push_str(&index.to_string());
+        last = range.end;
+    }
+    rewritten.push_str(&query[last..]);
+    let params = values.into_iter().enumerate()
+        .map(|(ix, value)| (format!("p{}", ix + 1), value))
+        .collect();
+    Ok((rewritten, params))
+}
+
+/// Byte offsets of whole word `keyword` in ASCII lowercase statement
+fn keyword_offsets(lower: &str, keyword: &str) -> Vec<usize> {
+    lower.match_indices(keyword)
+        .filter(|(ix, _)| {
+            let before = lower[..*ix].chars().next_back();
+            let after = lower[ix + keyword.len()..].chars().next();
+            before.is_none_or(char::is_whitespace) && after.is_none_or(char::is_whitespace)
+        })
+        .map(|(ix, _)| ix)
+        .collect()
+}
+
+fn is_named_param_used(query: &str, name: &str) -> bool {
+    let placeholder = format!(":{name}");
+    query.match_indices(&placeholder)
+        .any(|(ix, _)| !query[ix + placeholder.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// Query counting rows, which plain `DELETE` or `UPDATE` statement would change, with the params it uses.
+/// It is dry run of statement, which cannot be executed in rolled back transaction. Statements with more than one
+/// `WHERE`, with `LIMIT`, `RETURNING` or CTE are not supported, `None` is returned for them.
+pub(crate) fn dry_run_count_query(query: &str, params: Option<&Record>) -> Option<(String, Option<Record>)> {
+    let query = query.trim().trim_end_matches(';').trim_end();
+    let lower = query.to_ascii_lowercase();
+    if ["limit", "returning", "with"].iter().any(|keyword| !keyword_offsets(&lower, keyword).is_empty()) {
+        return None;
+    }
+    let words = lower.split_whitespace().collect::<Vec<_>>();
+    let wheres = keyword_offsets(&lower, "where");
+    let table = match words.as_slice() {
+        ["delete", "from", _, rest @ ..] if rest.is_empty() || rest[0] == "where" => query.split_whitespace().nth(2)?,
+        ["update", _, "set", ..] => query.split_whitespace().nth(1)?,
+        _ => return None,
+    };
+    if !is_valid_sql_identifier(table) || wheres.len() > 1 {
+        return None;
+    }
+    let count_query = match wheres.first() {
+        Some(ix) => format!("SELECT COUNT(*) FROM {table} {}", &query[*ix..]),
+        None => format!("SELECT COUNT(*) FROM {table}"),
+    };
+    let params = params.map(|params| params.iter()
+        .filter(|(name, _)| is_named_param_used(&count_query, name))
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect::<Record>());
+    Some((count_query, params))
+}
+
+/// Table and field names cannot be bound as SQL parameters, check them before formatting to a query
+pub(crate) fn is_valid_sql_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Bind value of SQL param, every `DbValue` variant has explicit SQLite storage class:
+/// - `Int` is INTEGER, `Bool` is INTEGER 0 or 1
+/// - `Double` is REAL, NaN is rejected, because SQLite would store it as NULL
+/// - `String` is TEXT, `DateTime` is RFC 3339 TEXT
+/// - `Blob` is BLOB, `Null` is NULL
 fn convert_dbvalue_to_sql(key: &str, value: &DbValue) -> Result<async_sqlite::rusqlite::types::Value, async_sqlite::rusqlite::Error> {
     match value {
         DbValue::String(s) => Ok(s.as_str().to_string().into()),
         DbValue::Int(i) => Ok((*i).into()),
+        DbValue::Double(d) if d.is_nan() => Err(async_sqlite::rusqlite::Error::ToSqlConversionFailure(
+            format!("NaN value of field {key} cannot be stored").into(),
+        )),
+        DbValue::Double(d) => Ok((*d).into()),
         DbValue::DateTime(dt) => Ok(dt.to_rfc3339().into()),
         DbValue::Null => Ok(async_sqlite::rusqlite::types::Value::Null),
         DbValue::Blob(b) => Ok(b.clone().into()),
         DbValue::Bool(b) => Ok((*b).into()),
-        _ => Err(async_sqlite::rusqlite::Error::ToSqlConversionFailure(
-            format!("Unsupported value type for field {}", key).into(),
-        )),
+    }
+}
+
+/// Declared type of result column, values of some types are decoded to other `DbValue` than the SQLite storage class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Plain,
+    /// `boolean` column stores 0 or 1, it is read back as `Bool`
+    Bool,
+    /// `timestamp` or `datetime` column stores text, it is read back as `DateTime`
+    DateTime,
+    /// `list` column stores CPON text of list param, it is read back as canonical CPON of the list,
+    /// `DbValue` has no list variant
+    List,
+}
+
+impl ColumnKind {
+    /// Expressions have no declared type, their values are not decoded
+    fn from_decl_type(decl_type: Option<&str>) -> Self {
+        match decl_type.map(str::to_ascii_lowercase).as_deref() {
+            Some("boolean" | "bool") => ColumnKind::Bool,
+            Some("timestamp" | "datetime") => ColumnKind::DateTime,
+            Some("list") => ColumnKind::List,
+            _ => ColumnKind::Plain,
+        }
+    }
+}
+
+/// Timestamp text is RFC 3339 written by bound `DateTime` params, or SQLite `CURRENT_TIMESTAMP` format in UTC
+fn parse_timestamp(s: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(s).ok().or_else(|| {
+        ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"].iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+            .map(|naive| naive.and_utc().fixed_offset())
+    })
+}
+
+/// Column value of query result, TEXT which is not valid UTF-8 is returned as blob instead of replacing invalid bytes.
+/// Timestamp or list, which cannot be parsed, is returned as string.
+fn convert_sql_to_dbvalue(value: ValueRef, kind: ColumnKind) -> DbValue {
+    match value {
+        ValueRef::Null => DbValue::Null,
+        ValueRef::Integer(i) if kind == ColumnKind::Bool => DbValue::Bool(i != 0),
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(r) => r.into(),
+        ValueRef::Text(t) => match (std::str::from_utf8(t), kind) {
+            (Ok(s), ColumnKind::DateTime) => parse_timestamp(s).map(DbValue::DateTime).unwrap_or_else(|| s.to_string().into()),
+            (Ok(s), ColumnKind::List) => RpcValue::from_cpon(s).ok()
+                .filter(RpcValue::is_list)
+                .map_or_else(|| s.to_string().into(), |list| list.to_cpon().into()),
+            (Ok(s), _) => s.to_string().into(),
+            (Err(_), _) => t.into(),
+        },
+        ValueRef::Blob(b) => b.into(),
+    }
+}
+
+/// Column value for RPC result, DateTime is passed as RFC 3339 string like in SQL params,
+/// non-finite double, which has no CPON representation, is passed as null
+pub(crate) fn db_value_to_rpcvalue(value: &DbValue) -> RpcValue {
+    match value {
+        DbValue::Null => RpcValue::null(),
+        DbValue::Int(n) => RpcValue::from(*n),
+        DbValue::Double(d) if !d.is_finite() => RpcValue::null(),
+        DbValue::Double(d) => RpcValue::from(*d),
+        DbValue::Bool(b) => RpcValue::from(*b),
+        DbValue::String(s) => RpcValue::from(s.as_str()),
+        DbValue::DateTime(dt) => RpcValue::from(dt.to_rfc3339()),
+        DbValue::Blob(b) => RpcValue::from(b.clone()),
     }
 }
 
@@ -76,6 +682,65 @@ pub(crate) fn create_param_refs(params: &[(String, async_sqlite::rusqlite::types
         .collect()
 }
 
+type SqlParams = Vec<(String, async_sqlite::rusqlite::types::Value)>;
+
+fn conn_query(conn: &async_sqlite::rusqlite::Connection, query: &str, params: &SqlParams) -> Result<QueryResult, async_sqlite::rusqlite::Error> {
+    let param_refs = create_param_refs(params);
+    let mut stmt = conn.prepare(query)?;
+    let fields: Vec<DbField> = stmt.column_names().iter().map(|s| DbField { name: s.to_string() }).collect();
+    let column_kinds: Vec<ColumnKind> = stmt.columns().iter().map(|column| ColumnKind::from_decl_type(column.decl_type())).collect();
+    let rows = stmt
+        .query_map(&param_refs[..], |row| {
+            let mut rec: Vec<DbValue> = Vec::new();
+            for i in 0..fields.len() {
+                rec.push(convert_sql_to_dbvalue(row.get_ref(i)?, column_kinds[i]));
+            }
+            Ok(rec)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(QueryResult { fields, rows })
+}
+
+fn conn_exec(conn: &async_sqlite::rusqlite::Connection, query: &str, params: &SqlParams) -> Result<ExecResult, async_sqlite::rusqlite::Error> {
+    let param_refs = create_param_refs(params);
+    let mut stmt = conn.prepare(query)?;
+    let rows_affected = stmt.execute(&param_refs[..])?;
+    let insert_id = (rows_affected > 0 && is_insert_statement(query)).then(|| conn.last_insert_rowid());
+    Ok(ExecResult { rows_affected: rows_affected as i64, insert_id })
+}
+
+/// Statement inserts rows, if its first keyword is INSERT or REPLACE, statements starting with WITH are not recognized
+fn is_insert_statement(query: &str) -> bool {
+    let keyword = query.trim_start().split(|c: char| !c.is_ascii_alphabetic()).next().unwrap_or_default();
+    keyword.eq_ignore_ascii_case("insert") || keyword.eq_ignore_ascii_case("replace")
+}
+
+/// Statements are executed until the first error, which is reported with index of failed statement
+fn conn_exec_script(conn: &async_sqlite::rusqlite::Connection, script: &str) -> ExecScriptResult {
+    let mut result = ExecScriptResult::default();
+    let mut batch = Batch::new(conn, script);
+    loop {
+        let index = result.rows_affected.len();
+        let rows_affected = match batch.next() {
+            Ok(None) => break,
+            Ok(Some(mut stmt)) if stmt.column_count() > 0 => stmt.query([]).and_then(|mut rows| {
+                while rows.next()?.is_some() {}
+                Ok(0)
+            }),
+            Ok(Some(mut stmt)) => stmt.execute([]),
+            Err(e) => Err(e),
+        };
+        match rows_affected {
+            Ok(rows_affected) => result.rows_affected.push(rows_affected as i64),
+            Err(e) => {
+                result.error = Some(ScriptError { index, message: e.to_string() });
+                break;
+            }
+        }
+    }
+    result
+}
+
 async fn sql_query(
     db_pool: &async_sqlite::Pool,
     query: &str,
@@ -85,26 +750,10 @@ async fn sql_query(
     let params = process_record_params(params)?;
     let table = db_pool
         .conn(move |conn| {
-            let param_refs = create_param_refs(&params);
-            let mut stmt = conn.prepare(&query)?;
-            let fields: Vec<DbField> = stmt.column_names().iter().map(|s| DbField { name: s.to_string() }).collect();
-            let rows = stmt
-                .query_map(&param_refs[..], |row| {
-                    let mut rec: Vec<DbValue> = Vec::new();
-                    for i in 0..fields.len() {
-                        let value = row.get_ref(i)?; // <-- get a ValueRef (borrowed SQL value)
-                        match value {
-                            ValueRef::Null => rec.push(DbValue::Null),
-                            ValueRef::Integer(i) => rec.push(i.into()),
-                            ValueRef::Real(r) => rec.push(r.into()),
-                            ValueRef::Text(t) => rec.push(String::from_utf8_lossy(t).to_string().into()),
-                            ValueRef::Blob(b) => rec.push(b.into()),
-                        };
-                    }
-                    Ok(rec)
-                })?
-                .collect::<Result<Vec<_>, _>>()?;
-            Ok(QueryResult { fields, rows })
+            let started = Instant::now();
+            let result = conn_query(conn, &query, &params);
+            sqlstats::record(&query, &params, started.elapsed(), result.is_ok());
+            result
         })
         .await?;
     Ok(table)
@@ -119,11 +768,148 @@ async fn sql_exec(
     let params = process_record_params(params)?;
     let result = db_pool
         .conn(move |conn| {
-            let param_refs = create_param_refs(&params);
-            let mut stmt = conn.prepare(&query)?;
-            let rows_affected = stmt.execute(&param_refs[..])?;
-            Ok(ExecResult { rows_affected: rows_affected as i64, insert_id: None })
+            let started = Instant::now();
+            let result = conn_exec(conn, &query, &params);
+            sqlstats::record(&query, &params, started.elapsed(), result.is_ok());
+            result
         })
         .await?;
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_sqlite::rusqlite::Connection;
+    use proptest::prelude::*;
+    use qxsql::sql::record_from_slice;
+    use shvproto::{make_list, make_map};
+
+    /// Store `value` bound as `:v` by `expr` to column of `decl_type` and read it back
+    fn round_trip_expr(decl_type: &str, expr: &str, value: DbValue) -> DbValue {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!("CREATE TABLE t (v {decl_type})")).unwrap();
+        let params = process_record_params(&record_from_slice(&[("v", value)])).unwrap();
+        conn_exec(&conn, &format!("INSERT INTO t (v) VALUES ({expr})"), &params).unwrap();
+        let result = conn_query(&conn, "SELECT v FROM t", &vec![]).unwrap();
+        result.value(0, 0).cloned().unwrap()
+    }
+
+    fn round_trip(decl_type: &str, value: DbValue) -> DbValue {
+        round_trip_expr(decl_type, ":v", value)
+    }
+
+    proptest! {
+        #[test]
+        fn int_round_trips(n in any::<i64>()) {
+            prop_assert!(matches!(round_trip("integer", n.into()), DbValue::Int(v) if v == n));
+        }
+
+        #[test]
+        fn double_round_trips(d in any::<f64>().prop_filter("NaN is rejected", |d| !d.is_nan())) {
+            prop_assert!(matches!(round_trip("double", d.into()), DbValue::Double(v) if v == d));
+        }
+
+        #[test]
+        fn string_round_trips(s in any::<String>()) {
+            let value = round_trip("text", s.as_str().into());
+            prop_assert_eq!(db_value_to_rpcvalue(&value).as_str(), s.as_str());
+        }
+
+        #[test]
+        fn invalid_utf8_text_is_read_as_blob(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let value = round_trip_expr("text", "CAST(:v AS TEXT)", bytes.as_slice().into());
+            match std::str::from_utf8(&bytes) {
+                Ok(s) => prop_assert_eq!(db_value_to_rpcvalue(&value).as_str(), s),
+                Err(_) => prop_assert!(matches!(value, DbValue::Blob(b) if b == bytes)),
+            }
+        }
+
+        #[test]
+        fn uint_above_i64_max_is_rejected(n in any::<u64>()) {
+            match rpcvalue_to_dbvalue(&RpcValue::from(n)) {
+                Ok(DbValue::Int(v)) => prop_assert_eq!(v as u64, n),
+                Ok(value) => prop_assert!(false, "unexpected value {value:?}"),
+                Err(_) => prop_assert!(n > i64::MAX as u64),
+            }
+        }
+
+        #[test]
+        fn positional_params_are_bound_outside_of_literals(literal in "[^']*", count in 0usize..8) {
+            let query = format!("SELECT '{literal}'{} -- ?", ", ?".repeat(count));
+            let values: Vec<DbValue> = (0..count as i64).map(DbValue::from).collect();
+            let (rewritten, params) = bind_positional_params(&query, values).unwrap();
+            let expected: String = (1..=count).map(|ix| format!(", :p{ix}")).collect();
+            prop_assert_eq!(rewritten, format!("SELECT '{literal}'{expected} -- ?"));
+            prop_assert_eq!(params.iter().count(), count);
+            prop_assert!(bind_positional_params(&query, vec![DbValue::Null; count + 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn nan_is_rejected() {
+        assert!(process_record_params(&record_from_slice(&[("v", f64::NAN.into())])).is_err());
+    }
+
+    #[test]
+    fn infinity_is_stored_and_passed_to_rpc_as_null() {
+        for d in [f64::INFINITY, f64::NEG_INFINITY] {
+            let value = round_trip("double", d.into());
+            assert!(matches!(value, DbValue::Double(v) if v == d));
+            assert!(db_value_to_rpcvalue(&value).is_null());
+        }
+    }
+
+    #[test]
+    fn bool_and_timestamp_are_decoded_by_declared_type() {
+        assert!(matches!(round_trip("boolean", DbValue::Bool(true)), DbValue::Bool(true)));
+        let dt = DateTime::parse_from_rfc3339("2026-05-01T10:20:30+02:00").unwrap();
+        assert!(matches!(round_trip("timestamp", DbValue::DateTime(dt)), DbValue::DateTime(v) if v == dt));
+    }
+
+    #[test]
+    fn decimal_is_stored_as_real() {
+        let value = rpcvalue_to_dbvalue(&RpcValue::from(shvproto::Decimal::new(12345, -2))).unwrap();
+        assert!(matches!(round_trip("numeric", value), DbValue::Double(v) if v == 123.45));
+    }
+
+    #[test]
+    fn list_is_stored_as_cpon_and_decoded_by_declared_type() {
+        let list = RpcValue::from(make_list![1, "a", make_list![true]]);
+        let value = round_trip("list", rpcvalue_to_dbvalue(&list).unwrap());
+        let DbValue::String(cpon) = value else {
+            panic!("unexpected value {value:?}");
+        };
+        assert_eq!(RpcValue::from_cpon(cpon.as_str()).unwrap(), list);
+        assert!(matches!(round_trip("list", "not cpon".into()), DbValue::String(s) if s.as_str() == "not cpon"));
+    }
+
+    #[test]
+    fn dry_run_counts_rows_matching_condition() {
+        let params = record_from_slice(&[("name", "x".into()), ("id", 1_i64.into())]);
+        let (query, params) = dry_run_count_query("UPDATE runs SET name = :name\nWHERE id = :id;", Some(&params)).unwrap();
+        assert_eq!(query, "SELECT COUNT(*) FROM runs WHERE id = :id");
+        assert_eq!(params.unwrap().iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>(), vec!["id".to_string()]);
+        assert_eq!(dry_run_count_query("delete from runs", None).unwrap().0, "SELECT COUNT(*) FROM runs");
+        assert!(dry_run_count_query("DELETE FROM runs WHERE id IN (SELECT id FROM runs WHERE x) ", None).is_none());
+        assert!(dry_run_count_query("DELETE FROM runs WHERE id = 1 RETURNING id", None).is_none());
+        assert!(dry_run_count_query("INSERT INTO runs (id) VALUES (1)", None).is_none());
+    }
+
+    #[test]
+    fn numbered_placeholders_are_scanned() {
+        let placeholders = scan_placeholders("SELECT ?2, ?, \"?\", [?], `?` /* ? */");
+        let indexes: Vec<usize> = placeholders.positional.iter().map(|(_, index)| *index).collect();
+        assert_eq!(indexes, vec![2, 3]);
+        assert!(!placeholders.named);
+        assert!(scan_placeholders("SELECT :id").named);
+    }
+
+    #[test]
+    fn map_params_are_rejected() {
+        assert!(SqlQueryParams::try_from(&RpcValue::from(make_list!["SELECT ?", make_list![make_list![1]]])).is_ok());
+        assert!(SqlQueryParams::try_from(&RpcValue::from(make_list!["SELECT ?", make_list![make_map!("b" => 1)]])).is_err());
+        assert!(SqlQueryParams::try_from(&RpcValue::from(make_list!["SELECT :a", make_map!("a" => make_map!("b" => 1))])).is_err());
+        assert!(SqlQueryParams::try_from(&RpcValue::from(make_list!["SELECT :a", make_map!("a" => 1)])).is_ok());
+    }
+}
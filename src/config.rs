@@ -1,19 +1,683 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
 use chrono::Duration;
 use duration_str::HumanFormat;
 use serde::{Deserialize, Serialize};
 use shvrpc::client::ClientConfig;
+use url::Url;
+
+use crate::appsqlapi::is_valid_sql_identifier;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub client: ClientConfig,
+    /// TLS options of `ssl://` and `wss://` broker connection
+    #[serde(default)]
+    pub tls: TlsConfig,
     pub data_dir: String,
     pub remote_events_mount_point: String,
+    /// New events are served by in-process SQL engine instead of remote qxsqld
+    #[serde(default)]
+    pub local_events: bool,
+    /// Open closed event automatically, when any of its methods is called
+    #[serde(default)]
+    pub auto_open_events: bool,
+    /// Reopen events, which were open when the daemon was shut down
+    #[serde(default)]
+    pub reopen_on_start: bool,
+    /// Run integrity and foreign key check of local event DB when the event is opened,
+    /// event with corrupted DB is not opened
+    #[serde(default)]
+    pub integrity_check_on_open: bool,
     #[serde(
         default,
         deserialize_with = "duration_str::deserialize_duration_chrono",
         serialize_with = "serialize_duration_as_string"
     )]
     pub event_expire_duration: chrono::Duration,
+    /// Local SQL statements running longer are logged with their parameters, zero disables the slow query log
+    #[serde(
+        default = "default_slow_query_threshold",
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub slow_query_threshold: chrono::Duration,
+    /// Period of `.app:heartbeat` signal with daemon status, zero disables the signal
+    #[serde(
+        default = "default_heartbeat_interval",
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub heartbeat_interval: chrono::Duration,
+    /// Period of competition rules evaluation of open events, zero disables the periodic evaluation
+    #[serde(
+        default = "default_rules_interval",
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub rules_interval: chrono::Duration,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub remote_call: RemoteCallConfig,
+    /// Spawn qxsqld child process for each opened remote event, remote event DB service
+    /// is expected to be mounted already, if not set
+    #[serde(default)]
+    pub qxsqld: Option<QxsqldConfig>,
+    #[serde(default)]
+    pub publish: PublishConfig,
+    /// Static HTML results are written under this directory
+    #[serde(default)]
+    pub export_dir: Option<String>,
+    /// Event tables, which records are only marked as deleted, so the tombstones can be synced to clients
+    #[serde(default)]
+    pub soft_delete_tables: Vec<String>,
+    /// Event tables with change history, old and new values of every change are kept in `record_history` table
+    #[serde(default = "default_history_tables")]
+    pub history_tables: Vec<String>,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    /// Per caller limits of calls on `sql` and `eventctl` nodes, calls are not limited if not set
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// Quotas checked when event is created or opened, to protect shared hosted instance
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub reconcile: ReconcileConfig,
+    #[serde(default)]
+    pub disk_space: DiskSpaceConfig,
+    /// Local event DBs are encrypted by SQLCipher, if set
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    #[serde(default)]
+    pub traffic_recorder: TrafficRecorderConfig,
+    #[serde(default)]
+    pub results_top: ResultsTopConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    /// MQTT bridge is disabled if not set
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    /// JSON-RPC gateway is disabled if not set
+    #[serde(default)]
+    pub gateway: Option<GatewayConfig>,
+}
+
+fn default_slow_query_threshold() -> chrono::Duration {
+    chrono::Duration::milliseconds(500)
+}
+
+fn default_heartbeat_interval() -> chrono::Duration {
+    chrono::Duration::minutes(1)
+}
+
+fn default_rules_interval() -> chrono::Duration {
+    chrono::Duration::minutes(1)
+}
+
+fn default_history_tables() -> Vec<String> {
+    vec![String::from("competitors"), String::from("runs"), String::from("protests")]
+}
+
+/// Event tables having `deleted` and `deletedAt` columns
+pub const SOFT_DELETE_CAPABLE_TABLES: &[&str] = &["competitors", "runs", "relays", "classes", "courses", "codes", "cards", "punches"];
+
+/// qxsqld child process command, `args` and `broker_url` can contain placeholders
+/// `{event_id}`, `{mount}`, `{db_file}` and `{broker_url}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QxsqldConfig {
+    pub executable: String,
+    pub broker_url: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub working_dir: Option<String>,
+    /// Child process is restarted, when its resident memory exceeds this limit
+    pub max_memory_mb: Option<u64>,
+    /// Child process is restarted, when its CPU usage in last watchdog interval exceeds this limit
+    pub max_cpu_percent: Option<f64>,
+    /// Hard limit of child process virtual memory (`RLIMIT_AS`), allocations above it fail
+    pub max_address_space_mb: Option<u64>,
+    /// Hard limit of child process CPU time (`RLIMIT_CPU`), child exceeding it is killed and restarted by watchdog
+    pub max_cpu_time_sec: Option<u64>,
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub watchdog_interval: chrono::Duration,
+    /// Maximal time to wait for the spawned child to mount on broker
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub startup_timeout: chrono::Duration,
+}
+
+impl Default for QxsqldConfig {
+    fn default() -> Self {
+        Self {
+            executable: String::from("qxsqld"),
+            broker_url: String::from("tcp://localhost:3755?user=test&password=test"),
+            args: ["--url", "{broker_url}", "--mount", "{mount}", "--database", "{db_file}"]
+                .into_iter().map(String::from).collect(),
+            env: Default::default(),
+            working_dir: None,
+            max_memory_mb: None,
+            max_cpu_percent: None,
+            max_address_space_mb: None,
+            max_cpu_time_sec: None,
+            watchdog_interval: chrono::Duration::seconds(30),
+            startup_timeout: chrono::Duration::seconds(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Daily,
+    Hourly,
+    Size,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Write log to files in addition to stderr
+    pub log_to_file: bool,
+    /// Log files directory, `<data_dir>/log` is used if not set
+    pub directory: Option<String>,
+    pub rotation: LogRotation,
+    /// Rotate log file when it exceeds this size, 0 means no size limit
+    pub rotate_size_mb: u64,
+    /// Number of rotated log files to keep, 0 means keep all
+    pub keep_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            log_to_file: false,
+            directory: None,
+            rotation: LogRotation::Daily,
+            rotate_size_mb: 100,
+            keep_files: 30,
+        }
+    }
+}
+
+/// Policy of SQL calls proxied to remote event DB services
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteCallConfig {
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub timeout: chrono::Duration,
+    /// Number of retries of timed out idempotent calls (query, read)
+    pub retries: u32,
+    /// Number of consecutive failed calls, which opens the circuit breaker
+    pub circuit_breaker_threshold: u32,
+    /// Calls fail fast for this time, when circuit breaker is open
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub circuit_breaker_cooldown: chrono::Duration,
+}
+
+impl Default for RemoteCallConfig {
+    fn default() -> Self {
+        Self {
+            timeout: chrono::Duration::seconds(10),
+            retries: 2,
+            circuit_breaker_threshold: 3,
+            circuit_breaker_cooldown: chrono::Duration::seconds(30),
+        }
+    }
+}
+
+/// Results publishing to OResults and liveresultat, it is enabled per event by `publish.service` event config key
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PublishConfig {
+    pub oresults_url: String,
+    pub liveresultat_url: String,
+    /// Changes are collected for this time and uploaded in one batch
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub min_interval: chrono::Duration,
+    /// Number of retries of failed upload
+    pub retries: u32,
+    /// Delay before first retry, it grows with each attempt
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub retry_delay: chrono::Duration,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        Self {
+            oresults_url: String::from("https://api.oresults.eu/results"),
+            liveresultat_url: String::from("https://liveresultat.orientering.se/api/results"),
+            min_interval: chrono::Duration::seconds(10),
+            retries: 3,
+            retry_delay: chrono::Duration::seconds(5),
+        }
+    }
+}
+
+/// Background jobs like backups, exports and recalculations
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// Number of jobs running at once, other jobs wait in the queue
+    pub workers: usize,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self { workers: 2 }
+    }
+}
+
+/// WAL checkpoint and incremental vacuum of idle local event DBs
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    /// Open events are checked with this period, zero disables the maintenance
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub interval: chrono::Duration,
+    /// Event is idle, when its DB was not used for this time
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub idle_time: chrono::Duration,
+    /// Number of free pages released by one incremental vacuum, 0 releases all of them
+    pub vacuum_pages: u32,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            interval: chrono::Duration::minutes(10),
+            idle_time: chrono::Duration::minutes(5),
+            vacuum_pages: 0,
+        }
+    }
+}
+
+/// Repair of event, which DB directory is missing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReconcileRepair {
+    /// Report inconsistencies only
+    #[default]
+    None,
+    /// Recreate missing event directory, event DB is created again when the event is opened
+    Recreate,
+    /// Mark event as broken, so it cannot be opened until its directory is restored
+    Mark,
+}
+
+/// Consistency check of `events` table and event directories in `data_dir`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconcileConfig {
+    /// Check the consistency on daemon start, before events are reopened
+    pub on_start: bool,
+    pub repair: ReconcileRepair,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            on_start: true,
+            repair: ReconcileRepair::None,
+        }
+    }
+}
+
+/// Free space in `data_dir` watchdog, thresholds set to zero are not checked
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiskSpaceConfig {
+    /// Free space is checked with this period, zero disables the watchdog
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub interval: chrono::Duration,
+    /// Warning is logged and signalled, when free space drops below this limit
+    pub warning_free_mb: u64,
+    /// Writes to event DBs stored in `data_dir` are rejected, when free space drops below this limit
+    pub read_only_free_mb: u64,
+}
+
+impl Default for DiskSpaceConfig {
+    fn default() -> Self {
+        Self {
+            interval: chrono::Duration::seconds(30),
+            warning_free_mb: 1024,
+            read_only_free_mb: 100,
+        }
+    }
+}
+
+/// TLS options of broker connection, they are passed to the client as `client.url` query parameters
+/// `ca`, `cert`, `key` and `insecure`, parameters already present in the URL take precedence
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file with CA certificates the broker certificate is verified with, system roots are used if not set
+    pub ca_file: Option<String>,
+    /// PEM file with client certificate, it must be set together with `client_key_file`
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+    /// Broker certificate is not verified, use for testing only
+    pub insecure: bool,
+}
+
+const TLS_CA_PARAM: &str = "ca";
+const TLS_CERT_PARAM: &str = "cert";
+const TLS_KEY_PARAM: &str = "key";
+const TLS_INSECURE_PARAM: &str = "insecure";
+const TLS_PARAMS: &[&str] = &[TLS_CA_PARAM, TLS_CERT_PARAM, TLS_KEY_PARAM, TLS_INSECURE_PARAM];
+
+fn is_tls_scheme(scheme: &str) -> bool {
+    matches!(scheme, "ssl" | "wss")
+}
+
+/// PEM file exists and contains `label` block
+fn check_pem_file(name: &str, file: &str, label: &str, errors: &mut Vec<String>) {
+    match std::fs::read_to_string(file) {
+        Ok(content) if content.contains("-----BEGIN ") && content.contains(label) => {}
+        Ok(_) => errors.push(format!("{name}: '{file}' is not a PEM file with {label}")),
+        Err(e) => errors.push(format!("{name}: cannot read '{file}': {e}")),
+    }
+}
+
+/// Source of master key of event DB encryption, exactly one of them must be set
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub master_key: Option<String>,
+    /// File containing the master key, trailing whitespace is ignored
+    pub master_key_file: Option<String>,
+    /// Command printing the master key to stdout, for example KMS client decrypting the key,
+    /// the first item is executable, the rest are arguments
+    pub master_key_command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrafficFormat {
+    /// JSON lines
+    #[default]
+    Json,
+    /// CPON message per line
+    Cpon,
+}
+
+/// Recording of requests and signals of events to `<data_dir>/<event_id>/traffic.<jsonl|cpon>`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrafficRecorderConfig {
+    pub enabled: bool,
+    /// Recorded events, traffic of all events is recorded if empty
+    pub events: Vec<i64>,
+    pub format: TrafficFormat,
+    /// Recording file is rotated to `<file>.1`, when it reaches this size, zero disables the rotation
+    pub max_file_size_mb: u64,
+}
+
+impl Default for TrafficRecorderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            events: vec![],
+            format: TrafficFormat::Json,
+            max_file_size_mb: 100,
+        }
+    }
+}
+
+/// Leaderboards of `results:top` for arena screens
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResultsTopConfig {
+    /// Result changed within this time is flagged as recent
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub recent_change: chrono::Duration,
+    /// Minimal interval of `topchng` signals, changed classes are collected meanwhile
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub refresh_interval: chrono::Duration,
+}
+
+impl Default for ResultsTopConfig {
+    fn default() -> Self {
+        Self {
+            recent_change: chrono::Duration::minutes(1),
+            refresh_interval: chrono::Duration::seconds(5),
+        }
+    }
+}
+
+/// Security of connection to SMTP relay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain connection, it is allowed to loopback relay only, like local MTA
+    #[default]
+    None,
+    /// Plain connection upgraded by STARTTLS, usually port 587
+    Starttls,
+    /// TLS from the start of connection, usually port 465
+    Tls,
+}
+
+/// Competitor notifications, they are enabled per event by `notify.transport` event config key
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// SMTP relay `host:port`
+    pub smtp_relay: String,
+    pub smtp_tls: SmtpTls,
+    /// SMTP login, mail is sent without authentication if it is empty
+    pub smtp_user: String,
+    pub smtp_password: String,
+    /// Sender address of notification mails, it is common to all events, so events cannot impersonate other senders
+    pub smtp_from: String,
+    /// Time to wait after card read, so the run result is computed by the card reader client
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub delay: chrono::Duration,
+    /// Timeout of SMTP and webhook connection
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub timeout: chrono::Duration,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            smtp_relay: String::from("localhost:25"),
+            smtp_tls: SmtpTls::None,
+            smtp_user: String::new(),
+            smtp_password: String::new(),
+            smtp_from: String::new(),
+            delay: chrono::Duration::seconds(10),
+            timeout: chrono::Duration::seconds(10),
+        }
+    }
+}
+
+/// MQTT bridge mirroring punches at radio controls and result changes for venue displays, topics can contain
+/// placeholders `{event_id}`, `{code}` and `{class_id}`, signal with empty topic is not mirrored
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// Broker `host:port`, port defaults to 1883
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub punch_topic: String,
+    pub results_topic: String,
+    /// Messages are published retained, so the broker sends the last one to new subscribers
+    pub retain: bool,
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub keep_alive: chrono::Duration,
+    /// Timeout of broker connection
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub timeout: chrono::Duration,
+    /// Delay before reconnect, messages are queued while the broker is disconnected
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub reconnect_delay: chrono::Duration,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            broker: String::from("localhost:1883"),
+            client_id: String::from("qxeventd"),
+            username: None,
+            password: None,
+            punch_topic: String::from("qxevent/{event_id}/punch/{code}"),
+            results_topic: String::from("qxevent/{event_id}/results/{class_id}"),
+            retain: false,
+            keep_alive: chrono::Duration::seconds(60),
+            timeout: chrono::Duration::seconds(10),
+            reconnect_delay: chrono::Duration::seconds(5),
+        }
+    }
+}
+
+/// Access granted to gateway token, it is compared with access level of called method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GatewayAccess {
+    Read,
+    Write,
+    Service,
+}
+
+/// Gateway client token, it can call methods of events listed in `events` and of events in `organizations` only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GatewayToken {
+    pub access: GatewayAccess,
+    /// Calls are made on behalf of this user, so event owner and organization member rights apply to it
+    pub user: String,
+    #[serde(default)]
+    pub events: Vec<i64>,
+    /// Organization ids
+    #[serde(default)]
+    pub organizations: Vec<i64>,
+}
+
+/// JSON-RPC over HTTP gateway to `eventctl` node tree for integrations without SHV client library,
+/// calls are routed through the broker to the daemon mount point, so the daemon user needs access to its own mount.
+/// Gateway speaks plain HTTP, it must listen on loopback address, remote clients have to connect
+/// through TLS terminating reverse proxy.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+    /// Listen address `host:port`, host must be loopback address
+    pub listen: String,
+    /// Daemon mount point on the broker, `client.mount` is used if not set
+    pub mount: Option<String>,
+    /// Bearer tokens of gateway clients
+    pub tokens: BTreeMap<String, GatewayToken>,
+    pub max_body_size_kb: usize,
+    /// Timeout of reading HTTP request
+    #[serde(
+        deserialize_with = "duration_str::deserialize_duration_chrono",
+        serialize_with = "serialize_duration_as_string"
+    )]
+    pub timeout: chrono::Duration,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            listen: String::from("127.0.0.1:8080"),
+            mount: None,
+            tokens: BTreeMap::new(),
+            max_body_size_kb: 10 * 1024,
+            timeout: chrono::Duration::seconds(30),
+        }
+    }
+}
+
+/// Token bucket of one caller, it is refilled by `rate` tokens per second up to `burst` tokens, each call takes one token
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TokenBucketConfig {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// Limits of methods with read and write access level of one node
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MethodRateLimits {
+    pub read: Option<TokenBucketConfig>,
+    pub write: Option<TokenBucketConfig>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub sql: MethodRateLimits,
+    pub eventctl: MethodRateLimits,
+}
+
+/// Resource limits of one owner or organization, limits which are not set are not checked
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuotaLimits {
+    pub max_events: Option<u64>,
+    pub max_open_events: Option<u64>,
+    /// Total size of local event DB files in MB
+    pub max_db_size_mb: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub owner: QuotaLimits,
+    pub organization: QuotaLimits,
 }
 
 pub fn serialize_duration_as_string<S>(
@@ -30,9 +694,303 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             client: ClientConfig::default(),
+            tls: TlsConfig::default(),
             data_dir: String::from("/tmp/qxeventd"),
             remote_events_mount_point: String::from("test/qx/remotedb"),
+            local_events: false,
+            auto_open_events: false,
+            reopen_on_start: false,
+            integrity_check_on_open: false,
             event_expire_duration: chrono::Duration::days(2),
+            slow_query_threshold: default_slow_query_threshold(),
+            heartbeat_interval: default_heartbeat_interval(),
+            rules_interval: default_rules_interval(),
+            logging: LoggingConfig::default(),
+            remote_call: RemoteCallConfig::default(),
+            qxsqld: None,
+            publish: PublishConfig::default(),
+            export_dir: None,
+            soft_delete_tables: vec![],
+            history_tables: default_history_tables(),
+            jobs: JobsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            quota: QuotaConfig::default(),
+            reconcile: ReconcileConfig::default(),
+            disk_space: DiskSpaceConfig::default(),
+            encryption: None,
+            traffic_recorder: TrafficRecorderConfig::default(),
+            results_top: ResultsTopConfig::default(),
+            notify: NotifyConfig::default(),
+            mqtt: None,
+            gateway: None,
+        }
+    }
+}
+
+const REDACTED: &str = "***";
+
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    ["password", "token", "secret", "master_key"].iter().any(|secret| key.contains(secret))
+}
+
+/// Mask URL password and secret query parameters, returns `None` if `s` is not an URL
+fn redact_url(s: &str) -> Option<String> {
+    let mut url = Url::parse(s).ok()?;
+    if url.cannot_be_a_base() {
+        return None;
+    }
+    if url.password().is_some() {
+        let _ = url.set_password(Some(REDACTED));
+    }
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url.query_pairs()
+            .map(|(key, value)| {
+                let value = if is_secret_key(&key) { REDACTED.to_string() } else { value.into_owned() };
+                (key.into_owned(), value)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    Some(url.to_string())
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_secret_key(key) && !value.is_null() {
+                    *value = REDACTED.into();
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(list) => list.iter_mut().for_each(redact),
+        serde_json::Value::String(s) => {
+            if let Some(redacted) = redact_url(s) {
+                *s = redacted;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_mount_point(name: &str, mount: &str, errors: &mut Vec<String>) {
+    if mount.is_empty() {
+        errors.push(format!("{name}: mount point is empty"));
+    } else if mount.starts_with('/') || mount.ends_with('/') || mount.contains("//") || mount.contains(char::is_whitespace) {
+        errors.push(format!("{name}: invalid mount point '{mount}'"));
+    }
+}
+
+fn check_directory(name: &str, dir: &str, errors: &mut Vec<String>) {
+    let path = Path::new(dir);
+    match std::fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => errors.push(format!("{name}: '{dir}' is not a directory")),
+        Ok(metadata) if metadata.permissions().readonly() => errors.push(format!("{name}: directory '{dir}' is read only")),
+        Ok(_) => {}
+        Err(_) => {
+            // directory is created on demand, its nearest existing ancestor must be a directory
+            let ancestor = path.ancestors().skip(1).find(|p| !p.as_os_str().is_empty() && p.exists());
+            if ancestor.is_some_and(|p| !p.is_dir()) {
+                errors.push(format!("{name}: directory '{dir}' cannot be created"));
+            }
+        }
+    }
+}
+
+fn check_executable(name: &str, executable: &str, errors: &mut Vec<String>) {
+    let found = if executable.contains('/') {
+        Path::new(executable).is_file()
+    } else {
+        std::env::var_os("PATH")
+            .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| dir.join(executable).is_file()))
+    };
+    if !found {
+        errors.push(format!("{name}: executable '{executable}' not found"));
+    }
+}
+
+impl Config {
+    /// Config as JSON value with passwords and tokens masked, suitable for printing
+    pub fn to_redacted_value(&self) -> anyhow::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        redact(&mut value);
+        Ok(value)
+    }
+
+    /// Set TLS query parameters of `client.url` from `tls` section
+    pub fn apply_tls_config(&mut self) {
+        let tls = &self.tls;
+        let params = [
+            (TLS_CA_PARAM, tls.ca_file.clone()),
+            (TLS_CERT_PARAM, tls.client_cert_file.clone()),
+            (TLS_KEY_PARAM, tls.client_key_file.clone()),
+            (TLS_INSECURE_PARAM, tls.insecure.then(|| "true".to_string())),
+        ];
+        let url = &mut self.client.url;
+        for (param, value) in params {
+            let Some(value) = value else {
+                continue;
+            };
+            if !url.query_pairs().any(|(name, _)| name == param) {
+                url.query_pairs_mut().append_pair(param, &value);
+            }
+        }
+    }
+
+    /// Broker connection is not verified, `insecure` TLS parameter is set
+    pub fn is_tls_insecure(&self) -> bool {
+        self.client.url.query_pairs().any(|(name, value)| name == TLS_INSECURE_PARAM && matches!(value.as_ref(), "true" | "1"))
+    }
+
+    /// Validate broker URL scheme and TLS parameters, these errors prevent the daemon from start,
+    /// because they would only show as repeated connection failures
+    pub fn check_transport(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let url = &self.client.url;
+        let redacted_url = || redact_url(url.as_str()).unwrap_or_default();
+        match url.scheme() {
+            "tcp" | "ssl" | "ws" | "wss" => {
+                if url.host_str().is_none_or(str::is_empty) {
+                    errors.push(format!("client.url: missing host in '{}'", redacted_url()));
+                }
+            }
+            "unix" | "serial" => {}
+            scheme => errors.push(format!("client.url: unsupported scheme '{scheme}'")),
+        }
+        let param = |name: &str| url.query_pairs().find(|(param, _)| param == name).map(|(_, value)| value.into_owned());
+        if !is_tls_scheme(url.scheme()) {
+            for name in TLS_PARAMS.iter().filter(|name| param(name).is_some()) {
+                errors.push(format!("client.url: TLS parameter '{name}' requires ssl:// or wss:// scheme, URL: '{}'", redacted_url()));
+            }
+            return errors;
+        }
+        if let Some(ca) = param(TLS_CA_PARAM) {
+            check_pem_file("tls.ca_file", &ca, "CERTIFICATE", &mut errors);
+        }
+        match (param(TLS_CERT_PARAM), param(TLS_KEY_PARAM)) {
+            (Some(cert), Some(key)) => {
+                check_pem_file("tls.client_cert_file", &cert, "CERTIFICATE", &mut errors);
+                check_pem_file("tls.client_key_file", &key, "PRIVATE KEY", &mut errors);
+            }
+            (None, None) => {}
+            _ => errors.push("tls: client_cert_file and client_key_file must be set together".to_string()),
+        }
+        if let Some(insecure) = param(TLS_INSECURE_PARAM) && !matches!(insecure.as_str(), "true" | "false" | "1" | "0") {
+            errors.push(format!("tls.insecure: invalid value '{insecure}', true or false expected"));
+        }
+        errors
+    }
+
+    /// Validate URLs, directories and mount points without connecting to the broker,
+    /// returns list of found problems.
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = self.check_transport();
+        if let Some(mount) = &self.client.mount {
+            check_mount_point("client.mount", mount, &mut errors);
+        }
+        check_mount_point("remote_events_mount_point", &self.remote_events_mount_point, &mut errors);
+        if !self.data_dir.is_empty() {
+            check_directory("data_dir", &self.data_dir, &mut errors);
+        }
+        if let Some(dir) = &self.logging.directory {
+            check_directory("logging.directory", dir, &mut errors);
+        }
+        if self.event_expire_duration <= chrono::Duration::zero() {
+            errors.push("event_expire_duration: must be positive".to_string());
+        }
+        if self.slow_query_threshold < chrono::Duration::zero() {
+            errors.push("slow_query_threshold: must not be negative".to_string());
+        }
+        if self.heartbeat_interval < chrono::Duration::zero() {
+            errors.push("heartbeat_interval: must not be negative".to_string());
+        }
+        if self.rules_interval < chrono::Duration::zero() {
+            errors.push("rules_interval: must not be negative".to_string());
+        }
+        if self.results_top.recent_change < chrono::Duration::zero() || self.results_top.refresh_interval < chrono::Duration::zero() {
+            errors.push("results_top: recent_change and refresh_interval must not be negative".to_string());
+        }
+        if let Some(qxsqld) = &self.qxsqld {
+            check_executable("qxsqld.executable", &qxsqld.executable, &mut errors);
+            if let Err(e) = Url::parse(&qxsqld.broker_url) {
+                errors.push(format!("qxsqld.broker_url: {e}"));
+            }
+            if let Some(dir) = &qxsqld.working_dir {
+                check_directory("qxsqld.working_dir", dir, &mut errors);
+            }
+        }
+        for table in &self.soft_delete_tables {
+            if !SOFT_DELETE_CAPABLE_TABLES.contains(&table.as_str()) {
+                errors.push(format!("soft_delete_tables: table '{table}' does not support soft delete"));
+            }
+        }
+        for table in &self.history_tables {
+            if !is_valid_sql_identifier(table) {
+                errors.push(format!("history_tables: invalid table name '{table}'"));
+            }
+        }
+        if self.jobs.workers == 0 {
+            errors.push("jobs.workers: must be positive".to_string());
+        }
+        if self.maintenance.interval < chrono::Duration::zero() || self.maintenance.idle_time < chrono::Duration::zero() {
+            errors.push("maintenance: interval and idle_time must not be negative".to_string());
+        }
+        if let Some(encryption) = &self.encryption {
+            let sources = usize::from(encryption.master_key.is_some())
+                + usize::from(encryption.master_key_file.is_some())
+                + usize::from(!encryption.master_key_command.is_empty());
+            if sources != 1 {
+                errors.push("encryption: exactly one of master_key, master_key_file and master_key_command must be set".to_string());
+            }
+            if let Some(executable) = encryption.master_key_command.first() {
+                check_executable("encryption.master_key_command", executable, &mut errors);
+            }
+        }
+        if self.disk_space.interval < chrono::Duration::zero() {
+            errors.push("disk_space.interval: must not be negative".to_string());
+        }
+        if self.disk_space.warning_free_mb > 0 && self.disk_space.warning_free_mb < self.disk_space.read_only_free_mb {
+            errors.push("disk_space.warning_free_mb: must not be less than read_only_free_mb".to_string());
+        }
+        if self.traffic_recorder.enabled && self.data_dir.is_empty() {
+            errors.push("traffic_recorder.enabled: data_dir must be set".to_string());
+        }
+        if self.notify.smtp_tls == SmtpTls::None {
+            let host = self.notify.smtp_relay.rsplit_once(':').map_or(self.notify.smtp_relay.as_str(), |(host, _)| host);
+            let is_loopback = host == "localhost" || host.trim_matches(['[', ']']).parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback());
+            if !is_loopback {
+                errors.push(format!("notify.smtp_tls: TLS is required by not loopback relay '{}'", self.notify.smtp_relay));
+            }
+            if !self.notify.smtp_user.is_empty() {
+                errors.push("notify.smtp_user: credentials cannot be sent without TLS".to_string());
+            }
+        }
+        if let Some(gateway) = &self.gateway {
+            match gateway.listen.parse::<std::net::SocketAddr>() {
+                Ok(addr) if !addr.ip().is_loopback() => errors.push(format!("gateway.listen: '{}' is not loopback address, use TLS terminating reverse proxy for remote clients", gateway.listen)),
+                Ok(_) => {}
+                Err(e) => errors.push(format!("gateway.listen: invalid address '{}': {e}", gateway.listen)),
+            }
+            for (ix, token) in gateway.tokens.values().enumerate() {
+                if token.user.is_empty() {
+                    errors.push(format!("gateway.tokens: user of token {ix} is not set"));
+                }
+                if token.events.is_empty() && token.organizations.is_empty() {
+                    errors.push(format!("gateway.tokens: token {ix} has no events nor organizations in its scope"));
+                }
+            }
+        }
+        for (name, limits) in [("rate_limit.sql", &self.rate_limit.sql), ("rate_limit.eventctl", &self.rate_limit.eventctl)] {
+            for (class, bucket) in [("read", &limits.read), ("write", &limits.write)] {
+                if let Some(bucket) = bucket && !(bucket.rate > 0.0 && bucket.burst > 0) {
+                    errors.push(format!("{name}.{class}: rate and burst must be positive"));
+                }
+            }
         }
+        errors
     }
 }
@@ -0,0 +1,127 @@
+//! Discipline presets of course validation and scoring. Preset follows from the event sport (`event.sportId`),
+//! other preset can be selected by `discipline.preset` config key and single rules can be overridden
+//! by `discipline.ordered`, `discipline.orderTolerance` and `discipline.penalizeMissedControls` keys.
+
+use std::collections::BTreeMap;
+
+use qxsql::QxSqlApi;
+use serde::Serialize;
+
+use crate::eventdb::Sport;
+
+const CKEY_SPORT_ID: &str = "event.sportId";
+const CKEY_PRESET: &str = "discipline.preset";
+const CKEY_ORDERED: &str = "discipline.ordered";
+const CKEY_ORDER_TOLERANCE: &str = "discipline.orderTolerance";
+const CKEY_PENALIZE_MISSED_CONTROLS: &str = "discipline.penalizeMissedControls";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DisciplineRules {
+    pub preset: Sport,
+    /// Controls have to be punched in course order
+    pub ordered: bool,
+    /// Number of controls, which can be punched out of course order
+    pub order_tolerance: usize,
+    /// Missed control is penalized by time, the run is not disqualified for missing punch
+    pub penalize_missed_controls: bool,
+}
+
+impl DisciplineRules {
+    pub fn preset(sport: Sport) -> Self {
+        match sport {
+            Sport::FootO => Self { preset: sport, ordered: true, order_tolerance: 0, penalize_missed_controls: false },
+            // controls on track network are often close to each other, one of them can be punched out of order
+            Sport::Mtbo => Self { preset: sport, ordered: true, order_tolerance: 1, penalize_missed_controls: false },
+            // ski-o sprint rules penalize missed control by time instead of disqualification
+            Sport::SkiO => Self { preset: sport, ordered: true, order_tolerance: 0, penalize_missed_controls: true },
+        }
+    }
+}
+
+pub(crate) async fn load_discipline_rules(sql_api: &(impl QxSqlApi + Sync)) -> anyhow::Result<DisciplineRules> {
+    let result = sql_api.query("SELECT ckey, cvalue FROM config WHERE ckey = 'event.sportId' OR ckey LIKE 'discipline.%'", None).await?;
+    let values = (0..result.row_count())
+        .filter_map(|row| {
+            let ckey = result.value(row, 0).and_then(|v| v.as_str())?;
+            let cvalue = result.value(row, 1).and_then(|v| v.as_str())?;
+            Some((ckey.to_string(), cvalue.trim().to_string()))
+        })
+        .filter(|(_, cvalue)| !cvalue.is_empty())
+        .collect::<BTreeMap<_, _>>();
+    let sport = match values.get(CKEY_PRESET) {
+        Some(preset) => preset.parse::<Sport>()?,
+        None => values.get(CKEY_SPORT_ID)
+            .and_then(|id| id.parse::<i64>().ok())
+            .and_then(Sport::from_quickevent_id)
+            .unwrap_or_default(),
+    };
+    let flag = |ckey: &str| values.get(ckey).map(|cvalue| matches!(cvalue.as_str(), "true" | "1"));
+    let mut rules = DisciplineRules::preset(sport);
+    if let Some(ordered) = flag(CKEY_ORDERED) {
+        rules.ordered = ordered;
+    }
+    if let Some(order_tolerance) = values.get(CKEY_ORDER_TOLERANCE).and_then(|cvalue| cvalue.parse::<usize>().ok()) {
+        rules.order_tolerance = order_tolerance;
+    }
+    if let Some(penalize_missed_controls) = flag(CKEY_PENALIZE_MISSED_CONTROLS) {
+        rules.penalize_missed_controls = penalize_missed_controls;
+    }
+    Ok(rules)
+}
+
+/// Course controls not punched and punched out of order
+#[derive(Debug, Clone)]
+pub(crate) struct PunchValidation {
+    pub missing_codes: Vec<i64>,
+    pub out_of_order_codes: Vec<i64>,
+    pub ok: bool,
+}
+
+/// Validate punches against `expected` course controls `(code, altCode)`.
+///
+/// Controls in order are the longest common subsequence of course and punches, so a single
+/// swapped control does not make all following controls out of order.
+pub(crate) fn validate_punches(expected: &[(i64, Option<i64>)], punched: &[i64], rules: &DisciplineRules) -> PunchValidation {
+    let matches = |(code, alt_code): &(i64, Option<i64>), punch: i64| punch == *code || Some(punch) == *alt_code;
+    let is_punched = |control: &(i64, Option<i64>)| punched.iter().any(|punch| matches(control, *punch));
+    let missing_codes: Vec<i64> = expected.iter()
+        .filter(|control| !is_punched(*control))
+        .map(|(code, _)| *code)
+        .collect();
+    let out_of_order_codes = if rules.ordered {
+        let (n, m) = (expected.len(), punched.len());
+        // lcs[i][j] is length of common subsequence of expected[i..] and punched[j..]
+        let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if matches(&expected[i], punched[j]) {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let mut in_order = vec![false; n];
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if matches(&expected[i], punched[j]) {
+                in_order[i] = true;
+                i += 1;
+                j += 1;
+            } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        expected.iter().zip(in_order)
+            .filter(|(control, in_order)| !in_order && is_punched(*control))
+            .map(|((code, _), _)| *code)
+            .collect()
+    } else {
+        vec![]
+    };
+    let ok = (missing_codes.is_empty() || rules.penalize_missed_controls) && out_of_order_codes.len() <= rules.order_tolerance;
+    PunchValidation { missing_codes, out_of_order_codes, ok }
+}
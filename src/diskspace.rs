@@ -0,0 +1,116 @@
+//! Watchdog of free space in `data_dir`. Low free space is logged and signalled in advance,
+//! and writes to event DBs stored in `data_dir` are rejected before the disk is full, so running
+//! event DBs are not corrupted by failed writes in the middle of a race.
+
+use std::ffi::CString;
+use std::sync::Mutex;
+
+use log::{error, info, warn};
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use shvrpc::RpcMessage;
+
+use crate::global_config;
+use crate::state::SharedAppState;
+
+pub const SIG_DISK_SPACE_CHNG: &str = "diskspacechng";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DiskSpaceLevel {
+    #[default]
+    Ok,
+    Low,
+    ReadOnly,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DiskSpaceStatus {
+    pub level: DiskSpaceLevel,
+    /// Free space available to unprivileged user, not set until the first check
+    pub free_mb: Option<u64>,
+    pub checked_at: Option<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct DiskSpace {
+    status: Mutex<DiskSpaceStatus>,
+}
+
+impl DiskSpace {
+    pub fn status(&self) -> DiskSpaceStatus {
+        self.status.lock().unwrap().clone()
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.status.lock().unwrap().level == DiskSpaceLevel::ReadOnly
+    }
+    /// Returns previous level
+    fn update(&self, free_mb: u64, level: DiskSpaceLevel) -> DiskSpaceLevel {
+        let mut status = self.status.lock().unwrap();
+        let prev_level = status.level;
+        *status = DiskSpaceStatus {
+            level,
+            free_mb: Some(free_mb),
+            checked_at: Some(chrono::Local::now().fixed_offset().to_rfc3339()),
+        };
+        prev_level
+    }
+}
+
+// statvfs field types differ between platforms
+#[allow(clippy::unnecessary_cast)]
+fn free_space_mb(dir: &str) -> anyhow::Result<u64> {
+    let path = CString::new(dir)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is a valid NUL terminated string and stat is a valid out pointer
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64) / (1024 * 1024))
+}
+
+fn disk_space_level(free_mb: u64) -> DiskSpaceLevel {
+    let config = &global_config().disk_space;
+    if config.read_only_free_mb > 0 && free_mb < config.read_only_free_mb {
+        DiskSpaceLevel::ReadOnly
+    } else if config.warning_free_mb > 0 && free_mb < config.warning_free_mb {
+        DiskSpaceLevel::Low
+    } else {
+        DiskSpaceLevel::Ok
+    }
+}
+
+pub(crate) async fn watchdog(app_state: SharedAppState, rpc_client: ClientCommandSender) {
+    let config = &global_config().disk_space;
+    let data_dir = &global_config().data_dir;
+    let interval = config.interval.to_std().unwrap_or_default();
+    if interval.is_zero() || data_dir.is_empty() {
+        return;
+    }
+    info!("Disk space watchdog started, data dir: {data_dir}, check interval: {} sec", interval.as_secs());
+    loop {
+        match free_space_mb(data_dir) {
+            Ok(free_mb) => {
+                let level = disk_space_level(free_mb);
+                let prev_level = app_state.disk_space.update(free_mb, level);
+                match level {
+                    DiskSpaceLevel::ReadOnly => error!("Free space in {data_dir} is {free_mb} MB, event DBs are read-only"),
+                    DiskSpaceLevel::Low => warn!("Free space in {data_dir} is {free_mb} MB"),
+                    DiskSpaceLevel::Ok if prev_level != DiskSpaceLevel::Ok => info!("Free space in {data_dir} is {free_mb} MB again"),
+                    DiskSpaceLevel::Ok => {}
+                }
+                if level != prev_level {
+                    let status = app_state.disk_space.status();
+                    let signal = RpcMessage::new_signal(".app", SIG_DISK_SPACE_CHNG)
+                        .with_param(shvproto::to_rpcvalue(&status).unwrap_or_default());
+                    if let Err(e) = rpc_client.send_message(signal) {
+                        error!("Failed to send {SIG_DISK_SPACE_CHNG} signal: {e}");
+                    }
+                }
+            }
+            Err(e) => warn!("Cannot check free space in {data_dir}: {e}"),
+        }
+        smol::Timer::after(interval).await;
+    }
+}
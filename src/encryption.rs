@@ -0,0 +1,125 @@
+//! Encryption of local event DBs at rest by SQLCipher. Every connection to event DB is keyed by the master key
+//! before its first use, SQLCipher derives the page key from it using random salt stored in each DB file.
+//! The binary must be built with `encryption` feature, which links SQLCipher instead of SQLite.
+
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, bail};
+use async_sqlite::Pool;
+use async_sqlite::rusqlite::{self, Connection, OptionalExtension};
+use log::info;
+
+use crate::global_config;
+
+static MASTER_KEY: OnceLock<Option<String>> = OnceLock::new();
+
+fn load_master_key() -> anyhow::Result<Option<String>> {
+    let Some(config) = &global_config().encryption else {
+        return Ok(None);
+    };
+    let key = if let Some(key) = &config.master_key {
+        key.clone()
+    } else if let Some(file) = &config.master_key_file {
+        std::fs::read_to_string(file)
+            .map_err(|e| anyhow!("Cannot read encryption master key file {file}: {e}"))?
+            .trim_end()
+            .to_string()
+    } else if let Some((executable, args)) = config.master_key_command.split_first() {
+        let output = std::process::Command::new(executable).args(args).output()
+            .map_err(|e| anyhow!("Cannot run encryption master key command {executable}: {e}"))?;
+        if !output.status.success() {
+            bail!("Encryption master key command {executable} failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        String::from_utf8(output.stdout)?.trim_end().to_string()
+    } else {
+        bail!("Encryption master key source is not configured");
+    };
+    if key.is_empty() {
+        bail!("Encryption master key is empty");
+    }
+    Ok(Some(key))
+}
+
+/// Load master key, it must be called once on start before any event DB is opened
+pub fn init() -> anyhow::Result<()> {
+    let key = load_master_key()?;
+    if key.is_some() {
+        info!("Event DB encryption enabled");
+    }
+    MASTER_KEY.set(key).map_err(|_| anyhow!("Encryption master key is already loaded"))
+}
+
+fn master_key() -> Option<&'static str> {
+    MASTER_KEY.get().and_then(|key| key.as_deref())
+}
+
+pub fn is_enabled() -> bool {
+    master_key().is_some()
+}
+
+/// Returns false, if SQLite library is not SQLCipher and the key was ignored
+fn key_connection(conn: &Connection, key: &str) -> rusqlite::Result<bool> {
+    conn.pragma_update(None, "key", key)?;
+    Ok(conn.query_row("PRAGMA cipher_version", [], |row| row.get::<_, String>(0)).optional()?.is_some())
+}
+
+fn check_cipher(is_cipher: bool) -> anyhow::Result<()> {
+    if !is_cipher {
+        bail!("Event DB encryption is configured, but the binary is built without SQLCipher, enable the 'encryption' feature");
+    }
+    Ok(())
+}
+
+/// Key all connections of event DB pool, the pool must be opened without journal mode,
+/// because encrypted DB cannot be read before it is keyed
+pub async fn key_pool(pool: &Pool) -> anyhow::Result<()> {
+    let Some(key) = master_key() else {
+        return Ok(());
+    };
+    for result in pool.conn_for_each(move |conn| key_connection(conn, key)).await {
+        check_cipher(result?)?;
+    }
+    Ok(())
+}
+
+/// Open connection to event DB file
+pub fn open_connection(db_file: &str) -> anyhow::Result<Connection> {
+    let conn = Connection::open(db_file)?;
+    if let Some(key) = master_key() {
+        check_cipher(key_connection(&conn, key)?)?;
+    }
+    Ok(conn)
+}
+
+/// Copy keyed event DB to not encrypted file
+pub fn export_plaintext(conn: &Connection, dest_file: &str) -> anyhow::Result<()> {
+    if !is_enabled() {
+        conn.execute("VACUUM INTO ?1", [dest_file])?;
+        return Ok(());
+    }
+    conn.execute("ATTACH DATABASE ?1 AS plaintext KEY ''", [dest_file])?;
+    let exported = conn.query_row("SELECT sqlcipher_export('plaintext')", [], |_| Ok(()));
+    conn.execute_batch("DETACH DATABASE plaintext")?;
+    Ok(exported?)
+}
+
+/// Encrypt not encrypted DB file in place, the file is left untouched, if encryption is not enabled
+pub fn encrypt_file(db_file: &str) -> anyhow::Result<()> {
+    let Some(key) = master_key() else {
+        return Ok(());
+    };
+    let encrypted_file = format!("{db_file}.encrypted");
+    {
+        let conn = Connection::open(db_file)?;
+        conn.execute("ATTACH DATABASE ?1 AS encrypted KEY ?2", [encrypted_file.as_str(), key])?;
+        let exported = conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))
+            .map_err(|e| anyhow!("Encryption of {db_file} failed: {e}"));
+        conn.execute_batch("DETACH DATABASE encrypted")?;
+        if let Err(e) = exported {
+            let _ = std::fs::remove_file(&encrypted_file);
+            return Err(e);
+        }
+    }
+    std::fs::rename(&encrypted_file, db_file)?;
+    Ok(())
+}
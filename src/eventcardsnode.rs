@@ -0,0 +1,177 @@
+use anyhow::{anyhow, bail};
+use qxsql::QxSqlApi;
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::RpcValue;
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::anyhow_to_rpc_error;
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::results::{RunFilter, RunStatus, format_time_ms, load_run_results, stage_time};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+
+const METH_PRINTOUT: &str = "printout";
+
+pub(crate) const EVENT_CARDS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_PRINTOUT, Flags::None, AccessLevel::Read, "i:cardId|{i:cardId,s|n:format,i|n:width}", "s|x", &[], "",
+    ),
+];
+
+const DEFAULT_RECEIPT_WIDTH: usize = 42;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PrintoutFormat {
+    /// Plain text string
+    #[default]
+    Text,
+    /// ESC/POS blob, it can be sent to thermal printer directly
+    Escpos,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrintoutParams {
+    card_id: i64,
+    #[serde(default)]
+    format: PrintoutFormat,
+    /// Receipt width in characters
+    #[serde(default)]
+    width: Option<usize>,
+}
+impl_rpcvalue_conversions!(PrintoutParams);
+
+impl PrintoutParams {
+    fn from_param(param: &RpcValue) -> anyhow::Result<Self> {
+        if param.is_int() {
+            return Ok(Self { card_id: param.as_int(), format: PrintoutFormat::Text, width: None });
+        }
+        Self::try_from(param)
+    }
+}
+
+/// `label` on the left, `value` aligned to the right
+fn receipt_line(label: &str, value: &str, width: usize) -> String {
+    let label: String = label.chars().take(width.saturating_sub(value.chars().count() + 1)).collect();
+    let padding = width.saturating_sub(label.chars().count() + value.chars().count());
+    format!("{label}{}{value}\n", " ".repeat(padding))
+}
+
+/// Split receipt lines, first line is the receipt title
+async fn receipt_lines(sql_api: &EventSqlApi, card_id: i64, width: usize) -> anyhow::Result<Vec<String>> {
+    let result = sql_api.query("SELECT runId, stageId FROM cards WHERE id = :id", Some(&record_from_slice(&[
+        ("id", card_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Card id: {card_id} not found");
+    }
+    let run_id = result.value(0, 0).and_then(|v| v.to_int())
+        .ok_or_else(|| anyhow!("Card id: {card_id} is not assigned to any run"))?;
+    let stage_id = result.value(0, 1).and_then(|v| v.to_int()).unwrap_or(1);
+
+    let run = load_run_results(sql_api, stage_id, RunFilter::Runs(&[run_id])).await?
+        .into_iter().next()
+        .ok_or_else(|| anyhow!("Run id: {run_id} not found"))?;
+    // preliminary place depends on results of runs finished so far
+    let class_results = load_run_results(sql_api, stage_id, RunFilter::Class(run.class_id)).await?;
+    let finished = class_results.iter().filter(|r| r.place.is_some()).count();
+    let place = class_results.iter().find(|r| r.run_id == run_id).and_then(|r| r.place);
+
+    let result = sql_api.query("SELECT position, code, stpTimeMs, lapTimeMs FROM runlaps WHERE runId = :runId ORDER BY position", Some(&record_from_slice(&[
+        ("runId", run_id.into()),
+    ]))).await?;
+
+    let stage_start = sql_api.stage_start(stage_id).await.ok();
+    let clock = |time_ms: Option<i64>| match (stage_start, time_ms) {
+        (Some(stage_start), Some(time_ms)) => stage_time(stage_start, time_ms).format("%H:%M:%S").to_string(),
+        (None, Some(time_ms)) => format_time_ms(time_ms),
+        _ => "-".to_string(),
+    };
+    let duration = |time_ms: Option<i64>| time_ms.map(format_time_ms).unwrap_or_else(|| "-".to_string());
+
+    let event_name = sql_api.query("SELECT cvalue FROM config WHERE ckey = 'event.name'", None).await?;
+    let mut lines = vec![event_name.value(0, 0).and_then(|v| v.as_str()).unwrap_or_default().to_string()];
+    lines.push("=".repeat(width));
+    lines.push(receipt_line(&run.name(), &run.class_name, width));
+    lines.push(receipt_line(&run.club, &run.si_id.map(|n| n.to_string()).unwrap_or_default(), width));
+    lines.push(receipt_line("Start", &clock(run.start_time_ms), width));
+    lines.push("-".repeat(width));
+    for row in 0..result.row_count() {
+        let int = |col| result.value(row, col).and_then(|v| v.to_int());
+        let position = int(0).unwrap_or_default();
+        let code = int(1).map(|c| c.to_string()).unwrap_or_else(|| "?".to_string());
+        let label = format!("{position:>2}. {code:>4}");
+        lines.push(receipt_line(&label, &format!("{:>9} {:>9}", duration(int(2)), duration(int(3))), width));
+    }
+    lines.push("-".repeat(width));
+    lines.push(receipt_line("Finish", &clock(run.finish_time_ms), width));
+    let total = match run.status {
+        RunStatus::Ok => duration(run.time_ms),
+        status => status.as_short_str().to_string(),
+    };
+    lines.push(receipt_line("Time", &total, width));
+    if let Some(place) = place {
+        lines.push(receipt_line("Place", &format!("{place}/{finished}"), width));
+    }
+    lines.push(receipt_line("", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(), width));
+    Ok(lines.into_iter().map(|line| if line.ends_with('\n') { line } else { format!("{line}\n") }).collect())
+}
+
+/// ESC/POS receipt, title is printed bold and the paper is cut at the end
+fn escpos_receipt(lines: &[String]) -> Vec<u8> {
+    const ESC: u8 = 0x1b;
+    const GS: u8 = 0x1d;
+    let mut data = vec![ESC, b'@'];
+    for (ix, line) in lines.iter().enumerate() {
+        if ix == 0 {
+            data.extend_from_slice(&[ESC, b'E', 1]);
+        }
+        // printers use single byte code pages, non ASCII characters are replaced
+        data.extend(line.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }));
+        if ix == 0 {
+            data.extend_from_slice(&[ESC, b'E', 0]);
+        }
+    }
+    data.extend_from_slice(&[ESC, b'd', 4, GS, b'V', 1]);
+    data
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_CARDS_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_CARDS_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_CARDS_NODE_METHODS).await;
+            match method {
+                METH_PRINTOUT => m.resolve(methods, async move || {
+                    let param = PrintoutParams::from_param(rq.param().unwrap_or_default())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let width = param.width.unwrap_or(DEFAULT_RECEIPT_WIDTH).clamp(24, 80);
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let lines = receipt_lines(&sql_api, param.card_id, width).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(match param.format {
+                        PrintoutFormat::Text => RpcValue::from(lines.concat()),
+                        PrintoutFormat::Escpos => RpcValue::from(escpos_receipt(&lines)),
+                    })
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{anyhow, bail};
+use log::error;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::anyhow_to_rpc_error;
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::{notify, publish};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState, refresh_event_record_from_event_config};
+use crate::traffic;
+
+const METH_GET: &str = "get";
+const METH_SET_VALUE: &str = "setValue";
+const METH_LIST: &str = "list";
+const SIG_CHNG: &str = "chng";
+
+pub(crate) const EVENT_CONFIG_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_GET, Flags::None, AccessLevel::Read, "s:ckey", "?", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SET_VALUE, Flags::None, AccessLevel::Write, "[s:ckey,?:value]", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_LIST, Flags::None, AccessLevel::Read, "", "{?}", &[], "",
+    ),
+];
+
+/// Config keys mirrored to the event record in master DB
+const EVENT_RECORD_CONFIG_KEYS: &[&str] = &["event.name", "event.currentStageId"];
+
+/// Keys set by `setValue` as event secrets, they are kept out of the config table and cannot be read back
+const SECRET_CONFIG_KEYS: &[&str] = &[notify::SKEY_WEBHOOK_SECRET, publish::SKEY_API_KEY];
+
+/// Config values are stored as strings, `ctype` is QuickEvent (Qt) type name of the value
+fn config_value_to_rpcvalue(cvalue: Option<&str>, ctype: Option<&str>) -> RpcValue {
+    let Some(cvalue) = cvalue else {
+        return RpcValue::null();
+    };
+    match ctype.unwrap_or_default() {
+        "int" => cvalue.trim().parse::<i64>().map(RpcValue::from).unwrap_or_else(|_| RpcValue::null()),
+        "double" => cvalue.trim().parse::<f64>().map(RpcValue::from).unwrap_or_else(|_| RpcValue::null()),
+        "bool" => RpcValue::from(matches!(cvalue.trim(), "true" | "1")),
+        _ => RpcValue::from(cvalue),
+    }
+}
+
+fn rpcvalue_to_config_value(ckey: &str, value: &RpcValue, ctype: &str) -> anyhow::Result<Option<String>> {
+    use shvproto::rpcvalue::Value;
+    let invalid = || anyhow!("Invalid value {} of config key '{ckey}' type: {ctype}", value.to_cpon());
+    let cvalue = match (&value.value, ctype) {
+        (Value::Null, _) => return Ok(None),
+        (Value::Int(n), "int" | "double") => n.to_string(),
+        (Value::UInt(n), "int" | "double") => n.to_string(),
+        (Value::String(s), "int") => s.trim().parse::<i64>().map_err(|_| invalid())?.to_string(),
+        (Value::Double(n), "double") => n.to_string(),
+        (Value::String(s), "double") => s.trim().parse::<f64>().map_err(|_| invalid())?.to_string(),
+        (Value::Bool(b), "bool") => b.to_string(),
+        (Value::Int(n), "bool") => (*n != 0).to_string(),
+        (Value::String(s), "QDate") => chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| invalid())?.format("%Y-%m-%d").to_string(),
+        (Value::String(s), "QTime") => chrono::NaiveTime::parse_from_str(s, "%H:%M:%S").map_err(|_| invalid())?.format("%H:%M:%S").to_string(),
+        (Value::String(s), "QDateTime") => chrono::DateTime::parse_from_rfc3339(s).map_err(|_| invalid())?.to_rfc3339(),
+        (Value::String(s), "QString" | "") => s.to_string(),
+        (Value::Int(_) | Value::UInt(_) | Value::Double(_) | Value::Bool(_), "QString" | "") => value.to_cpon(),
+        _ => return Err(invalid()),
+    };
+    Ok(Some(cvalue))
+}
+
+/// Type of value of new config key, it is derived from value itself
+fn ctype_of_rpcvalue(value: &RpcValue) -> &'static str {
+    use shvproto::rpcvalue::Value;
+    match &value.value {
+        Value::Int(_) | Value::UInt(_) => "int",
+        Value::Double(_) => "double",
+        Value::Bool(_) => "bool",
+        _ => "QString",
+    }
+}
+
+async fn config_value(sql_api: &EventSqlApi, ckey: &str) -> anyhow::Result<Option<(Option<String>, Option<String>)>> {
+    let result = sql_api.query("SELECT cvalue, ctype FROM config WHERE ckey = :ckey", Some(&record_from_slice(&[
+        ("ckey", ckey.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        return Ok(None);
+    }
+    let get = |col| result.value(0, col).and_then(|v| v.as_str()).map(str::to_string);
+    Ok(Some((get(0), get(1))))
+}
+
+pub(crate) async fn get_config_value(sql_api: &EventSqlApi, ckey: &str) -> anyhow::Result<RpcValue> {
+    if SECRET_CONFIG_KEYS.contains(&ckey) {
+        bail!("Config key '{ckey}' is secret, it cannot be read");
+    }
+    let (cvalue, ctype) = config_value(sql_api, ckey).await?
+        .ok_or_else(|| anyhow!("Unknown config key: '{ckey}'"))?;
+    Ok(config_value_to_rpcvalue(cvalue.as_deref(), ctype.as_deref()))
+}
+
+async fn set_config_value(sql_api: &EventSqlApi, ckey: &str, value: &RpcValue) -> anyhow::Result<bool> {
+    if ckey.is_empty() {
+        bail!("Config key cannot be empty");
+    }
+    if SECRET_CONFIG_KEYS.contains(&ckey) {
+        return set_secret_value(sql_api, ckey, value).await;
+    }
+    let ctype = match config_value(sql_api, ckey).await? {
+        Some((_, Some(ctype))) => ctype,
+        _ => ctype_of_rpcvalue(value).to_string(),
+    };
+    let cvalue = rpcvalue_to_config_value(ckey, value, &ctype)?;
+    let result = sql_api.exec("INSERT INTO config (ckey, cvalue, ctype) VALUES (:ckey, :cvalue, :ctype) \
+        ON CONFLICT (ckey) DO UPDATE SET cvalue = excluded.cvalue, ctype = COALESCE(ctype, excluded.ctype)",
+        Some(&record_from_slice(&[
+            ("ckey", ckey.into()),
+            ("cvalue", cvalue.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("ctype", ctype.into()),
+        ]))).await?;
+    Ok(result.rows_affected > 0)
+}
+
+async fn set_secret_value(sql_api: &EventSqlApi, ckey: &str, value: &RpcValue) -> anyhow::Result<bool> {
+    use shvproto::rpcvalue::Value;
+    let svalue = match &value.value {
+        Value::Null => None,
+        Value::String(s) => Some(s.trim()).filter(|s| !s.is_empty()),
+        _ => bail!("Invalid value {} of secret config key '{ckey}', string expected", value.to_cpon()),
+    };
+    sql_api.set_secret(ckey, svalue).await?;
+    // secret stored by older version in the config table is removed
+    sql_api.exec("DELETE FROM config WHERE ckey = :ckey", Some(&record_from_slice(&[
+        ("ckey", ckey.into()),
+    ]))).await?;
+    Ok(true)
+}
+
+async fn list_config_values(sql_api: &EventSqlApi) -> anyhow::Result<RpcValue> {
+    let result = sql_api.query("SELECT ckey, cvalue, ctype FROM config ORDER BY ckey", None).await?;
+    let mut map = shvproto::rpcvalue::Map::new();
+    for row in 0..result.row_count() {
+        let Some(ckey) = result.value(row, 0).and_then(|v| v.as_str()).filter(|ckey| !SECRET_CONFIG_KEYS.contains(ckey)) else {
+            continue;
+        };
+        let cvalue = result.value(row, 1).and_then(|v| v.as_str());
+        let ctype = result.value(row, 2).and_then(|v| v.as_str());
+        map.insert(ckey.to_string(), config_value_to_rpcvalue(cvalue, ctype));
+    }
+    Ok(map.into())
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_CONFIG_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_CONFIG_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            match method {
+                METH_GET => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_CONFIG_NODE_METHODS).await, async move || {
+                    let ckey = rq.param().unwrap_or_default().as_str();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    get_config_value(&sql_api, ckey).await
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_SET_VALUE => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_CONFIG_NODE_METHODS).await, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let ckey = param.first().map(|v| v.as_str()).unwrap_or_default();
+                    let value = param.get(1).cloned().unwrap_or_default();
+                    let sql_api = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let changed = set_config_value(&sql_api, ckey, &value).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    // value of secret is not broadcast
+                    if changed && !SECRET_CONFIG_KEYS.contains(&ckey) {
+                        let value = get_config_value(&sql_api, ckey).await
+                            .map_err(anyhow_to_rpc_error)?;
+                        let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/config"), SIG_CHNG)
+                            .with_param(make_map!("ckey" => ckey, "value" => value));
+                        traffic::record_signal(event_id, &signal);
+                        if let Err(e) = client_cmd_tx.send_message(signal) {
+                            error!("Failed to send event {event_id} config {SIG_CHNG} signal: {e}");
+                        }
+                        if EVENT_RECORD_CONFIG_KEYS.contains(&ckey) {
+                            refresh_event_record_from_event_config(app_state, client_cmd_tx, event_id).await
+                                .map_err(anyhow_to_rpc_error)?;
+                        }
+                    }
+                    Ok(changed)
+                }),
+                METH_LIST => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_CONFIG_NODE_METHODS).await, async move || {
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    list_config_values(&sql_api).await
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
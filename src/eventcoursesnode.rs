@@ -0,0 +1,199 @@
+use anyhow::{anyhow, bail};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::discipline::{load_discipline_rules, validate_punches};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, issuer};
+
+const METH_VARIANTS: &str = "variants";
+const METH_ASSIGN_RELAY_VARIANTS: &str = "assignRelayVariants";
+const METH_CHECK_PUNCHES: &str = "checkPunches";
+const METH_DISCIPLINE_RULES: &str = "disciplineRules";
+
+pub(crate) const EVENT_COURSES_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_VARIANTS, Flags::None, AccessLevel::Read, "i:courseId", "[s:variant]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_ASSIGN_RELAY_VARIANTS, Flags::None, AccessLevel::Write, "[i:relayId,[s|n:legVariant]]", "[i:runId]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_CHECK_PUNCHES, Flags::None, AccessLevel::Read, "i:runId", "{b:ok,s|n:variant,[i]:expectedCodes,[i]:missingCodes,[i]:outOfOrderCodes}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DISCIPLINE_RULES, Flags::None, AccessLevel::Read, "", "{s:preset,b:ordered,i:orderTolerance,b:penalizeMissedControls}", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CheckPunchesResult {
+    ok: bool,
+    variant: Option<String>,
+    expected_codes: Vec<i64>,
+    /// Missing controls are penalized instead of making the run invalid, if discipline rules say so
+    missing_codes: Vec<i64>,
+    out_of_order_codes: Vec<i64>,
+}
+impl_rpcvalue_conversions!(CheckPunchesResult);
+
+/// Forking keys defined in course
+async fn course_variants(sql_api: &EventSqlApi, course_id: i64) -> anyhow::Result<Vec<String>> {
+    let result = sql_api.query("SELECT DISTINCT variant FROM coursecodes WHERE courseId = :courseId AND variant IS NOT NULL ORDER BY variant", Some(&record_from_slice(&[
+        ("courseId", course_id.into()),
+    ]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.as_str()).map(str::to_string))
+        .collect())
+}
+
+/// Assign course variant to relay legs, `variants[0]` belongs to leg 1.
+///
+/// Returns ids of updated runs.
+async fn assign_relay_variants(sql_api: &EventSqlApi, relay_id: i64, variants: &[Option<String>], issuer: Option<String>) -> anyhow::Result<Vec<i64>> {
+    let result = sql_api.query("SELECT runs.id, runs.leg, COALESCE(runs.courseId, classdefs.courseId) FROM runs \
+        JOIN relays ON relays.id = runs.relayId \
+        LEFT JOIN classdefs ON classdefs.classId = relays.classId AND classdefs.stageId = runs.stageId \
+        WHERE runs.relayId = :relayId ORDER BY runs.leg", Some(&record_from_slice(&[
+        ("relayId", relay_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Relay id: {relay_id} has no legs");
+    }
+    let mut updated = vec![];
+    for row in 0..result.row_count() {
+        let Some(run_id) = result.value(row, 0).and_then(|v| v.to_int()) else {
+            continue;
+        };
+        let Some(leg) = result.value(row, 1).and_then(|v| v.to_int()).filter(|leg| *leg > 0) else {
+            continue;
+        };
+        let Some(variant) = variants.get(leg as usize - 1) else {
+            continue;
+        };
+        if let Some(variant) = variant {
+            let course_id = result.value(row, 2).and_then(|v| v.to_int())
+                .ok_or_else(|| anyhow!("Relay {relay_id} leg {leg} has no course"))?;
+            if !course_variants(sql_api, course_id).await?.contains(variant) {
+                bail!("Variant '{variant}' is not defined in course {course_id} of relay {relay_id} leg {leg}");
+            }
+        }
+        let record = record_from_slice(&[("courseVariant", variant.clone().map(DbValue::from).unwrap_or(DbValue::Null))]);
+        if sql_api.update_record_event("runs", run_id, &record, issuer.clone()).await? {
+            updated.push(run_id);
+        }
+    }
+    Ok(updated)
+}
+
+/// Check punches of run against codes of the assigned course variant, the order of punches is checked by discipline rules
+async fn check_punches(sql_api: &EventSqlApi, run_id: i64) -> anyhow::Result<CheckPunchesResult> {
+    let result = sql_api.query("SELECT runs.stageId, runs.courseVariant, COALESCE(runs.courseId, classdefs.courseId) FROM runs \
+        LEFT JOIN competitors ON competitors.id = runs.competitorId \
+        LEFT JOIN relays ON relays.id = runs.relayId \
+        LEFT JOIN classdefs ON classdefs.classId = COALESCE(competitors.classId, relays.classId) AND classdefs.stageId = runs.stageId \
+        WHERE runs.id = :runId", Some(&record_from_slice(&[
+        ("runId", run_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Run id: {run_id} not found");
+    }
+    let stage_id = result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default();
+    let variant = result.value(0, 1).and_then(|v| v.as_str()).map(str::to_string);
+    let course_id = result.value(0, 2).and_then(|v| v.to_int())
+        .ok_or_else(|| anyhow!("Run {run_id} has no course"))?;
+    if variant.is_none() && !course_variants(sql_api, course_id).await?.is_empty() {
+        bail!("Run {run_id} has no variant of forked course {course_id} assigned");
+    }
+
+    let result = sql_api.query("SELECT codes.code, codes.altCode FROM coursecodes JOIN codes ON codes.id = coursecodes.codeId \
+        WHERE coursecodes.courseId = :courseId AND (coursecodes.variant IS NULL OR coursecodes.variant = :variant) \
+        AND NOT codes.outOfOrder ORDER BY coursecodes.position", Some(&record_from_slice(&[
+        ("courseId", course_id.into()),
+        ("variant", variant.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+    ]))).await?;
+    let expected: Vec<(i64, Option<i64>)> = (0..result.row_count())
+        .filter_map(|row| Some((result.value(row, 0).and_then(|v| v.to_int())?, result.value(row, 1).and_then(|v| v.to_int()))))
+        .collect();
+
+    let result = sql_api.query("SELECT code FROM punches WHERE runId = :runId AND stageId = :stageId ORDER BY timeMs", Some(&record_from_slice(&[
+        ("runId", run_id.into()),
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    let punched: Vec<i64> = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect();
+
+    let rules = load_discipline_rules(sql_api).await?;
+    let validation = validate_punches(&expected, &punched, &rules);
+    Ok(CheckPunchesResult {
+        ok: validation.ok,
+        variant,
+        expected_codes: expected.into_iter().map(|(code, _)| code).collect(),
+        missing_codes: validation.missing_codes,
+        out_of_order_codes: validation.out_of_order_codes,
+    })
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_COURSES_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_COURSES_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_COURSES_NODE_METHODS).await;
+            match method {
+                METH_VARIANTS => m.resolve(methods, async move || {
+                    let course_id = rq.param().unwrap_or_default().as_int();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    course_variants(&sql_api, course_id).await
+                        .map(|variants| RpcValue::from(variants.into_iter().map(RpcValue::from).collect::<Vec<_>>()))
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_ASSIGN_RELAY_VARIANTS => m.resolve(methods, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let relay_id = param.first().map(|v| v.as_int()).unwrap_or_default();
+                    let variants: Vec<Option<String>> = param.get(1).into_iter().flat_map(|v| v.as_list().iter())
+                        .map(|v| (!v.is_null()).then(|| v.as_str().to_string()).filter(|s| !s.is_empty()))
+                        .collect();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    assign_relay_variants(&sql_api, relay_id, &variants, issuer(&rq)).await
+                        .map(|run_ids| RpcValue::from(run_ids.into_iter().map(RpcValue::from).collect::<Vec<_>>()))
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_CHECK_PUNCHES => m.resolve(methods, async move || {
+                    let run_id = rq.param().unwrap_or_default().as_int();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    check_punches(&sql_api, run_id).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_DISCIPLINE_RULES => m.resolve(methods, async move || {
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let rules = load_discipline_rules(&sql_api).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&rules).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
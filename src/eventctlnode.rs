@@ -1,41 +1,138 @@
 
-use log::warn;
-use qxsql::sql::{EXEC_PARAMS, EXEC_RESULT, QUERY_PARAMS, QUERY_RESULT, READ_PARAMS, READ_RESULT};
-use qxsql::{QueryAndParams, QxSqlApi, QxSqlApiRecChng, RecDeleteParam, RecInsertParam, RecReadParam, RecUpdateParam};
+use log::{error, info, warn};
+use qxsql::sql::{CREATE_PARAMS, CREATE_RESULT, DELETE_PARAMS, DELETE_RESULT, EXEC_RESULT, QUERY_RESULT, READ_PARAMS, READ_RESULT, UPDATE_PARAMS, UPDATE_RESULT};
+use qxsql::{QxSqlApi, RecDeleteParam, RecInsertParam, RecReadParam, RecUpdateParam};
+use qxsql::sql::Record;
 use serde::{Deserialize, Serialize};
 use shvclient::ClientCommandSender;
 use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
-use shvproto::{RpcValue, from_rpcvalue, to_rpcvalue};
+use shvproto::{RpcValue, from_rpcvalue, make_map, to_rpcvalue};
 use shvrpc::metamethod::{AccessLevel, MetaMethod, Flags};
 use shvrpc::{RpcMessage, RpcMessageMetaTags};
-use crate::eventsqlapi::EventSqlApi;
+use crate::anonymize::anonymize_event;
+use crate::eventdb::{Sport, integrity_check};
+use crate::eventsqlapi::{EventSqlApi, is_priority_table};
+use crate::personaldata::export_personal_data;
+use crate::selfservice::{IssuePersonalTokenParams, MyRaceParams, issue_personal_token, my_race};
+use crate::{gateway, organizations, qbeimport, eventconfignode, eventenumznode, eventrunsnode, eventfinishnode, eventdrawnode, eventcoursesnode, eventexportnode, eventcardsnode, evententriesnode, eventpaymentsnode, eventtimesyncnode, eventhistorynode, eventsimulatenode, eventrulesnode, eventresultsnode, eventspeakernode, eventprotestsnode, eventpenaltiesnode, eventreportsnode, eventmapsnode, traffic};
+use crate::ratelimit::LimitedNode;
+use crate::rqtrace::RequestTrace;
 use crate::qxchange::{self, Data, LateEntry, QxChangeRecord};
-use crate::{anyhow_to_rpc_error, str_to_rpc_error, string_to_rpc_error};
-use crate::state::{open_event, EventId, EventRecordChange, SharedAppState};
+use crate::appsqlapi::{EXEC_SCRIPT_PARAMS, EXEC_SCRIPT_RESULT, EXPLAIN_PARAMS, EXPLAIN_RESULT, ExecScriptParams, ExplainParams, SQL_QUERY_PARAMS, SqlQueryParams, UPSERT_PARAMS, UPSERT_RESULT, UpsertParams, is_valid_sql_identifier};
+use crate::{anyhow_to_rpc_error, generate_api_token, global_config, issuer, str_to_rpc_error, string_to_rpc_error};
+use crate::state::{close_event, delete_event, event_db_file, migrate_event, open_event, BlobUpload, EventId, MAX_BLOB_CHUNK_SIZE, EventNotOpen, EventRecord, EventRecordChange, SharedAppState};
 
 
 #[derive(Debug)]
 enum EventCtlNode {
     Root,
+    Import,
+    ImportQbe,
     Event(EventId),
     EventSql(EventId),
+    EventConfig(EventId),
+    EventEnumz(EventId),
+    EventRuns(EventId),
+    EventFinish(EventId),
+    EventDraw(EventId),
+    EventCourses(EventId),
+    EventExport(EventId),
+    EventCards(EventId),
+    EventEntries(EventId),
+    EventPayments(EventId),
+    EventTimesync(EventId),
+    EventHistory(EventId),
+    EventSimulate(EventId),
+    EventRules(EventId),
+    EventResults(EventId),
+    EventSpeaker(EventId),
+    EventProtests(EventId),
+    EventPenalties(EventId),
+    EventReports(EventId),
+    EventMaps(EventId),
 }
 
 impl EventCtlNode {
     fn from_path(path: &str) -> anyhow::Result<Self> {
-        if path.is_empty() {
-            return Ok(Self::Root);
+        match path {
+            "" => return Ok(Self::Root),
+            IMPORT_NODE => return Ok(Self::Import),
+            "import/qbe" => return Ok(Self::ImportQbe),
+            _ => {}
         }
-        if let Some(prefix) = path.strip_suffix("/sql") {
-            let event_id = prefix.parse::<i64>()?;
-            return Ok(Self::EventSql(event_id));
+        let (event_id, child) = path.split_once('/').unwrap_or((path, ""));
+        let event_id = event_id.parse::<i64>()?;
+        match child {
+            "" => Ok(Self::Event(event_id)),
+            "sql" => Ok(Self::EventSql(event_id)),
+            "config" => Ok(Self::EventConfig(event_id)),
+            "enumz" => Ok(Self::EventEnumz(event_id)),
+            "runs" => Ok(Self::EventRuns(event_id)),
+            "finish" => Ok(Self::EventFinish(event_id)),
+            "draw" => Ok(Self::EventDraw(event_id)),
+            "courses" => Ok(Self::EventCourses(event_id)),
+            "export" => Ok(Self::EventExport(event_id)),
+            "cards" => Ok(Self::EventCards(event_id)),
+            "entries" => Ok(Self::EventEntries(event_id)),
+            "payments" => Ok(Self::EventPayments(event_id)),
+            "timesync" => Ok(Self::EventTimesync(event_id)),
+            "history" => Ok(Self::EventHistory(event_id)),
+            "simulate" => Ok(Self::EventSimulate(event_id)),
+            "rules" => Ok(Self::EventRules(event_id)),
+            "results" => Ok(Self::EventResults(event_id)),
+            "speaker" => Ok(Self::EventSpeaker(event_id)),
+            "protests" => Ok(Self::EventProtests(event_id)),
+            "penalties" => Ok(Self::EventPenalties(event_id)),
+            "reports" => Ok(Self::EventReports(event_id)),
+            "maps" => Ok(Self::EventMaps(event_id)),
+            _ => Err(anyhow::anyhow!("Invalid event node: {child}")),
         }
-        let event_id = path.parse::<i64>()?;
-        Ok(Self::Event(event_id))
     }
 
+    fn event_id(&self) -> Option<EventId> {
+        match self {
+            Self::Root | Self::Import | Self::ImportQbe => None,
+            Self::Event(event_id) | Self::EventSql(event_id) | Self::EventConfig(event_id) | Self::EventEnumz(event_id) | Self::EventRuns(event_id) | Self::EventFinish(event_id) | Self::EventDraw(event_id) | Self::EventCourses(event_id) | Self::EventExport(event_id) | Self::EventCards(event_id) | Self::EventEntries(event_id) | Self::EventPayments(event_id) | Self::EventTimesync(event_id) | Self::EventHistory(event_id) | Self::EventSimulate(event_id) | Self::EventRules(event_id) | Self::EventResults(event_id) | Self::EventSpeaker(event_id) | Self::EventProtests(event_id) | Self::EventPenalties(event_id) | Self::EventReports(event_id) | Self::EventMaps(event_id) => Some(*event_id),
+        }
+    }
+
+    fn methods(&self) -> &'static [MetaMethod] {
+        match self {
+            Self::Root => EVENTCTL_ROOT_METHODS,
+            Self::Import => EVENTCTL_IMPORT_NODE_METHODS,
+            Self::ImportQbe => qbeimport::IMPORT_QBE_NODE_METHODS,
+            Self::Event(_) => EVENTCTL_NODE_METHODS,
+            Self::EventSql(_) => EVENTCTL_SQL_NODE_METHODS,
+            Self::EventConfig(_) => eventconfignode::EVENT_CONFIG_NODE_METHODS,
+            Self::EventEnumz(_) => eventenumznode::EVENT_ENUMZ_NODE_METHODS,
+            Self::EventRuns(_) => eventrunsnode::EVENT_RUNS_NODE_METHODS,
+            Self::EventFinish(_) => eventfinishnode::EVENT_FINISH_NODE_METHODS,
+            Self::EventDraw(_) => eventdrawnode::EVENT_DRAW_NODE_METHODS,
+            Self::EventCourses(_) => eventcoursesnode::EVENT_COURSES_NODE_METHODS,
+            Self::EventExport(_) => eventexportnode::EVENT_EXPORT_NODE_METHODS,
+            Self::EventCards(_) => eventcardsnode::EVENT_CARDS_NODE_METHODS,
+            Self::EventEntries(_) => evententriesnode::EVENT_ENTRIES_NODE_METHODS,
+            Self::EventPayments(_) => eventpaymentsnode::EVENT_PAYMENTS_NODE_METHODS,
+            Self::EventTimesync(_) => eventtimesyncnode::EVENT_TIMESYNC_NODE_METHODS,
+            Self::EventHistory(_) => eventhistorynode::EVENT_HISTORY_NODE_METHODS,
+            Self::EventSimulate(_) => eventsimulatenode::EVENT_SIMULATE_NODE_METHODS,
+            Self::EventRules(_) => eventrulesnode::EVENT_RULES_NODE_METHODS,
+            Self::EventResults(_) => eventresultsnode::EVENT_RESULTS_NODE_METHODS,
+            Self::EventSpeaker(_) => eventspeakernode::EVENT_SPEAKER_NODE_METHODS,
+            Self::EventProtests(_) => eventprotestsnode::EVENT_PROTESTS_NODE_METHODS,
+            Self::EventPenalties(_) => eventpenaltiesnode::EVENT_PENALTIES_NODE_METHODS,
+            Self::EventReports(_) => eventreportsnode::EVENT_REPORTS_NODE_METHODS,
+            Self::EventMaps(_) => eventmapsnode::EVENT_MAPS_NODE_METHODS,
+        }
+    }
 }
 
+/// Child nodes of event node
+const EVENT_CHILD_NODES: &[&str] = &[
+    "sql", "config", "enumz", "runs", "finish", "draw", "courses", "export", "cards", "entries", "payments", "timesync",
+    "history", "simulate", "rules", "results", "speaker", "protests", "penalties", "reports", "maps",
+];
+
 const METH_CREATE_EVENT: &str = "createEvent";
 const METH_OPEN_EVENT: &str = "openEvent";
 const METH_OPEN_EVENT_API_KEY: &str = "openEventApiKey";
@@ -44,12 +141,19 @@ const METH_UPDATE_EVENT_RECORD: &str = "updateEventRecord";
 const METH_DELETE_EVENT: &str = "deleteEvent";
 const METH_LIST_EVENTS: &str = "listEvents";
 const METH_EVENT_DATA: &str = "eventData";
+const METH_MIGRATE_EVENT: &str = "migrateEvent";
+const METH_CREATE_ORGANIZATION: &str = "createOrganization";
+const METH_SET_EVENT_ORGANIZATION: &str = "setEventOrganization";
+const METH_PERSONAL_DATA: &str = "personalData";
+const METH_ISSUE_PERSONAL_TOKEN: &str = "issuePersonalToken";
+const METH_MY_RACE: &str = "myRace";
 
 const EVENTCTL_ROOT_METHODS: &[MetaMethod] = &[
     META_METHOD_DIR,
     META_METHOD_LS,
     MetaMethod::new_static(
-        METH_CREATE_EVENT, Flags::None, AccessLevel::Write, "s:owner", "[i:event_id,s:api_token]", &[], "",
+        // owner is the calling user, other owner can be set by caller with Service access level
+        METH_CREATE_EVENT, Flags::UserIDRequired, AccessLevel::Write, "s|n:owner|[s|n:owner,b:is_local,s:sport]", "[i:event_id,s:api_token]", &[], "",
     ),
     MetaMethod::new_static(
         METH_OPEN_EVENT, Flags::None, AccessLevel::Read, "i:event_id", "s:mount_point", &[], "",
@@ -67,7 +171,7 @@ const EVENTCTL_ROOT_METHODS: &[MetaMethod] = &[
         METH_UPDATE_EVENT_RECORD, Flags::None, AccessLevel::Service, "[i:event_id,{?}:event_record]", "b", &[], "",
     ),
     MetaMethod::new_static(
-        METH_DELETE_EVENT, Flags::None, AccessLevel::Write, "s:api_token", "b:was_deleted", &[], "",
+        METH_DELETE_EVENT, Flags::None, AccessLevel::Write, "s:api_token|{s:api_token,b:dryRun}", "b:was_deleted|{?}", &[], "",
     ),
     MetaMethod::new_static(
         METH_LIST_EVENTS, Flags::None, AccessLevel::Read, "n", "[{?}]", &[], "",
@@ -75,11 +179,42 @@ const EVENTCTL_ROOT_METHODS: &[MetaMethod] = &[
     MetaMethod::new_static(
         METH_EVENT_DATA, Flags::None, AccessLevel::Read, "n", "{?}", &[], "",
     ),
+    MetaMethod::new_static(
+        METH_MIGRATE_EVENT, Flags::None, AccessLevel::Service, "{i:eventId,i:version}", "{i:fromVersion,i:toVersion,s:backupFile}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_CREATE_ORGANIZATION, Flags::None, AccessLevel::Service, "s:name", "i:organization_id", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SET_EVENT_ORGANIZATION, Flags::None, AccessLevel::Service, "{i:eventId,s|n:organization}", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        // data subject access request, all data of person with registration from all events
+        METH_PERSONAL_DATA, Flags::None, AccessLevel::Service, "s:registration", "{s:registration,s:exportedAt,[{?}]:events,[{i:eventId,s:reason}]:skippedEvents}", &[], "",
+    ),
+    MetaMethod::new_static(
+        // token identifying competitor in self-service, birth date allows lookup by registration without token
+        METH_ISSUE_PERSONAL_TOKEN, Flags::None, AccessLevel::Service, "{s:registration,s|n:birthDate}", "s:token", &[], "",
+    ),
+    MetaMethod::new_static(
+        // competitor self-service, start times, courses and results of competitor in all events
+        METH_MY_RACE, Flags::None, AccessLevel::Read, "{s:token}|{s:registration,s:birthDate}", "{s:registration,[{i:eventId,s:eventName,s:eventDate,[{...}]:stages}]:events}", &[], "",
+    ),
+];
+
+pub(crate) const IMPORT_NODE: &str = "import";
+
+const EVENTCTL_IMPORT_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
 ];
 
 const METH_EVENT_STATUS: &str = "status";
 const METH_EVENT_UPDATE_LATE_ENTRY: &str = "updateLateEntry";
 const METH_EVENT_CLOSE: &str = "close";
+const METH_EVENT_IS_OPEN: &str = "isOpen";
+const METH_EVENT_INTEGRITY_CHECK: &str = "integrityCheck";
+const METH_EVENT_ANONYMIZE: &str = "anonymize";
 const EVENTCTL_NODE_METHODS: &[MetaMethod] = &[
     META_METHOD_DIR,
     META_METHOD_LS,
@@ -92,48 +227,268 @@ const EVENTCTL_NODE_METHODS: &[MetaMethod] = &[
     MetaMethod::new_static(
         METH_EVENT_CLOSE, Flags::None, AccessLevel::Read, "",  "", &[], "",
     ),
+    MetaMethod::new_static(
+        METH_EVENT_IS_OPEN, Flags::None, AccessLevel::Read, "", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_EVENT_INTEGRITY_CHECK, Flags::None, AccessLevel::Service, "", "{b:ok,[s]:integrityErrors,[{s:table,i|n:rowId,s:parent,i:fkIndex}]:foreignKeyViolations}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_EVENT_ANONYMIZE, Flags::UserIDRequired, AccessLevel::Service, "", "{i:competitors,i:registrations,i:pendingEntries,i:runs,i:cards,i:punches,i:notifications,i:history}", &[], "",
+    ),
 ];
 
 const METH_SQL_QUERY: &str = "query";
 const METH_SQL_EXEC: &str = "exec";
+const METH_SQL_EXEC_RETURNING: &str = "execReturning";
+const METH_SQL_EXEC_SCRIPT: &str = "execScript";
 const METH_SQL_CREATE: &str = "create";
 const METH_SQL_READ: &str = "read";
 const METH_SQL_UPDATE: &str = "update";
 const METH_SQL_DELETE: &str = "delete";
+const METH_SQL_UPSERT: &str = "upsert";
+const METH_SQL_PURGE: &str = "purge";
+const METH_SQL_HISTORY: &str = "history";
+const METH_SQL_EXPLAIN: &str = "explain";
+const METH_SQL_UPLOAD_BEGIN: &str = "uploadBegin";
+const METH_SQL_UPLOAD_APPEND: &str = "uploadAppend";
+const METH_SQL_UPLOAD_COMMIT: &str = "uploadCommit";
+const METH_SQL_READ_BLOB: &str = "readBlob";
+
+/// Maximal size of blob assembled by chunked upload
+const MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
 
 const EVENTCTL_SQL_NODE_METHODS: &[MetaMethod] = &[
     META_METHOD_DIR,
     META_METHOD_LS,
     MetaMethod::new_static(
-        METH_SQL_QUERY, Flags::None, AccessLevel::Read, QUERY_PARAMS, QUERY_RESULT, &[], "",
+        METH_SQL_QUERY, Flags::None, AccessLevel::Read, SQL_QUERY_PARAMS, QUERY_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_EXEC, Flags::None, AccessLevel::Write, SQL_QUERY_PARAMS, EXEC_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_EXEC_RETURNING, Flags::None, AccessLevel::Write, SQL_QUERY_PARAMS, QUERY_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_EXEC_SCRIPT, Flags::None, AccessLevel::Write, EXEC_SCRIPT_PARAMS, EXEC_SCRIPT_RESULT, &[], "",
     ),
     MetaMethod::new_static(
-        METH_SQL_EXEC, Flags::None, AccessLevel::Write, EXEC_PARAMS, EXEC_RESULT, &[], "",
+        METH_SQL_CREATE, Flags::None, AccessLevel::Write, CREATE_PARAMS, CREATE_RESULT, &[], "",
     ),
     MetaMethod::new_static(
         METH_SQL_READ, Flags::None, AccessLevel::Read, READ_PARAMS, READ_RESULT, &[], "",
     ),
+    MetaMethod::new_static(
+        METH_SQL_UPDATE, Flags::None, AccessLevel::Write, UPDATE_PARAMS, UPDATE_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_DELETE, Flags::None, AccessLevel::Write, DELETE_PARAMS, DELETE_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_UPSERT, Flags::None, AccessLevel::Write, UPSERT_PARAMS, UPSERT_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_PURGE, Flags::None, AccessLevel::Config, "{s:table,s|n:before}", "i:purgedCount", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_EXPLAIN, Flags::None, AccessLevel::Service, EXPLAIN_PARAMS, EXPLAIN_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_HISTORY, Flags::None, AccessLevel::Read, "[s:table,i:id]", "[{i:id,s:operation,{}|n:oldValues,{}|n:newValues,s|n:issuer,s:changedAt}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_UPLOAD_BEGIN, Flags::None, AccessLevel::Write, "{s:table,i:id,s:field}", "s:upload_id", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_UPLOAD_APPEND, Flags::None, AccessLevel::Write, "[s:upload_id,x:chunk]", "i:size", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_UPLOAD_COMMIT, Flags::None, AccessLevel::Write, "s:upload_id", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SQL_READ_BLOB, Flags::None, AccessLevel::Read, "{s:table,i:id,s:field,i:offset,i:size}", "x", &[], "",
+    ),
 ];
 
-fn sanitize_user_id(rq: &RpcMessage) -> Option<&str> {
-    rq.user_id().map(|user_id| user_id.split(':').next().unwrap_or(user_id))
+pub(crate) fn sanitize_user_id(rq: &RpcMessage) -> Option<&str> {
+    gateway::forwarded_user(rq).or_else(|| rq.user_id().map(|user_id| user_id.split(':').next().unwrap_or(user_id)))
 }
 
 const QX_API_TOKEN: &str = "qx_api_token";
 
+const DRY_RUN: &str = "dryRun";
+
+const INCLUDE_DELETED: &str = "includeDeleted";
+
+const FROZEN_OVERRIDE: &str = "frozenOverride";
+
+/// `update` and `delete` of run in class with frozen results fails, unless its param map contains `frozenOverride: true`
+fn is_frozen_override(param: &RpcValue) -> bool {
+    param.is_map() && param.as_map().get(FROZEN_OVERRIDE).is_some_and(RpcValue::as_bool)
+}
+
+/// `read` of soft deleted record returns null, unless its param map contains `includeDeleted: true`
+fn is_include_deleted(param: &RpcValue) -> bool {
+    param.is_map() && param.as_map().get(INCLUDE_DELETED).is_some_and(RpcValue::as_bool)
+}
+
+/// Destructive methods accept map param with `dryRun: true`,
+/// they report what would change without committing anything then.
+fn is_dry_run(param: &RpcValue) -> bool {
+    param.is_map() && param.as_map().get(DRY_RUN).is_some_and(RpcValue::as_bool)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DryRunExecParams {
+    query: String,
+    #[serde(default)]
+    params: Option<Record>,
+}
+impl_rpcvalue_conversions!(DryRunExecParams);
+
+/// Remove soft deleted records of `table`, `before` is RFC 3339 time of the deletion
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PurgeParams {
+    table: String,
+    #[serde(default)]
+    before: Option<String>,
+}
+impl_rpcvalue_conversions!(PurgeParams);
+
+/// Move event to organization, event is removed from its organization if `organization` is not set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetEventOrganizationParams {
+    event_id: EventId,
+    #[serde(default)]
+    organization: Option<String>,
+}
+impl_rpcvalue_conversions!(SetEventOrganizationParams);
+
+/// Migrate closed event DB to schema `version`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MigrateEventParams {
+    event_id: EventId,
+    version: usize,
+}
+impl_rpcvalue_conversions!(MigrateEventParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryParams(String, i64);
+impl_rpcvalue_conversions!(HistoryParams);
+
 #[derive(Debug, Clone, Serialize,Deserialize)]
 struct UpdateEventRecordParams(i64, EventRecordChange);
 impl_rpcvalue_conversions!(UpdateEventRecordParams);
 
-async fn escalate_event_owner_rights(rq: &RpcMessage, app_state: SharedAppState, event_id: Option<EventId>, method_name: String, methods: &'static [MetaMethod]) -> Vec<MetaMethod> {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UploadBeginParams {
+    table: String,
+    id: i64,
+    field: String,
+}
+impl_rpcvalue_conversions!(UploadBeginParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReadBlobParams {
+    table: String,
+    id: i64,
+    field: String,
+    #[serde(default)]
+    offset: i64,
+    size: i64,
+}
+impl_rpcvalue_conversions!(ReadBlobParams);
+
+/// Owner of new event is the authenticated caller, event can be created on behalf of other owner
+/// by caller with Service access level only
+pub(crate) fn resolve_event_owner(rq: &RpcMessage, requested_owner: &str) -> anyhow::Result<String> {
+    let user_id = sanitize_user_id(rq).filter(|user_id| !user_id.is_empty());
+    match user_id {
+        Some(user_id) if requested_owner.is_empty() || requested_owner == user_id => Ok(user_id.to_string()),
+        _ if requested_owner.is_empty() => Err(anyhow::anyhow!("Event owner cannot be resolved, user id is not set")),
+        _ if rq.access_level().is_some_and(|level| level >= AccessLevel::Service as i32) => Ok(requested_owner.to_string()),
+        _ => Err(anyhow::anyhow!("Event can be created on behalf of other owner by service user only")),
+    }
+}
+
+/// Issuer of record change is the authenticated caller, change on behalf of other user is allowed
+/// to caller with Service access level only and both of them are recorded
+fn resolve_issuer(rq: &RpcMessage, on_behalf_of: Option<String>) -> anyhow::Result<Option<String>> {
+    let caller = issuer(rq);
+    match on_behalf_of.filter(|user| !user.is_empty()) {
+        None => Ok(caller),
+        Some(user) if caller.as_deref() == Some(user.as_str()) => Ok(caller),
+        Some(user) if rq.access_level().is_some_and(|level| level >= AccessLevel::Service as i32) => {
+            Ok(Some(format!("{} on behalf of {user}", caller.unwrap_or_default())))
+        }
+        Some(_) => Err(anyhow::anyhow!("Record can be changed on behalf of other user by service user only")),
+    }
+}
+
+/// Split `<org>/<event_id>/...` path of organization subtree, first segment of other paths is event id or fixed node name
+pub(crate) fn split_organization_path(path: &str) -> Option<(&str, &str)> {
+    let (first, rest) = path.split_once('/').unwrap_or((path, ""));
+    organizations::is_valid_organization_name(first).then_some((first, rest))
+}
+
+/// Methods of node at `eventctl` subtree `path`, organization subtree is not resolved
+pub(crate) fn node_methods(path: &str) -> Option<&'static [MetaMethod]> {
+    EventCtlNode::from_path(path).ok().map(|node| node.methods())
+}
+
+/// Path patterns of `eventctl` subtree relative to it and their methods, for API schema
+pub(crate) fn schema_nodes() -> Vec<(String, &'static [MetaMethod])> {
+    let mut nodes = vec![
+        (String::new(), EVENTCTL_ROOT_METHODS),
+        (IMPORT_NODE.to_string(), EVENTCTL_IMPORT_NODE_METHODS),
+        ("import/qbe".to_string(), qbeimport::IMPORT_QBE_NODE_METHODS),
+        ("{organization}".to_string(), organizations::ORGANIZATION_NODE_METHODS),
+        ("{eventId}".to_string(), EVENTCTL_NODE_METHODS),
+    ];
+    nodes.extend(EVENT_CHILD_NODES.iter()
+        .filter_map(|&child| node_methods(&format!("0/{child}")).map(|methods| (format!("{{eventId}}/{child}"), methods))));
+    nodes
+}
+
+/// `createEvent` param `owner` or `[owner, is_local, sport]`
+pub(crate) fn create_event_param(param: &RpcValue) -> anyhow::Result<(String, Option<bool>, Sport)> {
+    let (owner, is_local, sport) = if param.is_list() {
+        let param = param.as_list();
+        (
+            param.first().map(|v| v.as_str()).unwrap_or_default().to_owned(),
+            param.get(1).filter(|v| !v.is_null()).map(|v| v.as_bool()),
+            param.get(2).map(|v| v.as_str()).filter(|s| !s.is_empty()),
+        )
+    } else {
+        (param.as_str().to_owned(), None, None)
+    };
+    let sport = sport.map(str::parse::<Sport>).transpose()?
+        .unwrap_or_default();
+    Ok((owner, is_local, sport))
+}
+
+/// Caller is event owner, if it knows event API token, it is the owner user or a member of event organization
+pub(crate) async fn is_event_owner(app_state: &SharedAppState, event_record: &EventRecord, user_id: Option<&str>, api_token: Option<&str>) -> bool {
+    if api_token.is_some_and(|t| t == event_record.api_token) || user_id.is_some_and(|u| u == event_record.owner) {
+        return true;
+    }
+    match (event_record.organization_id, user_id) {
+        (Some(organization_id), Some(user_id)) => organizations::is_member(app_state, organization_id, user_id).await.unwrap_or(false),
+        _ => false,
+    }
+}
+
+pub(crate) async fn escalate_event_owner_rights(rq: &RpcMessage, app_state: SharedAppState, event_id: Option<EventId>, method_name: String, methods: &'static [MetaMethod]) -> Vec<MetaMethod> {
     // log::info!("escalate_event_owner_rights, method_name: {method_name}, event id: {event_id:?}");
-    if let Some(event_id) = event_id && let Ok(event_record) = app_state.read().await.event_record(event_id).await {
+    if let Some(event_id) = event_id && let Ok(event_record) = app_state.cached_event_record(event_id).await {
         let api_token = rq.meta().get(QX_API_TOKEN).map(|v| v.as_str());
         let user_id = sanitize_user_id(rq);
         // info!("event_id: {event_id}, user_id: {user_id:?}");
         if let Some(mm) = methods.iter().find(|&m| m.name == method_name) {
-            let called_by_event_owner = api_token.map(|t| t == event_record.api_token).unwrap_or(false)
-                || user_id.map(|u| u == event_record.owner).unwrap_or(false);
+            let called_by_event_owner = is_event_owner(&app_state, &event_record, user_id, api_token).await;
             if !called_by_event_owner && mm.flags.contains(Flags::UserIDRequired) {
                 warn!("Method: {method_name} not called by event owner");
                 warn!("User id: {:?}", user_id);
@@ -163,8 +518,25 @@ pub(crate) async fn request_handler(
         warn!("Not request");
         return err_unresolved_request();
     }
-    let shv_path = rq.shv_path().unwrap_or_default().to_string();
+    let trace = RequestTrace::new(&rq);
+    let mut shv_path = rq.shv_path().unwrap_or_default().to_string();
     // info!("shv_path2: {shv_path}");
+    if let Some((organization, event_path)) = split_organization_path(&shv_path) {
+        let (organization, event_path) = (organization.to_string(), event_path.to_string());
+        if event_path.is_empty() {
+            return organizations::request_handler(rq, client_cmd_tx, app_state, organization).await;
+        }
+        let event_id = event_path.split('/').next().and_then(|id| id.parse::<EventId>().ok());
+        let is_organization_event = match event_id {
+            Some(event_id) => organizations::is_organization_event(&app_state, &organization, event_id).await.unwrap_or(false),
+            None => false,
+        };
+        if !is_organization_event {
+            warn!("Invalid path: {shv_path}, event is not in organization {organization}");
+            return err_unresolved_request();
+        }
+        shv_path = event_path;
+    }
     let node_type = match EventCtlNode::from_path(&shv_path) {
         Ok(node_type) => node_type,
         Err(err) => {
@@ -172,12 +544,61 @@ pub(crate) async fn request_handler(
             return err_unresolved_request();
         }
     };
+    if let Some(event_id) = node_type.event_id() {
+        traffic::record_request(event_id, &rq);
+    }
+    if let Err(err) = app_state.rate_limiter.check(LimitedNode::EventCtl, &rq, node_type.methods()) {
+        return match Method::from_request(&rq) {
+            Method::Other(m) => m.resolve(node_type.methods(), async move || Err::<RpcValue, _>(err)),
+            _ => err_unresolved_request(),
+        };
+    }
+    if let EventCtlNode::Event(event_id) | EventCtlNode::EventSql(event_id) | EventCtlNode::EventConfig(event_id) | EventCtlNode::EventEnumz(event_id) | EventCtlNode::EventRuns(event_id) | EventCtlNode::EventFinish(event_id) | EventCtlNode::EventDraw(event_id) | EventCtlNode::EventCourses(event_id) | EventCtlNode::EventExport(event_id) | EventCtlNode::EventCards(event_id) | EventCtlNode::EventEntries(event_id) | EventCtlNode::EventPayments(event_id) | EventCtlNode::EventTimesync(event_id) | EventCtlNode::EventHistory(event_id) | EventCtlNode::EventSimulate(event_id) | EventCtlNode::EventRules(event_id) | EventCtlNode::EventResults(event_id) | EventCtlNode::EventSpeaker(event_id) | EventCtlNode::EventProtests(event_id) | EventCtlNode::EventPenalties(event_id) | EventCtlNode::EventReports(event_id) | EventCtlNode::EventMaps(event_id) = node_type
+        && global_config().auto_open_events
+        && !matches!(rq.method(), Some("dir" | "ls" | METH_EVENT_IS_OPEN | METH_EVENT_CLOSE))
+        && !app_state.is_event_open(event_id) {
+        info!("Auto opening event {event_id}");
+        if let Err(e) = open_event(app_state.clone(), event_id, client_cmd_tx.clone()).await {
+            warn!("Failed to auto open event {event_id}: {e}");
+        }
+    }
+    if let Some(event_id) = node_type.event_id()
+        && !matches!(node_type, EventCtlNode::Event(_) if matches!(rq.method(), Some(METH_EVENT_IS_OPEN | METH_EVENT_CLOSE)))
+        && !app_state.is_event_open(event_id) {
+        let exists = match app_state.event_exists(event_id).await {
+            Ok(exists) => exists,
+            Err(e) => {
+                warn!("Failed to check existence of event {event_id}: {e}");
+                true
+            }
+        };
+        match Method::from_request(&rq) {
+            // closed event node can be still browsed
+            Method::Dir(_) | Method::Ls(_) if exists => {}
+            Method::Other(m) => {
+                let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), m.method().to_owned(), node_type.methods()).await;
+                return m.resolve(methods, async move || Err::<RpcValue, _>(EventNotOpen { event_id, exists }.to_rpc_error()));
+            }
+            _ => return err_unresolved_request(),
+        }
+    }
     match node_type {
         EventCtlNode::Root => {
+            let caller = Caller::from_request(&rq);
             match Method::from_request(&rq) {
                 Method::Dir(dir) => dir.resolve(EVENTCTL_ROOT_METHODS),
                 Method::Ls(ls) => ls.resolve(EVENTCTL_ROOT_METHODS, async move || {
-                    Ok(list_events(app_state).await)
+                    let organizations = caller.organizations(&app_state).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    let mut nodes = vec![IMPORT_NODE.to_string()];
+                    match &organizations {
+                        Some(organizations) => nodes.extend(organizations.iter().map(|(_, name)| name.clone())),
+                        None => nodes.extend(organizations::list_organizations(&app_state).await
+                            .map_err(anyhow_to_rpc_error)?),
+                    }
+                    nodes.extend(list_events(&app_state, organizations.as_deref()).await
+                        .map_err(anyhow_to_rpc_error)?);
+                    Ok(nodes)
                 }),
                 Method::Other(m) => {
                     let method = m.method();
@@ -185,8 +606,11 @@ pub(crate) async fn request_handler(
                     let update_event_record_event_id = |rq: &RpcMessage| UpdateEventRecordParams::try_from(rq.param()).map(|p| p.0).ok();
                     match method {
                         METH_CREATE_EVENT => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
-                            let owner = rq.param().unwrap_or_default().as_str().to_owned();
-                            let (event_id, api_token) = app_state.write().await.create_event(owner, client_cmd_tx.clone()).await
+                            let (owner, is_local, sport) = create_event_param(rq.param().unwrap_or_default())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let owner = resolve_event_owner(&rq, &owner)
+                                .map_err(anyhow_to_rpc_error)?;
+                            let (event_id, api_token) = app_state.create_event(owner, is_local, sport, None, client_cmd_tx.clone()).await
                                 .map_err(anyhow_to_rpc_error)?;
                             Ok(RpcValue::from(vec![RpcValue::from(event_id), RpcValue::from(api_token)]))
                         }),
@@ -198,7 +622,7 @@ pub(crate) async fn request_handler(
                         }),
                         METH_OPEN_EVENT_API_KEY => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
                             let api_token = rq.param().unwrap_or_default().as_str();
-                            let event_id = app_state.read().await.api_token_to_event_id(api_token).await
+                            let event_id = app_state.api_token_to_event_id(api_token).await
                                 .map_err(anyhow_to_rpc_error)?;
                             let event_shv_path = open_event(app_state, event_id, client_cmd_tx).await
                                 .map_err(anyhow_to_rpc_error)?;
@@ -206,7 +630,7 @@ pub(crate) async fn request_handler(
                         }),
                         METH_READ_EVENT_RECORD => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), read_event_record_event_id(&rq), method.to_owned(), EVENTCTL_ROOT_METHODS).await, async move || {
                             let event_id = rq.param().unwrap_or_default().as_int();
-                            let res = app_state.read().await.event_record(event_id).await;
+                            let res = app_state.event_record(event_id).await;
                             res.map_err(anyhow_to_rpc_error)
                         }),
                         METH_UPDATE_EVENT_RECORD => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), update_event_record_event_id(&rq), method.to_owned(), EVENTCTL_ROOT_METHODS).await, async move || {
@@ -214,27 +638,98 @@ pub(crate) async fn request_handler(
                                 .map_err(anyhow_to_rpc_error)?;
                             let (event_id, change) = (p.0, p.1);
                             // info!("update_event_record, event_id: {event_id:?}, change: {change:?}");
-                            let res = app_state.write().await.update_event_record(event_id, change, client_cmd_tx).await
+                            let res = app_state.update_event_record(event_id, change, client_cmd_tx).await
                                 .map_err(anyhow_to_rpc_error)?;
                             Ok(res)
                         }),
 
                         METH_DELETE_EVENT => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
-                            let api_token = rq.param().unwrap_or_default().as_str();
-                            let event_id = app_state.read().await.api_token_to_event_id(api_token).await
+                            let param = rq.param().unwrap_or_default();
+                            let api_token = if param.is_map() {
+                                param.as_map().get("api_token").map(|v| v.as_str()).unwrap_or_default()
+                            } else {
+                                param.as_str()
+                            };
+                            let event_id = app_state.api_token_to_event_id(api_token).await
                                 .map_err(anyhow_to_rpc_error)?;
-                            let was_deleted = app_state.write().await.delete_event(event_id, client_cmd_tx).await
+                            if is_dry_run(param) {
+                                let event_record = app_state.event_record(event_id).await
+                                    .map_err(anyhow_to_rpc_error)?;
+                                let rows_affected = app_state.delete_event_dry_run(event_id).await
+                                    .map_err(anyhow_to_rpc_error)?;
+                                let db_file = event_db_file(event_id);
+                                let db_file_exists = std::path::Path::new(&db_file).exists();
+                                return Ok(make_map!(
+                                    DRY_RUN => true,
+                                    "event_id" => event_id,
+                                    "name" => event_record.name,
+                                    "owner" => event_record.owner,
+                                    "is_open" => app_state.is_event_open(event_id),
+                                    "rows_affected" => rows_affected,
+                                    // event DB file is not removed by deleteEvent
+                                    "db_file" => db_file,
+                                    "db_file_exists" => db_file_exists,
+                                ).into());
+                            }
+                            let was_deleted = delete_event(app_state, event_id, client_cmd_tx).await
                                 .map_err(anyhow_to_rpc_error)?;
                             Ok(RpcValue::from(was_deleted))
                         }),
+                        METH_CREATE_ORGANIZATION => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
+                            let name = rq.param().unwrap_or_default().as_str();
+                            organizations::create_organization(&app_state, name).await
+                                .map(RpcValue::from)
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_SET_EVENT_ORGANIZATION => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
+                            let param = SetEventOrganizationParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            organizations::set_event_organization(&app_state, param.event_id, param.organization.as_deref()).await
+                                .map(RpcValue::from)
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_PERSONAL_DATA => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
+                            let registration = rq.param().unwrap_or_default().as_str();
+                            export_personal_data(&app_state, registration, client_cmd_tx).await
+                                .map(|export| to_rpcvalue(&export).expect("serde should work"))
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_ISSUE_PERSONAL_TOKEN => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
+                            let param = IssuePersonalTokenParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            issue_personal_token(&app_state, &param).await
+                                .map(RpcValue::from)
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_MY_RACE => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
+                            let param = MyRaceParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            my_race(&app_state, &param, client_cmd_tx).await
+                                .map(|my_race| to_rpcvalue(&my_race).expect("serde should work"))
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_MIGRATE_EVENT => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
+                            let param = MigrateEventParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            migrate_event(app_state, param.event_id, param.version).await
+                                .map(|result| to_rpcvalue(&result).expect("serde should work"))
+                                .map_err(anyhow_to_rpc_error)
+                        }),
                         METH_LIST_EVENTS => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
-                            let events = app_state.write().await.list_events().await
+                            let organizations = caller.organizations(&app_state).await
+                                .map_err(anyhow_to_rpc_error)?;
+                            let mut events = visible_events(&app_state, organizations.as_deref()).await
                                 .map_err(anyhow_to_rpc_error)?;
+                            for event in events.iter_mut() {
+                                let is_open = event.get("id").and_then(|id| id.to_int())
+                                    .is_some_and(|id| app_state.is_event_open(id));
+                                event.insert("is_open".to_string(), is_open.into());
+                            }
                             to_rpcvalue(&events).map_err(|e| string_to_rpc_error(e.to_string()))
                         }),
                         METH_EVENT_DATA => m.resolve(EVENTCTL_ROOT_METHODS, async move || {
                             let event_id = rq.param().unwrap_or_default().as_int();
-                            let res = app_state.read().await.event_record(event_id).await;
+                            let res = app_state.event_record(event_id).await;
                             res.map(|mut rec| {
                                 rec.api_token = "".into();
                                 rec
@@ -245,17 +740,25 @@ pub(crate) async fn request_handler(
                 }
             }
         }
+        EventCtlNode::Import => {
+            match Method::from_request(&rq) {
+                Method::Dir(dir) => dir.resolve(EVENTCTL_IMPORT_NODE_METHODS),
+                Method::Ls(ls) => ls.resolve(EVENTCTL_IMPORT_NODE_METHODS, async move || { Ok(vec!["qbe".to_string()]) }),
+                Method::Other(_) => err_unresolved_request(),
+            }
+        }
+        EventCtlNode::ImportQbe => qbeimport::request_handler(rq, client_cmd_tx, app_state).await,
         EventCtlNode::Event(event_id) => {
             match Method::from_request(&rq) {
                 Method::Dir(dir) => dir.resolve(EVENTCTL_NODE_METHODS),
                 Method::Ls(ls) => ls.resolve(EVENTCTL_NODE_METHODS, async move || {
-                    Ok(vec!["sql".into()])
+                    Ok(EVENT_CHILD_NODES.iter().map(|&node| node.into()).collect())
                 }),
                 Method::Other(m) => {
                     let method = m.method();
                     match method {
                         METH_EVENT_STATUS => m.resolve(EVENTCTL_NODE_METHODS, async move || {
-                            let res = app_state.read().await.open_event_status(event_id);
+                            let res = app_state.open_event_status(event_id);
                             res.map_err(anyhow_to_rpc_error)
                         }),
                         METH_EVENT_UPDATE_LATE_ENTRY => {
@@ -266,19 +769,19 @@ pub(crate) async fn request_handler(
                                 };
                                 let params = LateEntryParams::try_from(rq.param().unwrap_or_default())
                                     .map_err(string_to_rpc_error)?;
-                                let current_stage = app_state.read().await.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
-                                let qxsql = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx.clone());
+                                let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                                let qxsql = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
                                 if let Some(qxchange_id) = params.change_id {
                                     if params.late_entry.is_none() {
                                         // delete existing record with no changes
-                                        let ok = qxsql.delete_record_event("qxchanges", qxchange_id, None).await.map_err(anyhow_to_rpc_error)?;
+                                        let ok = qxsql.delete_record_event("qxchanges", qxchange_id, issuer(&rq)).await.map_err(anyhow_to_rpc_error)?;
                                         return Ok(RpcValue::from(ok))
                                     }
                                     let qxchange = QxChangeRecord {
                                         data: params.late_entry.map(|le| Data::LateEntry(le)),
                                         ..Default::default()
                                     };
-                                    let ok = qxsql.update_record_event("qxchanges", qxchange_id, &qxchange.to_record(), None).await.map_err(anyhow_to_rpc_error)?;
+                                    let ok = qxsql.update_record_event("qxchanges", qxchange_id, &qxchange.to_record(), issuer(&rq)).await.map_err(anyhow_to_rpc_error)?;
                                     Ok(RpcValue::from(ok))
                                 } else {
                                     let (foreign_table, foreign_id) = if let Some(le) = &params.late_entry {
@@ -299,15 +802,36 @@ pub(crate) async fn request_handler(
                                         status: Some(qxchange::Status::Pending),
                                         status_message: None,
                                     };
-                                    let id = qxsql.create_record_event("qxchanges", &qxchange.to_record(), None).await.map_err(anyhow_to_rpc_error)?;
+                                    let id = qxsql.create_record_event("qxchanges", &qxchange.to_record(), issuer(&rq)).await.map_err(anyhow_to_rpc_error)?;
                                     Ok(RpcValue::from(id))
                                 }
                             })
                         },
+                        METH_EVENT_IS_OPEN => m.resolve(EVENTCTL_NODE_METHODS, async move || {
+                            Ok(app_state.is_event_open(event_id))
+                        }),
                         METH_EVENT_CLOSE => m.resolve(EVENTCTL_NODE_METHODS, async move || {
-                            let res = app_state.write().await.close_event(event_id, client_cmd_tx.clone()).await;
+                            let res = close_event(app_state, event_id, client_cmd_tx.clone()).await;
                             res.map_err(anyhow_to_rpc_error)
                         }),
+                        METH_EVENT_INTEGRITY_CHECK => m.resolve(EVENTCTL_NODE_METHODS, async move || {
+                            let Some(db) = app_state.with_open_event(event_id, |e| e.local_db.clone())
+                                .ok_or_else(|| string_to_rpc_error(format!("Event id: {event_id} is not open.")))? else {
+                                return Err(str_to_rpc_error("Integrity check is supported for local events only"));
+                            };
+                            let report = integrity_check(&db).await.map_err(anyhow_to_rpc_error)?;
+                            if !report.ok {
+                                error!("Event {event_id} DB integrity check failed: {}", report.summary());
+                            }
+                            Ok(to_rpcvalue(&report).expect("serde should work"))
+                        }),
+                        METH_EVENT_ANONYMIZE => m.resolve(EVENTCTL_NODE_METHODS, async move || {
+                            let event_record = app_state.event_record(event_id).await.map_err(anyhow_to_rpc_error)?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx);
+                            let result = anonymize_event(&sql_api, event_id, &event_record, issuer(&rq)).await
+                                .map_err(anyhow_to_rpc_error)?;
+                            Ok(to_rpcvalue(&result).expect("serde should work"))
+                        }),
                         _ => err_unresolved_request(),
                     }
                 }
@@ -321,27 +845,54 @@ pub(crate) async fn request_handler(
                     let method = m.method();
                     match method {
                         METH_SQL_QUERY => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
-                            let query = QueryAndParams::try_from(rq.param().unwrap_or_default())
-                                .map_err(string_to_rpc_error)?;
-                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx);
-                            sql_api.query(query.query(), query.params()).await
+                            let query = SqlQueryParams::try_from(rq.param().unwrap_or_default())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            sql_api.query(&query.query, query.params.as_ref()).await
                                 .map(|query_result| to_rpcvalue(&query_result).expect("serde should work"))
                                 .map_err(anyhow_to_rpc_error)
                         }),
 
                         METH_SQL_EXEC => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
-                            let query = QueryAndParams::try_from(rq.param().unwrap_or_default())
-                                .map_err(string_to_rpc_error)?;
-                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx);
-                            sql_api.exec(query.query(), query.params()).await
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            if is_dry_run(rq.param().unwrap_or_default()) {
+                                let param = DryRunExecParams::try_from(rq.param())
+                                    .map_err(anyhow_to_rpc_error)?;
+                                return sql_api.exec_dry_run(&param.query, param.params.as_ref()).await
+                                    .map(|exec_result| to_rpcvalue(&exec_result).expect("serde should work"))
+                                    .map_err(anyhow_to_rpc_error);
+                            }
+                            let query = SqlQueryParams::try_from(rq.param().unwrap_or_default())
+                                .map_err(anyhow_to_rpc_error)?;
+                            sql_api.exec(&query.query, query.params.as_ref()).await
+                                .map(|query_result| to_rpcvalue(&query_result).expect("serde should work"))
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_SQL_EXEC_RETURNING => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let query = SqlQueryParams::try_from(rq.param().unwrap_or_default())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            sql_api.exec_returning(&query.query, query.params.as_ref()).await
                                 .map(|query_result| to_rpcvalue(&query_result).expect("serde should work"))
                                 .map_err(anyhow_to_rpc_error)
                         }),
+                        METH_SQL_EXEC_SCRIPT => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let param = ExecScriptParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            sql_api.exec_script(&param.script).await
+                                .map(|script_result| to_rpcvalue(&script_result).expect("serde should work"))
+                                .map_err(anyhow_to_rpc_error)
+                        }),
                         METH_SQL_CREATE => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
                             let param = RecInsertParam::try_from(rq.param().unwrap_or_default())
                                 .map_err(string_to_rpc_error)?;
-                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone());
-                            sql_api.create_record_with_recchng(&param.table, &param.record, param.issuer).await
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id())
+                                .with_priority_lane(is_priority_table(&param.table));
+                            let issuer = resolve_issuer(&rq, param.issuer).map_err(anyhow_to_rpc_error)?;
+                            let record = eventtimesyncnode::correct_station_times(&sql_api, &param.table, param.record).await
+                                .map_err(anyhow_to_rpc_error)?;
+                            sql_api.create_record_event(&param.table, &record, issuer).await
                                 .map(|query_result| to_rpcvalue(&query_result).expect("serde should work"))
                                 .map_err(anyhow_to_rpc_error)
                         }),
@@ -349,7 +900,11 @@ pub(crate) async fn request_handler(
                             let param = RecReadParam::try_from(rq.param().unwrap_or_default())
                                 .map_err(string_to_rpc_error)?;
                             let fields = qxsql::string_list_to_ref_vec(&param.fields);
-                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx);
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            if !is_include_deleted(rq.param().unwrap_or_default())
+                                && sql_api.is_record_deleted(&param.table, param.id).await.map_err(anyhow_to_rpc_error)? {
+                                return Ok(RpcValue::null());
+                            }
                             sql_api.read_record(&param.table, param.id, fields).await
                                 .map(|query_result| to_rpcvalue(&query_result).expect("serde should work"))
                                 .map_err(anyhow_to_rpc_error)
@@ -357,35 +912,214 @@ pub(crate) async fn request_handler(
                         METH_SQL_UPDATE => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
                             let param = RecUpdateParam::try_from(rq.param().unwrap_or_default())
                                 .map_err(string_to_rpc_error)?;
-                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone());
-                            sql_api.update_record_with_recchng(&param.table, param.id, &param.record, param.issuer).await
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id())
+                                .with_priority_lane(is_priority_table(&param.table))
+                                .with_frozen_override(is_frozen_override(rq.param().unwrap_or_default()));
+                            let issuer = resolve_issuer(&rq, param.issuer).map_err(anyhow_to_rpc_error)?;
+                            sql_api.update_record_checked(&param.table, param.id, &param.record, issuer).await
                                 .map(|query_result| to_rpcvalue(&query_result).expect("serde should work"))
                                 .map_err(anyhow_to_rpc_error)
                         }),
                         METH_SQL_DELETE => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
                             let param = RecDeleteParam::try_from(rq.param().unwrap_or_default())
                                 .map_err(string_to_rpc_error)?;
-                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone());
-                            sql_api.delete_record_with_recchng(&param.table, param.id, param.issuer).await
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id())
+                                .with_priority_lane(is_priority_table(&param.table))
+                                .with_frozen_override(is_frozen_override(rq.param().unwrap_or_default()));
+                            if is_dry_run(rq.param().unwrap_or_default()) {
+                                return sql_api.delete_record_dry_run(&param.table, param.id).await
+                                    .map(|exec_result| to_rpcvalue(&exec_result).expect("serde should work"))
+                                    .map_err(anyhow_to_rpc_error);
+                            }
+                            let issuer = resolve_issuer(&rq, param.issuer).map_err(anyhow_to_rpc_error)?;
+                            sql_api.delete_record_event(&param.table, param.id, issuer).await
                                 .map(|query_result| to_rpcvalue(&query_result).expect("serde should work"))
                                 .map_err(anyhow_to_rpc_error)
                         }),
+                        METH_SQL_UPSERT => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let param = UpsertParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id())
+                                .with_priority_lane(is_priority_table(&param.0));
+                            sql_api.upsert_records(&param.0, &param.1, &param.2).await
+                                .map(|results| to_rpcvalue(&results).expect("serde should work"))
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_SQL_PURGE => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let param = PurgeParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let before = param.before.as_deref()
+                                .map(|before| chrono::DateTime::parse_from_rfc3339(before)
+                                    .map_err(|e| string_to_rpc_error(format!("Invalid time '{before}': {e}"))))
+                                .transpose()?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            sql_api.purge_deleted(&param.table, before).await
+                                .map(RpcValue::from)
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        // not escalated for event owner, query plans are for administrators
+                        METH_SQL_EXPLAIN => m.resolve(EVENTCTL_SQL_NODE_METHODS, async move || {
+                            let param = ExplainParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            sql_api.explain(&param.query, param.params.as_ref(), param.analyze).await
+                                .map(|result| to_rpcvalue(&result).expect("serde should work"))
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_SQL_HISTORY => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let param = HistoryParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            sql_api.record_history(&param.0, param.1).await
+                                .map(RpcValue::from)
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_SQL_UPLOAD_BEGIN => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let param = UploadBeginParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            if !is_valid_sql_identifier(&param.table) || !is_valid_sql_identifier(&param.field) {
+                                return Err(str_to_rpc_error("Invalid table or field name"));
+                            }
+                            let upload_id = generate_api_token();
+                            let upload = BlobUpload {
+                                table: param.table,
+                                id: param.id,
+                                field: param.field,
+                                data: vec![],
+                                touched_at: std::time::Instant::now(),
+                            };
+                            app_state.with_open_event_mut(event_id, |event| {
+                                event.blob_transfers.check_capacity(true, 0)?;
+                                event.blob_transfers.uploads.insert(upload_id.clone(), upload);
+                                Ok(())
+                            })
+                            .unwrap_or_else(|| Err(EventNotOpen { event_id, exists: true }.into()))
+                            .map_err(anyhow_to_rpc_error)?;
+                            Ok(RpcValue::from(upload_id))
+                        }),
+                        METH_SQL_UPLOAD_APPEND => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let param = rq.param().unwrap_or_default().as_list();
+                            let upload_id = param.first().map(|v| v.as_str()).unwrap_or_default();
+                            let chunk = param.get(1).map(|v| v.as_blob()).unwrap_or_default();
+                            if chunk.len() > MAX_BLOB_CHUNK_SIZE {
+                                return Err(string_to_rpc_error(format!("Upload chunk exceeds limit of {MAX_BLOB_CHUNK_SIZE} bytes")));
+                            }
+                            app_state.with_open_event_mut(event_id, |event| {
+                                event.blob_transfers.check_capacity(false, chunk.len())?;
+                                let upload = event.blob_transfers.uploads.get_mut(upload_id)
+                                    .ok_or_else(|| anyhow::anyhow!("Invalid or expired upload id: {upload_id}"))?;
+                                if upload.data.len() + chunk.len() > MAX_UPLOAD_SIZE {
+                                    anyhow::bail!("Upload size exceeds limit of {MAX_UPLOAD_SIZE} bytes");
+                                }
+                                upload.data.extend_from_slice(chunk);
+                                upload.touched_at = std::time::Instant::now();
+                                Ok(RpcValue::from(upload.data.len() as i64))
+                            })
+                            .unwrap_or_else(|| Err(EventNotOpen { event_id, exists: true }.into()))
+                            .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_SQL_UPLOAD_COMMIT => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let upload_id = rq.param().unwrap_or_default().as_str();
+                            let upload = app_state.with_open_event_mut(event_id, |event| {
+                                event.blob_transfers.expire();
+                                event.blob_transfers.uploads.remove(upload_id)
+                            })
+                                .flatten()
+                                .ok_or_else(|| string_to_rpc_error(format!("Invalid or expired upload id: {upload_id}")))?;
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                            sql_api.write_blob(&upload.table, upload.id, &upload.field, upload.data, issuer(&rq)).await
+                                .map(RpcValue::from)
+                                .map_err(anyhow_to_rpc_error)
+                        }),
+                        METH_SQL_READ_BLOB => m.resolve(escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENTCTL_SQL_NODE_METHODS).await, async move || {
+                            let param = ReadBlobParams::try_from(rq.param())
+                                .map_err(anyhow_to_rpc_error)?;
+                            if param.offset < 0 || param.size < 0 || param.size as usize > MAX_BLOB_CHUNK_SIZE {
+                                return Err(string_to_rpc_error(format!("Invalid offset or size, size limit is {MAX_BLOB_CHUNK_SIZE} bytes")));
+                            }
+                            let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                            sql_api.read_blob_chunk(&param.table, param.id, &param.field, param.offset, param.size).await
+                                .map(RpcValue::from)
+                                .map_err(anyhow_to_rpc_error)
+                        }),
                         _ => err_unresolved_request(),
                     }
                 }
             }
         }
+        EventCtlNode::EventConfig(event_id) => eventconfignode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventEnumz(event_id) => eventenumznode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventRuns(event_id) => eventrunsnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventFinish(event_id) => eventfinishnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventDraw(event_id) => eventdrawnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventCourses(event_id) => eventcoursesnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventExport(event_id) => eventexportnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventCards(event_id) => eventcardsnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventEntries(event_id) => evententriesnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventPayments(event_id) => eventpaymentsnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventTimesync(event_id) => eventtimesyncnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventHistory(event_id) => eventhistorynode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventSimulate(event_id) => eventsimulatenode::request_handler(rq, client_cmd_tx, app_state, event_id).await,
+        EventCtlNode::EventRules(event_id) => eventrulesnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventResults(event_id) => eventresultsnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventSpeaker(event_id) => eventspeakernode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventProtests(event_id) => eventprotestsnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventPenalties(event_id) => eventpenaltiesnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventReports(event_id) => eventreportsnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
+        EventCtlNode::EventMaps(event_id) => eventmapsnode::request_handler(rq, client_cmd_tx, app_state, event_id, trace).await,
     }
 }
 
-async fn list_events(app_state: SharedAppState) -> Vec<String> {
-    let mut events = app_state.read().await.open_events.keys().cloned().collect::<Vec<_>>();
+/// Caller of root node method, listings of events depend on its organizations
+struct Caller {
+    user_id: Option<String>,
+    is_service: bool,
+}
+
+impl Caller {
+    fn from_request(rq: &RpcMessage) -> Self {
+        Self {
+            user_id: sanitize_user_id(rq).filter(|user_id| !user_id.is_empty()).map(str::to_string),
+            is_service: rq.access_level().is_some_and(|level| level >= AccessLevel::Service as i32),
+        }
+    }
+    /// Organizations of the caller as pairs of id and name, `None` if the caller can see all of them
+    async fn organizations(&self, app_state: &SharedAppState) -> anyhow::Result<Option<Vec<(i64, String)>>> {
+        if self.is_service {
+            return Ok(None);
+        }
+        match &self.user_id {
+            Some(user_id) => Ok(Some(organizations::user_organizations(app_state, user_id).await?)),
+            None => Ok(Some(vec![])),
+        }
+    }
+}
+
+/// Events without organization and events of `organizations`, all events if `organizations` is not set
+async fn visible_events(app_state: &SharedAppState, organizations: Option<&[(i64, String)]>) -> anyhow::Result<Vec<Record>> {
+    let events = app_state.list_events().await?;
+    let Some(organizations) = organizations else {
+        return Ok(events);
+    };
+    Ok(events.into_iter()
+        .filter(|event| match event.get("organization_id").and_then(|id| id.to_int()) {
+            Some(organization_id) => organizations.iter().any(|(id, _)| *id == organization_id),
+            None => true,
+        })
+        .collect())
+}
+
+async fn list_events(app_state: &SharedAppState, organizations: Option<&[(i64, String)]>) -> anyhow::Result<Vec<String>> {
+    let events = visible_events(app_state, organizations).await?;
+    let mut events = events.iter()
+        .filter_map(|event| event.get("id").and_then(|id| id.to_int()))
+        .collect::<Vec<_>>();
     events.sort();
 
-    events
+    Ok(events
         .into_iter()
         .map(|id| format!("{id}"))
-        .collect()
+        .collect())
 }
 
 
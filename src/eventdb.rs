@@ -1,11 +1,17 @@
 use std::path::Path;
 
-use async_sqlite::{JournalMode, Pool, PoolBuilder};
+use anyhow::{anyhow, bail};
+use async_sqlite::rusqlite::{OpenFlags, OptionalExtension};
+use async_sqlite::{Pool, PoolBuilder};
 use log::info;
 use qxsql::sql::{QxSqlApi, record_from_slice};
 use rusqlite_migration::{M, Migrations};
+use serde::Serialize;
 use shvclient::ClientCommandSender;
 
+pub use qxeventd_api::Sport;
+
+use crate::encryption;
 use crate::{appsqlapi::AppSqlApi, state::EventRecord};
 
 fn check_file_exists(path: &str) -> bool {
@@ -23,21 +29,7 @@ pub async fn migrate_db(db_file: &str, event_data: &EventRecord, client_command_
         info!("Creating event database file {}", db_file);
         create_file_path(db_file)?;
     }
-    info!("Opening db {db_file}");
-
-    let pool = PoolBuilder::new()
-                    .path(db_file)
-                    .journal_mode(JournalMode::Wal);
-    let pool = pool.open()
-                    .await?;
-
-    // Update the database schema, atomically
-    pool.conn_mut(|conn| {
-        match MIGRATIONS.to_latest(conn) {
-            Ok(_) => Ok(()),
-            Err(e) => panic!("{}", e),
-        }
-    }).await?;
+    let pool = open_and_migrate(db_file).await?;
     let qxsql = AppSqlApi::new(pool.clone(), client_command_sender);
     if !db_file_exists {
         let config_entries = [
@@ -61,10 +53,522 @@ pub async fn migrate_db(db_file: &str, event_data: &EventRecord, client_command_
     Ok(pool)
 }
 
+/// Apply pending migrations to existing event DB file, used for offline administration
+pub async fn migrate_existing_db(db_file: &str) -> anyhow::Result<()> {
+    if !check_file_exists(db_file) {
+        bail!("Event database file {db_file} does not exist");
+    }
+    open_and_migrate(db_file).await?;
+    info!("Migration of: {db_file} OK");
+    Ok(())
+}
+
+/// Single connection pool of already migrated event DB, it serves latency critical writes,
+/// so they never wait in the queue of connection busy with long running query
+pub async fn open_priority_db(db_file: &str) -> anyhow::Result<Pool> {
+    open_event_db_pool(db_file, Some(1)).await
+}
+
+/// Fully migrated event DB in memory, it is used by self test
+pub async fn open_memory_event_db() -> anyhow::Result<Pool> {
+    let pool = PoolBuilder::new().path(":memory:").num_conns(1).open().await?;
+    pool.conn_mut(|conn| Ok(MIGRATIONS.to_latest(conn))).await?
+        .map_err(|e| anyhow!("Migration of in-memory event DB failed: {e}"))?;
+    Ok(pool)
+}
+
+/// Open event DB pool in WAL mode, connections are keyed before the journal mode is set,
+/// because encrypted DB cannot be read before it is keyed
+pub async fn open_event_db_pool(db_file: &str, num_conns: Option<usize>) -> anyhow::Result<Pool> {
+    let pool = PoolBuilder::new().path(db_file);
+    let pool = match num_conns {
+        Some(num_conns) => pool.num_conns(num_conns),
+        None => pool,
+    };
+    let pool = pool.open().await?;
+    encryption::key_pool(&pool).await?;
+    for result in pool.conn_for_each(|conn| conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))).await {
+        result?;
+    }
+    Ok(pool)
+}
+
+/// Single connection read-only pool of closed event DB, the DB file is neither migrated nor switched to WAL.
+/// DB of other than current schema version is refused, tables it misses or has extra would break the readers.
+pub async fn open_read_only_event_db(db_file: &str) -> anyhow::Result<Pool> {
+    let pool = PoolBuilder::new()
+        .path(db_file)
+        .flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI)
+        .num_conns(1)
+        .open().await?;
+    encryption::key_pool(&pool).await?;
+    let schema_version: usize = pool.conn(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get(0))).await?;
+    if schema_version != EVENT_DB_SCHEMA_VERSION {
+        bail!("Event DB {db_file} schema version {schema_version} is not current version {EVENT_DB_SCHEMA_VERSION}");
+    }
+    Ok(pool)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForeignKeyViolation {
+    pub table: String,
+    pub row_id: Option<i64>,
+    pub parent: String,
+    pub fk_index: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    pub ok: bool,
+    /// Messages of `PRAGMA integrity_check`, empty if the DB is not corrupted
+    pub integrity_errors: Vec<String>,
+    pub foreign_key_violations: Vec<ForeignKeyViolation>,
+}
+
+impl IntegrityReport {
+    pub fn summary(&self) -> String {
+        let mut problems = self.integrity_errors.clone();
+        problems.extend(self.foreign_key_violations.iter().map(|v| {
+            format!("{} row {} references missing {} record", v.table, v.row_id.map(|id| id.to_string()).unwrap_or_default(), v.parent)
+        }));
+        problems.join("; ")
+    }
+}
+
+/// Run `PRAGMA integrity_check` and `PRAGMA foreign_key_check` on event DB
+pub async fn integrity_check(pool: &Pool) -> anyhow::Result<IntegrityReport> {
+    let report = pool.conn(|conn| {
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let integrity_errors = stmt.query_map([], |row| row.get::<_, String>(0))?
+            .filter(|msg| !matches!(msg.as_deref(), Ok("ok")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut stmt = conn.prepare("PRAGMA foreign_key_check")?;
+        let foreign_key_violations = stmt.query_map([], |row| Ok(ForeignKeyViolation {
+                table: row.get(0)?,
+                row_id: row.get(1)?,
+                parent: row.get(2)?,
+                fk_index: row.get(3)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(IntegrityReport {
+            ok: integrity_errors.is_empty() && foreign_key_violations.is_empty(),
+            integrity_errors,
+            foreign_key_violations,
+        })
+    }).await?;
+    Ok(report)
+}
+
+/// QuickEvent data version of event DB schema, which is created by `create_event_db.sql`
+pub const EVENT_DB_DATA_VERSION: i64 = 30600;
+
+/// Refuse event DB created by newer daemon or newer QuickEvent, migrating it would damage the data.
+/// Schema version is the number of applied migrations stored in `PRAGMA user_version`,
+/// data version is stored in `db.version` key of the config table.
+async fn check_schema_version(pool: &Pool, db_file: &str) -> anyhow::Result<()> {
+    let (schema_version, data_version) = pool.conn(|conn| {
+        let schema_version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let has_config: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'config')", [], |row| row.get(0))?;
+        let data_version: Option<String> = if has_config {
+            conn.query_row("SELECT cvalue FROM config WHERE ckey = 'db.version'", [], |row| row.get(0)).optional()?.flatten()
+        } else {
+            None
+        };
+        Ok((schema_version, data_version))
+    }).await?;
+    let known_schema_version = EVENT_DB_SCHEMA_VERSION as i64;
+    if schema_version > known_schema_version {
+        bail!("Event DB {db_file} schema version {schema_version} is newer than supported version {known_schema_version}, upgrade qxeventd to open it");
+    }
+    if let Some(data_version) = data_version {
+        let data_version: i64 = data_version.trim().parse()
+            .map_err(|e| anyhow!("Event DB {db_file} has invalid data version '{data_version}': {e}"))?;
+        if data_version > EVENT_DB_DATA_VERSION {
+            bail!("Event DB {db_file} data version {data_version} is newer than supported version {EVENT_DB_DATA_VERSION}");
+        }
+    }
+    Ok(())
+}
+
+async fn open_and_migrate(db_file: &str) -> anyhow::Result<Pool> {
+    info!("Opening db {db_file}");
+
+    let pool = open_event_db_pool(db_file, None).await?;
+    check_schema_version(&pool, db_file).await?;
+
+    // Update the database schema, atomically
+    pool.conn_mut(|conn| Ok(MIGRATIONS.to_latest(conn))).await?
+        .map_err(|e| anyhow!("Migration of event DB {db_file} failed: {e}"))?;
+    Ok(pool)
+}
+
+/// Schema version of event DB, it is the number of applied migrations
+pub const EVENT_DB_SCHEMA_VERSION: usize = MIGRATION_ARRAY.len();
+
+/// Convert copy of event DB to the schema QuickEvent desktop application knows.
+/// Soft deleted records are removed, columns and tables added by migrations are dropped by down migrations
+/// and the DB is switched to rollback journal, so it is a single self-contained file.
+pub fn downgrade_to_legacy_schema(conn: &mut async_sqlite::rusqlite::Connection) -> anyhow::Result<()> {
+    let schema_version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if schema_version >= 8 {
+        // soft delete columns are added by migration 8
+        for table in crate::config::SOFT_DELETE_CAPABLE_TABLES {
+            conn.execute_batch(&format!("DELETE FROM {table} WHERE deleted"))?;
+        }
+    }
+    MIGRATIONS.to_version(conn, 1)
+        .map_err(|e| anyhow!("Downgrade of event DB schema failed: {e}"))?;
+    conn.pragma_update(None, "user_version", 0)?;
+    conn.query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))?;
+    conn.execute_batch("VACUUM")?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationResult {
+    pub from_version: i64,
+    pub to_version: usize,
+    /// Copy of event DB made before the migration
+    pub backup_file: String,
+}
+
+/// Migrate existing event DB up or down to schema `version`, the DB is copied to backup file first.
+/// Event must not be open.
+pub async fn migrate_event_db_to(db_file: &str, version: usize) -> anyhow::Result<MigrationResult> {
+    if !check_file_exists(db_file) {
+        bail!("Event database file {db_file} does not exist");
+    }
+    if version == 0 || version > EVENT_DB_SCHEMA_VERSION {
+        bail!("Invalid event DB schema version {version}, expected 1 to {EVENT_DB_SCHEMA_VERSION}");
+    }
+    let pool = open_event_db_pool(db_file, None).await?;
+    check_schema_version(&pool, db_file).await?;
+    let from_version: i64 = pool.conn(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get(0))).await?;
+    let backup_file = format!("{db_file}.v{from_version}.{}.bak", chrono::Local::now().format("%Y%m%d%H%M%S"));
+    let dest_file = backup_file.clone();
+    pool.conn(move |conn| conn.execute("VACUUM INTO ?1", [dest_file])).await?;
+    info!("Event DB {db_file} backed up to {backup_file}");
+    pool.conn_mut(move |conn| Ok(MIGRATIONS.to_version(conn, version))).await?
+        .map_err(|e| anyhow!("Migration of event DB {db_file} to version {version} failed: {e}, backup is {backup_file}"))?;
+    info!("Event DB {db_file} migrated from version {from_version} to {version}");
+    Ok(MigrationResult { from_version, to_version: version, backup_file })
+}
+
 const MIGRATIONS: Migrations = Migrations::from_slice(MIGRATION_ARRAY);
 
 const MIGRATION_ARRAY: &[M] = &[
+    // initial schema has no down migration, event DB cannot be migrated below version 1
     M::up(
         include_str!("create_event_db.sql"),
     ),
+    // course variants (forking), coursecodes without variant are common to all variants of course
+    M::up(
+        "ALTER TABLE coursecodes ADD COLUMN variant character varying;
+        CREATE INDEX coursecodes_ix3 ON coursecodes (courseId, variant);
+        ALTER TABLE runs ADD COLUMN courseVariant character varying;",
+    ).down(
+        "DROP INDEX coursecodes_ix3;
+        ALTER TABLE coursecodes DROP COLUMN variant;
+        ALTER TABLE runs DROP COLUMN courseVariant;",
+    ),
+    // self-service entries waiting for organizer approval, class entries can be limited
+    M::up(
+        "CREATE TABLE pending_entries (
+            id integer PRIMARY KEY,
+            firstName character varying,
+            lastName character varying,
+            registration character varying(10),
+            clubAbbr character varying,
+            classId integer,
+            siId integer,
+            email character varying,
+            note character varying,
+            status character varying NOT NULL DEFAULT 'pending',
+            statusKey character varying,
+            submittedAt timestamp,
+            CONSTRAINT pending_entries_foreign0 FOREIGN KEY (classId) REFERENCES classes (id) ON UPDATE RESTRICT ON DELETE RESTRICT
+        );
+        CREATE INDEX pending_entries_ix0 ON pending_entries (status);
+        ALTER TABLE classes ADD COLUMN maxEntries integer;",
+    ).down(
+        "DROP TABLE pending_entries;
+        ALTER TABLE classes DROP COLUMN maxEntries;",
+    ),
+    // entry approval, reject reason is visible to the submitter
+    M::up(
+        "ALTER TABLE pending_entries ADD COLUMN rejectReason character varying;
+        ALTER TABLE pending_entries ADD COLUMN decidedBy character varying;
+        ALTER TABLE pending_entries ADD COLUMN decidedAt timestamp;
+        ALTER TABLE pending_entries ADD COLUMN competitorId integer;",
+    ).down(
+        "ALTER TABLE pending_entries DROP COLUMN rejectReason;
+        ALTER TABLE pending_entries DROP COLUMN decidedBy;
+        ALTER TABLE pending_entries DROP COLUMN decidedAt;
+        ALTER TABLE pending_entries DROP COLUMN competitorId;",
+    ),
+    // entry fee payments, amounts are in the smallest currency unit
+    M::up(
+        "ALTER TABLE competitors ADD COLUMN amountDue integer;
+        ALTER TABLE competitors ADD COLUMN amountPaid integer NOT NULL DEFAULT 0;
+        ALTER TABLE competitors ADD COLUMN paymentReference character varying;
+        ALTER TABLE competitors ADD COLUMN paidAt timestamp;
+        CREATE INDEX competitors_ix2 ON competitors (paymentReference);",
+    ).down(
+        "DROP INDEX competitors_ix2;
+        ALTER TABLE competitors DROP COLUMN amountDue;
+        ALTER TABLE competitors DROP COLUMN amountPaid;
+        ALTER TABLE competitors DROP COLUMN paymentReference;
+        ALTER TABLE competitors DROP COLUMN paidAt;",
+    ),
+    // clock offsets of stations reported by station bridges, offset = station clock - daemon clock
+    M::up(
+        "CREATE TABLE station_clocks (
+            readerConnectionId integer PRIMARY KEY,
+            offsetMs integer NOT NULL DEFAULT 0,
+            brokerOffsetMs integer,
+            reportedAt timestamp
+        );",
+    ).down(
+        "DROP TABLE station_clocks;",
+    ),
+    // station times corrected by clock offset of the reader station, raw values are kept for recompute
+    M::up(
+        "ALTER TABLE cards ADD COLUMN rawCheckTime integer;
+        ALTER TABLE cards ADD COLUMN rawStartTime integer;
+        ALTER TABLE cards ADD COLUMN rawFinishTime integer;
+        ALTER TABLE cards ADD COLUMN clockOffsetMs integer;
+        ALTER TABLE punches ADD COLUMN readerConnectionId integer;
+        ALTER TABLE punches ADD COLUMN rawTime integer;
+        ALTER TABLE punches ADD COLUMN rawMsec integer;
+        ALTER TABLE punches ADD COLUMN rawTimeMs integer;
+        ALTER TABLE punches ADD COLUMN clockOffsetMs integer;",
+    ).down(
+        "ALTER TABLE cards DROP COLUMN rawCheckTime;
+        ALTER TABLE cards DROP COLUMN rawStartTime;
+        ALTER TABLE cards DROP COLUMN rawFinishTime;
+        ALTER TABLE cards DROP COLUMN clockOffsetMs;
+        ALTER TABLE punches DROP COLUMN readerConnectionId;
+        ALTER TABLE punches DROP COLUMN rawTime;
+        ALTER TABLE punches DROP COLUMN rawMsec;
+        ALTER TABLE punches DROP COLUMN rawTimeMs;
+        ALTER TABLE punches DROP COLUMN clockOffsetMs;",
+    ),
+    // soft delete, tables listed in soft_delete_tables config keep deleted records as tombstones
+    M::up(
+        "ALTER TABLE competitors ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE competitors ADD COLUMN deletedAt timestamp;
+        ALTER TABLE runs ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE runs ADD COLUMN deletedAt timestamp;
+        ALTER TABLE relays ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE relays ADD COLUMN deletedAt timestamp;
+        ALTER TABLE classes ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE classes ADD COLUMN deletedAt timestamp;
+        ALTER TABLE courses ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE courses ADD COLUMN deletedAt timestamp;
+        ALTER TABLE codes ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE codes ADD COLUMN deletedAt timestamp;
+        ALTER TABLE cards ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE cards ADD COLUMN deletedAt timestamp;
+        ALTER TABLE punches ADD COLUMN deleted boolean NOT NULL DEFAULT 0;
+        ALTER TABLE punches ADD COLUMN deletedAt timestamp;",
+    ).down(
+        "ALTER TABLE competitors DROP COLUMN deleted;
+        ALTER TABLE competitors DROP COLUMN deletedAt;
+        ALTER TABLE runs DROP COLUMN deleted;
+        ALTER TABLE runs DROP COLUMN deletedAt;
+        ALTER TABLE relays DROP COLUMN deleted;
+        ALTER TABLE relays DROP COLUMN deletedAt;
+        ALTER TABLE classes DROP COLUMN deleted;
+        ALTER TABLE classes DROP COLUMN deletedAt;
+        ALTER TABLE courses DROP COLUMN deleted;
+        ALTER TABLE courses DROP COLUMN deletedAt;
+        ALTER TABLE codes DROP COLUMN deleted;
+        ALTER TABLE codes DROP COLUMN deletedAt;
+        ALTER TABLE cards DROP COLUMN deleted;
+        ALTER TABLE cards DROP COLUMN deletedAt;
+        ALTER TABLE punches DROP COLUMN deleted;
+        ALTER TABLE punches DROP COLUMN deletedAt;",
+    ),
+    // optimistic concurrency control, row version is incremented by every update
+    M::up(
+        "ALTER TABLE competitors ADD COLUMN rowVersion integer NOT NULL DEFAULT 0;
+        ALTER TABLE runs ADD COLUMN rowVersion integer NOT NULL DEFAULT 0;
+        ALTER TABLE relays ADD COLUMN rowVersion integer NOT NULL DEFAULT 0;
+        ALTER TABLE classes ADD COLUMN rowVersion integer NOT NULL DEFAULT 0;",
+    ).down(
+        "ALTER TABLE competitors DROP COLUMN rowVersion;
+        ALTER TABLE runs DROP COLUMN rowVersion;
+        ALTER TABLE relays DROP COLUMN rowVersion;
+        ALTER TABLE classes DROP COLUMN rowVersion;",
+    ),
+    // change history of tables listed in history_tables config, values are CPON maps of changed fields
+    M::up(
+        "CREATE TABLE record_history (
+            id integer PRIMARY KEY,
+            tableName character varying NOT NULL,
+            recordId integer NOT NULL,
+            operation character varying NOT NULL,
+            oldValues character varying,
+            newValues character varying,
+            issuer character varying,
+            changedAt timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX record_history_ix0 ON record_history (tableName, recordId);",
+    ).down(
+        "DROP TABLE record_history;",
+    ),
+    // maximum running time of class in stage, runs over it are marked by rules engine
+    M::up(
+        "ALTER TABLE classdefs ADD COLUMN maxTimeMin integer;",
+    ).down(
+        "ALTER TABLE classdefs DROP COLUMN maxTimeMin;",
+    ),
+    // official results of class in stage, runs of frozen class can be changed with explicit override only
+    M::up(
+        "ALTER TABLE classdefs ADD COLUMN resultsFrozen boolean NOT NULL DEFAULT 0;",
+    ).down(
+        "ALTER TABLE classdefs DROP COLUMN resultsFrozen;",
+    ),
+    // protests and jury decisions, upheld protests adjust time or reinstate the run in results
+    M::up(
+        "CREATE TABLE protests (
+            id integer PRIMARY KEY,
+            runId integer NOT NULL,
+            reason character varying NOT NULL,
+            filedBy character varying,
+            filedAt timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            status character varying NOT NULL DEFAULT 'filed',
+            decision character varying,
+            decidedBy character varying,
+            decidedAt timestamp,
+            timeAdjustmentMs integer NOT NULL DEFAULT 0,
+            reinstate boolean NOT NULL DEFAULT 0,
+            CONSTRAINT protests_foreign0 FOREIGN KEY (runId) REFERENCES runs (id) ON UPDATE RESTRICT ON DELETE RESTRICT
+        );
+        CREATE INDEX protests_ix0 ON protests (runId);",
+    ).down(
+        "DROP TABLE protests;",
+    ),
+    // penalties of run, their sum is kept in runs.penaltyTimeMs and included in runs.timeMs
+    M::up(
+        "CREATE TABLE penalties (
+            id integer PRIMARY KEY,
+            runId integer NOT NULL,
+            kind character varying NOT NULL,
+            code integer,
+            timeMs integer NOT NULL,
+            reason character varying,
+            issuer character varying,
+            createdAt timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            CONSTRAINT penalties_foreign0 FOREIGN KEY (runId) REFERENCES runs (id) ON UPDATE RESTRICT ON DELETE RESTRICT
+        );
+        CREATE INDEX penalties_ix0 ON penalties (runId);",
+    ).down(
+        "DROP TABLE penalties;",
+    ),
+    // maps handed out to starters of class in stage
+    M::up(
+        "ALTER TABLE classdefs ADD COLUMN mapsIssued integer NOT NULL DEFAULT 0;",
+    ).down(
+        "ALTER TABLE classdefs DROP COLUMN mapsIssued;",
+    ),
+    // competitor contacts and sent notifications, one notification per run and trigger is sent
+    M::up(
+        "ALTER TABLE competitors ADD COLUMN email character varying;
+        ALTER TABLE competitors ADD COLUMN phone character varying;
+        CREATE TABLE notifications (
+            id integer PRIMARY KEY,
+            runId integer NOT NULL,
+            triggerType character varying NOT NULL,
+            transport character varying NOT NULL,
+            recipient character varying,
+            sentAt timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            error character varying,
+            CONSTRAINT notifications_foreign0 FOREIGN KEY (runId) REFERENCES runs (id) ON UPDATE RESTRICT ON DELETE RESTRICT
+        );
+        CREATE INDEX notifications_ix0 ON notifications (runId, triggerType);",
+    ).down(
+        "DROP TABLE notifications;
+        ALTER TABLE competitors DROP COLUMN email;
+        ALTER TABLE competitors DROP COLUMN phone;",
+    ),
 ];
+
+/// Disciplines of `sport` as enumz entries
+fn sport_disciplines(sport: Sport) -> &'static [(&'static str, &'static str)] {
+    match sport {
+        Sport::FootO => &[
+            ("classic", "Classic"),
+            ("middle", "Middle"),
+            ("sprint", "Sprint"),
+            ("night", "Night"),
+            ("relay", "Relay"),
+        ],
+        Sport::Mtbo | Sport::SkiO => &[
+            ("long", "Long"),
+            ("middle", "Middle"),
+            ("sprint", "Sprint"),
+            ("massstart", "Mass start"),
+            ("relay", "Relay"),
+        ],
+    }
+}
+
+const SPORTS_ENUMZ_GROUP: &str = "Sport";
+const DISCIPLINES_ENUMZ_GROUP: &str = "Discipline";
+
+/// Fill config and enumz tables with defaults of `sport`, QuickEvent compatible clients require them.
+///
+/// DB is considered seeded already, if enumz table is not empty.
+/// Existing config values are kept, only missing `cname` and `ctype` are set.
+pub async fn seed_event_db(qxsql: &impl QxSqlApi, sport: Sport) -> anyhow::Result<()> {
+    let result = qxsql.query("SELECT COUNT(*) FROM enumz", None).await?;
+    if result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default() > 0 {
+        return Ok(());
+    }
+    let config_entries = [
+        ("event.name", "Event name", String::new(), "QString"),
+        ("event.date", "Event date", String::new(), "QDate"),
+        ("event.time", "Event time", String::new(), "QTime"),
+        ("event.place", "Event place", String::new(), "QString"),
+        ("event.mainReferee", "Main referee", String::new(), "QString"),
+        ("event.director", "Director", String::new(), "QString"),
+        ("event.sportId", "Sport", sport.quickevent_id().to_string(), "int"),
+        ("event.disciplineId", "Discipline", "1".to_string(), "int"),
+        ("event.stageCount", "Stage count", "1".to_string(), "int"),
+        ("event.currentStageId", "Current stage", "1".to_string(), "int"),
+    ];
+    for (key, name, value, ctype) in config_entries {
+        qxsql.exec("INSERT INTO config (ckey, cname, cvalue, ctype) VALUES (:ckey, :cname, :cvalue, :ctype) \
+            ON CONFLICT (ckey) DO UPDATE SET cname = COALESCE(cname, excluded.cname), ctype = COALESCE(ctype, excluded.ctype)",
+            Some(&record_from_slice(&[
+                ("ckey", key.into()),
+                ("cname", name.into()),
+                ("cvalue", value.into()),
+                ("ctype", ctype.into()),
+            ]))).await?;
+    }
+
+    let sports = Sport::ALL.map(|s| (s.as_str(), s.as_str().to_uppercase()));
+    let disciplines = sport_disciplines(sport).iter().map(|(id, caption)| (*id, caption.to_string()));
+    let entries = sports.into_iter().map(|entry| (SPORTS_ENUMZ_GROUP, entry))
+        .chain(disciplines.map(|entry| (DISCIPLINES_ENUMZ_GROUP, entry)));
+    let mut pos: i64 = 0;
+    let mut last_group = "";
+    for (group_name, (group_id, caption)) in entries {
+        pos = if group_name == last_group { pos + 1 } else { 1 };
+        last_group = group_name;
+        qxsql.exec("INSERT INTO enumz (groupName, groupId, pos, caption) VALUES (:groupName, :groupId, :pos, :caption)",
+            Some(&record_from_slice(&[
+                ("groupName", group_name.into()),
+                ("groupId", group_id.into()),
+                ("pos", pos.into()),
+                ("caption", caption.into()),
+            ]))).await?;
+    }
+    info!("Event DB seeded with {} defaults", sport.as_str());
+    Ok(())
+}
@@ -0,0 +1,529 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail};
+use log::error;
+use qxsql::QxSqlApi;
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, issuer, str_to_rpc_error, traffic};
+
+const METH_FILL_VACANCY: &str = "fillVacancy";
+const METH_INSERT_SLOT: &str = "insertSlot";
+const METH_ASSIGN_BIBS: &str = "assignBibs";
+const METH_START_CONFLICTS: &str = "startConflicts";
+const SIG_START_LIST_CHNG: &str = "startlistchng";
+
+pub(crate) const EVENT_DRAW_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_FILL_VACANCY, Flags::None, AccessLevel::Write, "[i:classId,i:runId,i|n:startTimeMs]", "i:startTimeMs", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_INSERT_SLOT, Flags::None, AccessLevel::Write, "[i:classId,i:startTimeMs,i|n:runId]", "[i:shiftedRunId]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_ASSIGN_BIBS, Flags::None, AccessLevel::Write,
+        "{[{i:classId,i:first,i|n:last}]:classes,[{i:from,i:to}]:reserved,[i]:skip,b:keepExisting,b:dryRun}",
+        "[{i:competitorId,i:classId,i:startNumber}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_START_CONFLICTS, Flags::None, AccessLevel::Read, "{i|n:stageId,i|n:minGapMin}|n",
+        "{[{i:firstCode,[i]:classIds,i:conflictCount}]:conflicts,[{i:classId,s:className,i:firstCode,i:startSlotIndex,i:startTimeMin,i:startIntervalMin,i:proposedStartSlotIndex,i:proposedStartTimeMin,i:proposedStartIntervalMin}]:proposals}", &[], "",
+    ),
+];
+
+/// Classes starting at the same minute to the same first control are conflicting by default
+const DEFAULT_START_GAP_MIN: i64 = 1;
+/// Class is not shifted by more than this to solve conflicts
+const MAX_START_SHIFT_MIN: i64 = 24 * 60;
+
+/// Start slots definition of class in stage, times are in msec since stage start
+struct ClassStartSlots {
+    first_start_ms: i64,
+    interval_ms: i64,
+    vacants_after: i64,
+}
+
+impl ClassStartSlots {
+    async fn load(sql_api: &EventSqlApi, stage_id: i64, class_id: i64) -> anyhow::Result<Self> {
+        let result = sql_api.query("SELECT startTimeMin, startIntervalMin, vacantsAfter FROM classdefs WHERE classId = :classId AND stageId = :stageId", Some(&record_from_slice(&[
+            ("classId", class_id.into()),
+            ("stageId", stage_id.into()),
+        ]))).await?;
+        if result.row_count() == 0 {
+            bail!("Class {class_id} is not defined in stage {stage_id}");
+        }
+        let get = |col| result.value(0, col).and_then(|v| v.to_int());
+        let interval_ms = get(1).unwrap_or_default() * 60 * 1000;
+        if interval_ms <= 0 {
+            bail!("Class {class_id} has no start interval in stage {stage_id}");
+        }
+        Ok(Self {
+            first_start_ms: get(0).unwrap_or_default() * 60 * 1000,
+            interval_ms,
+            vacants_after: get(2).unwrap_or_default().max(0),
+        })
+    }
+    fn is_slot(&self, start_time_ms: i64) -> bool {
+        start_time_ms >= self.first_start_ms && (start_time_ms - self.first_start_ms) % self.interval_ms == 0
+    }
+}
+
+/// `(run_id, startTimeMs, finishTimeMs)` of runs in class with assigned start time
+async fn class_starts(sql_api: &EventSqlApi, stage_id: i64, class_id: i64) -> anyhow::Result<Vec<(i64, i64, Option<i64>)>> {
+    let result = sql_api.query("SELECT runs.id, runs.startTimeMs, runs.finishTimeMs FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+        WHERE competitors.classId = :classId AND runs.stageId = :stageId AND runs.startTimeMs IS NOT NULL ORDER BY runs.startTimeMs", Some(&record_from_slice(&[
+        ("classId", class_id.into()),
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| {
+            let id = result.value(row, 0).and_then(|v| v.to_int())?;
+            let start_time_ms = result.value(row, 1).and_then(|v| v.to_int())?;
+            Some((id, start_time_ms, result.value(row, 2).and_then(|v| v.to_int())))
+        })
+        .collect())
+}
+
+fn send_start_list_changed(client_cmd_tx: &ClientCommandSender, event_id: EventId, class_id: i64, run_ids: &[i64]) {
+    let run_ids: Vec<RpcValue> = run_ids.iter().map(|id| RpcValue::from(*id)).collect();
+    let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/draw"), SIG_START_LIST_CHNG)
+        .with_param(make_map!("classId" => class_id, "runIds" => run_ids));
+    traffic::record_signal(event_id, &signal);
+    if let Err(e) = client_cmd_tx.send_message(signal) {
+        error!("Failed to send event {event_id} {SIG_START_LIST_CHNG} signal: {e}");
+    }
+}
+
+/// Assign the first vacant start slot of class, or `start_time_ms` if it is vacant, to run
+async fn fill_vacancy(sql_api: &EventSqlApi, stage_id: i64, class_id: i64, run_id: i64, start_time_ms: Option<i64>, issuer: Option<String>) -> anyhow::Result<i64> {
+    // slot is found and taken in one transaction, so two vacancies cannot get the same slot
+    sql_api.with_savepoint(async |sql_api| {
+        let slots = ClassStartSlots::load(sql_api, stage_id, class_id).await?;
+        let occupied: BTreeSet<i64> = class_starts(sql_api, stage_id, class_id).await?.into_iter()
+            .filter(|(id, _, _)| *id != run_id)
+            .map(|(_, start_time_ms, _)| start_time_ms)
+            .collect();
+        let last_slot_ms = occupied.last().copied().unwrap_or(slots.first_start_ms - slots.interval_ms)
+            + slots.vacants_after * slots.interval_ms;
+        let start_time_ms = match start_time_ms {
+            Some(start_time_ms) => {
+                if !slots.is_slot(start_time_ms) || occupied.contains(&start_time_ms) {
+                    bail!("Start time {start_time_ms} is not a vacant slot of class {class_id}");
+                }
+                start_time_ms
+            }
+            None => (0..)
+                .map(|ix| slots.first_start_ms + ix * slots.interval_ms)
+                .take_while(|t| *t <= last_slot_ms)
+                .find(|t| !occupied.contains(t))
+                .ok_or_else(|| anyhow!("Class {class_id} has no vacant start slot, insert a new one"))?,
+        };
+        if !sql_api.update_record_event("runs", run_id, &record_from_slice(&[("startTimeMs", start_time_ms.into())]), issuer).await? {
+            bail!("Run id: {run_id} not found");
+        }
+        Ok(start_time_ms)
+    }).await
+}
+
+/// Shift starts of class at `start_time_ms` and later by one interval, optionally assign the freed slot to `run_id`.
+///
+/// Returns ids of shifted runs.
+async fn insert_slot(sql_api: &EventSqlApi, stage_id: i64, class_id: i64, start_time_ms: i64, run_id: Option<i64>, issuer: Option<String>) -> anyhow::Result<Vec<i64>> {
+    // starts are read, shifted and the freed slot assigned in one transaction, so all starts are shifted or none of them
+    sql_api.with_savepoint(async |sql_api| {
+        let slots = ClassStartSlots::load(sql_api, stage_id, class_id).await?;
+        if !slots.is_slot(start_time_ms) {
+            bail!("Start time {start_time_ms} is not a start slot of class {class_id}");
+        }
+        let mut shifted: Vec<_> = class_starts(sql_api, stage_id, class_id).await?.into_iter()
+            .filter(|(id, t, _)| *t >= start_time_ms && Some(*id) != run_id)
+            .collect();
+        if let Some((id, _, _)) = shifted.iter().find(|(_, _, finish_time_ms)| finish_time_ms.is_some()) {
+            bail!("Cannot shift start of already finished run {id}");
+        }
+        // latest start first, so no two runs of class share a start while shifting
+        shifted.sort_by_key(|(_, t, _)| std::cmp::Reverse(*t));
+        for (id, t, _) in &shifted {
+            let record = record_from_slice(&[("startTimeMs", (t + slots.interval_ms).into())]);
+            if !sql_api.update_record_event("runs", *id, &record, issuer.clone()).await? {
+                bail!("Run id: {id} not found");
+            }
+        }
+        if let Some(run_id) = run_id
+            && !sql_api.update_record_event("runs", run_id, &record_from_slice(&[("startTimeMs", start_time_ms.into())]), issuer).await? {
+            bail!("Run id: {run_id} not found");
+        }
+        Ok(shifted.into_iter().map(|(id, _, _)| id).collect())
+    }).await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BibBlock {
+    class_id: i64,
+    first: i64,
+    /// Block is open-ended if not set
+    #[serde(default)]
+    last: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BibRange {
+    from: i64,
+    to: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AssignBibsPolicy {
+    /// Classes get start numbers in the order of blocks
+    classes: Vec<BibBlock>,
+    /// Start numbers, which are never assigned, e.g. for late entries
+    #[serde(default)]
+    reserved: Vec<BibRange>,
+    /// Single start numbers, which are never assigned, e.g. 13 or 666
+    #[serde(default)]
+    skip: Vec<i64>,
+    /// Keep already assigned start numbers, which are valid by the policy
+    #[serde(default)]
+    keep_existing: bool,
+    /// Return assignment without writing it
+    #[serde(default)]
+    dry_run: bool,
+}
+impl_rpcvalue_conversions!(AssignBibsPolicy);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BibAssignment {
+    competitor_id: i64,
+    class_id: i64,
+    start_number: i64,
+}
+
+/// Allocate start numbers of competitors in policy classes, competitors are ordered by their start time
+/// in `stage_id`, then by name. Start numbers of competitors in other classes stay untouched and are
+/// not assigned again. Whole assignment is written in one transaction.
+async fn assign_bibs(sql_api: &EventSqlApi, stage_id: i64, policy: &AssignBibsPolicy) -> anyhow::Result<Vec<BibAssignment>> {
+    for block in &policy.classes {
+        if block.first <= 0 || block.last.is_some_and(|last| last < block.first) {
+            bail!("Invalid start number block of class {}", block.class_id);
+        }
+    }
+    if let Some(range) = policy.reserved.iter().find(|range| range.to < range.from) {
+        bail!("Invalid reserved start number range {}-{}", range.from, range.to);
+    }
+    let class_ids: BTreeSet<i64> = policy.classes.iter().map(|block| block.class_id).collect();
+    if class_ids.len() != policy.classes.len() {
+        bail!("Class can have one start number block only");
+    }
+    let result = sql_api.query("SELECT competitors.id, competitors.classId, competitors.startNumber FROM competitors \
+        LEFT JOIN runs ON runs.competitorId = competitors.id AND runs.stageId = :stageId \
+        ORDER BY runs.startTimeMs IS NULL, runs.startTimeMs, competitors.lastName, competitors.firstName, competitors.id", Some(&record_from_slice(&[
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let is_allowed = |bib: i64| !policy.skip.contains(&bib) && !policy.reserved.iter().any(|range| (range.from..=range.to).contains(&bib));
+    let mut taken = BTreeSet::new();
+    let mut class_competitors: BTreeMap<i64, Vec<(i64, Option<i64>)>> = BTreeMap::new();
+    for row in 0..result.row_count() {
+        let Some(competitor_id) = int(row, 0) else {
+            continue;
+        };
+        let class_id = int(row, 1).unwrap_or_default();
+        let start_number = int(row, 2).filter(|bib| *bib > 0);
+        if class_ids.contains(&class_id) {
+            class_competitors.entry(class_id).or_default().push((competitor_id, start_number));
+        } else if let Some(bib) = start_number {
+            taken.insert(bib);
+        }
+    }
+    let mut assignments = Vec::new();
+    for block in &policy.classes {
+        let Some(competitors) = class_competitors.get(&block.class_id) else {
+            continue;
+        };
+        let in_block = |bib: i64| bib >= block.first && block.last.is_none_or(|last| bib <= last);
+        let mut pending = Vec::new();
+        for (competitor_id, start_number) in competitors {
+            match start_number {
+                Some(bib) if policy.keep_existing && in_block(*bib) && is_allowed(*bib) && taken.insert(*bib) => {
+                    assignments.push(BibAssignment { competitor_id: *competitor_id, class_id: block.class_id, start_number: *bib });
+                }
+                _ => pending.push(*competitor_id),
+            }
+        }
+        let mut next = block.first;
+        for competitor_id in pending {
+            while !is_allowed(next) || taken.contains(&next) {
+                next += 1;
+            }
+            if !in_block(next) {
+                bail!("Start number block {}-{} of class {} is too small", block.first, block.last.unwrap_or_default(), block.class_id);
+            }
+            taken.insert(next);
+            assignments.push(BibAssignment { competitor_id, class_id: block.class_id, start_number: next });
+        }
+    }
+    if !policy.dry_run {
+        let records: Vec<_> = assignments.iter()
+            .map(|assignment| record_from_slice(&[
+                ("id", assignment.competitor_id.into()),
+                ("startNumber", assignment.start_number.into()),
+            ]))
+            .collect();
+        sql_api.upsert_records("competitors", "id", &records).await?;
+    }
+    Ok(assignments)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StartConflictsParams {
+    /// Current stage if not set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    /// Minimal gap between starts of runners heading to the same first control
+    #[serde(default)]
+    min_gap_min: Option<i64>,
+}
+impl_rpcvalue_conversions!(StartConflictsParams);
+
+/// Interval start of class in stage
+#[derive(Debug, Clone)]
+struct ClassStart {
+    class_id: i64,
+    class_name: String,
+    first_code: i64,
+    start_slot_index: i64,
+    start_time_min: i64,
+    start_interval_min: i64,
+    /// Runners and vacants
+    slot_count: i64,
+}
+
+impl ClassStart {
+    fn start_times(&self, start_time_min: i64, start_interval_min: i64) -> impl Iterator<Item = i64> {
+        (0..self.slot_count).map(move |slot| start_time_min + slot * start_interval_min)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StartConflict {
+    first_code: i64,
+    class_ids: Vec<i64>,
+    /// Pairs of starts of different classes closer than the minimal gap
+    conflict_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StartProposal {
+    class_id: i64,
+    class_name: String,
+    first_code: i64,
+    start_slot_index: i64,
+    start_time_min: i64,
+    start_interval_min: i64,
+    proposed_start_slot_index: i64,
+    proposed_start_time_min: i64,
+    proposed_start_interval_min: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StartConflicts {
+    conflicts: Vec<StartConflict>,
+    proposals: Vec<StartProposal>,
+}
+
+/// Interval start classes of stage with the first control of their course, classes without course are skipped
+async fn load_class_starts(sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<Vec<ClassStart>> {
+    let result = sql_api.query("SELECT classdefs.classId, classes.name, classdefs.startSlotIndex, classdefs.startTimeMin, classdefs.startIntervalMin, \
+        (SELECT codes.code FROM coursecodes JOIN codes ON codes.id = coursecodes.codeId \
+            WHERE coursecodes.courseId = classdefs.courseId ORDER BY coursecodes.position LIMIT 1), \
+        (SELECT COUNT(*) FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+            WHERE competitors.classId = classdefs.classId AND runs.stageId = classdefs.stageId AND runs.isRunning) \
+            + COALESCE(classdefs.vacantsBefore, 0) + COALESCE(classdefs.vacantsAfter, 0) \
+        FROM classdefs LEFT JOIN classes ON classes.id = classdefs.classId \
+        WHERE classdefs.stageId = :stageId AND classdefs.startIntervalMin > 0 \
+        ORDER BY classdefs.startTimeMin, classdefs.classId", Some(&record_from_slice(&[
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    Ok((0..result.row_count())
+        .filter_map(|row| Some(ClassStart {
+            class_id: int(row, 0)?,
+            class_name: result.value(row, 1).and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+            start_slot_index: int(row, 2).unwrap_or(-1),
+            start_time_min: int(row, 3).unwrap_or_default(),
+            start_interval_min: int(row, 4)?,
+            first_code: int(row, 5)?,
+            slot_count: int(row, 6).unwrap_or_default(),
+        }))
+        .collect())
+}
+
+/// Pairs of starts of different classes closer than `gap_min`
+fn count_start_conflicts(starts: &[(i64, i64)], gap_min: i64) -> i64 {
+    let mut sorted = starts.to_vec();
+    sorted.sort();
+    let mut count = 0;
+    for (ix, (time_min, class_id)) in sorted.iter().enumerate() {
+        count += sorted[ix + 1..].iter()
+            .take_while(|(other_time_min, _)| other_time_min - time_min < gap_min)
+            .filter(|(_, other_class_id)| other_class_id != class_id)
+            .count() as i64;
+    }
+    count
+}
+
+/// Detect classes starting to the same first control too close to each other and propose start settings without conflicts.
+///
+/// Classes of conflicting group are put into one start slot. If runners of the group cannot start
+/// with the minimal gap at the current intervals, intervals are prolonged, so the classes interleave.
+/// Then the classes are placed by their original start order, each one at the earliest start time
+/// not before its original one, where none of its starts conflicts with already placed classes.
+fn solve_start_conflicts(classes: &[ClassStart], gap_min: i64) -> anyhow::Result<StartConflicts> {
+    let mut groups: BTreeMap<i64, Vec<&ClassStart>> = BTreeMap::new();
+    for class in classes {
+        groups.entry(class.first_code).or_default().push(class);
+    }
+    let mut next_slot_index = classes.iter().map(|class| class.start_slot_index).max().unwrap_or(-1).max(-1) + 1;
+    let mut conflicts = Vec::new();
+    let mut proposals = Vec::new();
+    for (first_code, group) in groups {
+        let starts: Vec<(i64, i64)> = group.iter()
+            .flat_map(|class| class.start_times(class.start_time_min, class.start_interval_min).map(|time_min| (time_min, class.class_id)))
+            .collect();
+        let conflict_count = count_start_conflicts(&starts, gap_min);
+        if conflict_count == 0 {
+            continue;
+        }
+        conflicts.push(StartConflict { first_code, class_ids: group.iter().map(|class| class.class_id).collect(), conflict_count });
+
+        let slot_index = match group.iter().map(|class| class.start_slot_index).filter(|index| *index >= 0).min() {
+            Some(slot_index) => slot_index,
+            None => {
+                let slot_index = next_slot_index;
+                next_slot_index += 1;
+                slot_index
+            }
+        };
+        // every class occupies gap / interval of the start time line
+        let group_len = group.len() as i64;
+        let overloaded = group.iter().map(|class| gap_min as f64 / class.start_interval_min as f64).sum::<f64>() > 1.;
+        let mut occupied = BTreeSet::new();
+        for class in group {
+            let interval_min = if overloaded { class.start_interval_min.max(group_len * gap_min) } else { class.start_interval_min };
+            let is_free = |start_time_min: i64| class.start_times(start_time_min, interval_min)
+                .all(|time_min| occupied.range(time_min - gap_min + 1..time_min + gap_min).next().is_none());
+            let start_time_min = (class.start_time_min..=class.start_time_min + MAX_START_SHIFT_MIN)
+                .find(|start_time_min| is_free(*start_time_min))
+                .ok_or_else(|| anyhow!("Class {} cannot be started without conflicts within {MAX_START_SHIFT_MIN} minutes", class.class_name))?;
+            occupied.extend(class.start_times(start_time_min, interval_min));
+            if (slot_index, start_time_min, interval_min) != (class.start_slot_index, class.start_time_min, class.start_interval_min) {
+                proposals.push(StartProposal {
+                    class_id: class.class_id,
+                    class_name: class.class_name.clone(),
+                    first_code,
+                    start_slot_index: class.start_slot_index,
+                    start_time_min: class.start_time_min,
+                    start_interval_min: class.start_interval_min,
+                    proposed_start_slot_index: slot_index,
+                    proposed_start_time_min: start_time_min,
+                    proposed_start_interval_min: interval_min,
+                });
+            }
+        }
+    }
+    Ok(StartConflicts { conflicts, proposals })
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_DRAW_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_DRAW_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_DRAW_NODE_METHODS).await;
+            match method {
+                METH_FILL_VACANCY => m.resolve(methods, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let class_id = param.first().map(|v| v.as_int()).unwrap_or_default();
+                    let run_id = param.get(1).map(|v| v.as_int()).unwrap_or_default();
+                    let start_time_ms = param.get(2).filter(|v| v.is_int()).map(|v| v.as_int());
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let start_time_ms = fill_vacancy(&sql_api, current_stage, class_id, run_id, start_time_ms, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    send_start_list_changed(&client_cmd_tx, event_id, class_id, &[run_id]);
+                    Ok(RpcValue::from(start_time_ms))
+                }),
+                METH_INSERT_SLOT => m.resolve(methods, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let class_id = param.first().map(|v| v.as_int()).unwrap_or_default();
+                    let start_time_ms = param.get(1).map(|v| v.as_int()).unwrap_or_default();
+                    let run_id = param.get(2).filter(|v| v.is_int()).map(|v| v.as_int());
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let shifted = insert_slot(&sql_api, current_stage, class_id, start_time_ms, run_id, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    let changed: Vec<i64> = shifted.iter().copied().chain(run_id).collect();
+                    send_start_list_changed(&client_cmd_tx, event_id, class_id, &changed);
+                    Ok(RpcValue::from(shifted.into_iter().map(RpcValue::from).collect::<Vec<_>>()))
+                }),
+                METH_ASSIGN_BIBS => m.resolve(methods, async move || {
+                    let policy = AssignBibsPolicy::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let assignments = assign_bibs(&sql_api, current_stage, &policy).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&assignments).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_START_CONFLICTS => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => StartConflictsParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => StartConflictsParams::default(),
+                    };
+                    let gap_min = param.min_gap_min.unwrap_or(DEFAULT_START_GAP_MIN);
+                    if gap_min <= 0 {
+                        return Err(str_to_rpc_error("Minimal start gap must be positive"));
+                    }
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let classes = load_class_starts(&sql_api, stage_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    let conflicts = solve_start_conflicts(&classes, gap_min)
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&conflicts).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use anyhow::{anyhow, bail};
+use log::error;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::appsqlapi::db_value_to_rpcvalue;
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, generate_api_token, issuer, traffic};
+
+const METH_SUBMIT: &str = "submit";
+const METH_STATUS: &str = "status";
+const METH_ROTATE_TOKEN: &str = "rotateToken";
+const METH_LIST_PENDING: &str = "listPending";
+const METH_APPROVE: &str = "approve";
+const METH_REJECT: &str = "reject";
+const SIG_ENTRY_CHNG: &str = "entrychng";
+
+/// Event secret key of the token, which has to be presented by public entry submitters,
+/// self-service entries are disabled if it is not set
+const ENTRIES_TOKEN_SKEY: &str = "entries.token";
+
+pub(crate) const ENTRY_STATUS_PENDING: &str = "pending";
+pub(crate) const ENTRY_STATUS_ACCEPTED: &str = "accepted";
+pub(crate) const ENTRY_STATUS_REJECTED: &str = "rejected";
+
+pub(crate) const EVENT_ENTRIES_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_SUBMIT, Flags::None, AccessLevel::Read, "{s:token,s:firstName,s:lastName,s|n:registration,i|s:class,i|n:siId,s|n:email,s|n:note}", "{i:entryId,s:statusKey}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_STATUS, Flags::None, AccessLevel::Read, "{i:entryId,s:statusKey}", "{s:status,s|n:rejectReason}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_ROTATE_TOKEN, Flags::None, AccessLevel::Config, "", "s:token", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_LIST_PENDING, Flags::None, AccessLevel::Write, "", "[{i:id,s:firstName,s:lastName,s|n:registration,s|n:clubAbbr,i:classId,i|n:siId,s|n:email,s|n:note,s:submittedAt}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_APPROVE, Flags::None, AccessLevel::Write, "i:entryId", "i:competitorId", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_REJECT, Flags::None, AccessLevel::Write, "[i:entryId,s:reason]", "b", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum EntryClass {
+    Id(i64),
+    Name(String),
+}
+
+/// Entry submitted by competitor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubmitEntryParams {
+    token: String,
+    first_name: String,
+    last_name: String,
+    #[serde(default)]
+    registration: Option<String>,
+    class: EntryClass,
+    #[serde(default)]
+    si_id: Option<i64>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    note: Option<String>,
+}
+impl_rpcvalue_conversions!(SubmitEntryParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubmitEntryResult {
+    entry_id: i64,
+    /// Secret of the entry, submitter needs it to check the entry status
+    status_key: String,
+}
+impl_rpcvalue_conversions!(SubmitEntryResult);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryStatusParams {
+    entry_id: i64,
+    status_key: String,
+}
+impl_rpcvalue_conversions!(EntryStatusParams);
+
+fn opt_db_value<T: Into<DbValue>>(value: Option<T>) -> DbValue {
+    value.map(Into::into).unwrap_or(DbValue::Null)
+}
+
+async fn check_entries_token(sql_api: &EventSqlApi, token: &str) -> anyhow::Result<()> {
+    match sql_api.secret(ENTRIES_TOKEN_SKEY).await? {
+        Some(entries_token) if !entries_token.is_empty() => {
+            if entries_token != token {
+                bail!("Invalid entries token");
+            }
+            Ok(())
+        }
+        _ => bail!("Self-service entries are not enabled for this event"),
+    }
+}
+
+/// Returns `(class_id, max_entries)`
+async fn entry_class(sql_api: &EventSqlApi, class: &EntryClass) -> anyhow::Result<(i64, Option<i64>)> {
+    let result = match class {
+        EntryClass::Id(id) => sql_api.query("SELECT id, maxEntries FROM classes WHERE id = :id", Some(&record_from_slice(&[("id", (*id).into())]))).await?,
+        EntryClass::Name(name) => sql_api.query("SELECT id, maxEntries FROM classes WHERE name = :name", Some(&record_from_slice(&[("name", name.trim().into())]))).await?,
+    };
+    let class_id = result.value(0, 0).and_then(|v| v.to_int())
+        .ok_or_else(|| anyhow!("Unknown class: {class:?}"))?;
+    Ok((class_id, result.value(0, 1).and_then(|v| v.to_int())))
+}
+
+async fn submit_entry(sql_api: &EventSqlApi, param: SubmitEntryParams, issuer: Option<String>) -> anyhow::Result<SubmitEntryResult> {
+    check_entries_token(sql_api, &param.token).await?;
+    let first_name = param.first_name.trim();
+    let last_name = param.last_name.trim();
+    if first_name.is_empty() || last_name.is_empty() {
+        bail!("First name and last name are required");
+    }
+    let (class_id, max_entries) = entry_class(sql_api, &param.class).await?;
+
+    let mut si_id = param.si_id;
+    let mut club_abbr = None;
+    let registration = param.registration.as_deref().map(str::trim).filter(|s| !s.is_empty());
+    if let Some(registration) = registration {
+        let result = sql_api.query("SELECT lastName, clubAbbr, siId FROM registrations WHERE registration = :registration", Some(&record_from_slice(&[
+            ("registration", registration.into()),
+        ]))).await?;
+        if result.row_count() == 0 {
+            bail!("Unknown registration: {registration}");
+        }
+        let registered_last_name = result.value(0, 0).and_then(|v| v.as_str()).unwrap_or_default();
+        if registered_last_name.to_lowercase() != last_name.to_lowercase() {
+            bail!("Registration {registration} belongs to other competitor");
+        }
+        club_abbr = result.value(0, 1).and_then(|v| v.as_str()).map(str::to_string);
+        si_id = si_id.or_else(|| result.value(0, 2).and_then(|v| v.to_int()));
+        let result = sql_api.query("SELECT (SELECT COUNT(*) FROM competitors WHERE registration = :registration) \
+            + (SELECT COUNT(*) FROM pending_entries WHERE registration = :registration AND status = :status)", Some(&record_from_slice(&[
+            ("registration", registration.into()),
+            ("status", ENTRY_STATUS_PENDING.into()),
+        ]))).await?;
+        if result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default() > 0 {
+            bail!("Competitor {registration} is already entered");
+        }
+    }
+    if let Some(si_id) = si_id && !(1..=9_999_999).contains(&si_id) {
+        bail!("Invalid SI card number: {si_id}");
+    }
+    if let Some(max_entries) = max_entries {
+        let result = sql_api.query("SELECT (SELECT COUNT(*) FROM competitors WHERE classId = :classId) \
+            + (SELECT COUNT(*) FROM pending_entries WHERE classId = :classId AND status = :status)", Some(&record_from_slice(&[
+            ("classId", class_id.into()),
+            ("status", ENTRY_STATUS_PENDING.into()),
+        ]))).await?;
+        if result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default() >= max_entries {
+            bail!("Class is full, maximal number of entries is {max_entries}");
+        }
+    }
+
+    let status_key = generate_api_token();
+    let record = record_from_slice(&[
+        ("firstName", first_name.into()),
+        ("lastName", last_name.into()),
+        ("registration", opt_db_value(registration)),
+        ("clubAbbr", opt_db_value(club_abbr)),
+        ("classId", class_id.into()),
+        ("siId", opt_db_value(si_id)),
+        ("email", opt_db_value(param.email)),
+        ("note", opt_db_value(param.note)),
+        ("status", ENTRY_STATUS_PENDING.into()),
+        ("statusKey", status_key.as_str().into()),
+        ("submittedAt", chrono::Local::now().fixed_offset().into()),
+    ]);
+    let entry_id = sql_api.create_record_event("pending_entries", &record, issuer).await?;
+    Ok(SubmitEntryResult { entry_id, status_key })
+}
+
+async fn entry_status(sql_api: &EventSqlApi, param: EntryStatusParams) -> anyhow::Result<RpcValue> {
+    let result = sql_api.query("SELECT status, rejectReason FROM pending_entries WHERE id = :id AND statusKey = :statusKey", Some(&record_from_slice(&[
+        ("id", param.entry_id.into()),
+        ("statusKey", param.status_key.into()),
+    ]))).await?;
+    let status = result.value(0, 0).and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Entry id: {} not found", param.entry_id))?;
+    let reject_reason = result.value(0, 1).and_then(|v| v.as_str())
+        .map(RpcValue::from)
+        .unwrap_or_else(RpcValue::null);
+    Ok(make_map!("status" => status, "rejectReason" => reject_reason).into())
+}
+
+async fn list_pending_entries(sql_api: &EventSqlApi) -> anyhow::Result<RpcValue> {
+    let result = sql_api.query("SELECT id, firstName, lastName, registration, clubAbbr, classId, siId, email, note, submittedAt \
+        FROM pending_entries WHERE status = :status ORDER BY submittedAt, id", Some(&record_from_slice(&[
+        ("status", ENTRY_STATUS_PENDING.into()),
+    ]))).await?;
+    let entries: Vec<RpcValue> = (0..result.row_count())
+        .map(|row| {
+            let mut map = shvproto::rpcvalue::Map::new();
+            for (col, field) in result.fields.iter().enumerate() {
+                let value = result.value(row, col).map(db_value_to_rpcvalue).unwrap_or_else(RpcValue::null);
+                map.insert(field.name.clone(), value);
+            }
+            RpcValue::from(map)
+        })
+        .collect();
+    Ok(RpcValue::from(entries))
+}
+
+/// Fail if the entry was already decided
+async fn pending_entry_status(sql_api: &EventSqlApi, entry_id: i64) -> anyhow::Result<()> {
+    let result = sql_api.query("SELECT status FROM pending_entries WHERE id = :id", Some(&record_from_slice(&[
+        ("id", entry_id.into()),
+    ]))).await?;
+    match result.value(0, 0).and_then(|v| v.as_str()) {
+        None => bail!("Entry id: {entry_id} not found"),
+        Some(ENTRY_STATUS_PENDING) => Ok(()),
+        Some(status) => bail!("Entry id: {entry_id} is already {status}"),
+    }
+}
+
+/// Create competitor with run in every stage from pending entry, returns competitor id
+async fn approve_entry(sql_api: &EventSqlApi, entry_id: i64, issuer: Option<String>) -> anyhow::Result<i64> {
+    // entry is decided once, status is checked and changed in the same savepoint as the competitor is created
+    sql_api.with_savepoint(async |sql_api| {
+        pending_entry_status(sql_api, entry_id).await?;
+        let result = sql_api.query("SELECT firstName, lastName, registration, clubAbbr, classId, siId, email FROM pending_entries WHERE id = :id", Some(&record_from_slice(&[
+            ("id", entry_id.into()),
+        ]))).await?;
+        let value = |col| result.value(0, col).cloned().unwrap_or(DbValue::Null);
+        let si_id = value(5);
+        let competitor = record_from_slice(&[
+            ("firstName", value(0)),
+            ("lastName", value(1)),
+            ("registration", value(2)),
+            ("club", value(3)),
+            ("classId", value(4)),
+            ("siId", si_id.clone()),
+            ("email", value(6)),
+        ]);
+        let competitor_id = sql_api.create_record_event("competitors", &competitor, issuer.clone()).await?;
+        let stages = sql_api.query("SELECT id FROM stages ORDER BY id", None).await?;
+        let stage_ids: Vec<i64> = (0..stages.row_count())
+            .filter_map(|row| stages.value(row, 0).and_then(|v| v.to_int()))
+            .collect();
+        for stage_id in if stage_ids.is_empty() { vec![1] } else { stage_ids } {
+            let run = record_from_slice(&[
+                ("competitorId", competitor_id.into()),
+                ("stageId", stage_id.into()),
+                ("siId", si_id.clone()),
+            ]);
+            sql_api.create_record_event("runs", &run, issuer.clone()).await?;
+        }
+        let entry = record_from_slice(&[
+            ("status", ENTRY_STATUS_ACCEPTED.into()),
+            ("competitorId", competitor_id.into()),
+            ("decidedBy", opt_db_value(issuer.clone())),
+            ("decidedAt", chrono::Local::now().fixed_offset().into()),
+        ]);
+        sql_api.update_record_event("pending_entries", entry_id, &entry, issuer).await?;
+        Ok(competitor_id)
+    }).await
+}
+
+async fn reject_entry(sql_api: &EventSqlApi, entry_id: i64, reason: &str, issuer: Option<String>) -> anyhow::Result<bool> {
+    pending_entry_status(sql_api, entry_id).await?;
+    if reason.trim().is_empty() {
+        bail!("Reject reason is required");
+    }
+    let entry = record_from_slice(&[
+        ("status", ENTRY_STATUS_REJECTED.into()),
+        ("rejectReason", reason.trim().into()),
+        ("decidedBy", opt_db_value(issuer.clone())),
+        ("decidedAt", chrono::Local::now().fixed_offset().into()),
+    ]);
+    sql_api.update_record_event("pending_entries", entry_id, &entry, issuer).await
+}
+
+fn send_entry_changed(client_cmd_tx: &ClientCommandSender, event_id: EventId, entry_id: i64, status: &str) {
+    let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/entries"), SIG_ENTRY_CHNG)
+        .with_param(make_map!("entryId" => entry_id, "status" => status));
+    traffic::record_signal(event_id, &signal);
+    if let Err(e) = client_cmd_tx.send_message(signal) {
+        error!("Failed to send event {event_id} {SIG_ENTRY_CHNG} signal: {e}");
+    }
+}
+
+async fn rotate_entries_token(sql_api: &EventSqlApi) -> anyhow::Result<String> {
+    let token = generate_api_token();
+    sql_api.set_secret(ENTRIES_TOKEN_SKEY, Some(&token)).await?;
+    // token stored by older version in readable event config is removed
+    sql_api.exec("DELETE FROM config WHERE ckey = :ckey", Some(&record_from_slice(&[
+        ("ckey", ENTRIES_TOKEN_SKEY.into()),
+    ]))).await?;
+    Ok(token)
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_ENTRIES_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_ENTRIES_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_ENTRIES_NODE_METHODS).await;
+            match method {
+                METH_SUBMIT => m.resolve(methods, async move || {
+                    let param = SubmitEntryParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    submit_entry(&sql_api, param, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_STATUS => m.resolve(methods, async move || {
+                    let param = EntryStatusParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    entry_status(&sql_api, param).await
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_ROTATE_TOKEN => m.resolve(methods, async move || {
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    rotate_entries_token(&sql_api).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_LIST_PENDING => m.resolve(methods, async move || {
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    list_pending_entries(&sql_api).await
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_APPROVE => m.resolve(methods, async move || {
+                    let entry_id = rq.param().unwrap_or_default().as_int();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let competitor_id = approve_entry(&sql_api, entry_id, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    send_entry_changed(&client_cmd_tx, event_id, entry_id, ENTRY_STATUS_ACCEPTED);
+                    Ok(RpcValue::from(competitor_id))
+                }),
+                METH_REJECT => m.resolve(methods, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let entry_id = param.first().map(|v| v.as_int()).unwrap_or_default();
+                    let reason = param.get(1).map(|v| v.as_str()).unwrap_or_default();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let rejected = reject_entry(&sql_api, entry_id, reason, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    send_entry_changed(&client_cmd_tx, event_id, entry_id, ENTRY_STATUS_REJECTED);
+                    Ok(RpcValue::from(rejected))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, bail};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::{Record, record_from_slice};
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::appsqlapi::query_result_to_records;
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, issuer};
+
+const METH_LIST: &str = "list";
+const METH_CREATE: &str = "create";
+const METH_UPDATE: &str = "update";
+const METH_DELETE: &str = "delete";
+const METH_REORDER: &str = "reorder";
+
+const ENUMZ_TABLE: &str = "enumz";
+
+pub(crate) const EVENT_ENUMZ_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_LIST, Flags::None, AccessLevel::Read, "s:groupName|n", "[{?}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_CREATE, Flags::None, AccessLevel::Write, "{s:groupName,s:groupId,i|n:pos,s|n:caption,s|n:color,s|n:value}", "i:id", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_UPDATE, Flags::None, AccessLevel::Write, "[i:id,{s|n:groupId,s|n:caption,s|n:color,s|n:value}]", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DELETE, Flags::None, AccessLevel::Write, "i:id", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_REORDER, Flags::None, AccessLevel::Write, "[s:groupName,[s:groupId]]", "b", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EnumzCreateParams {
+    group_name: String,
+    group_id: String,
+    /// Appended to the end of the group if not set
+    #[serde(default)]
+    pos: Option<i64>,
+    #[serde(default)]
+    caption: Option<String>,
+    #[serde(default)]
+    color: Option<String>,
+    #[serde(default)]
+    value: Option<String>,
+}
+impl_rpcvalue_conversions!(EnumzCreateParams);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EnumzChange {
+    #[serde(default)]
+    group_id: Option<String>,
+    #[serde(default)]
+    caption: Option<String>,
+    #[serde(default)]
+    color: Option<String>,
+    #[serde(default)]
+    value: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EnumzUpdateParams(i64, EnumzChange);
+impl_rpcvalue_conversions!(EnumzUpdateParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EnumzReorderParams(String, Vec<String>);
+impl_rpcvalue_conversions!(EnumzReorderParams);
+
+/// Colors are stored in Qt `#RRGGBB` or `#AARRGGBB` format
+fn is_valid_color(color: &str) -> bool {
+    color.strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn optional_string(value: Option<String>) -> DbValue {
+    value.filter(|s| !s.is_empty()).map(DbValue::from).unwrap_or(DbValue::Null)
+}
+
+fn check_color(color: &Option<String>) -> anyhow::Result<()> {
+    match color.as_deref() {
+        Some(color) if !color.is_empty() && !is_valid_color(color) => bail!("Invalid color '{color}', #RRGGBB or #AARRGGBB expected"),
+        _ => Ok(()),
+    }
+}
+
+async fn enumz_group_id_exists(sql_api: &EventSqlApi, group_name: &str, group_id: &str) -> anyhow::Result<bool> {
+    let result = sql_api.query("SELECT id FROM enumz WHERE groupName = :groupName AND groupId = :groupId", Some(&record_from_slice(&[
+        ("groupName", group_name.into()),
+        ("groupId", group_id.into()),
+    ]))).await?;
+    Ok(result.row_count() > 0)
+}
+
+/// Returns `(groupName, pos)` of enumz entry
+async fn enumz_entry_position(sql_api: &EventSqlApi, id: i64) -> anyhow::Result<(String, i64)> {
+    let result = sql_api.query("SELECT groupName, pos FROM enumz WHERE id = :id", Some(&record_from_slice(&[
+        ("id", id.into()),
+    ]))).await?;
+    let group_name = result.value(0, 0).and_then(|v| v.as_str()).map(str::to_string)
+        .ok_or_else(|| anyhow!("Enumz entry id: {id} not found"))?;
+    let pos = result.value(0, 1).and_then(|v| v.to_int()).unwrap_or_default();
+    Ok((group_name, pos))
+}
+
+async fn list_enumz(sql_api: &EventSqlApi, group_name: Option<&str>) -> anyhow::Result<Vec<Record>> {
+    let result = match group_name {
+        Some(group_name) => sql_api.query("SELECT * FROM enumz WHERE groupName = :groupName ORDER BY pos, id", Some(&record_from_slice(&[
+            ("groupName", group_name.into()),
+        ]))).await?,
+        None => sql_api.query("SELECT * FROM enumz ORDER BY groupName, pos, id", None).await?,
+    };
+    Ok(query_result_to_records(&result))
+}
+
+async fn create_enumz(sql_api: &EventSqlApi, param: EnumzCreateParams, issuer: Option<String>) -> anyhow::Result<i64> {
+    if param.group_name.is_empty() || param.group_id.is_empty() {
+        bail!("Enumz groupName and groupId cannot be empty");
+    }
+    check_color(&param.color)?;
+    if enumz_group_id_exists(sql_api, &param.group_name, &param.group_id).await? {
+        bail!("Enumz entry {}.{} exists already", param.group_name, param.group_id);
+    }
+    let pos = match param.pos {
+        Some(pos) => pos,
+        None => {
+            let result = sql_api.query("SELECT MAX(pos) FROM enumz WHERE groupName = :groupName", Some(&record_from_slice(&[
+                ("groupName", param.group_name.as_str().into()),
+            ]))).await?;
+            result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default() + 1
+        }
+    };
+    let record = record_from_slice(&[
+        ("groupName", param.group_name.into()),
+        ("groupId", param.group_id.into()),
+        ("pos", pos.into()),
+        ("caption", optional_string(param.caption)),
+        ("color", optional_string(param.color)),
+        ("value", optional_string(param.value)),
+    ]);
+    sql_api.create_record_event(ENUMZ_TABLE, &record, issuer).await
+}
+
+async fn update_enumz(sql_api: &EventSqlApi, id: i64, change: EnumzChange, issuer: Option<String>) -> anyhow::Result<bool> {
+    check_color(&change.color)?;
+    let mut record = Record::new();
+    if let Some(group_id) = change.group_id {
+        if group_id.is_empty() {
+            bail!("Enumz groupId cannot be empty");
+        }
+        let (group_name, _) = enumz_entry_position(sql_api, id).await?;
+        let result = sql_api.query("SELECT id FROM enumz WHERE groupName = :groupName AND groupId = :groupId AND id != :id", Some(&record_from_slice(&[
+            ("groupName", group_name.as_str().into()),
+            ("groupId", group_id.as_str().into()),
+            ("id", id.into()),
+        ]))).await?;
+        if result.row_count() > 0 {
+            bail!("Enumz entry {group_name}.{group_id} exists already");
+        }
+        record.insert("groupId".to_string(), group_id.into());
+    }
+    if let Some(caption) = change.caption {
+        record.insert("caption".to_string(), optional_string(Some(caption)));
+    }
+    if let Some(color) = change.color {
+        record.insert("color".to_string(), optional_string(Some(color)));
+    }
+    if let Some(value) = change.value {
+        record.insert("value".to_string(), optional_string(Some(value)));
+    }
+    if record.is_empty() {
+        return Ok(false);
+    }
+    sql_api.update_record_event(ENUMZ_TABLE, id, &record, issuer).await
+}
+
+async fn delete_enumz(sql_api: &EventSqlApi, id: i64, issuer: Option<String>) -> anyhow::Result<bool> {
+    let (group_name, pos) = enumz_entry_position(sql_api, id).await?;
+    let was_deleted = sql_api.delete_record_event(ENUMZ_TABLE, id, issuer).await?;
+    if was_deleted {
+        // keep positions in group contiguous
+        sql_api.exec("UPDATE enumz SET pos = pos - 1 WHERE groupName = :groupName AND pos > :pos", Some(&record_from_slice(&[
+            ("groupName", group_name.into()),
+            ("pos", pos.into()),
+        ]))).await?;
+    }
+    Ok(was_deleted)
+}
+
+/// Set positions of group entries to order of `group_ids`, which must list all group entries
+async fn reorder_enumz(sql_api: &EventSqlApi, group_name: &str, group_ids: &[String], issuer: Option<String>) -> anyhow::Result<bool> {
+    let entries = list_enumz(sql_api, Some(group_name)).await?;
+    let current: BTreeSet<&str> = entries.iter()
+        .filter_map(|entry| entry.get("groupId").and_then(|v| v.as_str()))
+        .collect();
+    let requested: BTreeSet<&str> = group_ids.iter().map(String::as_str).collect();
+    if requested.len() != group_ids.len() || current != requested {
+        bail!("Reorder of enumz group {group_name} must list all its groupIds exactly once");
+    }
+    let mut changed = false;
+    for (ix, group_id) in group_ids.iter().enumerate() {
+        let pos = ix as i64 + 1;
+        let Some(entry) = entries.iter().find(|entry| entry.get("groupId").and_then(|v| v.as_str()) == Some(group_id)) else {
+            continue;
+        };
+        if entry.get("pos").and_then(|v| v.to_int()) == Some(pos) {
+            continue;
+        }
+        let id = entry.get("id").and_then(|v| v.to_int())
+            .ok_or_else(|| anyhow!("Enumz entry {group_name}.{group_id} without id"))?;
+        sql_api.update_record_event(ENUMZ_TABLE, id, &record_from_slice(&[("pos", pos.into())]), issuer.clone()).await?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_ENUMZ_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_ENUMZ_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_ENUMZ_NODE_METHODS).await;
+            match method {
+                METH_LIST => m.resolve(methods, async move || {
+                    let group_name = rq.param().filter(|p| p.is_string()).map(|p| p.as_str());
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    list_enumz(&sql_api, group_name).await
+                        .and_then(|records| to_rpcvalue(&records).map_err(|e| anyhow!(e)))
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_CREATE => m.resolve(methods, async move || {
+                    let param = EnumzCreateParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    create_enumz(&sql_api, param, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_UPDATE => m.resolve(methods, async move || {
+                    let EnumzUpdateParams(id, change) = EnumzUpdateParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    update_enumz(&sql_api, id, change, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_DELETE => m.resolve(methods, async move || {
+                    let id = rq.param().unwrap_or_default().as_int();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    delete_enumz(&sql_api, id, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_REORDER => m.resolve(methods, async move || {
+                    let EnumzReorderParams(group_name, group_ids) = EnumzReorderParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    reorder_enumz(&sql_api, &group_name, &group_ids, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
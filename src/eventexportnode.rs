@@ -0,0 +1,591 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail};
+use qxsql::QxSqlApi;
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::encryption;
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventdb::downgrade_to_legacy_schema;
+use crate::eventsqlapi::EventSqlApi;
+use crate::jobs::{JobProgress, spawn_job};
+use crate::pdf::{PaperSize, PdfDocument};
+use crate::penalties::{RunPenalties, load_run_penalties, penalty_breakdown};
+use crate::results::{RunFilter, RunResult, RunStatus, format_clock_time, format_time_ms, load_run_results, xml_escape};
+use crate::rqtrace::RequestTrace;
+use crate::state::{BlobDownload, EventId, MAX_BLOB_CHUNK_SIZE, SharedAppState};
+use crate::{anyhow_to_rpc_error, generate_api_token, global_config};
+
+const METH_HTML_RESULTS: &str = "htmlResults";
+const METH_START_LIST_PDF: &str = "startListPdf";
+const METH_RESULTS_PDF: &str = "resultsPdf";
+const METH_LABELS: &str = "labels";
+const METH_QBE: &str = "qbe";
+const METH_DOWNLOAD_CHUNK: &str = "downloadChunk";
+
+/// Event config key of bib label template, placeholders are `{bib}`, `{name}`, `{firstName}`, `{lastName}`,
+/// `{class}`, `{club}`, `{registration}`, `{siId}` and `{start}`
+const LABEL_TEMPLATE_CKEY: &str = "export.labelTemplate";
+const DEFAULT_LABEL_TEMPLATE: &str = "{bib}\n{name}\n{class}  {start}\n{club}  SI {siId}";
+const LABEL_CSV_COLUMNS: &[&str] = &["bib", "start", "class", "name", "firstName", "lastName", "club", "registration", "siId"];
+
+pub(crate) const EVENT_EXPORT_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_HTML_RESULTS, Flags::None, AccessLevel::Write, "{s|n:dir,i|n:stageId,b|n:job,b|n:chunked}", "x|[s:file]|i:jobId|{s:downloadId,i:size}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_START_LIST_PDF, Flags::None, AccessLevel::Read, "{i|n:stageId,i|n:classId,s|n:paperSize,s|n:groupBy,b|n:pageBreak,b|n:chunked}", "x|{s:downloadId,i:size}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_RESULTS_PDF, Flags::None, AccessLevel::Read, "{i|n:stageId,i|n:classId,s|n:paperSize,s|n:groupBy,b|n:pageBreak,b|n:chunked}", "x|{s:downloadId,i:size}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_LABELS, Flags::None, AccessLevel::Read, "{i|n:stageId,i|n:classId,s|n:format,s|n:paperSize}", "s|x", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_QBE, Flags::None, AccessLevel::Write, "{b|n:chunked}|n", "x|{s:downloadId,i:size}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DOWNLOAD_CHUNK, Flags::None, AccessLevel::Read, "[s:downloadId,i:offset]", "x", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HtmlResultsParams {
+    /// Target directory relative to the configured `export_dir`, zip blob is returned if not set
+    #[serde(default)]
+    dir: Option<String>,
+    /// Current stage is exported if not set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    /// Run export as background job, job id is returned and the result is reported by job progress signal
+    #[serde(default)]
+    job: bool,
+    /// Zip blob is read by `downloadChunk`, it is ignored for background job
+    #[serde(default)]
+    chunked: bool,
+}
+impl_rpcvalue_conversions!(HtmlResultsParams);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PdfGroupBy {
+    #[default]
+    Class,
+    Club,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PdfParams {
+    /// Current stage is printed if not set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    /// All classes are printed if not set
+    #[serde(default)]
+    class_id: Option<i64>,
+    /// A4, A5 or letter, A4 is default
+    #[serde(default)]
+    paper_size: Option<String>,
+    #[serde(default)]
+    group_by: PdfGroupBy,
+    /// Start every group on new page
+    #[serde(default)]
+    page_break: bool,
+    /// PDF is read by `downloadChunk`
+    #[serde(default)]
+    chunked: bool,
+}
+impl_rpcvalue_conversions!(PdfParams);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct QbeParams {
+    /// QuickEvent file is read by `downloadChunk`
+    #[serde(default)]
+    chunked: bool,
+}
+impl_rpcvalue_conversions!(QbeParams);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LabelsFormat {
+    #[default]
+    Csv,
+    Pdf,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LabelsParams {
+    #[serde(default)]
+    stage_id: Option<i64>,
+    #[serde(default)]
+    class_id: Option<i64>,
+    #[serde(default)]
+    format: LabelsFormat,
+    #[serde(default)]
+    paper_size: Option<String>,
+}
+impl_rpcvalue_conversions!(LabelsParams);
+
+/// Split times of run, `(code, stpTimeMs, lapTimeMs)`
+type RunSplits = BTreeMap<i64, Vec<(i64, Option<i64>, Option<i64>)>>;
+
+async fn load_splits(sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<RunSplits> {
+    let result = sql_api.query("SELECT runId, code, stpTimeMs, lapTimeMs FROM runlaps \
+        WHERE runId IN (SELECT id FROM runs WHERE stageId = :stageId) ORDER BY runId, position", Some(&record_from_slice(&[
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    let mut splits = RunSplits::new();
+    for row in 0..result.row_count() {
+        let int = |col| result.value(row, col).and_then(|v| v.to_int());
+        let (Some(run_id), Some(code)) = (int(0), int(1)) else {
+            continue;
+        };
+        splits.entry(run_id).or_default().push((code, int(2), int(3)));
+    }
+    Ok(splits)
+}
+
+/// File name of class page, class names can contain any characters
+fn class_file_name(prefix: &str, class: &RunResult) -> String {
+    let name: String = class.class_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!("{prefix}-{}-{name}.html", class.class_id)
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+        <style>body{{font-family:sans-serif}}table{{border-collapse:collapse}}td,th{{padding:2px 8px;text-align:left}}tr:nth-child(even){{background:#eee}}</style>\n\
+        </head>\n<body>\n{body}</body>\n</html>\n", title = xml_escape(title))
+}
+
+/// Penalty time of run with its breakdown, like `+02:00 (31 01:00, 45 01:00)`
+fn penalty_text(run: &RunResult, penalties: &RunPenalties) -> String {
+    let Some(penalty_time_ms) = run.penalty_time_ms else {
+        return String::new();
+    };
+    match penalties.get(&run.run_id) {
+        Some(run_penalties) => format!("+{} ({})", format_time_ms(penalty_time_ms), penalty_breakdown(run_penalties)),
+        None => format!("+{}", format_time_ms(penalty_time_ms)),
+    }
+}
+
+fn results_page(event_name: &str, class_results: &[RunResult], splits: &RunSplits, penalties: &RunPenalties) -> String {
+    let class_name = &class_results[0].class_name;
+    let mut body = format!("<h1>{}</h1>\n<h2>{}</h2>\n<p><a href=\"index.html\">&larr;</a></p>\n<table>\n\
+        <tr><th>#</th><th>Name</th><th>Club</th><th>Time</th><th>Penalty</th><th>Splits</th></tr>\n", xml_escape(event_name), xml_escape(class_name));
+    for run in class_results {
+        let time = match (run.status, run.time_ms) {
+            (RunStatus::Ok, Some(time_ms)) => format_time_ms(time_ms),
+            (status, _) => status.as_short_str().to_string(),
+        };
+        let run_splits = splits.get(&run.run_id).map(|run_splits| run_splits.iter()
+            .map(|(code, stp_time_ms, lap_time_ms)| format!("{code}:&nbsp;{}&nbsp;({})",
+                stp_time_ms.map(format_time_ms).unwrap_or_else(|| "-".into()),
+                lap_time_ms.map(format_time_ms).unwrap_or_else(|| "-".into())))
+            .collect::<Vec<_>>()
+            .join(" "))
+            .unwrap_or_default();
+        body.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{time}</td><td>{}</td><td>{run_splits}</td></tr>\n",
+            run.place.map(|p| format!("{p}.")).unwrap_or_default(), xml_escape(&run.name()), xml_escape(&run.club),
+            xml_escape(&penalty_text(run, penalties))));
+    }
+    body.push_str("</table>\n");
+    html_page(&format!("{event_name} - {class_name}"), &body)
+}
+
+fn start_list_page(event_name: &str, stage_start: Option<chrono::DateTime<chrono::FixedOffset>>, class_runs: &[RunResult]) -> String {
+    let class_name = &class_runs[0].class_name;
+    let mut runs: Vec<&RunResult> = class_runs.iter().collect();
+    runs.sort_by_key(|run| (run.start_time_ms.is_none(), run.start_time_ms, run.start_number));
+    let mut body = format!("<h1>{}</h1>\n<h2>{}</h2>\n<p><a href=\"index.html\">&larr;</a></p>\n<table>\n\
+        <tr><th>Start</th><th>Bib</th><th>Name</th><th>Club</th><th>SI</th></tr>\n", xml_escape(event_name), xml_escape(class_name));
+    for run in runs {
+        let start = format_clock_time(stage_start, run.start_time_ms);
+        body.push_str(&format!("<tr><td>{start}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            run.start_number.map(|n| n.to_string()).unwrap_or_default(), xml_escape(&run.name()), xml_escape(&run.club),
+            run.si_id.map(|n| n.to_string()).unwrap_or_default()));
+    }
+    body.push_str("</table>\n");
+    html_page(&format!("{event_name} - {class_name}"), &body)
+}
+
+/// Render result and start list pages of all classes in stage, returns `(file_name, content)` pairs
+async fn render_html_results(sql_api: &EventSqlApi, stage_id: i64, progress: Option<&JobProgress>) -> anyhow::Result<Vec<(String, String)>> {
+    let event_name = event_name(sql_api).await?;
+    let stage_start = sql_api.stage_start(stage_id).await.ok();
+    let results = load_run_results(sql_api, stage_id, RunFilter::Stage).await?;
+    let splits = load_splits(sql_api, stage_id).await?;
+    let penalties = load_run_penalties(sql_api, stage_id).await?;
+
+    let mut files = vec![];
+    let mut index = format!("<h1>{}</h1>\n<table>\n<tr><th>Class</th><th></th><th></th></tr>\n", xml_escape(&event_name));
+    let class_count = results.chunk_by(|a, b| a.class_id == b.class_id).count() as i64;
+    for (class_ix, class_results) in results.chunk_by(|a, b| a.class_id == b.class_id).enumerate() {
+        if let Some(progress) = progress {
+            progress.report(class_ix as i64, class_count);
+        }
+        let results_file = class_file_name("results", &class_results[0]);
+        let start_list_file = class_file_name("startlist", &class_results[0]);
+        index.push_str(&format!("<tr><td>{}</td><td><a href=\"{start_list_file}\">Start list</a></td><td><a href=\"{results_file}\">Results</a></td></tr>\n",
+            xml_escape(&class_results[0].class_name)));
+        files.push((results_file, results_page(&event_name, class_results, &splits, &penalties)));
+        files.push((start_list_file, start_list_page(&event_name, stage_start, class_results)));
+    }
+    index.push_str("</table>\n");
+    files.insert(0, ("index.html".to_string(), html_page(&event_name, &index)));
+    Ok(files)
+}
+
+/// Zip blob of the pages, or list of file names written to `dir` under the export directory
+async fn html_results(sql_api: &EventSqlApi, stage_id: i64, dir: Option<String>, progress: Option<&JobProgress>) -> anyhow::Result<RpcValue> {
+    let files = render_html_results(sql_api, stage_id, progress).await?;
+    let Some(dir) = dir else {
+        return zip_files(&files).map(RpcValue::from);
+    };
+    let dir = export_dir(&dir)?;
+    let file_names = smol::unblock(move || -> anyhow::Result<Vec<String>> {
+        std::fs::create_dir_all(&dir)?;
+        for (file_name, content) in &files {
+            std::fs::write(dir.join(file_name), content)?;
+        }
+        Ok(files.into_iter().map(|(file_name, _)| file_name).collect())
+    }).await?;
+    Ok(RpcValue::from(file_names.into_iter().map(RpcValue::from).collect::<Vec<_>>()))
+}
+
+async fn event_name(sql_api: &EventSqlApi) -> anyhow::Result<String> {
+    let result = sql_api.query("SELECT cvalue FROM config WHERE ckey = 'event.name'", None).await?;
+    Ok(result.value(0, 0).and_then(|v| v.as_str()).unwrap_or_default().to_string())
+}
+
+/// Split runs to printed groups, runs keep their order within group
+fn pdf_groups(runs: Vec<RunResult>, group_by: PdfGroupBy) -> Vec<(String, Vec<RunResult>)> {
+    let mut groups: BTreeMap<(String, i64), Vec<RunResult>> = BTreeMap::new();
+    for run in runs {
+        let key = match group_by {
+            PdfGroupBy::Class => (run.class_name.clone(), run.class_id),
+            PdfGroupBy::Club => (run.club.clone(), 0),
+        };
+        groups.entry(key).or_default().push(run);
+    }
+    groups.into_iter().map(|((name, _), runs)| (name, runs)).collect()
+}
+
+/// Paginated PDF start list or results, runs are grouped by class or club
+async fn render_pdf(sql_api: &EventSqlApi, stage_id: i64, param: &PdfParams, results: bool) -> anyhow::Result<Vec<u8>> {
+    let paper_size = param.paper_size.as_deref().map(str::parse::<PaperSize>).transpose()?.unwrap_or_default();
+    let event_name = event_name(sql_api).await?;
+    let stage_start = sql_api.stage_start(stage_id).await.ok();
+    let filter = param.class_id.map(RunFilter::Class).unwrap_or(RunFilter::Stage);
+    let mut runs = load_run_results(sql_api, stage_id, filter).await?;
+    if !results {
+        runs.sort_by_key(|run| (run.class_name.clone(), run.start_time_ms.is_none(), run.start_time_ms, run.start_number));
+    }
+    let title = format!("{event_name} - {}", if results { "Results" } else { "Start list" });
+    let mut doc = PdfDocument::new(paper_size, &title);
+    let w = doc.text_width();
+    // name and club/class columns positions are relative to the printable width
+    let (name_x, second_x, time_x, status_x) = (0.12 * w, 0.50 * w, 0.78 * w, 0.90 * w);
+    let second_title = if param.group_by == PdfGroupBy::Club { "Class" } else { "Club" };
+    for (ix, (group_name, group_runs)) in pdf_groups(runs, param.group_by).into_iter().enumerate() {
+        if param.page_break && ix > 0 {
+            doc.page_break();
+        }
+        doc.heading(&format!("{group_name} ({})", group_runs.len()));
+        if results {
+            doc.row(&[(0., "#"), (name_x, "Name"), (second_x, second_title), (time_x, "Time"), (status_x, "")], true);
+        } else {
+            doc.row(&[(0., "Start"), (name_x, "Name"), (second_x, second_title), (time_x, "Bib"), (status_x, "SI")], true);
+        }
+        for run in &group_runs {
+            let second = if param.group_by == PdfGroupBy::Club { &run.class_name } else { &run.club };
+            if results {
+                let place = run.place.map(|p| format!("{p}.")).unwrap_or_default();
+                let time = run.time_ms.filter(|_| run.status == RunStatus::Ok).map(format_time_ms).unwrap_or_default();
+                // penalty of valid result is printed in the status column
+                let status = match (run.status, run.penalty_time_ms) {
+                    (RunStatus::Ok, Some(penalty_time_ms)) => format!("+{}", format_time_ms(penalty_time_ms)),
+                    (status, _) => status.as_short_str().to_string(),
+                };
+                doc.row(&[(0., place.as_str()), (name_x, run.name().as_str()), (second_x, second.as_str()), (time_x, time.as_str()), (status_x, status.as_str())], false);
+            } else {
+                let start = format_clock_time(stage_start, run.start_time_ms);
+                let bib = run.start_number.map(|n| n.to_string()).unwrap_or_default();
+                let si_id = run.si_id.map(|n| n.to_string()).unwrap_or_default();
+                doc.row(&[(0., start.as_str()), (name_x, run.name().as_str()), (second_x, second.as_str()), (time_x, bib.as_str()), (status_x, si_id.as_str())], false);
+            }
+        }
+    }
+    Ok(doc.finish())
+}
+
+fn label_value(run: &RunResult, stage_start: Option<chrono::DateTime<chrono::FixedOffset>>, key: &str) -> Option<String> {
+    let value = match key {
+        "bib" => run.start_number.map(|n| n.to_string()).unwrap_or_default(),
+        "start" => format_clock_time(stage_start, run.start_time_ms),
+        "class" => run.class_name.clone(),
+        "name" => run.name(),
+        "firstName" => run.first_name.clone(),
+        "lastName" => run.last_name.clone(),
+        "club" => run.club.clone(),
+        "registration" => run.registration.clone(),
+        "siId" => run.si_id.map(|n| n.to_string()).unwrap_or_default(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Replace `{key}` placeholders in template, unknown placeholders are kept as they are
+fn render_label(template: &str, run: &RunResult, stage_start: Option<chrono::DateTime<chrono::FixedOffset>>) -> String {
+    let mut label = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        label.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let key = &rest[start + 1..start + len];
+        match label_value(run, stage_start, key) {
+            Some(value) => label.push_str(&value),
+            None => label.push_str(&rest[start..=start + len]),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    label.push_str(rest);
+    label
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Bib labels of runs ordered by class and start time
+async fn render_labels(sql_api: &EventSqlApi, stage_id: i64, param: &LabelsParams) -> anyhow::Result<RpcValue> {
+    let stage_start = sql_api.stage_start(stage_id).await.ok();
+    let filter = param.class_id.map(RunFilter::Class).unwrap_or(RunFilter::Stage);
+    let mut runs = load_run_results(sql_api, stage_id, filter).await?;
+    runs.sort_by_key(|run| (run.class_name.clone(), run.start_time_ms.is_none(), run.start_time_ms, run.start_number));
+    match param.format {
+        LabelsFormat::Csv => {
+            let mut csv = LABEL_CSV_COLUMNS.join(",");
+            csv.push('\n');
+            for run in &runs {
+                let row: Vec<String> = LABEL_CSV_COLUMNS.iter()
+                    .map(|key| csv_field(&label_value(run, stage_start, key).unwrap_or_default()))
+                    .collect();
+                csv.push_str(&row.join(","));
+                csv.push('\n');
+            }
+            Ok(RpcValue::from(csv))
+        }
+        LabelsFormat::Pdf => {
+            let paper_size = param.paper_size.as_deref().map(str::parse::<PaperSize>).transpose()?.unwrap_or_default();
+            let result = sql_api.query("SELECT cvalue FROM config WHERE ckey = :ckey", Some(&record_from_slice(&[
+                ("ckey", LABEL_TEMPLATE_CKEY.into()),
+            ]))).await?;
+            let template = result.value(0, 0).and_then(|v| v.as_str()).filter(|s| !s.trim().is_empty())
+                .unwrap_or(DEFAULT_LABEL_TEMPLATE)
+                .replace("\\n", "\n");
+            let mut doc = PdfDocument::new(paper_size, &event_name(sql_api).await?);
+            for run in &runs {
+                let label = render_label(&template, run, stage_start);
+                let mut lines = label.lines();
+                // first line is printed large, it is usually the bib number
+                doc.heading(lines.next().unwrap_or_default());
+                for line in lines {
+                    doc.row(&[(0., line)], false);
+                }
+            }
+            Ok(RpcValue::from(doc.finish()))
+        }
+    }
+}
+
+/// Resolve export directory, `dir` must not escape the configured export directory
+fn export_dir(dir: &str) -> anyhow::Result<PathBuf> {
+    let root = global_config().export_dir.as_deref()
+        .ok_or_else(|| anyhow!("Export directory is not configured"))?;
+    let dir = Path::new(dir);
+    if !dir.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        bail!("Invalid export directory: {}", dir.display());
+    }
+    Ok(Path::new(root).join(dir))
+}
+
+fn zip_files(files: &[(String, String)]) -> anyhow::Result<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (file_name, content) in files {
+        zip.start_file(file_name.as_str(), zip::write::SimpleFileOptions::default())?;
+        zip.write_all(content.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Self-contained copy of local event DB, which can be opened by QuickEvent desktop application
+async fn export_qbe(app_state: &SharedAppState, event_id: EventId) -> anyhow::Result<Vec<u8>> {
+    let db = app_state.with_open_event(event_id, |e| e.local_db.clone())
+        .ok_or_else(|| anyhow!("Event id: {event_id} is not open."))?
+        .ok_or_else(|| anyhow!("Export to QuickEvent file is supported for local events only"))?;
+    let export_dir = format!("{}/export", global_config().data_dir);
+    std::fs::create_dir_all(&export_dir)?;
+    let export_file = format!("{export_dir}/{event_id}-{}.qbe", chrono::Utc::now().timestamp_micros());
+    let dest_file = export_file.clone();
+    db.conn(move |conn| Ok(encryption::export_plaintext(conn, &dest_file))).await??;
+    let file = export_file.clone();
+    let data = smol::unblock(move || {
+        let mut conn = async_sqlite::rusqlite::Connection::open(&file)?;
+        downgrade_to_legacy_schema(&mut conn)?;
+        drop(conn);
+        Ok::<_, anyhow::Error>(std::fs::read(&file)?)
+    }).await;
+    let _ = std::fs::remove_file(&export_file);
+    data
+}
+
+/// Keep blob result of export for `downloadChunk`, so its size is not limited by broker frame size
+fn begin_download(app_state: &SharedAppState, event_id: EventId, data: Vec<u8>) -> anyhow::Result<RpcValue> {
+    let download_id = generate_api_token();
+    let size = data.len() as i64;
+    app_state.with_open_event_mut(event_id, |event| {
+        event.blob_transfers.check_capacity(true, data.len())?;
+        event.blob_transfers.downloads.insert(download_id.clone(), BlobDownload { data, touched_at: Instant::now() });
+        Ok::<_, anyhow::Error>(())
+    }).ok_or_else(|| anyhow!("Event id: {event_id} is not open."))??;
+    Ok(make_map!("downloadId" => download_id, "size" => size).into())
+}
+
+/// Chunk of download starting at `offset`, the download is finished when its last chunk is read
+fn download_chunk(app_state: &SharedAppState, event_id: EventId, download_id: &str, offset: i64) -> anyhow::Result<Vec<u8>> {
+    app_state.with_open_event_mut(event_id, |event| {
+        event.blob_transfers.expire();
+        let download = event.blob_transfers.downloads.get_mut(download_id)
+            .ok_or_else(|| anyhow!("Invalid or expired download id: {download_id}"))?;
+        let size = download.data.len();
+        let offset = usize::try_from(offset).ok().filter(|offset| *offset <= size)
+            .ok_or_else(|| anyhow!("Offset {offset} is out of download size {size}"))?;
+        let end = (offset + MAX_BLOB_CHUNK_SIZE).min(size);
+        let chunk = download.data[offset..end].to_vec();
+        download.touched_at = Instant::now();
+        if end == size {
+            event.blob_transfers.downloads.remove(download_id);
+        }
+        Ok(chunk)
+    }).ok_or_else(|| anyhow!("Event id: {event_id} is not open."))?
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_EXPORT_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_EXPORT_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_EXPORT_NODE_METHODS).await;
+            match method {
+                METH_HTML_RESULTS => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => HtmlResultsParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => HtmlResultsParams::default(),
+                    };
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    if param.job {
+                        let job_id = spawn_job(app_state, client_cmd_tx, METH_HTML_RESULTS, Some(event_id), move |progress| async move {
+                            html_results(&sql_api, stage_id, param.dir, Some(&progress)).await
+                        }).await.map_err(anyhow_to_rpc_error)?;
+                        return Ok(RpcValue::from(job_id));
+                    }
+                    let result = html_results(&sql_api, stage_id, param.dir, None).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    if param.chunked && result.is_blob() {
+                        return begin_download(&app_state, event_id, result.as_blob().to_vec())
+                            .map_err(anyhow_to_rpc_error);
+                    }
+                    Ok(result)
+                }),
+                METH_START_LIST_PDF | METH_RESULTS_PDF => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => PdfParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => PdfParams::default(),
+                    };
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let results = rq.method() == Some(METH_RESULTS_PDF);
+                    let sql_api = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let pdf = render_pdf(&sql_api, stage_id, &param, results).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    if param.chunked {
+                        return begin_download(&app_state, event_id, pdf)
+                            .map_err(anyhow_to_rpc_error);
+                    }
+                    Ok(RpcValue::from(pdf))
+                }),
+                METH_QBE => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => QbeParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => QbeParams::default(),
+                    };
+                    let data = export_qbe(&app_state, event_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    if param.chunked {
+                        return begin_download(&app_state, event_id, data)
+                            .map_err(anyhow_to_rpc_error);
+                    }
+                    Ok(RpcValue::from(data))
+                }),
+                METH_DOWNLOAD_CHUNK => m.resolve(methods, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let download_id = param.first().map(|v| v.as_str()).unwrap_or_default();
+                    let offset = param.get(1).map(|v| v.as_int()).unwrap_or_default();
+                    download_chunk(&app_state, event_id, download_id, offset)
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_LABELS => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => LabelsParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => LabelsParams::default(),
+                    };
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    render_labels(&sql_api, stage_id, &param).await
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{anyhow, bail};
+use log::warn;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::RpcValue;
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventrunsnode::run_ids_by_si_id;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::rules::{apply_rules, send_status_changed};
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, issuer};
+
+const METH_PUNCH: &str = "punch";
+
+pub(crate) const EVENT_FINISH_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_PUNCH, Flags::None, AccessLevel::Write, "{i|n:siId,i|n:bib,s|n:timestamp,i|n:finishTimeMs}", "{i:runId,i:finishTimeMs,i|n:timeMs}", &[], "",
+    ),
+];
+
+/// Finish impulse from photocell or finish station, runner is identified by card or bib number
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FinishPunchParams {
+    #[serde(default)]
+    si_id: Option<i64>,
+    /// Competitor start number
+    #[serde(default)]
+    bib: Option<i64>,
+    /// RFC 3339 time of the impulse
+    #[serde(default)]
+    timestamp: Option<String>,
+    /// Finish time relative to the stage start, it takes precedence over `timestamp`
+    #[serde(default)]
+    finish_time_ms: Option<i64>,
+}
+impl_rpcvalue_conversions!(FinishPunchParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FinishPunchResult {
+    run_id: i64,
+    finish_time_ms: i64,
+    time_ms: Option<i64>,
+}
+impl_rpcvalue_conversions!(FinishPunchResult);
+
+async fn run_id_by_bib(sql_api: &EventSqlApi, stage_id: i64, bib: i64) -> anyhow::Result<Option<i64>> {
+    let result = sql_api.query("SELECT runs.id FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+        WHERE runs.stageId = :stageId AND competitors.startNumber = :bib AND runs.isRunning ORDER BY runs.id", Some(&record_from_slice(&[
+        ("stageId", stage_id.into()),
+        ("bib", bib.into()),
+    ]))).await?;
+    Ok(result.value(0, 0).and_then(|v| v.to_int()))
+}
+
+async fn finish_punch(sql_api: &EventSqlApi, stage_id: i64, param: FinishPunchParams, issuer: Option<String>) -> anyhow::Result<FinishPunchResult> {
+    let run_id = match (param.si_id, param.bib) {
+        (Some(si_id), _) => run_ids_by_si_id(sql_api, stage_id, si_id).await?.first().copied()
+            .ok_or_else(|| anyhow!("Card {si_id} is not assigned to any run in stage {stage_id}"))?,
+        (None, Some(bib)) => run_id_by_bib(sql_api, stage_id, bib).await?
+            .ok_or_else(|| anyhow!("Start number {bib} not found in stage {stage_id}"))?,
+        (None, None) => bail!("siId or bib is required"),
+    };
+    let finish_time_ms = match (param.finish_time_ms, &param.timestamp) {
+        (Some(finish_time_ms), _) => finish_time_ms,
+        (None, timestamp) => {
+            let timestamp = match timestamp {
+                Some(timestamp) => chrono::DateTime::parse_from_rfc3339(timestamp)
+                    .map_err(|e| anyhow!("Invalid timestamp '{timestamp}': {e}"))?,
+                None => chrono::Local::now().fixed_offset(),
+            };
+            (timestamp - sql_api.stage_start(stage_id).await?).num_milliseconds()
+        }
+    };
+
+    let result = sql_api.query("SELECT startTimeMs, penaltyTimeMs FROM runs WHERE id = :id", Some(&record_from_slice(&[
+        ("id", run_id.into()),
+    ]))).await?;
+    let start_time_ms = result.value(0, 0).and_then(|v| v.to_int());
+    let penalty_time_ms = result.value(0, 1).and_then(|v| v.to_int()).unwrap_or_default();
+    let time_ms = match start_time_ms {
+        Some(start_time_ms) if finish_time_ms < start_time_ms => {
+            bail!("Run {run_id} finish time {finish_time_ms} is before its start time {start_time_ms}")
+        }
+        Some(start_time_ms) => Some(finish_time_ms - start_time_ms + penalty_time_ms),
+        None => {
+            warn!("Run {run_id} finished without start time");
+            None
+        }
+    };
+    let record = record_from_slice(&[
+        ("finishTimeMs", finish_time_ms.into()),
+        ("timeMs", time_ms.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("notFinish", false.into()),
+    ]);
+    if !sql_api.update_record_event("runs", run_id, &record, issuer).await? {
+        bail!("Run id: {run_id} not found");
+    }
+    Ok(FinishPunchResult { run_id, finish_time_ms, time_ms })
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_FINISH_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_FINISH_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_FINISH_NODE_METHODS).await;
+            match method {
+                METH_PUNCH => m.resolve(methods, async move || {
+                    let param = FinishPunchParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone())
+                        .with_correlation_id(trace.correlation_id())
+                        .with_priority_lane(true);
+                    let result = finish_punch(&sql_api, current_stage, param, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    match apply_rules(&sql_api, current_stage, Some(&[result.run_id]), issuer(&rq)).await {
+                        Ok(changes) => send_status_changed(&client_cmd_tx, event_id, &changes),
+                        Err(e) => warn!("Failed to evaluate rules of run {} in event {event_id}: {e}", result.run_id),
+                    }
+                    Ok(RpcValue::from(result))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
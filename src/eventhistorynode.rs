@@ -0,0 +1,161 @@
+use anyhow::{anyhow, bail};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::{Record, record_from_slice};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, from_rpcvalue, make_map};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::{EventSqlApi, HISTORY_DELETE, HISTORY_FROZEN_OVERRIDE, HISTORY_INSERT, HISTORY_UPDATE, ROW_VERSION};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, issuer};
+
+const METH_UNDO: &str = "undo";
+
+pub(crate) const EVENT_HISTORY_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_UNDO, Flags::None, AccessLevel::Write, "i:changeId", "{s:table,i:recordId,s:operation}", &[], "",
+    ),
+];
+
+struct HistoryEntry {
+    table: String,
+    record_id: i64,
+    operation: String,
+    old_values: Option<Record>,
+    new_values: Option<Record>,
+}
+
+fn record_field_names(record: &Option<Record>) -> Vec<String> {
+    record.iter()
+        .flat_map(|record| record.iter().map(|(key, _)| key.clone()))
+        .filter(|key| key != ROW_VERSION)
+        .collect()
+}
+
+async fn history_entry(sql_api: &EventSqlApi, change_id: i64) -> anyhow::Result<HistoryEntry> {
+    let result = sql_api.query("SELECT tableName, recordId, operation, oldValues, newValues FROM record_history WHERE id = :id", Some(&record_from_slice(&[
+        ("id", change_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Change id: {change_id} not found");
+    }
+    let string = |col| result.value(0, col).and_then(|v| v.as_str()).map(str::to_string);
+    let values = |col| -> anyhow::Result<Option<Record>> {
+        string(col)
+            .map(|cpon| {
+                let value = RpcValue::from_cpon(&cpon).map_err(|e| anyhow!("Invalid values of change id: {change_id}: {e}"))?;
+                from_rpcvalue::<Record>(&value).map_err(|e| anyhow!("Invalid values of change id: {change_id}: {e}"))
+            })
+            .transpose()
+    };
+    Ok(HistoryEntry {
+        table: string(0).unwrap_or_default(),
+        record_id: result.value(0, 1).and_then(|v| v.to_int()).unwrap_or_default(),
+        operation: string(2).unwrap_or_default(),
+        old_values: values(3)?,
+        new_values: values(4)?,
+    })
+}
+
+/// Later change of the same record, which would be overwritten by the undo.
+/// Update conflicts only with later changes of the same fields, insert and delete with any later change.
+/// Frozen results override entries only audit a change recorded by its own entry, they are skipped.
+async fn conflicting_change(sql_api: &EventSqlApi, change_id: i64, entry: &HistoryEntry) -> anyhow::Result<Option<i64>> {
+    let result = sql_api.query("SELECT id, oldValues, newValues FROM record_history \
+        WHERE tableName = :tableName AND recordId = :recordId AND id > :id AND operation != :auditOperation ORDER BY id", Some(&record_from_slice(&[
+        ("tableName", entry.table.as_str().into()),
+        ("recordId", entry.record_id.into()),
+        ("id", change_id.into()),
+        ("auditOperation", HISTORY_FROZEN_OVERRIDE.into()),
+    ]))).await?;
+    let fields = record_field_names(&entry.new_values);
+    for row in 0..result.row_count() {
+        let later_id = result.value(row, 0).and_then(|v| v.to_int()).unwrap_or_default();
+        if entry.operation != HISTORY_UPDATE {
+            return Ok(Some(later_id));
+        }
+        let later = |col| result.value(row, col)
+            .and_then(|v| v.as_str())
+            .and_then(|cpon| RpcValue::from_cpon(cpon).ok())
+            .and_then(|value| from_rpcvalue::<Record>(&value).ok());
+        let later_fields = [record_field_names(&later(1)), record_field_names(&later(2))].concat();
+        if later_fields.iter().any(|field| fields.contains(field)) {
+            return Ok(Some(later_id));
+        }
+    }
+    Ok(None)
+}
+
+/// Revert recorded change by the opposite operation, which is recorded in history too, so the undo can be undone as well
+async fn undo(sql_api: &EventSqlApi, change_id: i64, issuer: Option<String>) -> anyhow::Result<(HistoryEntry, &'static str)> {
+    let entry = history_entry(sql_api, change_id).await?;
+    if let Some(later_id) = conflicting_change(sql_api, change_id, &entry).await? {
+        bail!("Change id: {change_id} cannot be undone, {} id: {} was changed later by change id: {later_id}", entry.table, entry.record_id);
+    }
+    let operation = match entry.operation.as_str() {
+        HISTORY_INSERT => {
+            if !sql_api.delete_record_event(&entry.table, entry.record_id, issuer).await? {
+                bail!("{} id: {} does not exist any more", entry.table, entry.record_id);
+            }
+            HISTORY_DELETE
+        }
+        HISTORY_UPDATE => {
+            let old_values = entry.old_values.as_ref()
+                .ok_or_else(|| anyhow!("Change id: {change_id} has no old values"))?;
+            let record: Record = old_values.iter()
+                .filter(|(key, _)| key.as_str() != ROW_VERSION)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            if !sql_api.update_record_event(&entry.table, entry.record_id, &record, issuer).await? {
+                bail!("{} id: {} does not exist any more", entry.table, entry.record_id);
+            }
+            HISTORY_UPDATE
+        }
+        HISTORY_DELETE => {
+            let old_values = entry.old_values.as_ref()
+                .ok_or_else(|| anyhow!("Change id: {change_id} has no old values"))?;
+            let record: Record = old_values.iter()
+                .filter(|(key, _)| key.as_str() != "id")
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .chain([("id".to_string(), DbValue::from(entry.record_id))])
+                .collect();
+            sql_api.create_record_event(&entry.table, &record, issuer).await?;
+            HISTORY_INSERT
+        }
+        operation => bail!("Unknown operation '{operation}' of change id: {change_id}"),
+    };
+    Ok((entry, operation))
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_HISTORY_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_HISTORY_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_HISTORY_NODE_METHODS).await;
+            match method {
+                METH_UNDO => m.resolve(methods, async move || {
+                    let change_id = rq.param().unwrap_or_default().as_int();
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let (entry, operation) = undo(&sql_api, change_id, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(make_map!("table" => entry.table, "recordId" => entry.record_id, "operation" => operation).into())
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Maps of classes. Maps handed out at start are counted in `classdefs.mapsIssued`, class is short of maps,
+//! when its remaining maps do not cover remaining starters. With `stages.useAllMaps` set, vacants are given
+//! maps too, so they are counted as starters.
+
+use anyhow::{anyhow, bail};
+use log::warn;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::RpcMessage;
+
+use crate::{anyhow_to_rpc_error, issuer};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+
+const METH_COUNTS: &str = "counts";
+const METH_ISSUE: &str = "issue";
+
+const MAP_COUNT_RESULT: &str = "{i:classId,s:className,i|n:mapCount,i:mapsIssued,i|n:mapsRemaining,i:startersRemaining,b:short}";
+
+pub(crate) const EVENT_MAPS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_COUNTS, Flags::None, AccessLevel::Read, "i|n:stageId", "{b:useAllMaps,[{...}]:classes}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_ISSUE, Flags::None, AccessLevel::Write, "{i:classId,i|n:stageId,i|n:count}", MAP_COUNT_RESULT, &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MapCount {
+    pub class_id: i64,
+    pub class_name: String,
+    /// Maps printed for class, map count is not tracked if it is not set
+    pub map_count: Option<i64>,
+    pub maps_issued: i64,
+    pub maps_remaining: Option<i64>,
+    /// Starters, and vacants if all maps are used, who have not got map yet
+    pub starters_remaining: i64,
+    pub short: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MapCounts {
+    pub use_all_maps: bool,
+    pub classes: Vec<MapCount>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IssueParams {
+    class_id: i64,
+    /// Current stage if not set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    /// Number of issued maps, negative count returns maps, one map if not set
+    #[serde(default)]
+    count: Option<i64>,
+}
+impl_rpcvalue_conversions!(IssueParams);
+
+/// Map counts of classes in stage, ordered by class name, or of single class
+pub(crate) async fn load_map_counts(sql_api: &EventSqlApi, stage_id: i64, class_id: Option<i64>) -> anyhow::Result<MapCounts> {
+    let result = sql_api.query("SELECT useAllMaps FROM stages WHERE id = :stageId", Some(&record_from_slice(&[
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Stage id: {stage_id} not found");
+    }
+    let use_all_maps = result.value(0, 0).is_some_and(DbValue::to_bool);
+    let result = sql_api.query("SELECT classdefs.classId, classes.name, classdefs.mapCount, classdefs.mapsIssued, \
+        COALESCE(classdefs.vacantsBefore, 0) + COALESCE(classdefs.vacantsAfter, 0), \
+        (SELECT COUNT(*) FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+            WHERE competitors.classId = classdefs.classId AND runs.stageId = classdefs.stageId AND runs.isRunning) \
+        FROM classdefs JOIN classes ON classes.id = classdefs.classId \
+        WHERE classdefs.stageId = :stageId AND (:classId IS NULL OR classdefs.classId = :classId) \
+        ORDER BY classes.name",
+        Some(&record_from_slice(&[
+            ("stageId", stage_id.into()),
+            ("classId", class_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let classes = (0..result.row_count())
+        .map(|row| {
+            let map_count = int(row, 2);
+            let maps_issued = int(row, 3).unwrap_or_default();
+            let vacants = if use_all_maps { int(row, 4).unwrap_or_default() } else { 0 };
+            let starters_remaining = (int(row, 5).unwrap_or_default() + vacants - maps_issued).max(0);
+            let maps_remaining = map_count.map(|map_count| map_count - maps_issued);
+            MapCount {
+                class_id: int(row, 0).unwrap_or_default(),
+                class_name: result.value(row, 1).and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                map_count,
+                maps_issued,
+                maps_remaining,
+                starters_remaining,
+                short: maps_remaining.is_some_and(|maps_remaining| maps_remaining < starters_remaining),
+            }
+        })
+        .collect();
+    Ok(MapCounts { use_all_maps, classes })
+}
+
+async fn issue_maps(sql_api: &EventSqlApi, stage_id: i64, param: &IssueParams, issuer: Option<String>) -> anyhow::Result<MapCount> {
+    let count = param.count.unwrap_or(1);
+    let result = sql_api.query("SELECT id, mapsIssued FROM classdefs WHERE classId = :classId AND stageId = :stageId", Some(&record_from_slice(&[
+        ("classId", param.class_id.into()),
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    let int = |col| result.value(0, col).and_then(|v| v.to_int());
+    let Some(classdef_id) = int(0) else {
+        bail!("Class id: {} is not defined in stage: {stage_id}", param.class_id);
+    };
+    let maps_issued = int(1).unwrap_or_default() + count;
+    if maps_issued < 0 {
+        bail!("More maps returned than issued");
+    }
+    let record = record_from_slice(&[("mapsIssued", maps_issued.into())]);
+    sql_api.update_record_event("classdefs", classdef_id, &record, issuer).await?;
+    let map_count = load_map_counts(sql_api, stage_id, Some(param.class_id)).await?
+        .classes
+        .pop()
+        .ok_or_else(|| anyhow!("Class id: {} is not defined in stage: {stage_id}", param.class_id))?;
+    if map_count.short {
+        warn!("Class {} in stage {stage_id} is short of maps, remaining maps: {}, remaining starters: {}",
+            map_count.class_name, map_count.maps_remaining.unwrap_or_default(), map_count.starters_remaining);
+    }
+    Ok(map_count)
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_MAPS_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_MAPS_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_MAPS_NODE_METHODS).await;
+            match method {
+                METH_COUNTS => m.resolve(methods, async move || {
+                    let stage_id = match rq.param().filter(|param| param.is_int()).map(RpcValue::as_int) {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let map_counts = load_map_counts(&sql_api, stage_id, None).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&map_counts).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_ISSUE => m.resolve(methods, async move || {
+                    let param = IssueParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let map_count = issue_maps(&sql_api, stage_id, &param, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&map_count).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::{anyhow, bail};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::jobs::{JobProgress, spawn_job};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+use crate::webhooks::{self, WebhookEvent};
+use crate::{anyhow_to_rpc_error, issuer};
+
+const METH_SET_DUE: &str = "setDue";
+const METH_MARK_PAID: &str = "markPaid";
+const METH_IMPORT_BANK_CSV: &str = "importBankCsv";
+const METH_UNPAID: &str = "unpaid";
+
+pub(crate) const EVENT_PAYMENTS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_SET_DUE, Flags::None, AccessLevel::Write, "{i:competitorId,i:amountDue,s|n:reference}", "s:reference", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_MARK_PAID, Flags::None, AccessLevel::Write, "{i|n:competitorId,s|n:reference,i:amount}", "{i:competitorId,i:amountPaid,i|n:amountDue}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_IMPORT_BANK_CSV, Flags::None, AccessLevel::Write, "{s:csv,s|n:referenceColumn,s|n:amountColumn,s|n:delimiter,b|n:job}", "{[{i:competitorId,i:amount}]:matched,[{i:line,s:reference,i:amount}]:unmatched}|i:jobId", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_UNPAID, Flags::None, AccessLevel::Read, "", "[{i:competitorId,s:name,s:club,s:className,i:amountDue,i:amountPaid,s|n:reference}]", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetDueParams {
+    competitor_id: i64,
+    amount_due: i64,
+    /// Variable symbol of the payment, competitor id is used if not set
+    #[serde(default)]
+    reference: Option<String>,
+}
+impl_rpcvalue_conversions!(SetDueParams);
+
+/// Payment of competitor identified by id or by payment reference
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MarkPaidParams {
+    #[serde(default)]
+    competitor_id: Option<i64>,
+    #[serde(default)]
+    reference: Option<String>,
+    amount: i64,
+}
+impl_rpcvalue_conversions!(MarkPaidParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MarkPaidResult {
+    competitor_id: i64,
+    amount_paid: i64,
+    amount_due: Option<i64>,
+}
+impl_rpcvalue_conversions!(MarkPaidResult);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportBankCsvParams {
+    csv: String,
+    #[serde(default)]
+    reference_column: Option<String>,
+    #[serde(default)]
+    amount_column: Option<String>,
+    #[serde(default)]
+    delimiter: Option<String>,
+    /// Run import as background job, job id is returned and the result is reported by job progress signal
+    #[serde(default)]
+    job: bool,
+}
+impl_rpcvalue_conversions!(ImportBankCsvParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MatchedPayment {
+    competitor_id: i64,
+    amount: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UnmatchedPayment {
+    /// CSV line number, header is line 1
+    line: usize,
+    reference: String,
+    amount: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ImportBankCsvResult {
+    matched: Vec<MatchedPayment>,
+    unmatched: Vec<UnmatchedPayment>,
+}
+impl_rpcvalue_conversions!(ImportBankCsvResult);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UnpaidEntry {
+    competitor_id: i64,
+    name: String,
+    club: String,
+    class_name: String,
+    amount_due: i64,
+    amount_paid: i64,
+    reference: Option<String>,
+}
+
+/// Import job progress is reported after this number of lines
+const PROGRESS_LINES: usize = 50;
+
+/// Column names of variable symbol and amount used by common bank statement exports
+const DEFAULT_REFERENCE_COLUMNS: &[&str] = &["vs", "variable symbol", "variabilní symbol", "reference"];
+const DEFAULT_AMOUNT_COLUMNS: &[&str] = &["amount", "částka", "objem"];
+
+async fn set_due(sql_api: &EventSqlApi, param: SetDueParams, issuer: Option<String>) -> anyhow::Result<String> {
+    if param.amount_due < 0 {
+        bail!("Amount due cannot be negative");
+    }
+    let reference = param.reference.as_deref().map(normalize_reference).filter(|s| !s.is_empty())
+        .unwrap_or_else(|| param.competitor_id.to_string());
+    let result = sql_api.query("SELECT id FROM competitors WHERE paymentReference = :reference AND id != :id", Some(&record_from_slice(&[
+        ("reference", reference.as_str().into()),
+        ("id", param.competitor_id.into()),
+    ]))).await?;
+    if let Some(other_id) = result.value(0, 0).and_then(|v| v.to_int()) {
+        bail!("Payment reference {reference} is already used by competitor {other_id}");
+    }
+    let record = record_from_slice(&[
+        ("amountDue", param.amount_due.into()),
+        ("paymentReference", reference.as_str().into()),
+    ]);
+    if !sql_api.update_record_event("competitors", param.competitor_id, &record, issuer).await? {
+        bail!("Competitor id: {} not found", param.competitor_id);
+    }
+    Ok(reference)
+}
+
+/// Banks pad variable symbols with leading zeros
+fn normalize_reference(reference: &str) -> String {
+    reference.trim().trim_start_matches('0').to_string()
+}
+
+async fn competitor_by_reference(sql_api: &EventSqlApi, reference: &str) -> anyhow::Result<Option<i64>> {
+    let result = sql_api.query("SELECT id FROM competitors WHERE paymentReference = :reference", Some(&record_from_slice(&[
+        ("reference", normalize_reference(reference).into()),
+    ]))).await?;
+    Ok(result.value(0, 0).and_then(|v| v.to_int()))
+}
+
+/// Add payment to already paid amount of competitor
+async fn add_payment(sql_api: &EventSqlApi, competitor_id: i64, amount: i64, issuer: Option<String>) -> anyhow::Result<MarkPaidResult> {
+    let result = sql_api.query("SELECT amountPaid, amountDue FROM competitors WHERE id = :id", Some(&record_from_slice(&[
+        ("id", competitor_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Competitor id: {competitor_id} not found");
+    }
+    let amount_paid = result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default() + amount;
+    let amount_due = result.value(0, 1).and_then(|v| v.to_int());
+    let paid_at = if amount_due.is_some_and(|due| amount_paid >= due) {
+        DbValue::from(chrono::Local::now().fixed_offset())
+    } else {
+        DbValue::Null
+    };
+    let record = record_from_slice(&[
+        ("amountPaid", amount_paid.into()),
+        ("paidAt", paid_at),
+    ]);
+    sql_api.update_record_event("competitors", competitor_id, &record, issuer).await?;
+    Ok(MarkPaidResult { competitor_id, amount_paid, amount_due })
+}
+
+async fn mark_paid(sql_api: &EventSqlApi, param: MarkPaidParams, issuer: Option<String>) -> anyhow::Result<MarkPaidResult> {
+    let competitor_id = match (param.competitor_id, &param.reference) {
+        (Some(competitor_id), _) => competitor_id,
+        (None, Some(reference)) => competitor_by_reference(sql_api, reference).await?
+            .ok_or_else(|| anyhow!("No competitor with payment reference {reference}"))?,
+        (None, None) => bail!("competitorId or reference is required"),
+    };
+    add_payment(sql_api, competitor_id, param.amount, issuer).await
+}
+
+/// Split CSV line to fields, quoted fields can contain delimiter and doubled quotes
+fn csv_fields(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            c if c == delimiter && !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Parse bank statement amount like `1 250,50` or `1250.5` to the smallest currency unit
+fn parse_amount(s: &str) -> Option<i64> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace() && *c != '\u{a0}').collect();
+    let s = s.replace(',', ".");
+    let amount = s.parse::<f64>().ok()?;
+    Some((amount * 100.).round() as i64)
+}
+
+fn column_index(header: &[String], column: Option<&str>, defaults: &[&str]) -> anyhow::Result<usize> {
+    let names: Vec<String> = match column {
+        Some(column) => vec![column.to_lowercase()],
+        None => defaults.iter().map(|s| s.to_string()).collect(),
+    };
+    header.iter()
+        .position(|h| names.contains(&h.trim().to_lowercase()))
+        .ok_or_else(|| anyhow!("Column {names:?} not found in CSV header"))
+}
+
+/// Match incoming payments of bank statement to competitors by variable symbol, outgoing payments are skipped
+async fn import_bank_csv(sql_api: &EventSqlApi, param: ImportBankCsvParams, issuer: Option<String>, progress: Option<&JobProgress>) -> anyhow::Result<ImportBankCsvResult> {
+    let delimiter = param.delimiter.as_deref().and_then(|d| d.chars().next())
+        .unwrap_or_else(|| if param.csv.lines().next().unwrap_or_default().contains(';') { ';' } else { ',' });
+    let mut lines = param.csv.lines().enumerate();
+    let (_, header) = lines.next().ok_or_else(|| anyhow!("Empty CSV"))?;
+    let header = csv_fields(header.trim_start_matches('\u{feff}'), delimiter);
+    let reference_ix = column_index(&header, param.reference_column.as_deref(), DEFAULT_REFERENCE_COLUMNS)?;
+    let amount_ix = column_index(&header, param.amount_column.as_deref(), DEFAULT_AMOUNT_COLUMNS)?;
+
+    let line_count = param.csv.lines().count() as i64 - 1;
+    let mut result = ImportBankCsvResult::default();
+    for (ix, line) in lines {
+        if let Some(progress) = progress
+            && ix % PROGRESS_LINES == 0 {
+            progress.report(ix as i64, line_count);
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = csv_fields(line, delimiter);
+        let reference = fields.get(reference_ix).map(|s| normalize_reference(s)).unwrap_or_default();
+        let Some(amount) = fields.get(amount_ix).and_then(|s| parse_amount(s)).filter(|amount| *amount > 0) else {
+            continue;
+        };
+        match competitor_by_reference(sql_api, &reference).await? {
+            Some(competitor_id) if !reference.is_empty() => {
+                add_payment(sql_api, competitor_id, amount, issuer.clone()).await?;
+                result.matched.push(MatchedPayment { competitor_id, amount });
+            }
+            _ => result.unmatched.push(UnmatchedPayment { line: ix + 1, reference, amount }),
+        }
+    }
+    Ok(result)
+}
+
+async fn unpaid_entries(sql_api: &EventSqlApi) -> anyhow::Result<Vec<UnpaidEntry>> {
+    let result = sql_api.query("SELECT competitors.id, competitors.lastName || ' ' || competitors.firstName, competitors.club, classes.name, \
+        competitors.amountDue, competitors.amountPaid, competitors.paymentReference \
+        FROM competitors LEFT JOIN classes ON classes.id = competitors.classId \
+        WHERE competitors.amountDue > competitors.amountPaid ORDER BY classes.name, competitors.lastName", None).await?;
+    Ok((0..result.row_count())
+        .map(|row| {
+            let int = |col| result.value(row, col).and_then(|v| v.to_int()).unwrap_or_default();
+            let string = |col| result.value(row, col).and_then(|v| v.as_str()).map(str::to_string);
+            UnpaidEntry {
+                competitor_id: int(0),
+                name: string(1).unwrap_or_default(),
+                club: string(2).unwrap_or_default(),
+                class_name: string(3).unwrap_or_default(),
+                amount_due: int(4),
+                amount_paid: int(5),
+                reference: string(6),
+            }
+        })
+        .collect())
+}
+
+fn emit_import_finished(db_pool: async_sqlite::Pool, event_id: EventId, result: &ImportBankCsvResult) {
+    webhooks::emit(db_pool, WebhookEvent::ImportFinished, Some(event_id), serde_json::json!({
+        "kind": "bankCsv",
+        "matched": result.matched.len(),
+        "unmatched": result.unmatched.len(),
+    }));
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_PAYMENTS_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_PAYMENTS_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_PAYMENTS_NODE_METHODS).await;
+            match method {
+                METH_SET_DUE => m.resolve(methods, async move || {
+                    let param = SetDueParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    set_due(&sql_api, param, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_MARK_PAID => m.resolve(methods, async move || {
+                    let param = MarkPaidParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    mark_paid(&sql_api, param, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_IMPORT_BANK_CSV => m.resolve(methods, async move || {
+                    let param = ImportBankCsvParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let db_pool = app_state.db_pool.clone();
+                    if param.job {
+                        let issuer = issuer(&rq);
+                        let job_id = spawn_job(app_state, client_cmd_tx, METH_IMPORT_BANK_CSV, Some(event_id), move |progress| async move {
+                            import_bank_csv(&sql_api, param, issuer, Some(&progress)).await
+                                .inspect(|result| emit_import_finished(db_pool, event_id, result))
+                                .map(RpcValue::from)
+                        }).await.map_err(anyhow_to_rpc_error)?;
+                        return Ok(RpcValue::from(job_id));
+                    }
+                    import_bank_csv(&sql_api, param, issuer(&rq), None).await
+                        .inspect(|result| emit_import_finished(db_pool, event_id, result))
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_UNPAID => m.resolve(methods, async move || {
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let entries = unpaid_entries(&sql_api).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(to_rpcvalue(&entries).expect("serde should work"))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{anyhow, bail};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::RpcMessage;
+
+use crate::{anyhow_to_rpc_error, issuer, str_to_rpc_error};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::penalties::{PenaltyKind, RunPenalty, load_penalties, missed_control_penalty_ms, new_run_penalty, penalty_sum, set_run_penalty};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+
+const METH_LIST: &str = "list";
+const METH_ADD: &str = "add";
+const METH_REMOVE: &str = "remove";
+const METH_RECOMPUTE: &str = "recompute";
+
+const RUN_PENALTY_RESULT: &str = "{i:runId,i:penaltyTimeMs,i|n:timeMs}";
+
+pub(crate) const EVENT_PENALTIES_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_LIST, Flags::None, AccessLevel::Read, "{i|n:stageId,i|n:runId}|n",
+        "[{i:id,i:runId,s:kind,i|n:code,i:timeMs,s|n:reason,s|n:issuer,s:createdAt}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_ADD, Flags::None, AccessLevel::Write, "{i:runId,s:kind,[i]|n:codes,i|n:timeMs,s|n:reason}", RUN_PENALTY_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_REMOVE, Flags::None, AccessLevel::Write, "i:penaltyId", RUN_PENALTY_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_RECOMPUTE, Flags::None, AccessLevel::Write, "{i|n:stageId,b|n:dryRun}|i|n", "[{i:runId,i:penaltyTimeMs,i|n:timeMs}]", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListParams {
+    /// Current stage if neither stage nor run is set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    #[serde(default)]
+    run_id: Option<i64>,
+}
+impl_rpcvalue_conversions!(ListParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AddParams {
+    run_id: i64,
+    kind: PenaltyKind,
+    /// Missed controls, one penalty is added for every code
+    #[serde(default)]
+    codes: Vec<i64>,
+    /// Penalty of manual penalty or of every missed control, missed control penalty of event config is used if not set
+    #[serde(default)]
+    time_ms: Option<i64>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+impl_rpcvalue_conversions!(AddParams);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecomputeParams {
+    /// Current stage if not set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    /// Return runs, which penalty or time would change, without changing them
+    #[serde(default)]
+    dry_run: bool,
+}
+impl_rpcvalue_conversions!(RecomputeParams);
+
+async fn add_penalties(sql_api: &EventSqlApi, param: &AddParams, issuer: Option<String>) -> anyhow::Result<RunPenalty> {
+    let (codes, time_ms) = match param.kind {
+        PenaltyKind::MissedControl => {
+            if param.codes.is_empty() {
+                bail!("Missed control codes are required");
+            }
+            let time_ms = match param.time_ms {
+                Some(time_ms) => time_ms,
+                None => missed_control_penalty_ms(sql_api).await?
+                    .ok_or_else(|| anyhow!("Missed control penalty is not configured, penalty time is required"))?,
+            };
+            (param.codes.iter().copied().map(Some).collect::<Vec<_>>(), time_ms)
+        }
+        PenaltyKind::Manual => {
+            let time_ms = param.time_ms.ok_or_else(|| anyhow!("Penalty time is required"))?;
+            (vec![None], time_ms)
+        }
+    };
+    if time_ms <= 0 {
+        bail!("Penalty time must be positive");
+    }
+    // penalty records and run penalty time are changed together
+    sql_api.with_savepoint(async |sql_api| {
+        let penalty_time_ms = penalty_sum(sql_api, param.run_id).await? + time_ms * codes.len() as i64;
+        let run_penalty = set_run_penalty(sql_api, param.run_id, penalty_time_ms, issuer.clone()).await?;
+        let now = chrono::Local::now().fixed_offset();
+        for code in codes {
+            let record = record_from_slice(&[
+                ("runId", param.run_id.into()),
+                ("kind", param.kind.as_str().into()),
+                ("code", code.map(DbValue::from).unwrap_or(DbValue::Null)),
+                ("timeMs", time_ms.into()),
+                ("reason", param.reason.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+                ("issuer", issuer.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+                ("createdAt", now.into()),
+            ]);
+            sql_api.create_record_event("penalties", &record, issuer.clone()).await?;
+        }
+        Ok(run_penalty)
+    }).await
+}
+
+async fn remove_penalty(sql_api: &EventSqlApi, penalty_id: i64, issuer: Option<String>) -> anyhow::Result<RunPenalty> {
+    sql_api.with_savepoint(async |sql_api| {
+        let result = sql_api.query("SELECT runId, timeMs FROM penalties WHERE id = :id", Some(&record_from_slice(&[
+            ("id", penalty_id.into()),
+        ]))).await?;
+        let int = |col| result.value(0, col).and_then(|v| v.to_int());
+        let (Some(run_id), Some(time_ms)) = (int(0), int(1)) else {
+            bail!("Penalty id: {penalty_id} not found");
+        };
+        let penalty_time_ms = penalty_sum(sql_api, run_id).await? - time_ms;
+        let run_penalty = set_run_penalty(sql_api, run_id, penalty_time_ms, issuer.clone()).await?;
+        sql_api.delete_record_event("penalties", penalty_id, issuer).await?;
+        Ok(run_penalty)
+    }).await
+}
+
+/// Recompute penalty and time of runs with penalties, after their start or finish times were corrected
+async fn recompute_penalties(sql_api: &EventSqlApi, stage_id: i64, dry_run: bool, issuer: Option<String>) -> anyhow::Result<Vec<RunPenalty>> {
+    if dry_run {
+        let mut run_penalties = vec![];
+        for run_id in penalized_runs(sql_api, stage_id).await? {
+            let (run_penalty, changed) = new_run_penalty(sql_api, run_id, penalty_sum(sql_api, run_id).await?).await?;
+            if changed {
+                run_penalties.push(run_penalty);
+            }
+        }
+        return Ok(run_penalties);
+    }
+    sql_api.with_savepoint(async |sql_api| {
+        let run_ids = penalized_runs(sql_api, stage_id).await?;
+        let mut run_penalties = Vec::with_capacity(run_ids.len());
+        for run_id in run_ids {
+            let penalty_time_ms = penalty_sum(sql_api, run_id).await?;
+            run_penalties.push(set_run_penalty(sql_api, run_id, penalty_time_ms, issuer.clone()).await?);
+        }
+        Ok(run_penalties)
+    }).await
+}
+
+async fn penalized_runs(sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<Vec<i64>> {
+    let result = sql_api.query("SELECT DISTINCT penalties.runId FROM penalties JOIN runs ON runs.id = penalties.runId \
+        WHERE runs.stageId = :stageId ORDER BY penalties.runId", Some(&record_from_slice(&[
+        ("stageId", stage_id.into()),
+    ]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect())
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_PENALTIES_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_PENALTIES_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_PENALTIES_NODE_METHODS).await;
+            match method {
+                METH_LIST => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => ListParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => ListParams::default(),
+                    };
+                    let stage_id = match (param.stage_id, param.run_id) {
+                        (None, None) => Some(app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage),
+                        (stage_id, _) => stage_id,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let penalties = load_penalties(&sql_api, stage_id, param.run_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&penalties).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_ADD => m.resolve(methods, async move || {
+                    let param = AddParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let run_penalty = add_penalties(&sql_api, &param, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&run_penalty).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_REMOVE => m.resolve(methods, async move || {
+                    let Some(penalty_id) = rq.param().filter(|param| param.is_int()).map(RpcValue::as_int) else {
+                        return Err(str_to_rpc_error("Penalty id expected"));
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let run_penalty = remove_penalty(&sql_api, penalty_id, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&run_penalty).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_RECOMPUTE => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if param.is_map() => RecomputeParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        param => RecomputeParams { stage_id: param.filter(|p| p.is_int()).map(RpcValue::as_int), dry_run: false },
+                    };
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let run_penalties = recompute_penalties(&sql_api, stage_id, param.dry_run, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&run_penalties).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
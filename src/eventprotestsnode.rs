@@ -0,0 +1,252 @@
+//! Protests against results and jury decisions on them. Upheld protest adjusts run time or reinstates
+//! disqualified run in results, the protest record keeps who filed and who decided it and why.
+
+use anyhow::{anyhow, bail};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::to_rpcvalue;
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::RpcMessage;
+
+use crate::{anyhow_to_rpc_error, issuer};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+
+const METH_LIST: &str = "list";
+const METH_FILE: &str = "file";
+const METH_DECIDE: &str = "decide";
+
+const PROTEST_RESULT: &str = "{i:id,i:runId,i:stageId,s:className,s:name,s:reason,s|n:filedBy,s:filedAt,s:status,s|n:decision,s|n:decidedBy,s|n:decidedAt,i:timeAdjustmentMs,b:reinstate}";
+
+pub(crate) const EVENT_PROTESTS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_LIST, Flags::None, AccessLevel::Read, "{i|n:stageId,i|n:runId}|n", "[{...}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_FILE, Flags::None, AccessLevel::Write, "{i:runId,s:reason}", "i:protestId", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DECIDE, Flags::None, AccessLevel::Config, "{i:protestId,s:status,s|n:decision,i|n:timeAdjustmentMs,b|n:reinstate}", PROTEST_RESULT, &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ProtestStatus {
+    /// Waiting for jury decision
+    Filed,
+    /// Decision is applied to results
+    Upheld,
+    Rejected,
+}
+
+impl ProtestStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ProtestStatus::Filed => "filed",
+            ProtestStatus::Upheld => "upheld",
+            ProtestStatus::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Protest {
+    id: i64,
+    run_id: i64,
+    stage_id: i64,
+    class_name: String,
+    name: String,
+    reason: String,
+    filed_by: Option<String>,
+    filed_at: String,
+    status: String,
+    decision: Option<String>,
+    decided_by: Option<String>,
+    decided_at: Option<String>,
+    time_adjustment_ms: i64,
+    reinstate: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListParams {
+    /// Current stage if neither stage nor run is set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    #[serde(default)]
+    run_id: Option<i64>,
+}
+impl_rpcvalue_conversions!(ListParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileParams {
+    run_id: i64,
+    reason: String,
+}
+impl_rpcvalue_conversions!(FileParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DecideParams {
+    protest_id: i64,
+    status: ProtestStatus,
+    /// Jury reasoning
+    #[serde(default)]
+    decision: Option<String>,
+    /// Added to run time in results, negative value returns lost time
+    #[serde(default)]
+    time_adjustment_ms: Option<i64>,
+    /// Cancel disqualification of run in results
+    #[serde(default)]
+    reinstate: Option<bool>,
+}
+impl_rpcvalue_conversions!(DecideParams);
+
+async fn load_protests(sql_api: &EventSqlApi, stage_id: Option<i64>, run_id: Option<i64>, protest_id: Option<i64>) -> anyhow::Result<Vec<Protest>> {
+    let result = sql_api.query("SELECT protests.id, protests.runId, runs.stageId, classes.name, competitors.firstName, competitors.lastName, \
+        protests.reason, protests.filedBy, protests.filedAt, protests.status, protests.decision, protests.decidedBy, protests.decidedAt, \
+        protests.timeAdjustmentMs, protests.reinstate \
+        FROM protests JOIN runs ON runs.id = protests.runId JOIN competitors ON competitors.id = runs.competitorId \
+        LEFT JOIN classes ON classes.id = competitors.classId \
+        WHERE (:stageId IS NULL OR runs.stageId = :stageId) AND (:runId IS NULL OR protests.runId = :runId) \
+        AND (:protestId IS NULL OR protests.id = :protestId) ORDER BY protests.id",
+        Some(&record_from_slice(&[
+            ("stageId", stage_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("runId", run_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("protestId", protest_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int()).unwrap_or_default();
+    let string = |row, col| match result.value(row, col) {
+        Some(DbValue::String(s)) => Some(s.as_str().to_string()),
+        Some(DbValue::DateTime(dt)) => Some(dt.to_rfc3339()),
+        _ => None,
+    };
+    Ok((0..result.row_count())
+        .map(|row| Protest {
+            id: int(row, 0),
+            run_id: int(row, 1),
+            stage_id: int(row, 2),
+            class_name: string(row, 3).unwrap_or_default(),
+            name: format!("{} {}", string(row, 5).unwrap_or_default(), string(row, 4).unwrap_or_default()).trim().to_string(),
+            reason: string(row, 6).unwrap_or_default(),
+            filed_by: string(row, 7),
+            filed_at: string(row, 8).unwrap_or_default(),
+            status: string(row, 9).unwrap_or_default(),
+            decision: string(row, 10),
+            decided_by: string(row, 11),
+            decided_at: string(row, 12),
+            time_adjustment_ms: int(row, 13),
+            reinstate: result.value(row, 14).is_some_and(DbValue::to_bool),
+        })
+        .collect())
+}
+
+async fn file_protest(sql_api: &EventSqlApi, param: &FileParams, issuer: Option<String>) -> anyhow::Result<i64> {
+    if param.reason.trim().is_empty() {
+        bail!("Protest reason is required");
+    }
+    sql_api.with_savepoint(async |sql_api| {
+        let result = sql_api.query("SELECT id FROM runs WHERE id = :id", Some(&record_from_slice(&[
+            ("id", param.run_id.into()),
+        ]))).await?;
+        if result.row_count() == 0 {
+            bail!("Run id: {} not found", param.run_id);
+        }
+        let record = record_from_slice(&[
+            ("runId", param.run_id.into()),
+            ("reason", param.reason.trim().into()),
+            ("filedBy", issuer.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("filedAt", chrono::Local::now().fixed_offset().into()),
+            ("status", ProtestStatus::Filed.as_str().into()),
+        ]);
+        sql_api.create_record_event("protests", &record, issuer).await
+    }).await
+}
+
+/// Record jury decision, it can be changed by later decision, rejected protest never affects results
+async fn decide_protest(sql_api: &EventSqlApi, param: &DecideParams, issuer: Option<String>) -> anyhow::Result<Protest> {
+    let (time_adjustment_ms, reinstate) = match param.status {
+        ProtestStatus::Filed => bail!("Decision status must be upheld or rejected"),
+        ProtestStatus::Upheld => (param.time_adjustment_ms.unwrap_or_default(), param.reinstate.unwrap_or_default()),
+        ProtestStatus::Rejected => {
+            if param.time_adjustment_ms.is_some_and(|ms| ms != 0) || param.reinstate == Some(true) {
+                bail!("Rejected protest cannot adjust results");
+            }
+            (0, false)
+        }
+    };
+    let record = record_from_slice(&[
+        ("status", param.status.as_str().into()),
+        ("decision", param.decision.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("decidedBy", issuer.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("decidedAt", chrono::Local::now().fixed_offset().into()),
+        ("timeAdjustmentMs", time_adjustment_ms.into()),
+        ("reinstate", reinstate.into()),
+    ]);
+    if !sql_api.update_record_event("protests", param.protest_id, &record, issuer).await? {
+        bail!("Protest id: {} not found", param.protest_id);
+    }
+    load_protests(sql_api, None, None, Some(param.protest_id)).await?
+        .pop()
+        .ok_or_else(|| anyhow!("Protest id: {} not found", param.protest_id))
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_PROTESTS_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_PROTESTS_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_PROTESTS_NODE_METHODS).await;
+            match method {
+                METH_LIST => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => ListParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => ListParams::default(),
+                    };
+                    let stage_id = match (param.stage_id, param.run_id) {
+                        (None, None) => Some(app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage),
+                        (stage_id, _) => stage_id,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let protests = load_protests(&sql_api, stage_id, param.run_id, None).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&protests).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_FILE => m.resolve(methods, async move || {
+                    let param = FileParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let protest_id = file_protest(&sql_api, &param, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&protest_id).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_DECIDE => m.resolve(methods, async move || {
+                    let param = DecideParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let protest = decide_protest(&sql_api, &param, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&protest).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Reports for event organizers. Entry statistics count competitors and pending entries per class
+//! and per day, entry day of competitor is the day its record was inserted according to the record history.
+
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::RpcMessage;
+
+use crate::anyhow_to_rpc_error;
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventmapsnode::load_map_counts;
+use crate::evententriesnode::ENTRY_STATUS_PENDING;
+use crate::eventsqlapi::{EventSqlApi, HISTORY_INSERT};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+
+const METH_ENTRY_STATS: &str = "entryStats";
+
+/// Event config key of the last day of entries, event date is used if it is not set
+const CKEY_ENTRIES_CLOSE_DATE: &str = "entries.closeDate";
+const CKEY_EVENT_DATE: &str = "event.date";
+
+pub(crate) const EVENT_REPORTS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_ENTRY_STATS, Flags::None, AccessLevel::Read, "i|n:stageId",
+        "{i:stageId,b:useAllMaps,[{...}]:classes,[{s:date,i:entries,i:total}]:days,i:total,i:pending,i:undated,s|n:openedAt,s|n:closesAt,i:projectedTotal}", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClassEntryStats {
+    class_id: i64,
+    class_name: String,
+    /// Competitors of class running in stage
+    competitors: i64,
+    /// Entries waiting for approval
+    pending: i64,
+    max_entries: Option<i64>,
+    map_count: Option<i64>,
+    #[serde(skip)]
+    vacants: i64,
+    /// Maps needed for competitors, pending entries and vacants
+    maps_needed: i64,
+    /// Competitors and pending entries expected when entries close
+    projected: i64,
+    /// Maps missing for projected entries and vacants, when class map count is set
+    maps_missing: Option<i64>,
+    maps_issued: i64,
+    maps_remaining: Option<i64>,
+    /// Remaining maps do not cover remaining starters
+    maps_short: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DayEntryStats {
+    date: String,
+    entries: i64,
+    /// Entries up to and including this day
+    total: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryStats {
+    stage_id: i64,
+    use_all_maps: bool,
+    classes: Vec<ClassEntryStats>,
+    days: Vec<DayEntryStats>,
+    total: i64,
+    pending: i64,
+    /// Competitors without insert in record history, like imported before history was enabled
+    undated: i64,
+    /// Day of the first dated entry
+    opened_at: Option<String>,
+    closes_at: Option<String>,
+    projected_total: i64,
+}
+
+fn db_date(value: Option<&DbValue>) -> Option<NaiveDate> {
+    match value {
+        Some(DbValue::DateTime(dt)) => Some(dt.date_naive()),
+        Some(DbValue::String(s)) => s.as_str().get(..10).and_then(|date| date.parse::<NaiveDate>().ok()),
+        _ => None,
+    }
+}
+
+async fn config_date(sql_api: &EventSqlApi, ckey: &str) -> anyhow::Result<Option<NaiveDate>> {
+    let result = sql_api.query("SELECT cvalue FROM config WHERE ckey = :ckey", Some(&record_from_slice(&[
+        ("ckey", ckey.into()),
+    ]))).await?;
+    Ok(db_date(result.value(0, 0)))
+}
+
+/// Linear projection of `count` entries made during `elapsed_days` to `remaining_days` more days
+fn project(count: i64, elapsed_days: i64, remaining_days: i64) -> i64 {
+    if elapsed_days <= 0 {
+        return count;
+    }
+    count + (count as f64 * remaining_days as f64 / elapsed_days as f64).round() as i64
+}
+
+async fn entry_stats(sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<EntryStats> {
+    let result = sql_api.query("SELECT classes.id, classes.name, classes.maxEntries, classdefs.mapCount, \
+        COALESCE(classdefs.vacantsBefore, 0) + COALESCE(classdefs.vacantsAfter, 0), \
+        (SELECT COUNT(*) FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+            WHERE competitors.classId = classes.id AND runs.stageId = :stageId AND runs.isRunning), \
+        (SELECT COUNT(*) FROM pending_entries WHERE pending_entries.classId = classes.id AND pending_entries.status = :pending) \
+        FROM classes LEFT JOIN classdefs ON classdefs.classId = classes.id AND classdefs.stageId = :stageId \
+        ORDER BY classes.name",
+        Some(&record_from_slice(&[
+            ("stageId", stage_id.into()),
+            ("pending", ENTRY_STATUS_PENDING.into()),
+        ]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let mut classes = (0..result.row_count())
+        .map(|row| {
+            let competitors = int(row, 5).unwrap_or_default();
+            let pending = int(row, 6).unwrap_or_default();
+            let vacants = int(row, 4).unwrap_or_default();
+            ClassEntryStats {
+                class_id: int(row, 0).unwrap_or_default(),
+                class_name: result.value(row, 1).and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                competitors,
+                pending,
+                max_entries: int(row, 2),
+                map_count: int(row, 3),
+                vacants,
+                maps_needed: competitors + pending + vacants,
+                projected: competitors + pending,
+                maps_missing: None,
+                maps_issued: 0,
+                maps_remaining: None,
+                maps_short: false,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // accepted entries are counted by insert of their competitor, pending ones by submission
+    let mut day_entries = BTreeMap::<NaiveDate, i64>::new();
+    let result = sql_api.query("SELECT changedAt FROM record_history WHERE tableName = 'competitors' AND operation = :operation \
+        AND recordId IN (SELECT id FROM competitors)", Some(&record_from_slice(&[
+        ("operation", HISTORY_INSERT.into()),
+    ]))).await?;
+    for row in 0..result.row_count() {
+        if let Some(date) = db_date(result.value(row, 0)) {
+            *day_entries.entry(date).or_default() += 1;
+        }
+    }
+    let result = sql_api.query("SELECT submittedAt FROM pending_entries WHERE status = :pending", Some(&record_from_slice(&[
+        ("pending", ENTRY_STATUS_PENDING.into()),
+    ]))).await?;
+    for row in 0..result.row_count() {
+        if let Some(date) = db_date(result.value(row, 0)) {
+            *day_entries.entry(date).or_default() += 1;
+        }
+    }
+    let mut days_total = 0;
+    let days = day_entries.iter()
+        .map(|(date, entries)| {
+            days_total += entries;
+            DayEntryStats { date: date.to_string(), entries: *entries, total: days_total }
+        })
+        .collect::<Vec<_>>();
+
+    let result = sql_api.query("SELECT COUNT(*) FROM competitors", None).await?;
+    let competitors = result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default();
+    let pending = classes.iter().map(|class| class.pending).sum::<i64>();
+    let total = competitors + pending;
+
+    let opened_at = day_entries.keys().next().copied();
+    let closes_at = match config_date(sql_api, CKEY_ENTRIES_CLOSE_DATE).await? {
+        Some(date) => Some(date),
+        None => config_date(sql_api, CKEY_EVENT_DATE).await?,
+    };
+    let today = chrono::Local::now().date_naive();
+    let (elapsed_days, remaining_days) = match (opened_at, closes_at) {
+        (Some(opened_at), Some(closes_at)) => (
+            (today - opened_at).num_days() + 1,
+            (closes_at - today).num_days().max(0),
+        ),
+        _ => (0, 0),
+    };
+    let map_counts = load_map_counts(sql_api, stage_id, None).await?;
+    let class_map_counts = map_counts.classes.into_iter()
+        .map(|map_count| (map_count.class_id, map_count))
+        .collect::<BTreeMap<_, _>>();
+    for class in &mut classes {
+        if let Some(map_count) = class_map_counts.get(&class.class_id) {
+            class.maps_issued = map_count.maps_issued;
+            class.maps_remaining = map_count.maps_remaining;
+            class.maps_short = map_count.short;
+        }
+        class.projected = project(class.projected, elapsed_days, remaining_days);
+        class.maps_missing = class.map_count.map(|map_count| (class.projected + class.vacants - map_count).max(0));
+    }
+    Ok(EntryStats {
+        stage_id,
+        use_all_maps: map_counts.use_all_maps,
+        classes,
+        days,
+        total,
+        pending,
+        undated: (total - days_total).max(0),
+        opened_at: opened_at.map(|date| date.to_string()),
+        closes_at: closes_at.map(|date| date.to_string()),
+        projected_total: project(total, elapsed_days, remaining_days),
+    })
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_REPORTS_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_REPORTS_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_REPORTS_NODE_METHODS).await;
+            match method {
+                METH_ENTRY_STATS => m.resolve(methods, async move || {
+                    let stage_id = match rq.param().filter(|param| param.is_int()).map(RpcValue::as_int) {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let stats = entry_stats(&sql_api, stage_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&stats).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+use log::info;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::{anyhow_to_rpc_error, issuer, str_to_rpc_error};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::results::{ClubScoreParams, RunFilter, club_score, load_run_results};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, EventNotOpen, SharedAppState};
+use crate::views::{SUBSCRIPTION_TTL, EventViews, ResultRow};
+
+const METH_CLUB_SCORE: &str = "clubScore";
+const METH_CURRENT: &str = "current";
+const METH_EXPECTED_FINISHERS: &str = "expectedFinishers";
+const METH_FREEZE: &str = "freeze";
+const METH_FROZEN: &str = "frozen";
+const METH_SUBSCRIBE_DELTA: &str = "subscribeDelta";
+const METH_TOP: &str = "top";
+const METH_UNFREEZE: &str = "unfreeze";
+
+const DEFAULT_TOP_COUNT: i64 = 10;
+
+pub(crate) const EVENT_RESULTS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_CLUB_SCORE, Flags::None, AccessLevel::Read,
+        "{i|n:stageId,s|n:scheme,i|n:bestCount,[i]|n:points,[i]|n:classIds}|n",
+        "[{i:place,s:club,i:score,[{i:runId,s:name,s:className,i:place,i:points}]:runs}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_CURRENT, Flags::None, AccessLevel::Read, "i|n:classId",
+        "[{i:runId,i:classId,s:className,i|n:startNumber,s:name,s:registration,s:club,i|n:startTimeMs,i|n:finishTimeMs,i|n:timeMs,s:status,i|n:place,i:adjustmentMs,b:reinstated}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_EXPECTED_FINISHERS, Flags::None, AccessLevel::Read, "",
+        "[{i:runId,i:classId,s:className,i|n:startNumber,s:name,s:club,i:startTimeMs,i:runningTimeMs,i|n:expectedFinishMs}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_FREEZE, Flags::None, AccessLevel::Write, "{i|n:classId,i|n:stageId}|n", "[i:classId]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_FROZEN, Flags::None, AccessLevel::Read, "i|n:stageId", "[{i:classId,s:className,s:frozenAt}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SUBSCRIBE_DELTA, Flags::None, AccessLevel::Read, "i:classId", "{i:ttlSec,[{...}]:results}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_TOP, Flags::None, AccessLevel::Read, "[i:classId,i|n:count]", "[{i:place,s:name,s:club,i:timeMs,b:recent}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_UNFREEZE, Flags::None, AccessLevel::Config, "{i|n:classId,i|n:stageId}|n", "[i:classId]", &[], "",
+    ),
+];
+
+/// Official results of one class or of all classes in stage
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FreezeParams {
+    /// All classes of stage if not set
+    #[serde(default)]
+    class_id: Option<i64>,
+    /// Current stage if not set
+    #[serde(default)]
+    stage_id: Option<i64>,
+}
+impl_rpcvalue_conversions!(FreezeParams);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FrozenClass {
+    class_id: i64,
+    class_name: String,
+    frozen_at: String,
+}
+
+/// Freeze or unfreeze results of classes, freezing marks the moment of official results in `resultsPrintTS`.
+/// Returns ids of classes, which were changed.
+async fn set_results_frozen(sql_api: &EventSqlApi, stage_id: i64, class_id: Option<i64>, frozen: bool, issuer: Option<String>) -> anyhow::Result<Vec<i64>> {
+    let result = sql_api.query("SELECT id, classId, resultsFrozen FROM classdefs \
+        WHERE stageId = :stageId AND (:classId IS NULL OR classId = :classId) ORDER BY classId",
+        Some(&record_from_slice(&[
+            ("stageId", stage_id.into()),
+            ("classId", class_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ]))).await?;
+    if let Some(class_id) = class_id && result.row_count() == 0 {
+        bail!("Class id: {class_id} is not defined in stage {stage_id}");
+    }
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int()).unwrap_or_default();
+    let changed: Vec<(i64, i64)> = (0..result.row_count())
+        .filter(|row| result.value(*row, 2).is_some_and(DbValue::to_bool) != frozen)
+        .map(|row| (int(row, 0), int(row, 1)))
+        .collect();
+    let now = chrono::Local::now().fixed_offset();
+    for (classdef_id, _) in &changed {
+        let record = if frozen {
+            record_from_slice(&[("resultsFrozen", true.into()), ("resultsPrintTS", now.into())])
+        } else {
+            record_from_slice(&[("resultsFrozen", false.into())])
+        };
+        sql_api.update_record_event("classdefs", *classdef_id, &record, issuer.clone()).await?;
+    }
+    let class_ids: Vec<i64> = changed.iter().map(|(_, class_id)| *class_id).collect();
+    if !class_ids.is_empty() {
+        info!("Results of classes {class_ids:?} in stage {stage_id} {} by {}", if frozen { "frozen" } else { "unfrozen" }, issuer.unwrap_or_default());
+    }
+    Ok(class_ids)
+}
+
+async fn frozen_classes(sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<Vec<FrozenClass>> {
+    let result = sql_api.query("SELECT classdefs.classId, classes.name, classdefs.resultsPrintTS FROM classdefs \
+        LEFT JOIN classes ON classes.id = classdefs.classId \
+        WHERE classdefs.stageId = :stageId AND classdefs.resultsFrozen ORDER BY classes.name",
+        Some(&record_from_slice(&[("stageId", stage_id.into())]))).await?;
+    let string = |row, col| match result.value(row, col) {
+        Some(DbValue::String(s)) => s.as_str().to_string(),
+        Some(DbValue::DateTime(dt)) => dt.to_rfc3339(),
+        _ => String::new(),
+    };
+    Ok((0..result.row_count())
+        .map(|row| FrozenClass {
+            class_id: result.value(row, 0).and_then(|v| v.to_int()).unwrap_or_default(),
+            class_name: string(row, 1),
+            frozen_at: string(row, 2),
+        })
+        .collect())
+}
+
+fn event_views(app_state: &SharedAppState, event_id: EventId) -> anyhow::Result<Arc<EventViews>> {
+    app_state.with_open_event(event_id, |e| e.views.clone())
+        .ok_or_else(|| anyhow::Error::new(EventNotOpen { event_id, exists: true }))
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_RESULTS_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_RESULTS_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_RESULTS_NODE_METHODS).await;
+            match method {
+                METH_CLUB_SCORE => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => ClubScoreParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => ClubScoreParams::default(),
+                    };
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let views = event_views(&app_state, event_id).map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let results = if stage_id == current_stage {
+                        views.results(&sql_api, stage_id, None).await
+                    } else {
+                        load_run_results(&sql_api, stage_id, RunFilter::Stage).await
+                    }.map_err(anyhow_to_rpc_error)?;
+                    let scores = club_score(&results, &param)
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&scores).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_CURRENT => m.resolve(methods, async move || {
+                    let class_id = rq.param().filter(|param| param.is_int()).map(RpcValue::as_int);
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let views = event_views(&app_state, event_id).map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let results = views.results(&sql_api, current_stage, class_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    let rows: Vec<ResultRow> = results.iter().map(ResultRow::from).collect();
+                    to_rpcvalue(&rows).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_EXPECTED_FINISHERS => m.resolve(methods, async move || {
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let views = event_views(&app_state, event_id).map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let finishers = views.expected_finishers(&sql_api, current_stage).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&finishers).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_SUBSCRIBE_DELTA => m.resolve(methods, async move || {
+                    let Some(class_id) = rq.param().filter(|param| param.is_int()).map(RpcValue::as_int) else {
+                        return Err(str_to_rpc_error("Class id expected"));
+                    };
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let views = event_views(&app_state, event_id).map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let results = views.subscribe_delta(&sql_api, current_stage, class_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    let rows: Vec<ResultRow> = results.iter().map(ResultRow::from).collect();
+                    Ok(RpcValue::from(make_map!(
+                        "ttlSec" => SUBSCRIPTION_TTL.as_secs() as i64,
+                        "results" => to_rpcvalue(&rows).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))?,
+                    )))
+                }),
+                METH_TOP => m.resolve(methods, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let Some(class_id) = param.first().filter(|v| v.is_int()).map(|v| v.as_int()) else {
+                        return Err(str_to_rpc_error("Class id expected"));
+                    };
+                    let count = param.get(1).filter(|v| v.is_int()).map(|v| v.as_int()).unwrap_or(DEFAULT_TOP_COUNT);
+                    if count <= 0 {
+                        return Err(str_to_rpc_error("Count must be positive"));
+                    }
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let views = event_views(&app_state, event_id).map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let top = views.top(&sql_api, current_stage, class_id, count as usize).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&top).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_FREEZE | METH_UNFREEZE => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => FreezeParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => FreezeParams::default(),
+                    };
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let frozen = rq.method() == Some(METH_FREEZE);
+                    let sql_api = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let class_ids = set_results_frozen(&sql_api, stage_id, param.class_id, frozen, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    if frozen {
+                        for class_id in &class_ids {
+                            app_state.notify_results_final(event_id, stage_id, *class_id);
+                        }
+                    }
+                    to_rpcvalue(&class_ids).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_FROZEN => m.resolve(methods, async move || {
+                    let stage_id = match rq.param().filter(|param| param.is_int()).map(RpcValue::as_int) {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let frozen = frozen_classes(&sql_api, stage_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&frozen).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{anyhow, bail};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::RpcMessage;
+
+use crate::{anyhow_to_rpc_error, issuer};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::rules::{apply_rules, load_rules_config, send_status_changed};
+use crate::state::{EventId, SharedAppState};
+
+const METH_CONFIG: &str = "config";
+const METH_EVALUATE: &str = "evaluate";
+const METH_SET_MAX_TIME: &str = "setMaxTime";
+
+pub(crate) const EVENT_RULES_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_CONFIG, Flags::None, AccessLevel::Read, "", "{b:enabled,i|n:maxTimeMin,i:notFinishGraceMin}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_EVALUATE, Flags::None, AccessLevel::Write, "i|n:stageId", "[{i:runId,b:overTime,b:notFinish}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SET_MAX_TIME, Flags::None, AccessLevel::Write, "{i:classId,i|n:stageId,i|n:maxTimeMin}", "[{i:runId,b:overTime,b:notFinish}]", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetMaxTimeParams {
+    class_id: i64,
+    /// Current stage if not set
+    #[serde(default)]
+    stage_id: Option<i64>,
+    /// Maximum time is removed if not set
+    #[serde(default)]
+    max_time_min: Option<i64>,
+}
+impl_rpcvalue_conversions!(SetMaxTimeParams);
+
+async fn set_max_time(sql_api: &EventSqlApi, stage_id: i64, param: &SetMaxTimeParams) -> anyhow::Result<()> {
+    if param.max_time_min.is_some_and(|min| min <= 0) {
+        bail!("Maximum time must be positive");
+    }
+    let result = sql_api.exec("UPDATE classdefs SET maxTimeMin = :maxTimeMin WHERE classId = :classId AND stageId = :stageId",
+        Some(&record_from_slice(&[
+            ("maxTimeMin", param.max_time_min.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("classId", param.class_id.into()),
+            ("stageId", stage_id.into()),
+        ]))).await?;
+    if result.rows_affected == 0 {
+        bail!("Class id: {} is not defined in stage {stage_id}", param.class_id);
+    }
+    Ok(())
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_RULES_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_RULES_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_RULES_NODE_METHODS).await;
+            match method {
+                METH_CONFIG => m.resolve(methods, async move || {
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let config = load_rules_config(&sql_api).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&config).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_EVALUATE => m.resolve(methods, async move || {
+                    let stage_id = match rq.param().filter(|param| param.is_int()).map(RpcValue::as_int) {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let changes = apply_rules(&sql_api, stage_id, None, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    send_status_changed(&client_cmd_tx, event_id, &changes);
+                    to_rpcvalue(&changes).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_SET_MAX_TIME => m.resolve(methods, async move || {
+                    let param = SetMaxTimeParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let stage_id = match param.stage_id {
+                        Some(stage_id) => stage_id,
+                        None => app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage,
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    set_max_time(&sql_api, stage_id, &param).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    let changes = apply_rules(&sql_api, stage_id, None, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    send_status_changed(&client_cmd_tx, event_id, &changes);
+                    to_rpcvalue(&changes).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{anyhow, bail};
+use log::{error, warn};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, issuer, traffic};
+
+const METH_RECORD_CHECK: &str = "recordCheck";
+const METH_MARK_NOT_START: &str = "markNotStart";
+const METH_MARK_STARTED: &str = "markStarted";
+const METH_CARD_CONFLICTS: &str = "cardConflicts";
+const SIG_NOT_START_CHNG: &str = "notstartchng";
+
+pub(crate) const EVENT_RUNS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_RECORD_CHECK, Flags::None, AccessLevel::Write, "i:siId|{i:siId,i|n:runId,i|n:checkTimeMs}", "{i|n:runId,i:checkTimeMs,b:badCheck}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_MARK_NOT_START, Flags::None, AccessLevel::Write, "[i:runId]", "[i:runId]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_MARK_STARTED, Flags::None, AccessLevel::Write, "[i:runId]", "[i:runId]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_CARD_CONFLICTS, Flags::None, AccessLevel::Read, "i|n:stageId", "[{s:kind,i:siId,i:stageId,[i]:runIds}]", &[], "",
+    ),
+];
+
+/// Start gate client reports card checked in the start corridor,
+/// `runId` is the run of competitor expected at the gate, if the start official knows it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordCheckParams {
+    si_id: i64,
+    #[serde(default)]
+    run_id: Option<i64>,
+    /// Check time relative to the stage start, current time is used if not set
+    #[serde(default)]
+    check_time_ms: Option<i64>,
+}
+impl_rpcvalue_conversions!(RecordCheckParams);
+
+impl RecordCheckParams {
+    fn from_param(param: &RpcValue) -> anyhow::Result<Self> {
+        if param.is_int() {
+            return Ok(Self { si_id: param.as_int(), run_id: None, check_time_ms: None });
+        }
+        Self::try_from(param)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordCheckResult {
+    run_id: Option<i64>,
+    check_time_ms: i64,
+    bad_check: bool,
+}
+impl_rpcvalue_conversions!(RecordCheckResult);
+
+/// Ids of runs in stage using card `si_id`
+pub(crate) async fn run_ids_by_si_id(sql_api: &EventSqlApi, stage_id: i64, si_id: i64) -> anyhow::Result<Vec<i64>> {
+    let result = sql_api.query("SELECT id FROM runs WHERE stageId = :stageId AND siId = :siId AND isRunning ORDER BY id", Some(&record_from_slice(&[
+        ("stageId", stage_id.into()),
+        ("siId", si_id.into()),
+    ]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect())
+}
+
+async fn record_check(sql_api: &EventSqlApi, stage_id: i64, param: RecordCheckParams, issuer: Option<String>) -> anyhow::Result<RecordCheckResult> {
+    let now = chrono::Local::now().fixed_offset();
+    let check_time_ms = match param.check_time_ms {
+        Some(check_time_ms) => check_time_ms,
+        None => (now - sql_api.stage_start(stage_id).await?).num_milliseconds(),
+    };
+    let card_run_ids = run_ids_by_si_id(sql_api, stage_id, param.si_id).await?;
+    if card_run_ids.len() > 1 {
+        warn!("Card {} is assigned to more runs in stage {stage_id}: {card_run_ids:?}", param.si_id);
+    }
+    let (run_id, bad_check) = match (param.run_id, card_run_ids.first()) {
+        (Some(expected_run_id), _) => (expected_run_id, !card_run_ids.contains(&expected_run_id)),
+        (None, Some(card_run_id)) => (*card_run_id, false),
+        (None, None) => bail!("Card {} is not assigned to any run in stage {stage_id}", param.si_id),
+    };
+    if bad_check {
+        warn!("Bad check, card {} does not belong to run {run_id}", param.si_id);
+    }
+    // single statement update, check fields are never written partially
+    let record = record_from_slice(&[
+        ("corridorTime", now.into()),
+        ("checkTimeMs", check_time_ms.into()),
+        ("badCheck", bad_check.into()),
+    ]);
+    if !sql_api.update_record_event("runs", run_id, &record, issuer).await? {
+        bail!("Run id: {run_id} not found");
+    }
+    Ok(RecordCheckResult { run_id: Some(run_id), check_time_ms, bad_check })
+}
+
+/// Set `notStart` flag of runs, returns ids of runs found.
+///
+/// Every run change is published as record change, so speaker and results clients
+/// drop or restore the runner in the expected finishers and recalculate results.
+async fn mark_not_start(sql_api: &EventSqlApi, run_ids: &[i64], not_start: bool, issuer: Option<String>) -> anyhow::Result<Vec<i64>> {
+    let record = record_from_slice(&[("notStart", not_start.into())]);
+    let mut updated = Vec::with_capacity(run_ids.len());
+    for run_id in run_ids {
+        if sql_api.update_record_event("runs", *run_id, &record, issuer.clone()).await? {
+            updated.push(*run_id);
+        } else {
+            warn!("Run id: {run_id} not found");
+        }
+    }
+    Ok(updated)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum CardConflictKind {
+    /// Card is assigned to more than one running run in the stage
+    Duplicate,
+    /// Card was lent to other competitor in some previous stage and it was not returned yet
+    LentNotReturned,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CardConflict {
+    kind: CardConflictKind,
+    si_id: i64,
+    stage_id: i64,
+    run_ids: Vec<i64>,
+}
+
+/// Find card assignments, which would fail at readout, all stages are checked if `stage_id` is not set
+async fn card_conflicts(sql_api: &EventSqlApi, stage_id: Option<i64>) -> anyhow::Result<Vec<CardConflict>> {
+    let stage_param = record_from_slice(&[("stageId", stage_id.map(Into::into).unwrap_or(DbValue::Null))]);
+    let mut conflicts: Vec<CardConflict> = vec![];
+
+    let result = sql_api.query("SELECT runs.stageId, runs.siId, runs.id FROM runs \
+        WHERE runs.isRunning AND runs.siId > 0 AND (:stageId IS NULL OR runs.stageId = :stageId) \
+        AND EXISTS (SELECT 1 FROM runs AS other WHERE other.stageId = runs.stageId AND other.siId = runs.siId AND other.isRunning AND other.id != runs.id) \
+        ORDER BY runs.stageId, runs.siId, runs.id", Some(&stage_param)).await?;
+    for row in 0..result.row_count() {
+        let int = |col| result.value(row, col).and_then(|v| v.to_int()).unwrap_or_default();
+        let (stage_id, si_id, run_id) = (int(0), int(1), int(2));
+        match conflicts.last_mut() {
+            Some(last) if last.stage_id == stage_id && last.si_id == si_id => last.run_ids.push(run_id),
+            _ => conflicts.push(CardConflict { kind: CardConflictKind::Duplicate, si_id, stage_id, run_ids: vec![run_id] }),
+        }
+    }
+
+    let result = sql_api.query("SELECT lent.siId, runs.stageId, lent.id, runs.id FROM runs AS lent \
+        INNER JOIN runs ON runs.siId = lent.siId AND runs.stageId > lent.stageId AND runs.competitorId IS NOT lent.competitorId \
+        WHERE lent.cardLent AND NOT lent.cardReturned AND lent.siId > 0 AND runs.isRunning \
+        AND (:stageId IS NULL OR runs.stageId = :stageId) \
+        ORDER BY runs.stageId, lent.siId, runs.id", Some(&stage_param)).await?;
+    for row in 0..result.row_count() {
+        let int = |col| result.value(row, col).and_then(|v| v.to_int()).unwrap_or_default();
+        conflicts.push(CardConflict {
+            kind: CardConflictKind::LentNotReturned,
+            si_id: int(0),
+            stage_id: int(1),
+            run_ids: vec![int(2), int(3)],
+        });
+    }
+    Ok(conflicts)
+}
+
+fn run_ids_from_param(param: &RpcValue) -> anyhow::Result<Vec<i64>> {
+    if param.is_int() {
+        return Ok(vec![param.as_int()]);
+    }
+    if !param.is_list() {
+        bail!("List of run ids expected, got: {}", param.to_cpon());
+    }
+    param.as_list().iter()
+        .map(|v| if v.is_int() { Ok(v.as_int()) } else { Err(anyhow!("Invalid run id: {}", v.to_cpon())) })
+        .collect()
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_RUNS_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_RUNS_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_RUNS_NODE_METHODS).await;
+            match method {
+                METH_RECORD_CHECK => m.resolve(methods, async move || {
+                    let param = RecordCheckParams::from_param(rq.param().unwrap_or_default())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    record_check(&sql_api, current_stage, param, issuer(&rq)).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_MARK_NOT_START | METH_MARK_STARTED => m.resolve(methods, async move || {
+                    let not_start = rq.method() == Some(METH_MARK_NOT_START);
+                    let run_ids = run_ids_from_param(rq.param().unwrap_or_default())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    let updated = mark_not_start(&sql_api, &run_ids, not_start, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    let updated: Vec<RpcValue> = updated.into_iter().map(RpcValue::from).collect();
+                    if !updated.is_empty() {
+                        let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/runs"), SIG_NOT_START_CHNG)
+                            .with_param(make_map!("runIds" => updated.clone(), "notStart" => not_start));
+                        traffic::record_signal(event_id, &signal);
+                        if let Err(e) = client_cmd_tx.send_message(signal) {
+                            error!("Failed to send event {event_id} {SIG_NOT_START_CHNG} signal: {e}");
+                        }
+                    }
+                    Ok(RpcValue::from(updated))
+                }),
+                METH_CARD_CONFLICTS => m.resolve(methods, async move || {
+                    let stage_id = rq.param().filter(|p| p.is_int()).map(RpcValue::as_int);
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let conflicts = card_conflicts(&sql_api, stage_id).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(to_rpcvalue(&conflicts).expect("serde should work"))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Secrets of events, like token of public entries or credentials of external services. They are stored
+//! in master DB, not in the event config table, which anyone with read access to the event can read
+//! by `config:get`, `config:list` or `sql:query`.
+
+use async_sqlite::Pool;
+use qxsql::QxSqlApi;
+use qxsql::sql::record_from_slice;
+
+use crate::appsqlapi::AppSqlApi;
+use crate::state::EventId;
+
+pub(crate) async fn event_secret(db: &Pool, event_id: EventId, skey: &str) -> anyhow::Result<Option<String>> {
+    let result = AppSqlApi::new_without_recchng(db.clone())
+        .query("SELECT svalue FROM event_secrets WHERE event_id = :eventId AND skey = :skey", Some(&record_from_slice(&[
+            ("eventId", event_id.into()),
+            ("skey", skey.into()),
+        ]))).await?;
+    Ok(result.value(0, 0).and_then(|v| v.as_str()).map(str::to_string))
+}
+
+/// Set or, if `svalue` is `None`, delete secret of event
+pub(crate) async fn set_event_secret(db: &Pool, event_id: EventId, skey: &str, svalue: Option<&str>) -> anyhow::Result<()> {
+    let qxsql = AppSqlApi::new_without_recchng(db.clone());
+    let mut params = record_from_slice(&[
+        ("eventId", event_id.into()),
+        ("skey", skey.into()),
+    ]);
+    match svalue {
+        Some(svalue) => {
+            params.insert("svalue".to_string(), svalue.into());
+            qxsql.exec("INSERT INTO event_secrets (event_id, skey, svalue) VALUES (:eventId, :skey, :svalue) \
+                ON CONFLICT (event_id, skey) DO UPDATE SET svalue = excluded.svalue", Some(&params)).await?;
+        }
+        None => {
+            qxsql.exec("DELETE FROM event_secrets WHERE event_id = :eventId AND skey = :skey", Some(&params)).await?;
+        }
+    }
+    Ok(())
+}
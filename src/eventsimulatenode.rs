@@ -0,0 +1,64 @@
+use anyhow::anyhow;
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::RpcMessage;
+
+use crate::{anyhow_to_rpc_error, issuer};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::simulate::{SimulateParams, start_simulation};
+use crate::state::{EventId, SharedAppState};
+
+const METH_START: &str = "start";
+const METH_STOP: &str = "stop";
+const METH_STATUS: &str = "status";
+
+pub(crate) const EVENT_SIMULATE_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_START, Flags::None, AccessLevel::Service, "{i:sourceEventId,f|n:speed,i|n:maxGapSec}", "{...}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_STOP, Flags::None, AccessLevel::Service, "", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_STATUS, Flags::None, AccessLevel::Read, "", "{...}|n", &[], "",
+    ),
+];
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_SIMULATE_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_SIMULATE_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_SIMULATE_NODE_METHODS).await;
+            match method {
+                METH_START => m.resolve(methods, async move || {
+                    let params = SimulateParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let status = start_simulation(&app_state, event_id, params, client_cmd_tx, issuer(&rq)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    to_rpcvalue(&status).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))
+                }),
+                METH_STOP => m.resolve(methods, async move || {
+                    Ok(app_state.simulations.stop(event_id).into())
+                }),
+                METH_STATUS => m.resolve(methods, async move || {
+                    match app_state.simulations.status(event_id) {
+                        Some(status) => to_rpcvalue(&status).map_err(|e| anyhow_to_rpc_error(anyhow!(e))),
+                        None => Ok(RpcValue::null()),
+                    }
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
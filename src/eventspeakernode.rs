@@ -0,0 +1,64 @@
+use anyhow::anyhow;
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::RpcMessage;
+
+use crate::{anyhow_to_rpc_error, str_to_rpc_error};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::rqtrace::RequestTrace;
+use crate::speaker::recent_prewarnings;
+use crate::state::{EventId, EventNotOpen, SharedAppState};
+use crate::views::SUBSCRIPTION_TTL;
+
+const METH_PREWARNING: &str = "prewarning";
+
+/// Number of recent punches returned, when prewarning is subscribed
+const RECENT_PREWARNING_COUNT: i64 = 10;
+
+pub(crate) const EVENT_SPEAKER_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_PREWARNING, Flags::None, AccessLevel::Read, "i:controlCode",
+        "{i:ttlSec,[{i:code,i:runId,i:classId,s:className,i|n:startNumber,s:name,s:club,i|n:runTimeMs,i|n:position,i|n:gapMs}]:recent}", &[], "",
+    ),
+];
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_SPEAKER_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_SPEAKER_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_SPEAKER_NODE_METHODS).await;
+            match method {
+                METH_PREWARNING => m.resolve(methods, async move || {
+                    let Some(code) = rq.param().filter(|param| param.is_int()).map(RpcValue::as_int) else {
+                        return Err(str_to_rpc_error("Control code expected"));
+                    };
+                    let current_stage = app_state.open_event_status(event_id).map_err(anyhow_to_rpc_error)?.current_stage;
+                    let views = app_state.with_open_event(event_id, |e| e.views.clone())
+                        .ok_or_else(|| anyhow_to_rpc_error(anyhow::Error::new(EventNotOpen { event_id, exists: true })))?;
+                    views.subscribe_prewarning(code);
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let recent = recent_prewarnings(&sql_api, current_stage, code, RECENT_PREWARNING_COUNT).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(RpcValue::from(make_map!(
+                        "ttlSec" => SUBSCRIPTION_TTL.as_secs() as i64,
+                        "recent" => to_rpcvalue(&recent).map_err(|e| anyhow_to_rpc_error(anyhow!(e)))?,
+                    )))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
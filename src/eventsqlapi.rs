@@ -1,19 +1,173 @@
 use anyhow::anyhow;
 use async_sqlite::Pool;
 use async_trait::async_trait;
-use qxsql::{QxSqlApiRecChng, RecDeleteParam, RecUpdateParam};
+use chrono::{DateTime, FixedOffset};
+use qxsql::{QxSqlApi, QxSqlApiRecChng, RecDeleteParam, RecUpdateParam};
 use qxsql::sql::{ExecResult, QueryResult, RecChng, RecInsertParam};
-use qxsql::sql::Record;
+use qxsql::sql::{Record, record_from_slice};
+use qxsql::DbValue;
 use shvclient::ClientCommandSender;
-use shvproto::{make_list, to_rpcvalue, from_rpcvalue};
-use crate::appsqlapi::AppSqlApi;
-use crate::state::remote_event_sql_path;
-use crate::{call_rpc_error_to_anyhow, state::{EventId, SharedAppState}};
+use shvrpc::rpcmessage::RpcErrorCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
+use shvproto::{RpcValue, make_list, make_map, to_rpcvalue, from_rpcvalue};
+use crate::appsqlapi::{self, AppSqlApi, ExecScriptParams, ExecScriptResult, ExplainResult, UpsertResult, is_valid_sql_identifier};
+use crate::eventdb::open_event_db_pool;
+use crate::eventsecrets;
+use crate::rqtrace::{CorrelationId, META_CORRELATION_ID};
+use crate::state::{EventNotOpen, MAX_BLOB_CHUNK_SIZE, event_db_file, remote_event_sql_path};
+use crate::rpccall::{CallError, call_rpc_method_with_meta};
+use crate::{global_config, state::{EventId, SharedAppState}};
+
+pub(crate) const HISTORY_INSERT: &str = "insert";
+pub(crate) const HISTORY_UPDATE: &str = "update";
+pub(crate) const HISTORY_DELETE: &str = "delete";
+/// Audit entry of change of run in class with frozen results
+pub(crate) const HISTORY_FROZEN_OVERRIDE: &str = "frozenOverride";
+
+/// Table is listed in `history_tables` config
+fn has_history(table: &str) -> bool {
+    global_config().history_tables.iter().any(|t| t == table)
+}
+
+pub(crate) const ROW_VERSION: &str = "rowVersion";
+/// Tables with `rowVersion` column, it is incremented by every update
+const ROW_VERSION_TABLES: &[&str] = &["competitors", "runs", "relays", "classes"];
+
+fn has_row_version(table: &str) -> bool {
+    ROW_VERSION_TABLES.contains(&table)
+}
+
+fn record_int(record: &Record, field: &str) -> Option<i64> {
+    record.iter().find(|(key, _)| key.as_str() == field).and_then(|(_, value)| value.to_int())
+}
+
+fn with_row_version(record: &Record, row_version: i64) -> Record {
+    let mut record = without_row_version(record);
+    record.insert(ROW_VERSION.to_string(), row_version.into());
+    record
+}
+
+fn without_row_version(record: &Record) -> Record {
+    record.iter()
+        .filter(|(key, _)| key.as_str() != ROW_VERSION)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// UPDATE statement, which increments row version and returns it, the version is checked against `expected`, if it is set
+fn versioned_update_query(table: &str, id: i64, record: &Record, expected: Option<i64>) -> anyhow::Result<(String, Record)> {
+    if !is_valid_sql_identifier(table) {
+        return Err(anyhow!("Invalid table name: {table}"));
+    }
+    if let Some((field, _)) = record.iter().find(|(field, _)| !is_valid_sql_identifier(field)) {
+        return Err(anyhow!("Invalid field name: {field}"));
+    }
+    let fields = record.iter().map(|(key, _)| key.as_str()).filter(|key| *key != ROW_VERSION);
+    let assignments = fields
+        .map(|field| format!("{field} = :{field}"))
+        .chain([format!("{ROW_VERSION} = {ROW_VERSION} + 1")])
+        .collect::<Vec<_>>()
+        .join(", ");
+    let params = record.iter()
+        .filter(|(key, _)| key.as_str() != ROW_VERSION)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .chain([
+            ("versioned_row_id".to_string(), DbValue::from(id)),
+            ("versioned_row_version".to_string(), expected.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ])
+        .collect();
+    let query = format!("UPDATE {table} SET {assignments} WHERE id = :versioned_row_id \
+        AND (:versioned_row_version IS NULL OR {ROW_VERSION} = :versioned_row_version) RETURNING {ROW_VERSION}");
+    Ok((query, params))
+}
+
+/// Record was updated by somebody else since the client has read it
+#[derive(Debug)]
+pub(crate) struct RowVersionConflict {
+    pub table: String,
+    pub id: i64,
+    pub expected: i64,
+    pub current: Record,
+}
+
+impl std::fmt::Display for RowVersionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let current = to_rpcvalue(&self.current).map(|v| v.to_cpon()).unwrap_or_default();
+        write!(f, "Conflict: {} id: {} was changed, expected {ROW_VERSION}: {}, current record: {current}", self.table, self.id, self.expected)
+    }
+}
+
+impl std::error::Error for RowVersionConflict {}
+
+/// Table is listed in `soft_delete_tables` config
+pub(crate) fn is_soft_delete_table(table: &str) -> bool {
+    global_config().soft_delete_tables.iter().any(|t| t == table)
+}
+
+/// Tables written by card readout and punch ingestion, their writes should not wait for report queries
+pub(crate) const PRIORITY_TABLES: &[&str] = &["punches", "cards", "runs"];
+
+pub(crate) fn is_priority_table(table: &str) -> bool {
+    PRIORITY_TABLES.contains(&table)
+}
+
+/// Methods safe to retry after timeout, they only read the event DB
+fn is_idempotent_sql_method(method: &str) -> bool {
+    matches!(method, "query" | "read")
+}
+
+/// Remote event DB service cannot be reached, it is not an error reported by the service
+fn is_unavailable_error(err: &CallError) -> bool {
+    match err {
+        CallError::Transport(_) => true,
+        CallError::Rpc(err) => matches!(err.code, RpcErrorCode::MethodNotFound),
+    }
+}
+
+/// Fails proxied calls fast, when remote event DB service stops responding
+#[derive(Debug, Default)]
+pub(crate) struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn is_open(&self) -> bool {
+        self.open_until.map(|t| Instant::now() < t).unwrap_or(false)
+    }
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+    /// Returns true if the breaker was opened by this failure
+    pub fn record_failure(&mut self, threshold: u32, cooldown: Duration) -> bool {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= threshold.max(1) {
+            self.consecutive_failures = 0;
+            self.open_until = Some(Instant::now() + cooldown);
+            return true;
+        }
+        false
+    }
+}
+
+/// Connection of local event savepoint and records changed in it, they are notified when the outermost savepoint is released
+struct SavepointScope {
+    db: Pool,
+    depth: usize,
+    changed_records: Arc<Mutex<Vec<(String, i64)>>>,
+}
 
 pub struct EventSqlApi {
     event_id: EventId,
     app_state: SharedAppState,
     rpc_client: ClientCommandSender,
+    correlation_id: Option<CorrelationId>,
+    priority_lane: bool,
+    frozen_override: bool,
+    savepoint: Option<SavepointScope>,
 }
 
 impl EventSqlApi {
@@ -22,19 +176,169 @@ impl EventSqlApi {
             event_id,
             app_state,
             rpc_client,
+            correlation_id: None,
+            priority_lane: false,
+            frozen_override: false,
+            savepoint: None,
+        }
+    }
+    pub fn with_correlation_id(mut self, correlation_id: CorrelationId) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+    /// Serve SQL of local event from its dedicated priority connection, remote event DB service has no lanes
+    pub fn with_priority_lane(mut self, priority_lane: bool) -> Self {
+        self.priority_lane = priority_lane;
+        self
+    }
+    /// Allow changes of runs in classes with frozen results, every such change is audited in record history
+    pub fn with_frozen_override(mut self, frozen_override: bool) -> Self {
+        self.frozen_override = frozen_override;
+        self
+    }
+    /// Change of run in class with frozen results fails, unless it is overridden.
+    /// Returns `true` if the frozen results are overridden by the change.
+    async fn check_frozen(&self, table: &str, id: i64) -> anyhow::Result<bool> {
+        if table != "runs" {
+            return Ok(false);
+        }
+        let result = self.query("SELECT classdefs.classId, classdefs.stageId FROM runs \
+            JOIN competitors ON competitors.id = runs.competitorId \
+            JOIN classdefs ON classdefs.classId = competitors.classId AND classdefs.stageId = runs.stageId \
+            WHERE runs.id = :id AND classdefs.resultsFrozen", Some(&record_from_slice(&[
+            ("id", id.into()),
+        ]))).await?;
+        if result.row_count() == 0 {
+            return Ok(false);
+        }
+        let int = |col| result.value(0, col).and_then(|v| v.to_int()).unwrap_or_default();
+        if !self.frozen_override {
+            return Err(anyhow!("Results of class id: {} in stage {} are frozen, runs id: {id} can be changed with frozen results override only", int(0), int(1)));
         }
+        warn!("Event id: {} frozen results of class id: {} in stage {} overridden by change of runs id: {id}", self.event_id, int(0), int(1));
+        Ok(true)
+    }
+    /// API is used for events, which were open already, so the event is reported as existing
+    fn not_open_error(&self) -> anyhow::Error {
+        anyhow::Error::new(EventNotOpen { event_id: self.event_id, exists: true })
+    }
+    async fn call_remote_sql(&self, method: &str, param: RpcValue) -> anyhow::Result<RpcValue> {
+        let policy = &global_config().remote_call;
+        let cid = self.correlation_id.map(|cid| cid.to_string()).unwrap_or_default();
+        let (remote_mount, circuit_breaker) = self.app_state.with_open_event(self.event_id, |e| (e.remote_mount.clone(), e.circuit_breaker.clone()))
+            .ok_or_else(|| self.not_open_error())?;
+        let remote_mount = remote_mount.ok_or_else(|| anyhow!("Event id: {} is not a remote event.", self.event_id))?;
+        let path = remote_event_sql_path(&remote_mount);
+        if !is_idempotent_sql_method(method) {
+            self.check_writable()?;
+        }
+        if circuit_breaker.lock().unwrap().is_open() {
+            return Err(anyhow!("Event id: {} DB service is not responding, call {path}:{method} rejected.", self.event_id));
+        }
+        let timeout = policy.timeout.to_std().unwrap_or(Duration::from_secs(10));
+        let attempts = if is_idempotent_sql_method(method) { policy.retries + 1 } else { 1 };
+        let meta: Vec<(&str, RpcValue)> = self.correlation_id.iter().map(|cid| (META_CORRELATION_ID, cid.to_rpcvalue())).collect();
+        let mut last_error = String::new();
+        for attempt in 1..=attempts {
+            debug!("[{cid}] proxy call --> {path}:{method} attempt: {attempt}/{attempts}");
+            let call = async {
+                Some(call_rpc_method_with_meta(&self.rpc_client, &path, method, Some(param.clone()), &meta).await)
+            };
+            let timer = async {
+                smol::Timer::after(timeout).await;
+                None
+            };
+            match smol::future::or(call, timer).await {
+                Some(Ok(result)) => {
+                    debug!("[{cid}] proxy call <-- {path}:{method} ok");
+                    circuit_breaker.lock().unwrap().record_success();
+                    return Ok(result);
+                }
+                Some(Err(e)) if is_unavailable_error(&e) => {
+                    warn!("[{cid}] proxy call {path}:{method} failed: {e}");
+                    last_error = format!("Call {path}:{method} failed: {e}");
+                }
+                Some(Err(e)) => {
+                    // error reported by the service itself, it is responding
+                    debug!("[{cid}] proxy call <-- {path}:{method} error: {e}");
+                    return Err(anyhow!(e));
+                }
+                None => {
+                    warn!("[{cid}] proxy call {path}:{method} timed out after {} msec", timeout.as_millis());
+                    last_error = format!("Call {path}:{method} timed out.");
+                }
+            }
+        }
+        let cooldown = policy.circuit_breaker_cooldown.to_std().unwrap_or_default();
+        if circuit_breaker.lock().unwrap().record_failure(policy.circuit_breaker_threshold, cooldown) {
+            error!("Event id: {} DB service is not responding, circuit breaker open for {} sec", self.event_id, cooldown.as_secs());
+        }
+        Err(anyhow!(last_error))
     }
     async fn local_event_db(&self) -> anyhow::Result<Option<Pool>> {
-        self.app_state.read().await.open_events.get(&self.event_id)
-            .map(|e| e.local_db.clone())
-            .ok_or_else(|| anyhow!("Event id: {} is not open.", self.event_id))
+        if let Some(savepoint) = &self.savepoint {
+            return Ok(Some(savepoint.db.clone()));
+        }
+        self.app_state.with_open_event(self.event_id, |e| {
+            if e.local_db.is_some() {
+                *e.sql_called_at.lock().unwrap() = Instant::now();
+            }
+            if self.priority_lane { e.priority_db.clone().or_else(|| e.local_db.clone()) } else { e.local_db.clone() }
+        })
+            .ok_or_else(|| self.not_open_error())
+    }
+    /// Event DBs stored in `data_dir` are read-only, when disk space watchdog detects that the disk is almost full
+    fn check_writable(&self) -> anyhow::Result<()> {
+        let stored_in_data_dir = self.app_state.with_open_event(self.event_id, |e| e.local_db.is_some() || e.qxsqld_process.is_some())
+            .unwrap_or_default();
+        if stored_in_data_dir && self.app_state.disk_space.is_read_only() {
+            return Err(anyhow!("Event id: {} DB is read-only, there is not enough free disk space.", self.event_id));
+        }
+        Ok(())
     }
     async fn is_local_event_db(&self) -> anyhow::Result<bool> {
-        self.app_state.read().await.open_events.get(&self.event_id)
-            .map(|e| e.local_db.is_some())
-            .ok_or_else(|| anyhow!("Event id: {} is not open.", self.event_id))
+        self.app_state.with_open_event(self.event_id, |e| e.local_db.is_some())
+            .ok_or_else(|| self.not_open_error())
+    }
+    // Row versions, soft delete and change history need columns and tables added by event DB migrations,
+    // remote event DB is not migrated by qxeventd, so they are used for local event DB only.
+    async fn uses_history(&self, table: &str) -> anyhow::Result<bool> {
+        Ok(has_history(table) && self.is_local_event_db().await?)
+    }
+    async fn uses_row_version(&self, table: &str) -> anyhow::Result<bool> {
+        Ok(has_row_version(table) && self.is_local_event_db().await?)
+    }
+    async fn uses_soft_delete(&self, table: &str) -> anyhow::Result<bool> {
+        Ok(is_soft_delete_table(table) && self.is_local_event_db().await?)
+    }
+    /// Secret of event, it is kept in master DB out of reach of SQL API of the event
+    pub async fn secret(&self, skey: &str) -> anyhow::Result<Option<String>> {
+        eventsecrets::event_secret(&self.app_state.db_pool, self.event_id, skey).await
+    }
+    pub async fn set_secret(&self, skey: &str, svalue: Option<&str>) -> anyhow::Result<()> {
+        eventsecrets::set_event_secret(&self.app_state.db_pool, self.event_id, skey, svalue).await
+    }
+    /// Notify subscribers of event data, which are not fed by record change signals
+    pub fn notify_record_changed(&self, table: &str, id: i64) {
+        if let Some(savepoint) = &self.savepoint {
+            savepoint.changed_records.lock().unwrap().push((table.to_string(), id));
+            return;
+        }
+        self.app_state.notify_record_changed(self.event_id, table, id);
+        if table == "runs" {
+            self.app_state.notify_run_changed(self.event_id, id);
+        }
     }
     pub async fn create_record_event(&self, table: &str, record: &Record, issuer: Option<String>) -> anyhow::Result<i64> {
+        let id = self.create_record_event_impl(table, record, issuer.clone()).await?;
+        if self.uses_history(table).await? {
+            self.write_history(table, id, HISTORY_INSERT, None, Some(record), issuer).await;
+        }
+        self.notify_record_changed(table, id);
+        Ok(id)
+    }
+    async fn create_record_event_impl(&self, table: &str, record: &Record, issuer: Option<String>) -> anyhow::Result<i64> {
+        self.check_writable()?;
         if self.is_local_event_db().await? {
             return self.create_record_with_recchng(table, record, issuer).await;
         } else {
@@ -43,12 +347,150 @@ impl EventSqlApi {
                 record: record.clone(),
                 issuer,
             };
-            let id: i64 = self.rpc_client.call_rpc_method(remote_event_sql_path(self.event_id), "create", Some(to_rpcvalue(&param)?), None, None, None::<fn(f64)>).await
-                .map_err(call_rpc_error_to_anyhow)?;
+            let rpc_value = self.call_remote_sql("create", to_rpcvalue(&param)?).await?;
+            let id: i64 = from_rpcvalue(&rpc_value)?;
             Ok(id)
         }
     }
     pub async fn update_record_event(&self, table: &str, id: i64, record: &Record, issuer: Option<String>) -> anyhow::Result<bool> {
+        let frozen_override = self.check_frozen(table, id).await?;
+        self.update_record(table, id, record, None, issuer, frozen_override).await
+    }
+    /// Record of table with row version is updated only if its version is `expected_version`, if it is set
+    async fn update_record(&self, table: &str, id: i64, record: &Record, expected_version: Option<i64>, issuer: Option<String>, frozen_override: bool) -> anyhow::Result<bool> {
+        let with_history = self.uses_history(table).await?;
+        let audit_frozen_override = frozen_override && self.is_local_event_db().await?;
+        let old_values = if with_history || audit_frozen_override {
+            let fields: Vec<&str> = record.iter().map(|(key, _)| key.as_str()).collect();
+            self.record_values(table, id, &fields).await?
+        } else {
+            None
+        };
+        let versioned;
+        let (updated, record) = if self.uses_row_version(table).await? {
+            match self.update_versioned_record(table, id, record, expected_version).await? {
+                Some(row_version) => {
+                    versioned = with_row_version(record, row_version);
+                    (true, &versioned)
+                }
+                None => (false, record),
+            }
+        } else if has_row_version(table) {
+            versioned = without_row_version(record);
+            (self.update_record_event_impl(table, id, &versioned, issuer.clone()).await?, &versioned)
+        } else {
+            (self.update_record_event_impl(table, id, record, issuer.clone()).await?, record)
+        };
+        if updated {
+            if let Some(old_values) = &old_values {
+                if with_history {
+                    self.write_history(table, id, HISTORY_UPDATE, Some(old_values), Some(record), issuer.clone()).await;
+                }
+                if audit_frozen_override {
+                    self.write_history(table, id, HISTORY_FROZEN_OVERRIDE, Some(old_values), Some(record), issuer).await;
+                }
+            }
+            self.notify_record_changed(table, id);
+        }
+        Ok(updated)
+    }
+    /// Update record on behalf of client, which has to pass `rowVersion` of the record it has edited,
+    /// the update fails with [`RowVersionConflict`] if somebody else has updated the record meanwhile.
+    pub async fn update_record_checked(&self, table: &str, id: i64, record: &Record, issuer: Option<String>) -> anyhow::Result<bool> {
+        let frozen_override = self.check_frozen(table, id).await?;
+        if !self.uses_row_version(table).await? {
+            return self.update_record(table, id, record, None, issuer, frozen_override).await;
+        }
+        let expected = record_int(record, ROW_VERSION)
+            .ok_or_else(|| anyhow!("Update of {table} id: {id} requires {ROW_VERSION} of the edited record"))?;
+        if self.update_record(table, id, record, Some(expected), issuer, frozen_override).await? {
+            return Ok(true);
+        }
+        let Some(current) = self.record_values(table, id, &[]).await? else {
+            return Ok(false);
+        };
+        Err(RowVersionConflict { table: table.to_string(), id, expected, current }.into())
+    }
+    /// Current values of record `fields`, all fields if `fields` is empty, `None` if the record does not exist
+    async fn record_values(&self, table: &str, id: i64, fields: &[&str]) -> anyhow::Result<Option<Record>> {
+        if !is_valid_sql_identifier(table) {
+            return Err(anyhow!("Invalid table name: {table}"));
+        }
+        let columns = if fields.is_empty() {
+            "*".to_string()
+        } else if let Some(field) = fields.iter().find(|field| !is_valid_sql_identifier(field)) {
+            return Err(anyhow!("Invalid field name: {field}"));
+        } else {
+            fields.join(", ")
+        };
+        let result = self.query(&format!("SELECT {columns} FROM {table} WHERE id = :id"), Some(&record_from_slice(&[
+            ("id", id.into()),
+        ]))).await?;
+        if result.row_count() == 0 {
+            return Ok(None);
+        }
+        Ok(Some(result.fields.iter().enumerate()
+            .map(|(col, field)| (field.name.clone(), result.value(0, col).cloned().unwrap_or(DbValue::Null)))
+            .collect()))
+    }
+    /// History is best effort, failure to write it does not fail the change itself
+    async fn write_history(&self, table: &str, id: i64, operation: &str, old_values: Option<&Record>, new_values: Option<&Record>, issuer: Option<String>) {
+        let cpon = |values: Option<&Record>| values
+            .and_then(|values| to_rpcvalue(values).ok())
+            .map(|values| DbValue::from(values.to_cpon()))
+            .unwrap_or(DbValue::Null);
+        let result = self.exec("INSERT INTO record_history (tableName, recordId, operation, oldValues, newValues, issuer, changedAt) \
+            VALUES (:tableName, :recordId, :operation, :oldValues, :newValues, :issuer, :changedAt)", Some(&record_from_slice(&[
+            ("tableName", table.into()),
+            ("recordId", id.into()),
+            ("operation", operation.into()),
+            ("oldValues", cpon(old_values)),
+            ("newValues", cpon(new_values)),
+            ("issuer", issuer.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("changedAt", chrono::Local::now().fixed_offset().into()),
+        ]))).await;
+        if let Err(e) = result {
+            error!("Event id: {} failed to write history of {table} id: {id}: {e}", self.event_id);
+        }
+    }
+    /// Changes of record from the oldest one
+    pub async fn record_history(&self, table: &str, id: i64) -> anyhow::Result<Vec<RpcValue>> {
+        if !self.is_local_event_db().await? {
+            return Err(anyhow!("Event id: {} is remote, history is kept for local events only", self.event_id));
+        }
+        let result = self.query("SELECT operation, oldValues, newValues, issuer, changedAt, id FROM record_history \
+            WHERE tableName = :tableName AND recordId = :recordId ORDER BY id", Some(&record_from_slice(&[
+            ("tableName", table.into()),
+            ("recordId", id.into()),
+        ]))).await?;
+        let string = |row, col| match result.value(row, col) {
+            Some(DbValue::String(s)) => Some(s.as_str().to_string()),
+            Some(DbValue::DateTime(dt)) => Some(dt.to_rfc3339()),
+            _ => None,
+        };
+        let values = |row, col| string(row, col)
+            .and_then(|cpon| RpcValue::from_cpon(&cpon).ok())
+            .unwrap_or_else(RpcValue::null);
+        Ok((0..result.row_count())
+            .map(|row| make_map!(
+                "operation" => string(row, 0).unwrap_or_default(),
+                "oldValues" => values(row, 1),
+                "newValues" => values(row, 2),
+                "issuer" => string(row, 3).map(RpcValue::from).unwrap_or_else(RpcValue::null),
+                "changedAt" => string(row, 4).unwrap_or_default(),
+                "id" => result.value(row, 5).and_then(|v| v.to_int()).unwrap_or_default(),
+            ).into())
+            .collect())
+    }
+    /// Update record and increment its row version in one statement, the row is changed only if its version is equal to `expected`
+    /// or always if it is not set. Returns the new version or `None` if no row was changed.
+    async fn update_versioned_record(&self, table: &str, id: i64, record: &Record, expected: Option<i64>) -> anyhow::Result<Option<i64>> {
+        let (query, params) = versioned_update_query(table, id, record, expected)?;
+        let result = self.exec_returning(&query, Some(&params)).await?;
+        Ok(result.value(0, 0).and_then(|v| v.to_int()))
+    }
+    async fn update_record_event_impl(&self, table: &str, id: i64, record: &Record, issuer: Option<String>) -> anyhow::Result<bool> {
+        self.check_writable()?;
         if self.is_local_event_db().await? {
             return self.update_record_with_recchng(table, id, record, issuer).await;
         } else {
@@ -58,13 +500,221 @@ impl EventSqlApi {
                 record: record.clone(),
                 issuer,
             };
-            let updated: bool = self.rpc_client.call_rpc_method(remote_event_sql_path(self.event_id), "update", Some(to_rpcvalue(&param)?), None, None, None::<fn(f64)>).await
-                .map_err(call_rpc_error_to_anyhow)?;
+            let rpc_value = self.call_remote_sql("update", to_rpcvalue(&param)?).await?;
+            let updated: bool = from_rpcvalue(&rpc_value)?;
             Ok(updated)
         }
     }
-    #[allow(dead_code)]
+    /// Write assembled upload to blob field. Remote event DB service gets the blob by chunks of
+    /// [`MAX_BLOB_CHUNK_SIZE`], they are appended to the field, so no call exceeds broker frame size.
+    pub async fn write_blob(&self, table: &str, id: i64, field: &str, data: Vec<u8>, issuer: Option<String>) -> anyhow::Result<bool> {
+        if !is_valid_sql_identifier(table) || !is_valid_sql_identifier(field) {
+            return Err(anyhow!("Invalid table or field name: {table}.{field}"));
+        }
+        if self.is_local_event_db().await? {
+            return self.update_record_event(table, id, &record_from_slice(&[(field, DbValue::Blob(data))]), issuer).await;
+        }
+        let mut chunks = data.chunks(MAX_BLOB_CHUNK_SIZE);
+        let first = chunks.next().unwrap_or_default();
+        // record change, history and row version are made by the first chunk
+        if !self.update_record_event(table, id, &record_from_slice(&[(field, DbValue::Blob(first.to_vec()))]), issuer).await? {
+            return Ok(false);
+        }
+        // concatenation yields text, cast keeps its bytes
+        let append = format!("UPDATE {table} SET {field} = CAST({field} || :chunk AS BLOB) WHERE id = :id");
+        for chunk in chunks {
+            self.exec(&append, Some(&record_from_slice(&[
+                ("chunk", DbValue::Blob(chunk.to_vec())),
+                ("id", id.into()),
+            ]))).await?;
+        }
+        Ok(true)
+    }
+    /// Read `size` bytes of blob field starting at `offset`
+    pub async fn read_blob_chunk(&self, table: &str, id: i64, field: &str, offset: i64, size: i64) -> anyhow::Result<Vec<u8>> {
+        if !is_valid_sql_identifier(table) || !is_valid_sql_identifier(field) {
+            return Err(anyhow!("Invalid table or field name: {table}.{field}"));
+        }
+        let result = self.query(&format!("SELECT substr({field}, :offset + 1, :size) FROM {table} WHERE id = :id"), Some(&record_from_slice(&[
+            ("offset", offset.into()),
+            ("size", size.into()),
+            ("id", id.into()),
+        ]))).await?;
+        match result.value(0, 0) {
+            Some(DbValue::Blob(data)) => Ok(data.clone()),
+            Some(DbValue::String(data)) => Ok(data.as_bytes().to_vec()),
+            Some(DbValue::Null) => Ok(vec![]),
+            Some(_) => Err(anyhow!("Field {table}.{field} is not a blob")),
+            None => Err(anyhow!("Record {table}.{id} not found")),
+        }
+    }
+    /// Start of the stage, run times in msec are relative to it
+    pub async fn stage_start(&self, stage_id: i64) -> anyhow::Result<DateTime<FixedOffset>> {
+        let result = self.query("SELECT startDateTime FROM stages WHERE id = :id", Some(&record_from_slice(&[
+            ("id", stage_id.into()),
+        ]))).await?;
+        result.value(0, 0).and_then(|v| v.to_datetime())
+            .ok_or_else(|| anyhow!("Stage {stage_id} start time is not set"))
+    }
+    /// Number of rows `exec` would affect, nothing is committed. Remote event DB service has no transactions,
+    /// rows matching condition of `DELETE` or `UPDATE` are counted there, see [`appsqlapi::dry_run_count_query`].
+    pub async fn exec_dry_run(&self, query: &str, params: Option<&Record>) -> anyhow::Result<ExecResult> {
+        if let Some(db) = self.local_event_db().await? {
+            return AppSqlApi::new_without_recchng(db).exec_dry_run(query, params).await;
+        }
+        let (count_query, params) = appsqlapi::dry_run_count_query(query, params)
+            .ok_or_else(|| anyhow!("Event id: {} dry run of remote event supports plain DELETE and UPDATE statements only", self.event_id))?;
+        let result = self.query(&count_query, params.as_ref()).await?;
+        let rows_affected = result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default();
+        Ok(ExecResult { rows_affected, insert_id: None })
+    }
+    /// Execute writing statement with RETURNING clause, see [`AppSqlApi::exec_returning`]
+    pub async fn exec_returning(&self, query: &str, params: Option<&Record>) -> anyhow::Result<QueryResult> {
+        if let Some(db) = self.local_event_db().await? {
+            self.check_writable()?;
+            return AppSqlApi::new_without_recchng(db).exec_returning(query, params).await;
+        }
+        let params = to_rpcvalue(&params)?;
+        let rpc_value = self.call_remote_sql("execReturning", make_list![query, params].into()).await?;
+        Ok(from_rpcvalue(&rpc_value)?)
+    }
+    /// Execute statements of script in transaction, see [`AppSqlApi::exec_script`]
+    pub async fn exec_script(&self, script: &str) -> anyhow::Result<ExecScriptResult> {
+        if let Some(db) = self.local_event_db().await? {
+            self.check_writable()?;
+            return AppSqlApi::new_without_recchng(db).exec_script(script).await;
+        }
+        let param = to_rpcvalue(&ExecScriptParams { script: script.to_string() })?;
+        let rpc_value = self.call_remote_sql("execScript", param).await?;
+        Ok(from_rpcvalue(&rpc_value)?)
+    }
+    /// Query plan of statement, see [`AppSqlApi::explain`]
+    pub async fn explain(&self, query: &str, params: Option<&Record>, analyze: bool) -> anyhow::Result<ExplainResult> {
+        let db = self.local_event_db().await?
+            .ok_or_else(|| anyhow!("Event id: {} explain is not supported for remote event", self.event_id))?;
+        AppSqlApi::new_without_recchng(db).explain(query, params, analyze).await
+    }
+    /// Run `f` in savepoint, changes made through `EventSqlApi` passed to `f` are rolled back when `f` fails,
+    /// record changes are notified after the outermost savepoint is released. Savepoint of local event
+    /// has connection dedicated to it. Remote event DB service has no savepoints, `f` is called directly then,
+    /// so changes made before its failure are not rolled back, the fallback is logged.
+    pub async fn with_savepoint<T>(&self, f: impl AsyncFnOnce(&EventSqlApi) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let savepoint = match &self.savepoint {
+            Some(savepoint) => SavepointScope { db: savepoint.db.clone(), depth: savepoint.depth + 1, changed_records: savepoint.changed_records.clone() },
+            None => {
+                if !self.is_local_event_db().await? {
+                    warn!("Event id: {} DB is remote, changes are made without savepoint and they are not rolled back on failure", self.event_id);
+                    return f(self).await;
+                }
+                self.check_writable()?;
+                let db = open_event_db_pool(&event_db_file(self.event_id), Some(1)).await?;
+                SavepointScope { db, depth: 0, changed_records: Default::default() }
+            }
+        };
+        let (db, depth, changed_records) = (savepoint.db.clone(), savepoint.depth, savepoint.changed_records.clone());
+        let changed_before = changed_records.lock().unwrap().len();
+        let sql_api = EventSqlApi {
+            event_id: self.event_id,
+            app_state: self.app_state.clone(),
+            rpc_client: self.rpc_client.clone(),
+            correlation_id: self.correlation_id,
+            priority_lane: self.priority_lane,
+            frozen_override: self.frozen_override,
+            savepoint: Some(savepoint),
+        };
+        let result = appsqlapi::run_in_savepoint(&db, depth, async || f(&sql_api).await).await;
+        let mut changed_records = changed_records.lock().unwrap();
+        if result.is_err() {
+            changed_records.truncate(changed_before);
+        } else if depth == 0 {
+            for (table, id) in changed_records.drain(..) {
+                self.notify_record_changed(&table, id);
+            }
+        }
+        result
+    }
+    /// Number of rows `delete` would remove, nothing is committed
+    pub async fn delete_record_dry_run(&self, table: &str, id: i64) -> anyhow::Result<ExecResult> {
+        if !is_valid_sql_identifier(table) {
+            return Err(anyhow!("Invalid table name: {table}"));
+        }
+        let result = self.query(&format!("SELECT COUNT(*) FROM {table} WHERE id = :id"), Some(&record_from_slice(&[
+            ("id", id.into()),
+        ]))).await?;
+        let rows_affected = result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default();
+        Ok(ExecResult { rows_affected, insert_id: None })
+    }
+    /// Mark record as deleted, the update record change is the tombstone propagated to clients
+    pub async fn soft_delete_record(&self, table: &str, id: i64, issuer: Option<String>) -> anyhow::Result<bool> {
+        let record = record_from_slice(&[
+            ("deleted", true.into()),
+            ("deletedAt", chrono::Local::now().fixed_offset().into()),
+        ]);
+        self.update_record_event(table, id, &record, issuer).await
+    }
+    pub async fn is_record_deleted(&self, table: &str, id: i64) -> anyhow::Result<bool> {
+        if !self.uses_soft_delete(table).await? {
+            return Ok(false);
+        }
+        let result = self.query(&format!("SELECT deleted FROM {table} WHERE id = :id"), Some(&record_from_slice(&[
+            ("id", id.into()),
+        ]))).await?;
+        Ok(result.value(0, 0).is_some_and(DbValue::to_bool))
+    }
+    /// Remove tombstones of records deleted before `before`, all of them if not set
+    pub async fn purge_deleted(&self, table: &str, before: Option<DateTime<FixedOffset>>) -> anyhow::Result<i64> {
+        if !self.uses_soft_delete(table).await? {
+            return Err(anyhow!("Table {table} does not use soft delete in event id: {}", self.event_id));
+        }
+        let result = self.exec(&format!("DELETE FROM {table} WHERE deleted AND (:before IS NULL OR deletedAt < :before)"), Some(&record_from_slice(&[
+            ("before", before.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ]))).await?;
+        Ok(result.rows_affected)
+    }
+    /// Insert or update records by `key_field` in one transaction, remote event DB service has to support `upsert` too
+    pub async fn upsert_records(&self, table: &str, key_field: &str, records: &[Record]) -> anyhow::Result<Vec<UpsertResult>> {
+        let results = if let Some(db) = self.local_event_db().await? {
+            AppSqlApi::new(db, self.rpc_client.clone()).upsert_records(table, key_field, records, has_row_version(table).then_some(ROW_VERSION)).await?
+        } else {
+            let records: Vec<Record> = if has_row_version(table) { records.iter().map(without_row_version).collect() } else { records.to_vec() };
+            let rpc_value = self.call_remote_sql("upsert", make_list![table, key_field, to_rpcvalue(&records)?].into()).await?;
+            from_rpcvalue(&rpc_value)?
+        };
+        for result in &results {
+            self.notify_record_changed(table, result.id);
+        }
+        Ok(results)
+    }
     pub async fn delete_record_event(&self, table: &str, id: i64, issuer: Option<String>) -> anyhow::Result<bool> {
+        // soft delete is checked and recorded as update of the deleted flag
+        let soft_delete = self.uses_soft_delete(table).await?;
+        let frozen_override = !soft_delete && self.check_frozen(table, id).await?;
+        let with_history = !soft_delete && self.uses_history(table).await?;
+        let audit_frozen_override = frozen_override && self.is_local_event_db().await?;
+        let old_values = if with_history || audit_frozen_override {
+            self.record_values(table, id, &[]).await?
+        } else {
+            None
+        };
+        let deleted = self.delete_record_event_impl(table, id, issuer.clone()).await?;
+        if deleted {
+            if let Some(old_values) = &old_values {
+                if with_history {
+                    self.write_history(table, id, HISTORY_DELETE, Some(old_values), None, issuer.clone()).await;
+                }
+                if audit_frozen_override {
+                    self.write_history(table, id, HISTORY_FROZEN_OVERRIDE, Some(old_values), None, issuer).await;
+                }
+            }
+            self.notify_record_changed(table, id);
+        }
+        Ok(deleted)
+    }
+    async fn delete_record_event_impl(&self, table: &str, id: i64, issuer: Option<String>) -> anyhow::Result<bool> {
+        self.check_writable()?;
+        if self.uses_soft_delete(table).await? {
+            return self.soft_delete_record(table, id, issuer).await;
+        }
         if self.is_local_event_db().await? {
             return self.delete_record_with_recchng(table, id, issuer).await;
         } else {
@@ -73,8 +723,8 @@ impl EventSqlApi {
                 id,
                 issuer,
             };
-            let deleted: bool = self.rpc_client.call_rpc_method(remote_event_sql_path(self.event_id), "delete", Some(to_rpcvalue(&param)?), None, None, None::<fn(f64)>).await
-                .map_err(call_rpc_error_to_anyhow)?;
+            let rpc_value = self.call_remote_sql("delete", to_rpcvalue(&param)?).await?;
+            let deleted: bool = from_rpcvalue(&rpc_value)?;
             Ok(deleted)
         }
     }
@@ -88,8 +738,7 @@ impl qxsql::QxSqlApi for EventSqlApi {
             qxsql.query(query, params).await
         } else {
             let params = to_rpcvalue(&params)?;
-            let rpc_value = self.rpc_client.call_rpc_method(remote_event_sql_path(self.event_id), "query", Some(make_list![query, params].into()), None, None, None::<fn(f64)>).await
-                .map_err(call_rpc_error_to_anyhow)?;
+            let rpc_value = self.call_remote_sql("query", make_list![query, params].into()).await?;
             let res: QueryResult = from_rpcvalue(&rpc_value)?;
             Ok(res)
         }
@@ -97,12 +746,12 @@ impl qxsql::QxSqlApi for EventSqlApi {
 
     async fn exec(&self, query: &str, params: Option<&Record>) -> anyhow::Result<ExecResult> {
         if let Some(db) = self.local_event_db().await? {
+            self.check_writable()?;
             let qxsql = AppSqlApi::new(db, self.rpc_client.clone());
             qxsql.exec(query, params).await
         } else {
             let params = to_rpcvalue(&params)?;
-            let rpc_value = self.rpc_client.call_rpc_method(remote_event_sql_path(self.event_id), "exec", Some(make_list![query, params].into()), None, None, None::<fn(f64)>).await
-                .map_err(call_rpc_error_to_anyhow)?;
+            let rpc_value = self.call_remote_sql("exec", make_list![query, params].into()).await?;
             let res: ExecResult = from_rpcvalue(&rpc_value)?;
             Ok(res)
         }
@@ -123,3 +772,31 @@ impl qxsql::QxSqlApiRecChng for EventSqlApi {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_sqlite::PoolBuilder;
+
+    #[test]
+    fn update_of_changed_record_is_conflict() {
+        smol::block_on(async {
+            let pool = PoolBuilder::new().path(":memory:").num_conns(1).open().await.unwrap();
+            pool.conn(|conn| conn.execute_batch("CREATE TABLE runs (id INTEGER PRIMARY KEY, siId INTEGER, rowVersion INTEGER NOT NULL DEFAULT 0); \
+                INSERT INTO runs (id, siId) VALUES (1, 100)")).await.unwrap();
+            let sql_api = AppSqlApi::new_without_recchng(pool);
+            let update = async |si_id: i64, expected: i64| {
+                let record = record_from_slice(&[("siId", si_id.into()), (ROW_VERSION, expected.into())]);
+                let (query, params) = versioned_update_query("runs", 1, &record, Some(expected)).unwrap();
+                let result = sql_api.exec_returning(&query, Some(&params)).await.unwrap();
+                result.value(0, 0).and_then(|v| v.to_int())
+            };
+            assert_eq!(update(200, 0).await, Some(1));
+            // second client edited the record at version 0 too
+            assert_eq!(update(300, 0).await, None);
+            let result = sql_api.query("SELECT siId, rowVersion FROM runs WHERE id = 1", None).await.unwrap();
+            assert_eq!(result.value(0, 0).and_then(|v| v.to_int()), Some(200));
+            assert_eq!(result.value(0, 1).and_then(|v| v.to_int()), Some(1));
+        });
+    }
+}
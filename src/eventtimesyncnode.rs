@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::{Record, record_from_slice};
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, make_map, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::{anyhow_to_rpc_error, issuer};
+use crate::eventctlnode::escalate_event_owner_rights;
+use crate::eventsqlapi::EventSqlApi;
+use crate::jobs::{JobProgress, spawn_job};
+use crate::rqtrace::RequestTrace;
+use crate::state::{EventId, SharedAppState};
+
+const METH_NOW: &str = "now";
+const METH_REPORT_CLOCK: &str = "reportClock";
+const METH_OFFSETS: &str = "offsets";
+const METH_RECOMPUTE: &str = "recompute";
+
+pub(crate) const EVENT_TIMESYNC_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_NOW, Flags::None, AccessLevel::Read, "", "{i:utcMs,s:utc}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_REPORT_CLOCK, Flags::None, AccessLevel::Write, "{i:readerConnectionId,i:stationTimeMs,i|n:brokerTimeMs}", "{i:offsetMs,i|n:brokerOffsetMs}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_OFFSETS, Flags::None, AccessLevel::Read, "", "[{i:readerConnectionId,i:offsetMs,i|n:brokerOffsetMs,s:reportedAt}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_RECOMPUTE, Flags::None, AccessLevel::Write, "{i|n:readerConnectionId,b|n:job,b|n:dryRun}|i|n", "i:updatedCount|i:jobId", &[], "",
+    ),
+];
+
+/// SI station times of cards are in seconds since midnight, `0xEEEE` means no time
+pub(crate) const SI_NO_TIME: i64 = 0xEEEE;
+const SECONDS_PER_DAY: i64 = 24 * 3600;
+/// `(corrected field, raw field)` pairs of tables with station times
+const CARD_TIME_FIELDS: &[(&str, &str)] = &[("checkTime", "rawCheckTime"), ("startTime", "rawStartTime"), ("finishTime", "rawFinishTime")];
+const PUNCH_TIME_FIELDS: &[(&str, &str)] = &[("time", "rawTime"), ("msec", "rawMsec"), ("timeMs", "rawTimeMs")];
+
+fn time_fields(table: &str) -> &'static [(&'static str, &'static str)] {
+    match table {
+        "cards" => CARD_TIME_FIELDS,
+        "punches" => PUNCH_TIME_FIELDS,
+        _ => &[],
+    }
+}
+
+/// Station bridge reports clock of station connected to reader `reader_connection_id`,
+/// times are UTC milliseconds since epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportClockParams {
+    reader_connection_id: i64,
+    station_time_ms: i64,
+    /// Broker time as seen by the bridge, if it knows it
+    #[serde(default)]
+    broker_time_ms: Option<i64>,
+}
+impl_rpcvalue_conversions!(ReportClockParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecomputeParams {
+    /// All readers are recomputed if not set
+    #[serde(default)]
+    reader_connection_id: Option<i64>,
+    /// Run as a background job and return its id
+    #[serde(default)]
+    job: bool,
+    /// Count records, which would be corrected, without changing them
+    #[serde(default)]
+    dry_run: bool,
+}
+impl_rpcvalue_conversions!(RecomputeParams);
+
+/// Offsets are `station or broker clock - daemon clock`, so positive offset means the station is ahead
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClockOffset {
+    reader_connection_id: i64,
+    offset_ms: i64,
+    broker_offset_ms: Option<i64>,
+    reported_at: String,
+}
+
+async fn report_clock(sql_api: &EventSqlApi, param: ReportClockParams) -> anyhow::Result<(i64, Option<i64>)> {
+    let now = chrono::Utc::now();
+    let now_ms = now.timestamp_millis();
+    let offset_ms = param.station_time_ms - now_ms;
+    let broker_offset_ms = param.broker_time_ms.map(|t| t - now_ms);
+    sql_api.exec("INSERT INTO station_clocks (readerConnectionId, offsetMs, brokerOffsetMs, reportedAt) \
+        VALUES (:readerConnectionId, :offsetMs, :brokerOffsetMs, :reportedAt) \
+        ON CONFLICT (readerConnectionId) DO UPDATE SET offsetMs = excluded.offsetMs, brokerOffsetMs = excluded.brokerOffsetMs, reportedAt = excluded.reportedAt",
+        Some(&record_from_slice(&[
+            ("readerConnectionId", param.reader_connection_id.into()),
+            ("offsetMs", offset_ms.into()),
+            ("brokerOffsetMs", broker_offset_ms.map(Into::into).unwrap_or(DbValue::Null)),
+            ("reportedAt", now.fixed_offset().into()),
+        ]))).await?;
+    Ok((offset_ms, broker_offset_ms))
+}
+
+/// Last reported clock offset of station connected to reader `reader_connection_id`
+pub(crate) async fn station_offset_ms(sql_api: &EventSqlApi, reader_connection_id: i64) -> anyhow::Result<Option<i64>> {
+    let result = sql_api.query("SELECT offsetMs FROM station_clocks WHERE readerConnectionId = :readerConnectionId", Some(&record_from_slice(&[
+        ("readerConnectionId", reader_connection_id.into()),
+    ]))).await?;
+    Ok(result.value(0, 0).and_then(|v| v.to_int()))
+}
+
+/// Raw and corrected values of station times, `raw` returns the value of time field as reported by the station
+fn corrected_times(table: &str, raw: impl Fn(&str) -> Option<i64>, offset_ms: i64) -> Vec<(&'static str, DbValue)> {
+    let mut fields = vec![("clockOffsetMs", offset_ms.into())];
+    match table {
+        "cards" => {
+            let offset_s = (offset_ms as f64 / 1000.).round() as i64;
+            for (field, raw_field) in CARD_TIME_FIELDS {
+                if let Some(time) = raw(field).filter(|time| *time != SI_NO_TIME) {
+                    fields.push((raw_field, time.into()));
+                    fields.push((field, (time - offset_s).rem_euclid(SECONDS_PER_DAY).into()));
+                }
+            }
+        }
+        "punches" => {
+            if let Some(time) = raw("time") {
+                let msec = raw("msec").unwrap_or_default();
+                let corrected = (time * 1000 + msec - offset_ms).rem_euclid(SECONDS_PER_DAY * 1000);
+                fields.push(("rawTime", time.into()));
+                fields.push(("rawMsec", msec.into()));
+                fields.push(("time", (corrected / 1000).into()));
+                fields.push(("msec", (corrected % 1000).into()));
+            }
+            if let Some(time_ms) = raw("timeMs") {
+                fields.push(("rawTimeMs", time_ms.into()));
+                fields.push(("timeMs", (time_ms - offset_ms).into()));
+            }
+        }
+        _ => {}
+    }
+    fields
+}
+
+/// Correct station times of card or punch record being inserted by the offset of its reader station clock,
+/// records of other tables or readers without reported clock are returned unchanged.
+pub(crate) async fn correct_station_times(sql_api: &EventSqlApi, table: &str, record: Record) -> anyhow::Result<Record> {
+    if time_fields(table).is_empty() {
+        return Ok(record);
+    }
+    let int = |field: &str| record.iter().find(|(key, _)| key.as_str() == field).and_then(|(_, value)| value.to_int());
+    let Some(reader_connection_id) = int("readerConnectionId") else {
+        return Ok(record);
+    };
+    let Some(offset_ms) = station_offset_ms(sql_api, reader_connection_id).await? else {
+        return Ok(record);
+    };
+    let corrected = corrected_times(table, int, offset_ms);
+    Ok(record.iter()
+        .filter(|(key, _)| !corrected.iter().any(|(field, _)| key.as_str() == *field))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .chain(corrected.into_iter().map(|(field, value)| (field.to_string(), value)))
+        .collect())
+}
+
+/// Apply current clock offsets to already stored cards and punches, raw times are used if they were stored before
+async fn recompute(sql_api: &EventSqlApi, reader_connection_id: Option<i64>, dry_run: bool, issuer: Option<String>, progress: Option<&JobProgress>) -> anyhow::Result<i64> {
+    let offsets: HashMap<i64, i64> = clock_offsets(sql_api).await?.into_iter()
+        .map(|offset| (offset.reader_connection_id, offset.offset_ms))
+        .collect();
+    let mut updated_count = 0;
+    let tables = ["cards", "punches"];
+    for (table_ix, table) in tables.into_iter().enumerate() {
+        let fields = time_fields(table);
+        let columns = fields.iter().flat_map(|(field, raw_field)| [*field, *raw_field]).collect::<Vec<_>>().join(", ");
+        let result = sql_api.query(&format!("SELECT id, readerConnectionId, {columns} FROM {table} \
+            WHERE readerConnectionId IS NOT NULL AND (:readerConnectionId IS NULL OR readerConnectionId = :readerConnectionId)"),
+            Some(&record_from_slice(&[
+                ("readerConnectionId", reader_connection_id.map(Into::into).unwrap_or(DbValue::Null)),
+            ]))).await?;
+        for row in 0..result.row_count() {
+            let int = |col| result.value(row, col).and_then(|v| v.to_int());
+            let (Some(id), Some(offset_ms)) = (int(0), int(1).and_then(|reader| offsets.get(&reader).copied())) else {
+                continue;
+            };
+            let raw = |field: &str| fields.iter().position(|(f, _)| *f == field)
+                .and_then(|ix| int(2 + 2 * ix + 1).or_else(|| int(2 + 2 * ix)));
+            if dry_run {
+                updated_count += 1;
+                continue;
+            }
+            let record = record_from_slice(&corrected_times(table, raw, offset_ms));
+            if sql_api.update_record_event(table, id, &record, issuer.clone()).await? {
+                updated_count += 1;
+            }
+        }
+        if let Some(progress) = progress {
+            progress.report(table_ix as i64 + 1, tables.len() as i64);
+        }
+    }
+    Ok(updated_count)
+}
+
+async fn clock_offsets(sql_api: &EventSqlApi) -> anyhow::Result<Vec<ClockOffset>> {
+    let result = sql_api.query("SELECT readerConnectionId, offsetMs, brokerOffsetMs, reportedAt FROM station_clocks ORDER BY readerConnectionId", None).await?;
+    Ok((0..result.row_count())
+        .map(|row| ClockOffset {
+            reader_connection_id: result.value(row, 0).and_then(|v| v.to_int()).unwrap_or_default(),
+            offset_ms: result.value(row, 1).and_then(|v| v.to_int()).unwrap_or_default(),
+            broker_offset_ms: result.value(row, 2).and_then(|v| v.to_int()),
+            reported_at: match result.value(row, 3) {
+                Some(DbValue::DateTime(dt)) => dt.to_rfc3339(),
+                Some(DbValue::String(s)) => s.as_str().to_string(),
+                _ => String::new(),
+            },
+        })
+        .collect())
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    event_id: EventId,
+    trace: RequestTrace,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(EVENT_TIMESYNC_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(EVENT_TIMESYNC_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            let method = m.method();
+            let methods = escalate_event_owner_rights(&rq, app_state.clone(), Some(event_id), method.to_owned(), EVENT_TIMESYNC_NODE_METHODS).await;
+            match method {
+                METH_NOW => m.resolve(methods, async move || {
+                    let now = chrono::Utc::now();
+                    Ok(make_map!("utcMs" => now.timestamp_millis(), "utc" => now.to_rfc3339()).into())
+                }),
+                METH_REPORT_CLOCK => m.resolve(methods, async move || {
+                    let param = ReportClockParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let (offset_ms, broker_offset_ms) = report_clock(&sql_api, param).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(make_map!("offsetMs" => offset_ms, "brokerOffsetMs" => broker_offset_ms.map(RpcValue::from).unwrap_or_else(RpcValue::null)).into())
+                }),
+                METH_OFFSETS => m.resolve(methods, async move || {
+                    let sql_api = EventSqlApi::new(event_id, app_state, client_cmd_tx).with_correlation_id(trace.correlation_id());
+                    let offsets = clock_offsets(&sql_api).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(to_rpcvalue(&offsets).expect("serde should work"))
+                }),
+                METH_RECOMPUTE => m.resolve(methods, async move || {
+                    let param = match rq.param() {
+                        Some(param) if param.is_map() => RecomputeParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        param => RecomputeParams {
+                            reader_connection_id: param.filter(|p| p.is_int()).map(RpcValue::as_int),
+                            job: false,
+                            dry_run: false,
+                        },
+                    };
+                    let sql_api = EventSqlApi::new(event_id, app_state.clone(), client_cmd_tx.clone()).with_correlation_id(trace.correlation_id());
+                    if param.job {
+                        let issuer = issuer(&rq);
+                        let job_id = spawn_job(app_state, client_cmd_tx, METH_RECOMPUTE, Some(event_id), move |progress| async move {
+                            recompute(&sql_api, param.reader_connection_id, param.dry_run, issuer, Some(&progress)).await
+                                .map(RpcValue::from)
+                        }).await.map_err(anyhow_to_rpc_error)?;
+                        return Ok(RpcValue::from(job_id));
+                    }
+                    recompute(&sql_api, param.reader_connection_id, param.dry_run, issuer(&rq), None).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! JSON-RPC 2.0 gateway for integrations from languages without SHV client library. Requests are POSTed
+//! to `/rpc` with bearer token from `gateway.tokens`, method is SHV path and method in RI notation, like
+//! `eventctl/12/runs:list`, params are SHV param in JSON. Only event subtrees of `eventctl` in the token scope
+//! are exposed, including event `sql` nodes. The calls are routed through the broker to the daemon itself,
+//! token user is forwarded in request meta, so event owner and organization member rights apply to it.
+//! Requests without id are notifications, they are called, but not answered. Batch requests are called in order.
+//! Gateway speaks plain HTTP and listens on loopback only, TLS is left to a reverse proxy.
+
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvproto::{from_rpcvalue, to_rpcvalue};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+use shvrpc::metamethod::AccessLevel;
+use shvrpc::util::join_path;
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::{TcpListener, TcpStream};
+
+use crate::config::{GatewayAccess, GatewayToken};
+use crate::eventctlnode::{node_methods, split_organization_path};
+use crate::global_config;
+use crate::rpccall::call_rpc_method_with_meta;
+use crate::state::{EventId, SharedAppState};
+
+const RPC_PATH: &str = "/rpc";
+const EVENTCTL_PATH: &str = "eventctl";
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+/// Request meta tag with user of gateway token
+const GATEWAY_USER_META: &str = "qx_gateway_user";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// Server defined error, SHV call failed
+const CALL_ERROR: i64 = -32000;
+const PERMISSION_DENIED: i64 = -32001;
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    method: String,
+    #[serde(default)]
+    params: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
+impl JsonRpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonRpcResponse {
+    jsonrpc: &'static str,
+    id: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<JsonRpcError>,
+}
+
+impl JsonRpcResponse {
+    fn new(id: serde_json::Value, result: Result<serde_json::Value, JsonRpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self { jsonrpc: "2.0", id, result, error }
+    }
+}
+
+#[derive(Debug)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct HttpResponse {
+    status: &'static str,
+    body: String,
+}
+
+impl HttpResponse {
+    fn error(status: &'static str, message: impl Into<String>) -> Self {
+        Self { status, body: message.into() }
+    }
+}
+
+fn access_level(access: GatewayAccess) -> AccessLevel {
+    match access {
+        GatewayAccess::Read => AccessLevel::Read,
+        GatewayAccess::Write => AccessLevel::Write,
+        GatewayAccess::Service => AccessLevel::Service,
+    }
+}
+
+async fn read_request(stream: &mut TcpStream, max_body_size: usize) -> Result<HttpRequest, HttpResponse> {
+    let bad_request = |message: String| HttpResponse::error("400 Bad Request", message);
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.map_err(|e| bad_request(e.to_string()))?;
+        if n == 0 {
+            return Err(bad_request("Connection closed".to_string()));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > MAX_HEADER_SIZE {
+            return Err(HttpResponse::error("431 Request Header Fields Too Large", "HTTP header is too large"));
+        }
+    };
+    let head = std::str::from_utf8(&buf[..header_end]).map_err(|e| bad_request(e.to_string()))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let mut authorization = None;
+    let mut content_length = 0;
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "authorization" => authorization = Some(value.trim().to_string()),
+            "content-length" => content_length = value.trim().parse::<usize>()
+                .map_err(|_| bad_request(format!("Invalid Content-Length: {value}")))?,
+            _ => {}
+        }
+    }
+    if content_length > max_body_size {
+        return Err(HttpResponse::error("413 Content Too Large", format!("Request body is larger than {max_body_size} bytes")));
+    }
+    let mut body = buf.split_off(header_end + 4);
+    if body.len() < content_length {
+        let mut rest = vec![0u8; content_length - body.len()];
+        stream.read_exact(&mut rest).await.map_err(|e| bad_request(e.to_string()))?;
+        body.extend_from_slice(&rest);
+    }
+    body.truncate(content_length);
+    Ok(HttpRequest { method, path, authorization, body })
+}
+
+/// User forwarded by gateway, the meta tag is trusted only in requests, which the daemon sent to itself
+pub(crate) fn forwarded_user(rq: &RpcMessage) -> Option<&str> {
+    global_config().gateway.as_ref()?;
+    let user = rq.meta().get(GATEWAY_USER_META)?.as_str();
+    let daemon_user = global_config().client.url.username();
+    let caller = rq.user_id()?.split([';', ':']).next()?;
+    (!daemon_user.is_empty() && caller == daemon_user && !user.is_empty()).then_some(user)
+}
+
+/// Event id and path of node in event subtree, `node_path` is relative to `eventctl`,
+/// organization subtree `<org>/<event_id>/...` is accepted too
+fn event_node_path(node_path: &str) -> Option<(EventId, &str)> {
+    let event_path = match split_organization_path(node_path) {
+        Some((_, event_path)) => event_path,
+        None => node_path,
+    };
+    let event_id = event_path.split('/').next()?.parse::<EventId>().ok()?;
+    Some((event_id, event_path))
+}
+
+async fn call(client_cmd_tx: &ClientCommandSender, app_state: &SharedAppState, mount: &str, token: &GatewayToken, request: JsonRpcRequest) -> Result<serde_json::Value, JsonRpcError> {
+    if request.jsonrpc != "2.0" {
+        return Err(JsonRpcError::new(INVALID_REQUEST, "JSON-RPC 2.0 request expected"));
+    }
+    let Some((path, method)) = request.method.rsplit_once(':') else {
+        return Err(JsonRpcError::new(METHOD_NOT_FOUND, "Method must be in form <path>:<method>"));
+    };
+    let Some((event_id, event_path)) = path.strip_prefix(EVENTCTL_PATH)
+        .and_then(|node_path| node_path.strip_prefix('/'))
+        .and_then(event_node_path) else {
+        return Err(JsonRpcError::new(METHOD_NOT_FOUND, format!("Path {path} is not exposed by gateway, only event subtrees are")));
+    };
+    let Some(mm) = node_methods(event_path).and_then(|methods| methods.iter().find(|mm| mm.name == method)) else {
+        return Err(JsonRpcError::new(METHOD_NOT_FOUND, format!("Method {} not found", request.method)));
+    };
+    let event_record = app_state.cached_event_record(event_id).await
+        .map_err(|e| JsonRpcError::new(CALL_ERROR, e.to_string()))?;
+    let in_scope = token.events.contains(&event_id)
+        || event_record.organization_id.is_some_and(|organization_id| token.organizations.contains(&organization_id));
+    if !in_scope {
+        return Err(JsonRpcError::new(PERMISSION_DENIED, format!("Event id: {event_id} is not in scope of the token")));
+    }
+    // token access is the upper bound, event ownership of the token user does not raise it
+    if mm.access as i32 > access_level(token.access) as i32 {
+        return Err(JsonRpcError::new(PERMISSION_DENIED, format!("Method {} requires {:?} access", request.method, mm.access)));
+    }
+    let param = request.params.as_ref()
+        .map(to_rpcvalue)
+        .transpose()
+        .map_err(|e| JsonRpcError::new(INVALID_PARAMS, e.to_string()))?;
+    let result = call_rpc_method_with_meta(client_cmd_tx, &join_path(mount, path), method, param, &[(GATEWAY_USER_META, token.user.as_str().into())]).await
+        .map_err(|e| JsonRpcError::new(CALL_ERROR, e.to_string()))?;
+    from_rpcvalue::<serde_json::Value>(&result)
+        .map_err(|e| JsonRpcError::new(INTERNAL_ERROR, format!("Result cannot be converted to JSON: {e}")))
+}
+
+/// Response to single request, notification is not answered
+async fn process(client_cmd_tx: &ClientCommandSender, app_state: &SharedAppState, mount: &str, token: &GatewayToken, request: serde_json::Value) -> Option<JsonRpcResponse> {
+    let request = match serde_json::from_value::<JsonRpcRequest>(request) {
+        Ok(request) => request,
+        Err(e) => return Some(JsonRpcResponse::new(serde_json::Value::Null, Err(JsonRpcError::new(INVALID_REQUEST, e.to_string())))),
+    };
+    let id = request.id.clone();
+    let result = call(client_cmd_tx, app_state, mount, token, request).await;
+    id.map(|id| JsonRpcResponse::new(id, result))
+}
+
+async fn process_body(client_cmd_tx: &ClientCommandSender, app_state: &SharedAppState, mount: &str, token: &GatewayToken, body: &[u8]) -> anyhow::Result<Option<String>> {
+    let request = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(request) => request,
+        Err(e) => {
+            let response = JsonRpcResponse::new(serde_json::Value::Null, Err(JsonRpcError::new(PARSE_ERROR, e.to_string())));
+            return Ok(Some(serde_json::to_string(&response)?));
+        }
+    };
+    match request {
+        serde_json::Value::Array(requests) if requests.is_empty() => {
+            let response = JsonRpcResponse::new(serde_json::Value::Null, Err(JsonRpcError::new(INVALID_REQUEST, "Empty batch")));
+            Ok(Some(serde_json::to_string(&response)?))
+        }
+        serde_json::Value::Array(requests) => {
+            let mut responses = vec![];
+            for request in requests {
+                responses.extend(process(client_cmd_tx, app_state, mount, token, request).await);
+            }
+            if responses.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(serde_json::to_string(&responses)?))
+        }
+        request => process(client_cmd_tx, app_state, mount, token, request).await
+            .map(|response| serde_json::to_string(&response))
+            .transpose()
+            .map_err(anyhow::Error::from),
+    }
+}
+
+async fn respond(client_cmd_tx: &ClientCommandSender, app_state: &SharedAppState, mount: &str, request: HttpRequest) -> HttpResponse {
+    let Some(config) = &global_config().gateway else {
+        return HttpResponse::error("503 Service Unavailable", "Gateway is not configured");
+    };
+    if request.path != RPC_PATH {
+        return HttpResponse::error("404 Not Found", format!("Only {RPC_PATH} is served"));
+    }
+    if request.method != "POST" {
+        return HttpResponse::error("405 Method Not Allowed", "POST expected");
+    }
+    let Some(token) = request.authorization.as_deref()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .and_then(|token| config.tokens.get(token.trim())) else {
+        return HttpResponse::error("401 Unauthorized", "Valid bearer token required");
+    };
+    match process_body(client_cmd_tx, app_state, mount, token, &request.body).await {
+        Ok(Some(body)) => HttpResponse { status: "200 OK", body },
+        Ok(None) => HttpResponse { status: "204 No Content", body: String::new() },
+        Err(e) => HttpResponse::error("500 Internal Server Error", e.to_string()),
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, client_cmd_tx: ClientCommandSender, app_state: SharedAppState, mount: String, timeout: Duration, max_body_size: usize) -> anyhow::Result<()> {
+    let request = smol::future::or(
+        read_request(&mut stream, max_body_size),
+        async {
+            smol::Timer::after(timeout).await;
+            Err(HttpResponse::error("408 Request Timeout", "Request is not complete"))
+        },
+    ).await;
+    let response = match request {
+        Ok(request) => respond(&client_cmd_tx, &app_state, &mount, request).await,
+        Err(response) => response,
+    };
+    let content_type = if response.status.starts_with('2') { "application/json" } else { "text/plain; charset=utf-8" };
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, response.body.len());
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Serve JSON-RPC gateway, if it is configured
+pub(crate) async fn server(client_cmd_tx: ClientCommandSender, app_state: SharedAppState) {
+    let Some(config) = &global_config().gateway else {
+        return;
+    };
+    let Some(mount) = config.mount.clone().or_else(|| global_config().client.mount.clone()) else {
+        error!("JSON-RPC gateway is not started, daemon mount point is not known, set gateway.mount");
+        return;
+    };
+    match config.listen.parse::<std::net::SocketAddr>() {
+        Ok(addr) if addr.ip().is_loopback() => {}
+        _ => {
+            error!("JSON-RPC gateway is not started, it serves plain HTTP and must listen on loopback address, not on {}", config.listen);
+            return;
+        }
+    }
+    let listener = match TcpListener::bind(config.listen.as_str()).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("JSON-RPC gateway cannot listen on {}: {e}", config.listen);
+            return;
+        }
+    };
+    info!("JSON-RPC gateway listening on {}", config.listen);
+    let timeout = config.timeout.to_std().unwrap_or(Duration::from_secs(30));
+    let max_body_size = config.max_body_size_kb.saturating_mul(1024);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let (client_cmd_tx, app_state, mount) = (client_cmd_tx.clone(), app_state.clone(), mount.clone());
+                smol::spawn(async move {
+                    if let Err(e) = handle_connection(stream, client_cmd_tx, app_state, mount, timeout, max_body_size).await {
+                        debug!("JSON-RPC gateway connection from {peer} failed: {e}");
+                    }
+                }).detach();
+            }
+            Err(e) => warn!("JSON-RPC gateway accept failed: {e}"),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Periodic `.app:heartbeat` signal with daemon status. Missing signal means that the daemon is hung,
+//! monitoring clients can detect it even if the broker connection of the daemon is still alive.
+
+use std::time::{Duration, Instant};
+
+use log::error;
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use shvrpc::RpcMessage;
+
+use crate::diskspace::DiskSpaceLevel;
+use crate::global_config;
+use crate::state::SharedAppState;
+
+pub const SIG_HEARTBEAT: &str = "heartbeat";
+
+/// App DB, which does not answer in this time, is reported as not healthy
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DaemonStatus {
+    pub version: &'static str,
+    pub uptime_sec: u64,
+    pub open_events: usize,
+    /// Queued and running jobs
+    pub active_jobs: usize,
+    /// App DB error, it is not set if the DB is healthy
+    pub db_error: Option<String>,
+    pub disk_space: DiskSpaceLevel,
+}
+
+pub(crate) async fn check_app_db(app_state: &SharedAppState) -> Option<String> {
+    let check = async {
+        Some(app_state.db_pool.conn(|conn| conn.query_row("SELECT 1", [], |_| Ok(()))).await
+            .map_err(|e| e.to_string()))
+    };
+    let timeout = async {
+        smol::Timer::after(DB_CHECK_TIMEOUT).await;
+        None
+    };
+    match smol::future::or(check, timeout).await {
+        Some(Ok(())) => None,
+        Some(Err(e)) => Some(e),
+        None => Some(format!("App DB did not respond in {} sec", DB_CHECK_TIMEOUT.as_secs())),
+    }
+}
+
+pub(crate) async fn daemon_status(app_state: &SharedAppState, started_at: Instant) -> DaemonStatus {
+    DaemonStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_sec: started_at.elapsed().as_secs(),
+        open_events: app_state.open_event_ids().len(),
+        active_jobs: app_state.jobs.active_count(),
+        db_error: check_app_db(app_state).await,
+        disk_space: app_state.disk_space.status().level,
+    }
+}
+
+pub(crate) async fn heartbeat(app_state: SharedAppState, rpc_client: ClientCommandSender) {
+    let interval = global_config().heartbeat_interval.to_std().unwrap_or_default();
+    if interval.is_zero() {
+        return;
+    }
+    let started_at = Instant::now();
+    loop {
+        smol::Timer::after(interval).await;
+        let status = daemon_status(&app_state, started_at).await;
+        if let Some(e) = &status.db_error {
+            error!("App DB health check failed: {e}");
+        }
+        let signal = RpcMessage::new_signal(".app", SIG_HEARTBEAT)
+            .with_param(shvproto::to_rpcvalue(&status).unwrap_or_default());
+        if let Err(e) = rpc_client.send_message(signal) {
+            error!("Failed to send {SIG_HEARTBEAT} signal: {e}");
+        }
+    }
+}
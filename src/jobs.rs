@@ -0,0 +1,262 @@
+//! Long running operations like backups, bulk imports, exports and recalculations.
+//!
+//! Jobs are queued and run by smol tasks, at most `jobs.workers` of them at once. Method starting a job returns its id
+//! immediately, progress and completion is reported by `progress` signal on `.app/jobs/<id>` path. Queued or running job
+//! can be cancelled, it is dropped at its next await point. Job states are persisted in the `jobs` table of master DB,
+//! so finished jobs can be listed after the daemon restart.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+use log::{error, info};
+use qxsql::{DbValue, QxSqlApi, QxSqlApiRecChng};
+use qxsql::sql::{Record, record_from_slice};
+use shvclient::ClientCommandSender;
+use shvproto::RpcValue;
+use shvrpc::RpcMessage;
+use smol::channel;
+use smol::lock::Semaphore;
+
+pub(crate) use qxeventd_api::{JobId, JobState, JobStatus};
+
+use crate::appsqlapi::AppSqlApi;
+use crate::state::{EventId, SharedAppState};
+
+pub(crate) const JOBS_SHV_PATH: &str = ".app/jobs";
+
+const SIG_PROGRESS: &str = "progress";
+
+/// Number of finished jobs, which status is kept in memory for `status` method
+const KEEP_FINISHED_JOBS: usize = 100;
+
+/// Number of jobs kept in the persisted job log
+const KEEP_JOB_LOG: i64 = 1000;
+
+/// Cancellation token of queued or running job, the job is cancelled by closing the channel
+#[derive(Clone)]
+struct CancelToken {
+    sender: channel::Sender<()>,
+    receiver: channel::Receiver<()>,
+}
+
+impl CancelToken {
+    fn new() -> Self {
+        let (sender, receiver) = channel::bounded(1);
+        Self { sender, receiver }
+    }
+    fn cancel(&self) {
+        self.sender.close();
+    }
+    async fn cancelled(&self) {
+        // nothing is ever sent, recv returns when the channel is closed
+        let _ = self.receiver.recv().await;
+    }
+}
+
+/// Job queue and status of queued, running and recently finished jobs
+pub(crate) struct Jobs {
+    workers: Semaphore,
+    statuses: Mutex<BTreeMap<JobId, JobStatus>>,
+    cancel_tokens: Mutex<BTreeMap<JobId, CancelToken>>,
+}
+
+impl Jobs {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: Semaphore::new(workers.max(1)),
+            statuses: Default::default(),
+            cancel_tokens: Default::default(),
+        }
+    }
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        self.statuses.lock().unwrap().get(&id).cloned()
+    }
+    pub fn job_ids(&self) -> Vec<JobId> {
+        self.statuses.lock().unwrap().keys().copied().collect()
+    }
+    /// Number of queued and running jobs
+    pub fn active_count(&self) -> usize {
+        self.statuses.lock().unwrap().values().filter(|status| !status.state.is_finished()).count()
+    }
+    /// Cancel queued or running job, returns false if the job is not active
+    pub fn cancel(&self, id: JobId) -> bool {
+        match self.cancel_tokens.lock().unwrap().get(&id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+    fn add(&self, id: JobId, name: &str, event_id: Option<EventId>, cancel_token: CancelToken) {
+        let status = JobStatus { id, name: name.to_string(), event_id, state: JobState::Queued, done: 0, total: 0, error: None, result: None };
+        self.statuses.lock().unwrap().insert(id, status);
+        self.cancel_tokens.lock().unwrap().insert(id, cancel_token);
+    }
+    fn update(&self, id: JobId, f: impl FnOnce(&mut JobStatus)) -> Option<JobStatus> {
+        let mut statuses = self.statuses.lock().unwrap();
+        let status = statuses.get_mut(&id)?;
+        f(status);
+        let status = status.clone();
+        if status.state.is_finished() {
+            self.cancel_tokens.lock().unwrap().remove(&id);
+        }
+        let finished: Vec<JobId> = statuses.values().filter(|s| s.state.is_finished()).map(|s| s.id).collect();
+        for id in finished.iter().take(finished.len().saturating_sub(KEEP_FINISHED_JOBS)) {
+            statuses.remove(id);
+        }
+        Some(status)
+    }
+}
+
+/// Handle passed to the job to report its progress
+#[derive(Clone)]
+pub(crate) struct JobProgress {
+    id: JobId,
+    app_state: SharedAppState,
+    client_cmd_tx: ClientCommandSender,
+}
+
+impl JobProgress {
+    /// `done` of `total` items processed
+    pub fn report(&self, done: i64, total: i64) {
+        if let Some(status) = self.app_state.jobs.update(self.id, |status| {
+            status.done = done;
+            status.total = total;
+        }) {
+            self.send_signal(&status);
+        }
+    }
+    fn set_state(&self, f: impl FnOnce(&mut JobStatus)) -> Option<JobStatus> {
+        let status = self.app_state.jobs.update(self.id, f)?;
+        self.send_signal(&status);
+        Some(status)
+    }
+    fn send_signal(&self, status: &JobStatus) {
+        let signal = RpcMessage::new_signal(&format!("{JOBS_SHV_PATH}/{}", self.id), SIG_PROGRESS)
+            .with_param(RpcValue::from(status));
+        if let Err(e) = self.client_cmd_tx.send_message(signal) {
+            error!("Failed to send job {} {SIG_PROGRESS} signal: {e}", self.id);
+        }
+    }
+}
+
+fn now() -> String {
+    chrono::Local::now().fixed_offset().to_rfc3339()
+}
+
+/// Persist job state to the job log, failure is only logged, the job itself is not affected by it
+async fn log_job_state(app_state: &SharedAppState, status: &JobStatus) {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let time_column = if status.state.is_finished() { "finished" } else { "started" };
+    let query = format!("UPDATE jobs SET state = :state, error = :error, {time_column} = :time WHERE id = :id");
+    if let Err(e) = qxsql.exec(&query, Some(&record_from_slice(&[
+        ("state", status.state.as_str().into()),
+        ("error", status.error.clone().map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("time", now().into()),
+        ("id", status.id.into()),
+    ]))).await {
+        error!("Failed to log job {} state: {e}", status.id);
+    }
+}
+
+/// Jobs left queued or running by previous daemon instance can never finish, mark them failed and prune old job log entries
+pub(crate) async fn fail_interrupted_jobs(db_pool: &async_sqlite::Pool) -> anyhow::Result<()> {
+    let qxsql = AppSqlApi::new_without_recchng(db_pool.clone());
+    qxsql.exec("UPDATE jobs SET state = :state, error = :error, finished = :time WHERE state IN ('queued', 'running')", Some(&record_from_slice(&[
+        ("state", JobState::Failed.as_str().into()),
+        ("error", "Interrupted by daemon restart".into()),
+        ("time", now().into()),
+    ]))).await?;
+    qxsql.exec("DELETE FROM jobs WHERE id <= (SELECT MAX(id) FROM jobs) - :keep", Some(&record_from_slice(&[
+        ("keep", KEEP_JOB_LOG.into()),
+    ]))).await?;
+    Ok(())
+}
+
+/// Persisted job log, newest jobs first
+pub(crate) async fn job_log(app_state: &SharedAppState, event_id: Option<EventId>, limit: i64) -> anyhow::Result<Vec<Record>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id, name, event_id, state, error, created, started, finished FROM jobs \
+        WHERE :event_id IS NULL OR event_id = :event_id ORDER BY id DESC LIMIT :limit", Some(&record_from_slice(&[
+        ("event_id", event_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("limit", limit.into()),
+    ]))).await?;
+    Ok(result.rows.iter()
+        .map(|row| result.fields.iter().map(|field| field.name.clone()).zip(row.iter().cloned()).collect())
+        .collect())
+}
+
+/// Insert job to the job log and queue it, returns job id and channel receiving final job status
+async fn enqueue_job<F, Fut>(app_state: SharedAppState, client_cmd_tx: ClientCommandSender, name: &str, event_id: Option<EventId>, job: F)
+    -> anyhow::Result<(JobId, channel::Receiver<JobStatus>)>
+where
+    F: FnOnce(JobProgress) -> Fut,
+    Fut: Future<Output = anyhow::Result<RpcValue>> + Send + 'static,
+{
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let id = qxsql.create_record_with_recchng("jobs", &record_from_slice(&[
+        ("name", name.into()),
+        ("event_id", event_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("state", JobState::Queued.as_str().into()),
+        ("created", now().into()),
+    ]), None).await?;
+    let cancel_token = CancelToken::new();
+    app_state.jobs.add(id, name, event_id, cancel_token.clone());
+    let progress = JobProgress { id, app_state, client_cmd_tx };
+    let job = job(progress.clone());
+    let name = name.to_string();
+    let (done_tx, done_rx) = channel::bounded(1);
+    smol::spawn(async move {
+        let app_state = progress.app_state.clone();
+        let run = async {
+            let _worker = app_state.jobs.workers.acquire().await;
+            if let Some(status) = progress.set_state(|status| status.state = JobState::Running) {
+                log_job_state(&app_state, &status).await;
+            }
+            job.await
+        };
+        let result = smol::future::or(async { Some(run.await) }, async {
+            cancel_token.cancelled().await;
+            None
+        }).await;
+        let status = progress.set_state(|status| match result {
+            Some(Ok(result)) => {
+                status.state = JobState::Finished;
+                status.result = Some(result);
+            }
+            Some(Err(e)) => {
+                status.state = JobState::Failed;
+                status.error = Some(e.to_string());
+            }
+            None => status.state = JobState::Cancelled,
+        });
+        if let Some(status) = status {
+            info!("Job {id} {name} {}", status.state.as_str());
+            log_job_state(&app_state, &status).await;
+            let _ = done_tx.send(status).await;
+        }
+    }).detach();
+    Ok((id, done_rx))
+}
+
+/// Queue `job` to run in background, returns job id immediately
+pub(crate) async fn spawn_job<F, Fut>(app_state: SharedAppState, client_cmd_tx: ClientCommandSender, name: &str, event_id: Option<EventId>, job: F) -> anyhow::Result<JobId>
+where
+    F: FnOnce(JobProgress) -> Fut,
+    Fut: Future<Output = anyhow::Result<RpcValue>> + Send + 'static,
+{
+    let (id, _done) = enqueue_job(app_state, client_cmd_tx, name, event_id, job).await?;
+    Ok(id)
+}
+
+/// Queue `job` and wait until it is finished, failed or cancelled
+pub(crate) async fn run_job<F, Fut>(app_state: SharedAppState, client_cmd_tx: ClientCommandSender, name: &str, event_id: Option<EventId>, job: F) -> anyhow::Result<JobStatus>
+where
+    F: FnOnce(JobProgress) -> Fut,
+    Fut: Future<Output = anyhow::Result<RpcValue>> + Send + 'static,
+{
+    let (id, done) = enqueue_job(app_state, client_cmd_tx, name, event_id, job).await?;
+    done.recv().await.map_err(|_| anyhow::anyhow!("Job {id} status lost"))
+}
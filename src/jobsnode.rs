@@ -0,0 +1,136 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::admin::backup_databases;
+use crate::jobs::{JobId, job_log, spawn_job};
+use crate::state::{EventId, SharedAppState};
+use crate::{anyhow_to_rpc_error, global_config, str_to_rpc_error};
+
+const METH_STATUS: &str = "status";
+const METH_CANCEL: &str = "cancel";
+const METH_LOG: &str = "log";
+const METH_BACKUP: &str = "backup";
+
+const JOB_STATUS_RESULT: &str = "{i:id,s:name,i|n:eventId,s:state,i:done,i:total,s|n:error,?:result}";
+
+/// Number of job log entries returned, when limit is not specified
+const DEFAULT_LOG_LIMIT: i64 = 100;
+
+pub(crate) const JOBS_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_STATUS, Flags::None, AccessLevel::Read, "i:jobId", JOB_STATUS_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_CANCEL, Flags::None, AccessLevel::Write, "i:jobId", "b:wasActive", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_LOG, Flags::None, AccessLevel::Read, "{i|n:eventId,i|n:limit}|n",
+        "[{i:id,s:name,i|n:event_id,s:state,s|n:error,s:created,s|n:started,s|n:finished}]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_BACKUP, Flags::None, AccessLevel::Service, "s|n:destDir", "i:jobId", &[], "",
+    ),
+];
+
+pub(crate) const JOB_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_STATUS, Flags::None, AccessLevel::Read, "", JOB_STATUS_RESULT, &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_CANCEL, Flags::None, AccessLevel::Write, "", "b:wasActive", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogParams {
+    #[serde(default)]
+    event_id: Option<EventId>,
+    #[serde(default)]
+    limit: Option<i64>,
+}
+impl_rpcvalue_conversions!(LogParams);
+
+fn job_status(app_state: &SharedAppState, job_id: JobId) -> Result<RpcValue, shvrpc::rpcmessage::RpcError> {
+    app_state.jobs.status(job_id)
+        .map(|status| RpcValue::from(&status))
+        .ok_or_else(|| str_to_rpc_error(&format!("Job id: {job_id} not found")))
+}
+
+/// Backup is written to `<data_dir>/backup/<timestamp>`, if destination is not specified
+fn backup_dest_dir(dest_dir: &str) -> String {
+    if dest_dir.is_empty() {
+        format!("{}/backup/{}", global_config().data_dir, chrono::Local::now().format("%Y%m%dT%H%M%S"))
+    } else {
+        dest_dir.to_string()
+    }
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+) -> RequestHandlerResult {
+    let shv_path = rq.shv_path().unwrap_or_default().to_string();
+    if shv_path.is_empty() {
+        return match Method::from_request(&rq) {
+            Method::Dir(dir) => dir.resolve(JOBS_NODE_METHODS),
+            Method::Ls(ls) => ls.resolve(JOBS_NODE_METHODS, async move || {
+                Ok(app_state.jobs.job_ids().into_iter().map(|id| id.to_string()).collect())
+            }),
+            Method::Other(m) => match m.method() {
+                METH_STATUS => m.resolve(JOBS_NODE_METHODS, async move || {
+                    job_status(&app_state, rq.param().unwrap_or_default().as_int())
+                }),
+                METH_CANCEL => m.resolve(JOBS_NODE_METHODS, async move || {
+                    Ok(RpcValue::from(app_state.jobs.cancel(rq.param().unwrap_or_default().as_int())))
+                }),
+                METH_LOG => m.resolve(JOBS_NODE_METHODS, async move || {
+                    let param = match rq.param() {
+                        Some(param) if !param.is_null() => LogParams::try_from(param).map_err(anyhow_to_rpc_error)?,
+                        _ => LogParams::default(),
+                    };
+                    let jobs = job_log(&app_state, param.event_id, param.limit.unwrap_or(DEFAULT_LOG_LIMIT)).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(to_rpcvalue(&jobs).expect("serde should work"))
+                }),
+                METH_BACKUP => m.resolve(JOBS_NODE_METHODS, async move || {
+                    let dest_dir = backup_dest_dir(rq.param().unwrap_or_default().as_str());
+                    let db_pool = app_state.db_pool.clone();
+                    let job_id = spawn_job(app_state, client_cmd_tx, METH_BACKUP, None, move |progress| async move {
+                        backup_databases(&db_pool, &dest_dir, Some(&progress)).await
+                            .map(|files| to_rpcvalue(&files).expect("serde should work"))
+                    }).await.map_err(anyhow_to_rpc_error)?;
+                    Ok(RpcValue::from(job_id))
+                }),
+                _ => err_unresolved_request(),
+            },
+        };
+    }
+    let Ok(job_id) = shv_path.parse::<JobId>() else {
+        warn!("Invalid path: {shv_path}");
+        return err_unresolved_request();
+    };
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(JOB_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(JOB_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => match m.method() {
+            METH_STATUS => m.resolve(JOB_NODE_METHODS, async move || {
+                job_status(&app_state, job_id)
+            }),
+            METH_CANCEL => m.resolve(JOB_NODE_METHODS, async move || {
+                Ok(RpcValue::from(app_state.jobs.cancel(job_id)))
+            }),
+            _ => err_unresolved_request(),
+        },
+    }
+}
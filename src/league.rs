@@ -0,0 +1,489 @@
+//! Series (league) of events scored together. Series and its events are stored in master DB, results are read
+//! from event DBs, when the standings are computed. Runner gets points for its place in class by the series
+//! points table, the best `best_count` results are summed. Runners are matched across events by registration,
+//! or by name if they have no registration, classes by name.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::bail;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::appsqlapi::AppSqlApi;
+use crate::eventdb::open_read_only_event_db;
+use crate::eventsqlapi::EventSqlApi;
+use crate::results::{RunFilter, RunResult, load_run_results};
+use crate::state::{EventId, SharedAppState, event_db_file};
+use crate::anyhow_to_rpc_error;
+
+pub(crate) const SERIES_SHV_PATH: &str = "series";
+
+const METH_CREATE: &str = "create";
+const METH_CONFIG: &str = "config";
+const METH_SET_CONFIG: &str = "setConfig";
+const METH_DELETE: &str = "delete";
+const METH_ADD_EVENT: &str = "addEvent";
+const METH_REMOVE_EVENT: &str = "removeEvent";
+const METH_STANDINGS: &str = "standings";
+const METH_EXPORT_CSV: &str = "exportCsv";
+
+/// Points for places used when series has no points table
+const DEFAULT_POINTS: &[i64] = &[25, 20, 16, 13, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1];
+
+pub(crate) const SERIES_ROOT_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_CREATE, Flags::None, AccessLevel::Service, "{s:name,[i]|n:points,i|n:bestCount}", "i:seriesId", &[], "",
+    ),
+];
+
+pub(crate) const SERIES_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_CONFIG, Flags::None, AccessLevel::Read, "", "{i:id,s:name,[i]:points,i|n:bestCount,[{i:eventId,i:stage}]:events}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_SET_CONFIG, Flags::None, AccessLevel::Service, "{s|n:name,[i]|n:points,i|n:bestCount}", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DELETE, Flags::None, AccessLevel::Service, "", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_ADD_EVENT, Flags::None, AccessLevel::Service, "{i:eventId,i|n:stage}", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_REMOVE_EVENT, Flags::None, AccessLevel::Service, "{i:eventId,i|n:stage}", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_STANDINGS, Flags::None, AccessLevel::Read, "",
+        "{[{s:className,[{i:place,s:name,s:registration,s:club,i:total,[{i:eventId,i:stage,i:place,i:points,b:counted}]:results}]:runners}]:classes,[{i:eventId,s:reason}]:skippedEvents}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_EXPORT_CSV, Flags::None, AccessLevel::Read, "", "s", &[], "",
+    ),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateSeriesParams {
+    name: String,
+    #[serde(default)]
+    points: Option<Vec<i64>>,
+    /// All results are counted if not set
+    #[serde(default)]
+    best_count: Option<i64>,
+}
+impl_rpcvalue_conversions!(CreateSeriesParams);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetSeriesConfigParams {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    points: Option<Vec<i64>>,
+    /// Zero counts all results
+    #[serde(default)]
+    best_count: Option<i64>,
+}
+impl_rpcvalue_conversions!(SetSeriesConfigParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeriesEventParams {
+    event_id: EventId,
+    #[serde(default)]
+    stage: Option<i64>,
+}
+impl_rpcvalue_conversions!(SeriesEventParams);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SeriesEvent {
+    pub event_id: EventId,
+    pub stage: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SeriesConfig {
+    pub id: i64,
+    pub name: String,
+    pub points: Vec<i64>,
+    pub best_count: Option<i64>,
+    pub events: Vec<SeriesEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SeriesEventResult {
+    pub event_id: EventId,
+    pub stage: i64,
+    pub place: usize,
+    pub points: i64,
+    /// Result is one of the best results counted to the total
+    pub counted: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SeriesRunner {
+    pub place: usize,
+    pub name: String,
+    pub registration: String,
+    pub club: String,
+    pub total: i64,
+    pub results: Vec<SeriesEventResult>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SeriesClass {
+    pub class_name: String,
+    pub runners: Vec<SeriesRunner>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SkippedSeriesEvent {
+    pub event_id: EventId,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SeriesStandings {
+    pub classes: Vec<SeriesClass>,
+    /// Events, which results could not be read, they are not scored
+    pub skipped_events: Vec<SkippedSeriesEvent>,
+}
+
+fn points_to_string(points: &[i64]) -> String {
+    points.iter().map(i64::to_string).collect::<Vec<_>>().join(",")
+}
+
+fn points_from_string(points: &str) -> Vec<i64> {
+    points.split(',').filter_map(|p| p.trim().parse::<i64>().ok()).collect()
+}
+
+fn check_points(points: &[i64]) -> anyhow::Result<()> {
+    if points.iter().any(|p| *p < 0) {
+        bail!("Points cannot be negative");
+    }
+    Ok(())
+}
+
+async fn create_series(app_state: &SharedAppState, param: &CreateSeriesParams) -> anyhow::Result<i64> {
+    let name = param.name.trim();
+    if name.is_empty() {
+        bail!("Series name cannot be empty");
+    }
+    let points = param.points.clone().unwrap_or_default();
+    check_points(&points)?;
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    if qxsql.query("SELECT id FROM series WHERE name = :name", Some(&record_from_slice(&[("name", name.into())]))).await?.row_count() > 0 {
+        bail!("Series '{name}' already exists");
+    }
+    qxsql.create_record("series", &record_from_slice(&[
+        ("name", name.into()),
+        ("points", points_to_string(&points).into()),
+        ("best_count", param.best_count.filter(|n| *n > 0).map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("created", chrono::Local::now().fixed_offset().to_rfc3339().into()),
+    ])).await
+}
+
+async fn list_series(app_state: &SharedAppState) -> anyhow::Result<Vec<String>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id FROM series ORDER BY id", None).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .map(|id| id.to_string())
+        .collect())
+}
+
+async fn series_config(app_state: &SharedAppState, series_id: i64) -> anyhow::Result<Option<SeriesConfig>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let params = record_from_slice(&[("series_id", series_id.into())]);
+    let result = qxsql.query("SELECT name, points, best_count FROM series WHERE id = :series_id", Some(&params)).await?;
+    if result.row_count() == 0 {
+        return Ok(None);
+    }
+    let points = points_from_string(result.value(0, 1).and_then(|v| v.as_str()).unwrap_or_default());
+    let mut config = SeriesConfig {
+        id: series_id,
+        name: result.value(0, 0).and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+        points: if points.is_empty() { DEFAULT_POINTS.to_vec() } else { points },
+        best_count: result.value(0, 2).and_then(|v| v.to_int()),
+        events: vec![],
+    };
+    let result = qxsql.query("SELECT series_events.event_id, series_events.stage FROM series_events \
+        JOIN events ON events.id = series_events.event_id \
+        WHERE series_events.series_id = :series_id ORDER BY events.date, series_events.event_id, series_events.stage", Some(&params)).await?;
+    config.events = (0..result.row_count())
+        .filter_map(|row| Some(SeriesEvent {
+            event_id: result.value(row, 0).and_then(|v| v.to_int())?,
+            stage: result.value(row, 1).and_then(|v| v.to_int()).unwrap_or(1),
+        }))
+        .collect();
+    Ok(Some(config))
+}
+
+async fn set_series_config(app_state: &SharedAppState, series_id: i64, param: &SetSeriesConfigParams) -> anyhow::Result<bool> {
+    let mut fields = vec![];
+    if let Some(name) = &param.name {
+        if name.trim().is_empty() {
+            bail!("Series name cannot be empty");
+        }
+        fields.push(("name", DbValue::from(name.trim())));
+    }
+    if let Some(points) = &param.points {
+        check_points(points)?;
+        fields.push(("points", points_to_string(points).into()));
+    }
+    if let Some(best_count) = param.best_count {
+        fields.push(("best_count", Some(best_count).filter(|n| *n > 0).map(DbValue::from).unwrap_or(DbValue::Null)));
+    }
+    if fields.is_empty() {
+        return Ok(false);
+    }
+    let assignments = fields.iter().map(|(field, _)| format!("{field} = :{field}")).collect::<Vec<_>>().join(", ");
+    fields.push(("id", series_id.into()));
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.exec(&format!("UPDATE series SET {assignments} WHERE id = :id"), Some(&record_from_slice(&fields))).await?;
+    Ok(result.rows_affected > 0)
+}
+
+async fn delete_series(app_state: &SharedAppState, series_id: i64) -> anyhow::Result<bool> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let params = record_from_slice(&[("series_id", series_id.into())]);
+    qxsql.exec("DELETE FROM series_events WHERE series_id = :series_id", Some(&params)).await?;
+    Ok(qxsql.exec("DELETE FROM series WHERE id = :series_id", Some(&params)).await?.rows_affected > 0)
+}
+
+async fn set_series_event(app_state: &SharedAppState, series_id: i64, param: &SeriesEventParams, is_member: bool) -> anyhow::Result<bool> {
+    // fails for not existing event
+    app_state.event_record(param.event_id).await?;
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let query = if is_member {
+        "INSERT OR IGNORE INTO series_events (series_id, event_id, stage) VALUES (:series_id, :event_id, :stage)"
+    } else {
+        "DELETE FROM series_events WHERE series_id = :series_id AND event_id = :event_id AND stage = :stage"
+    };
+    let result = qxsql.exec(query, Some(&record_from_slice(&[
+        ("series_id", series_id.into()),
+        ("event_id", param.event_id.into()),
+        ("stage", param.stage.unwrap_or(1).into()),
+    ]))).await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Final results of event stage, closed local events are read from their DB file
+async fn event_results(app_state: &SharedAppState, event: &SeriesEvent, rpc_client: ClientCommandSender) -> anyhow::Result<Vec<RunResult>> {
+    // event is not opened, while its closed DB is read
+    let event_lock = app_state.event_lock(event.event_id);
+    let _event_guard = event_lock.lock().await;
+    if app_state.is_event_open(event.event_id) {
+        let sql_api = EventSqlApi::new(event.event_id, app_state.clone(), rpc_client);
+        return load_run_results(&sql_api, event.stage, RunFilter::Stage).await;
+    }
+    if !app_state.event_record(event.event_id).await?.is_local {
+        bail!("Remote event is not open");
+    }
+    let db_file = event_db_file(event.event_id);
+    if !Path::new(&db_file).exists() {
+        bail!("Event DB file does not exist");
+    }
+    // closed DB is only read, DB closed by older version is skipped rather than migrated
+    let pool = open_read_only_event_db(&db_file).await?;
+    load_run_results(&AppSqlApi::new_without_recchng(pool), event.stage, RunFilter::Stage).await
+}
+
+pub(crate) async fn series_standings(app_state: &SharedAppState, config: &SeriesConfig, rpc_client: ClientCommandSender) -> anyhow::Result<SeriesStandings> {
+    let mut standings = SeriesStandings::default();
+    // class name -> runner key -> runner
+    let mut classes: BTreeMap<String, BTreeMap<String, SeriesRunner>> = BTreeMap::new();
+    for event in &config.events {
+        let results = match event_results(app_state, event, rpc_client.clone()).await {
+            Ok(results) => results,
+            Err(e) => {
+                standings.skipped_events.push(SkippedSeriesEvent { event_id: event.event_id, reason: e.to_string() });
+                continue;
+            }
+        };
+        for run in results {
+            let Some(place) = run.place else {
+                continue;
+            };
+            let key = if run.registration.trim().is_empty() { run.name() } else { run.registration.trim().to_string() };
+            let runner = classes.entry(run.class_name.clone()).or_default()
+                .entry(key)
+                .or_insert_with(|| SeriesRunner {
+                    place: 0,
+                    name: run.name(),
+                    registration: run.registration.clone(),
+                    club: run.club.clone(),
+                    total: 0,
+                    results: vec![],
+                });
+            runner.results.push(SeriesEventResult {
+                event_id: event.event_id,
+                stage: event.stage,
+                place,
+                points: config.points.get(place - 1).copied().unwrap_or_default(),
+                counted: false,
+            });
+        }
+    }
+    let best_count = config.best_count.and_then(|n| usize::try_from(n).ok()).filter(|n| *n > 0);
+    for (class_name, runners) in classes {
+        let mut runners: Vec<SeriesRunner> = runners.into_values()
+            .map(|mut runner| {
+                let mut best: Vec<usize> = (0..runner.results.len()).collect();
+                best.sort_by_key(|i| std::cmp::Reverse(runner.results[*i].points));
+                best.truncate(best_count.unwrap_or(best.len()));
+                for i in best {
+                    runner.results[i].counted = true;
+                    runner.total += runner.results[i].points;
+                }
+                runner
+            })
+            .collect();
+        runners.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+        let mut prev: Option<(i64, usize)> = None;
+        for (index, runner) in runners.iter_mut().enumerate() {
+            // equal totals share the place
+            runner.place = match prev {
+                Some((total, place)) if total == runner.total => place,
+                _ => index + 1,
+            };
+            prev = Some((runner.total, runner.place));
+        }
+        standings.classes.push(SeriesClass { class_name, runners });
+    }
+    Ok(standings)
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([';', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Standings as CSV with `;` separator, one column of points for each series event
+pub(crate) fn standings_to_csv(config: &SeriesConfig, standings: &SeriesStandings) -> String {
+    let mut csv = String::from("class;place;name;registration;club;total");
+    for event in &config.events {
+        csv.push_str(&format!(";{}/{}", event.event_id, event.stage));
+    }
+    csv.push('\n');
+    for class in &standings.classes {
+        for runner in &class.runners {
+            let (place, total) = (runner.place.to_string(), runner.total.to_string());
+            let mut line = [class.class_name.as_str(), place.as_str(), runner.name.as_str(), runner.registration.as_str(), runner.club.as_str(), total.as_str()]
+                .map(csv_field)
+                .join(";");
+            for event in &config.events {
+                line.push(';');
+                if let Some(result) = runner.results.iter().find(|r| r.event_id == event.event_id && r.stage == event.stage) {
+                    line.push_str(&result.points.to_string());
+                }
+            }
+            csv.push_str(&line);
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+async fn existing_series_config(app_state: &SharedAppState, series_id: i64) -> anyhow::Result<SeriesConfig> {
+    series_config(app_state, series_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Series id: {series_id} not found"))
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+) -> RequestHandlerResult {
+    let shv_path = rq.shv_path().unwrap_or_default().to_string();
+    if shv_path.is_empty() {
+        return match Method::from_request(&rq) {
+            Method::Dir(dir) => dir.resolve(SERIES_ROOT_METHODS),
+            Method::Ls(ls) => ls.resolve(SERIES_ROOT_METHODS, async move || {
+                list_series(&app_state).await.map_err(anyhow_to_rpc_error)
+            }),
+            Method::Other(m) => match m.method() {
+                METH_CREATE => m.resolve(SERIES_ROOT_METHODS, async move || {
+                    let param = CreateSeriesParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    create_series(&app_state, &param).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                _ => err_unresolved_request(),
+            },
+        };
+    }
+    let Ok(series_id) = shv_path.parse::<i64>() else {
+        log::warn!("Invalid path: {shv_path}");
+        return err_unresolved_request();
+    };
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(SERIES_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(SERIES_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => match m.method() {
+            METH_CONFIG => m.resolve(SERIES_NODE_METHODS, async move || {
+                let config = existing_series_config(&app_state, series_id).await
+                    .map_err(anyhow_to_rpc_error)?;
+                Ok(to_rpcvalue(&config).expect("serde should work"))
+            }),
+            METH_SET_CONFIG => m.resolve(SERIES_NODE_METHODS, async move || {
+                let param = SetSeriesConfigParams::try_from(rq.param())
+                    .map_err(anyhow_to_rpc_error)?;
+                set_series_config(&app_state, series_id, &param).await
+                    .map(RpcValue::from)
+                    .map_err(anyhow_to_rpc_error)
+            }),
+            METH_DELETE => m.resolve(SERIES_NODE_METHODS, async move || {
+                delete_series(&app_state, series_id).await
+                    .map(RpcValue::from)
+                    .map_err(anyhow_to_rpc_error)
+            }),
+            METH_ADD_EVENT | METH_REMOVE_EVENT => m.resolve(SERIES_NODE_METHODS, async move || {
+                let param = SeriesEventParams::try_from(rq.param())
+                    .map_err(anyhow_to_rpc_error)?;
+                existing_series_config(&app_state, series_id).await
+                    .map_err(anyhow_to_rpc_error)?;
+                set_series_event(&app_state, series_id, &param, rq.method() == Some(METH_ADD_EVENT)).await
+                    .map(RpcValue::from)
+                    .map_err(anyhow_to_rpc_error)
+            }),
+            METH_STANDINGS => m.resolve(SERIES_NODE_METHODS, async move || {
+                let config = existing_series_config(&app_state, series_id).await
+                    .map_err(anyhow_to_rpc_error)?;
+                let standings = series_standings(&app_state, &config, client_cmd_tx).await
+                    .map_err(anyhow_to_rpc_error)?;
+                Ok(to_rpcvalue(&standings).expect("serde should work"))
+            }),
+            METH_EXPORT_CSV => m.resolve(SERIES_NODE_METHODS, async move || {
+                let config = existing_series_config(&app_state, series_id).await
+                    .map_err(anyhow_to_rpc_error)?;
+                let standings = series_standings(&app_state, &config, client_cmd_tx).await
+                    .map_err(anyhow_to_rpc_error)?;
+                Ok(RpcValue::from(standings_to_csv(&config, &standings)))
+            }),
+            _ => err_unresolved_request(),
+        },
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::OnceLock;
+
+use flexi_logger::{Age, Cleanup, Criterion, Duplicate, FileSpec, Logger, LoggerHandle, Naming};
+
+use crate::config::{Config, LogRotation};
+
+static LOGGER_HANDLE: OnceLock<LoggerHandle> = OnceLock::new();
+
+/// Modules declared in `main.rs`, their paths are prefixed by the crate name in log spec, new module has to be added here
+const LOCAL_MODULES: &[&str] = &[
+    "state", "config", "migrate", "appsqlapi", "eventsqlapi", "appnode", "eventctlnode", "eventconfignode",
+    "eventenumznode", "eventfinishnode", "eventrunsnode", "eventdrawnode", "eventcoursesnode", "eventexportnode",
+    "eventcardsnode", "evententriesnode", "eventpaymentsnode", "eventtimesyncnode", "eventhistorynode",
+    "eventsimulatenode", "eventrulesnode", "eventresultsnode", "eventspeakernode", "eventprotestsnode",
+    "eventpenaltiesnode", "eventreportsnode", "eventmapsnode", "eventdb", "eventsecrets", "qbeimport", "organizations",
+    "league", "quota", "anonymize", "personaldata", "selfservice", "notify", "webhooks", "mqtt", "gateway", "apischema",
+    "reconcile", "jobs", "jobsnode", "ratelimit", "sqlstats", "maintenance", "diskspace", "heartbeat", "selftest",
+    "simulate", "traffic", "rules", "encryption", "results", "views", "speaker", "penalties", "discipline", "publish",
+    "pdf", "qxchange", "logger", "rqtrace", "rpccall", "qxsqld", "admin",
+];
+
+fn is_local_module(module: &str) -> bool {
+    LOCAL_MODULES.contains(&module.split("::").next().unwrap_or_default())
+}
+
+/// Convert verbose CLI string in format `module:L,module2:L,...` to flexi_logger spec.
+///
+/// Level `L` is one of `T`, `D`, `I`, `W`, `E` or the full level name,
+/// empty module name sets the default level, for example `:D,eventctlnode:T`.
+/// Modules of this crate are prefixed by its name, other crates like `shvclient` are passed as they are.
+pub fn verbose_to_log_spec(verbose: Option<&str>) -> String {
+    let mut default_level = "info".to_string();
+    let mut modules = Vec::new();
+    for module_level in verbose.unwrap_or_default().split(',') {
+        let module_level = module_level.trim();
+        if module_level.is_empty() {
+            continue;
+        }
+        let (module, level) = module_level.split_once(':').unwrap_or((module_level, "T"));
+        let level = match level.to_ascii_uppercase().as_str() {
+            "T" | "TRACE" => "trace",
+            "D" | "DEBUG" => "debug",
+            "I" | "INFO" => "info",
+            "W" | "WARN" => "warn",
+            "E" | "ERROR" => "error",
+            "O" | "OFF" => "off",
+            _ => "debug",
+        };
+        match module {
+            "" | "." => default_level = level.to_string(),
+            module if is_local_module(module) => modules.push(format!("{}::{module}={level}", env!("CARGO_CRATE_NAME"))),
+            module => modules.push(format!("{module}={level}")),
+        }
+    }
+    std::iter::once(default_level).chain(modules).collect::<Vec<_>>().join(",")
+}
+
+pub fn setup_logger(verbose: Option<&str>, config: &Config) -> anyhow::Result<()> {
+    let logger = Logger::try_with_str(verbose_to_log_spec(verbose))?;
+    let logging = &config.logging;
+    let log_dir = logging.directory.clone().or_else(|| {
+        if config.data_dir.is_empty() {
+            None
+        } else {
+            Some(format!("{}/log", config.data_dir))
+        }
+    });
+    let logger = match log_dir {
+        Some(log_dir) if logging.log_to_file => {
+            let criterion = match (logging.rotation, logging.rotate_size_mb) {
+                (LogRotation::Daily, 0) => Criterion::Age(Age::Day),
+                (LogRotation::Hourly, 0) => Criterion::Age(Age::Hour),
+                (LogRotation::Daily, size) => Criterion::AgeOrSize(Age::Day, size * 1024 * 1024),
+                (LogRotation::Hourly, size) => Criterion::AgeOrSize(Age::Hour, size * 1024 * 1024),
+                (LogRotation::Size, size) => Criterion::Size(size.max(1) * 1024 * 1024),
+            };
+            let cleanup = match logging.keep_files {
+                0 => Cleanup::Never,
+                n => Cleanup::KeepLogFiles(n),
+            };
+            logger
+                .log_to_file(FileSpec::default().directory(log_dir).basename(env!("CARGO_PKG_NAME")))
+                .rotate(criterion, Naming::Timestamps, cleanup)
+                .append()
+                .duplicate_to_stderr(Duplicate::All)
+                .format_for_files(flexi_logger::detailed_format)
+                .format_for_stderr(flexi_logger::colored_detailed_format)
+        }
+        _ => logger
+            .log_to_stderr()
+            .format_for_stderr(flexi_logger::colored_detailed_format),
+    };
+    let handle = logger.start()?;
+    if LOGGER_HANDLE.set(handle).is_err() {
+        anyhow::bail!("Logger should be initialized only once");
+    }
+    Ok(())
+}
+
+/// Replace current log specification, `verbose` has the same format as the `--verbose` CLI option.
+///
+/// Returns the effective flexi_logger spec.
+pub fn set_log_level(verbose: &str) -> anyhow::Result<String> {
+    let handle = LOGGER_HANDLE.get().ok_or_else(|| anyhow::anyhow!("Logger is not initialized"))?;
+    let spec = verbose_to_log_spec(Some(verbose));
+    handle.parse_new_spec(&spec)?;
+    Ok(spec)
+}
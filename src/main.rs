@@ -1,19 +1,22 @@
-use std::{backtrace::Backtrace, sync::OnceLock};
+use std::{backtrace::Backtrace, sync::{Mutex, OnceLock}};
 use clap::Parser;
 use log::{error, info, warn};
 use qxsql::{QxSqlApiRecChng, RecDeleteParam, RecInsertParam, RecListParam, RecReadParam, RecUpdateParam, string_list_to_ref_vec};
-use qxsql::sql::{CREATE_PARAMS, CREATE_RESULT, DELETE_PARAMS, DELETE_RESULT, EXEC_PARAMS, EXEC_RESULT, LIST_PARAMS, LIST_RESULT, READ_PARAMS, READ_RESULT, UPDATE_PARAMS, UPDATE_RESULT};
-use shvclient::clientapi::CallRpcMethodError;
+use qxsql::sql::{CREATE_PARAMS, CREATE_RESULT, DELETE_PARAMS, DELETE_RESULT, EXEC_RESULT, LIST_PARAMS, LIST_RESULT, READ_PARAMS, READ_RESULT, UPDATE_PARAMS, UPDATE_RESULT};
 use shvclient::{ClientCommandSender, ClientEvent, ClientEventsReceiver};
 use shvclient::appnodes::{DotDeviceNode};
 use shvrpc::{RpcMessage, RpcMessageMetaTags};
 use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
-use smol::{lock::RwLock, channel};
+use smol::channel;
 use futures::{select, FutureExt};
 use url::Url;
 
 use crate::appnode::AppNode;
-use crate::appsqlapi::AppSqlApi;
+use crate::appsqlapi::{AppSqlApi, EXEC_SCRIPT_PARAMS, EXEC_SCRIPT_RESULT, ExecScriptParams, EXPLAIN_PARAMS, EXPLAIN_RESULT, ExplainParams, SQL_QUERY_PARAMS, SqlQueryParams, UPSERT_PARAMS, UPSERT_RESULT, UpsertParams};
+use crate::jobs::{JOBS_SHV_PATH, Jobs};
+use crate::league::SERIES_SHV_PATH;
+use crate::webhooks::WEBHOOKS_SHV_PATH;
+use crate::ratelimit::{LimitedNode, RateLimitedNode};
 use crate::state::SharedAppState;
 use crate::{
     state::{State},
@@ -21,10 +24,11 @@ use crate::{
     migrate::create_db_connection,
 };
 use shvproto::{RpcValue, to_rpcvalue};
-use qxsql::{sql::{QxSqlApi, QUERY_PARAMS, QUERY_RESULT, QueryAndParams}};
+use qxsql::{sql::{QxSqlApi, QUERY_RESULT}};
 
 #[macro_use]
-mod macros;
+extern crate qxeventd_api;
+
 mod state;
 mod config;
 mod migrate;
@@ -32,8 +36,66 @@ mod appsqlapi;
 mod eventsqlapi;
 mod appnode;
 mod eventctlnode;
+mod eventconfignode;
+mod eventenumznode;
+mod eventfinishnode;
+mod eventrunsnode;
+mod eventdrawnode;
+mod eventcoursesnode;
+mod eventexportnode;
+mod eventcardsnode;
+mod evententriesnode;
+mod eventpaymentsnode;
+mod eventtimesyncnode;
+mod eventhistorynode;
+mod eventsimulatenode;
+mod eventrulesnode;
+mod eventresultsnode;
+mod eventspeakernode;
+mod eventprotestsnode;
+mod eventpenaltiesnode;
+mod eventreportsnode;
+mod eventmapsnode;
 mod eventdb;
+mod eventsecrets;
+mod qbeimport;
+mod organizations;
+mod league;
+mod quota;
+mod anonymize;
+mod personaldata;
+mod selfservice;
+mod notify;
+mod webhooks;
+mod mqtt;
+mod gateway;
+mod apischema;
+mod reconcile;
+mod jobs;
+mod jobsnode;
+mod ratelimit;
+mod sqlstats;
+mod maintenance;
+mod diskspace;
+mod heartbeat;
+mod selftest;
+mod simulate;
+mod traffic;
+mod rules;
+mod encryption;
+mod results;
+mod views;
+mod speaker;
+mod penalties;
+mod discipline;
+mod publish;
+mod pdf;
 mod qxchange;
+mod logger;
+mod rqtrace;
+mod rpccall;
+mod qxsqld;
+mod admin;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -65,13 +127,34 @@ struct Opts {
     #[arg(long)]
     event_expire_duration: Option<String>,
 
-    /// Print effective config
+    /// Print effective config, secrets are masked
     #[arg(long)]
     print_config: bool,
 
+    /// Format of printed config
+    #[arg(long, value_enum, default_value_t = ConfigFormat::Yaml)]
+    format: ConfigFormat,
+
+    /// Validate config URLs, directories and mount points and exit without starting the client
+    #[arg(long)]
+    check_config: bool,
+
+    /// Write log to rotated files in addition to stderr
+    #[arg(long)]
+    log_to_file: bool,
+
     /// Verbose mode (module, .)
     #[arg(short, long)]
     verbose: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<admin::Command>,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ConfigFormat {
+    Yaml,
+    Json,
 }
 
 static GLOBAL_CONFIG: OnceLock<Config> = OnceLock::new();
@@ -83,7 +166,21 @@ fn global_config() -> &'static Config {
 fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     let cli_opts = Opts::parse();
 
-    qxsql::setup_flexi_logger(cli_opts.verbose.as_deref())?;
+    // config must be loaded before logger setup, because it contains logging settings
+    let mut config = if let Some(config_path) = &cli_opts.config {
+        let f = std::fs::File::open(config_path)?;
+        serde_yaml::from_reader(f)?
+    } else {
+        crate::config::Config::default()
+    };
+    if let Some(data_dir) = cli_opts.data_directory {
+        config.data_dir = data_dir;
+    }
+    if cli_opts.log_to_file {
+        config.logging.log_to_file = true;
+    }
+
+    logger::setup_logger(cli_opts.verbose.as_deref(), &config)?;
 
     log::info!("=====================================================");
     log::info!("{} starting", env!("CARGO_PKG_NAME"));
@@ -95,20 +192,13 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     // log::debug!("DEBUG");
     // log::trace!("TRACE");
 
-    let mut config = if let Some(config_path) = cli_opts.config {
-        info!("Loading config file {config_path}");
-        let f = std::fs::File::open(config_path)?;
-        serde_yaml::from_reader(f)?
-    } else {
-        crate::config::Config::default()
-    };
+    if let Some(config_path) = &cli_opts.config {
+        info!("Loaded config file {config_path}");
+    }
 
     if let Some(url) = cli_opts.url {
         config.client.url = Url::parse(&url)?;
     }
-    if let Some(data_dir) = cli_opts.data_directory {
-        config.data_dir = data_dir;
-    }
     if let Some(mount) = cli_opts.mount {
         config.client.mount = Some(mount);
     }
@@ -125,19 +215,54 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    config.apply_tls_config();
+
     info!("local events data directory: {:?}", config.data_dir);
     info!("qxevent mount point: {:?}", config.client.mount);
     info!("qxsql events mount point base: {}", config.remote_events_mount_point);
 
     if cli_opts.print_config {
-        let yaml = serde_yaml::to_string(&config)?;
-        println!("{}", yaml);
+        let config = config.to_redacted_value()?;
+        let text = match cli_opts.format {
+            ConfigFormat::Yaml => serde_yaml::to_string(&config)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(&config)?,
+        };
+        println!("{}", text);
         return Ok(());
     }
+    if cli_opts.check_config {
+        let errors = config.check();
+        if errors.is_empty() {
+            println!("Config OK");
+            return Ok(());
+        }
+        for err in &errors {
+            eprintln!("{err}");
+        }
+        return Err(format!("Config check failed, {} error(s) found", errors.len()).into());
+    }
 
     GLOBAL_CONFIG
         .set(config)
         .expect("Global config should only be set once");
+    encryption::init()?;
+
+    if let Some(command) = cli_opts.command
+        && !matches!(command, admin::Command::Run) {
+        smol::block_on(admin::run_command(command))?;
+        return Ok(());
+    }
+
+    let transport_errors = global_config().check_transport();
+    if !transport_errors.is_empty() {
+        for err in &transport_errors {
+            error!("{err}");
+        }
+        return Err(format!("Invalid broker connection config, {} error(s) found", transport_errors.len()).into());
+    }
+    if global_config().is_tls_insecure() {
+        warn!("Broker TLS certificate is not verified, 'insecure' TLS option is set");
+    }
 
     // Run the async application
     const SMOL_THREADS: &str = "SMOL_THREADS";
@@ -155,40 +280,60 @@ struct SqlNode {
 
 shvclient::impl_static_node! {
     SqlNode(&self, request, rpc_client) {
-        "query" [None, Read, QUERY_PARAMS, QUERY_RESULT] (query: QueryAndParams) => {
-            let qxsql = AppSqlApi::new(self.app_state.read().await.db_pool.clone(), rpc_client.clone());
-            let result = qxsql.query(query.query(), query.params()).await;
+        "query" [None, Read, SQL_QUERY_PARAMS, QUERY_RESULT] (query: SqlQueryParams) => {
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
+            let result = qxsql.query(&query.query, query.params.as_ref()).await;
+            Some(res_to_rpcvalue(result))
+        }
+        "exec" [None, Read, SQL_QUERY_PARAMS, EXEC_RESULT] (query: SqlQueryParams) => {
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
+            let result = qxsql.exec(&query.query, query.params.as_ref()).await;
+            Some(res_to_rpcvalue(result))
+        }
+        "execReturning" [None, Write, SQL_QUERY_PARAMS, QUERY_RESULT] (query: SqlQueryParams) => {
+            let qxsql = AppSqlApi::new_without_recchng(self.app_state.db_pool.clone());
+            let result = qxsql.exec_returning(&query.query, query.params.as_ref()).await;
             Some(res_to_rpcvalue(result))
         }
-        "exec" [None, Read, EXEC_PARAMS, EXEC_RESULT] (query: QueryAndParams) => {
-            let qxsql = AppSqlApi::new(self.app_state.read().await.db_pool.clone(), rpc_client.clone());
-            let result = qxsql.exec(query.query(), query.params()).await;
+        "execScript" [None, Write, EXEC_SCRIPT_PARAMS, EXEC_SCRIPT_RESULT] (param: ExecScriptParams) => {
+            let qxsql = AppSqlApi::new_without_recchng(self.app_state.db_pool.clone());
+            let result = qxsql.exec_script(&param.script).await;
+            Some(res_to_rpcvalue(result))
+        }
+        "explain" [None, Service, EXPLAIN_PARAMS, EXPLAIN_RESULT] (param: ExplainParams) => {
+            let qxsql = AppSqlApi::new_without_recchng(self.app_state.db_pool.clone());
+            let result = qxsql.explain(&param.query, param.params.as_ref(), param.analyze).await;
             Some(res_to_rpcvalue(result))
         }
         "list" [None, Read, LIST_PARAMS, LIST_RESULT] (param: RecListParam) => {
-            let qxsql = AppSqlApi::new(self.app_state.read().await.db_pool.clone(), rpc_client.clone());
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
             let fields = string_list_to_ref_vec(&param.fields);
             let result = qxsql.list_records(&param.table, fields, param.ids_above, param.limit).await;
             Some(res_to_rpcvalue(result))
         }
         "create" [None, Write, CREATE_PARAMS, CREATE_RESULT] (param: RecInsertParam) => {
-            let qxsql = AppSqlApi::new(self.app_state.read().await.db_pool.clone(), rpc_client.clone());
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
             let insert_id = qxsql.create_record_with_recchng(&param.table, &param.record, issuer(&request)).await;
             Some(res_to_rpcvalue(insert_id))
         }
+        "upsert" [None, Write, UPSERT_PARAMS, UPSERT_RESULT] (param: UpsertParams) => {
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
+            let result = qxsql.upsert_records(&param.0, &param.1, &param.2, None).await;
+            Some(res_to_rpcvalue(result))
+        }
         "read" [None, Read, READ_PARAMS, READ_RESULT] (param: RecReadParam) => {
-            let qxsql = AppSqlApi::new(self.app_state.read().await.db_pool.clone(), rpc_client.clone());
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
             let fields = string_list_to_ref_vec(&param.fields);
             let result = qxsql.read_record(&param.table, param.id, fields).await;
             Some(res_to_rpcvalue(result))
         }
         "update" [None, Write, UPDATE_PARAMS, UPDATE_RESULT] (param: RecUpdateParam) => {
-            let qxsql = AppSqlApi::new(self.app_state.read().await.db_pool.clone(), rpc_client.clone());
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
             let update_success = qxsql.update_record_with_recchng(&param.table, param.id, &param.record, issuer(&request)).await;
             Some(res_to_rpcvalue(update_success))
         }
         "delete" [None, Write, DELETE_PARAMS, DELETE_RESULT] (param: RecDeleteParam) => {
-            let qxsql = AppSqlApi::new(self.app_state.read().await.db_pool.clone(), rpc_client.clone());
+            let qxsql = AppSqlApi::new(self.app_state.db_pool.clone(), rpc_client.clone());
             let was_deleted = qxsql.delete_record_with_recchng(&param.table, param.id, issuer(&request)).await;
             Some(res_to_rpcvalue(was_deleted))
         }
@@ -197,17 +342,39 @@ shvclient::impl_static_node! {
 
 async fn async_main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     let db_pool = create_db_connection().await?;
-    let (shutdown_sender, shutdown_receiver) = channel::bounded(1);
-    let app_state = SharedAppState::new(RwLock::new(State {
-        db_pool,
-        open_events: Default::default(),
-        shutdown_sender: Some(shutdown_sender),
-    }));
+    jobs::fail_interrupted_jobs(&db_pool).await?;
     let config = GLOBAL_CONFIG
         .get()
         .expect("Global config should be initialized");
+    let (shutdown_sender, shutdown_receiver) = channel::bounded(1);
+    let app_state = SharedAppState::new(State {
+        db_pool,
+        open_events: Default::default(),
+        shutdown_sender: Mutex::new(Some(shutdown_sender)),
+        event_record_cache: Default::default(),
+        event_locks: Default::default(),
+        jobs: Jobs::new(config.jobs.workers),
+        rate_limiter: Default::default(),
+        maintenance: Default::default(),
+        disk_space: Default::default(),
+        simulations: Default::default(),
+    });
+    smol::spawn(maintenance::scheduler(app_state.clone())).detach();
+    mqtt::start();
+    if config.reconcile.on_start {
+        // broken events must be marked before they are reopened
+        match reconcile::reconcile_events(&app_state, config.reconcile.repair).await {
+            Ok(report) if report.is_consistent() => info!("Events and event directories are consistent"),
+            Ok(report) => warn!("Events and event directories are not consistent, missing directories: {:?}, orphaned directories: {:?}",
+                report.missing_dirs, report.orphaned_dirs),
+            Err(e) => error!("Consistency check of events failed: {e}"),
+        }
+    }
 
     let app_state2 = app_state.clone();
+    let app_state3 = app_state.clone();
+    let app_state4 = app_state.clone();
+    let app_state5 = app_state.clone();
     let app_tasks = {
         let app_state = app_state2.clone();
         move |client_cmd_tx, client_evt_rx| {
@@ -218,10 +385,19 @@ async fn async_main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     let ret = shvclient::Client::new()
         .device(DotDeviceNode::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), Some("00000".into())))
         .mount_static(".app", AppNode::new(env!("CARGO_PKG_NAME"), app_state.clone()))
-        .mount_static("sql", SqlNode { app_state: app_state.clone() })
+        .mount_static("sql", RateLimitedNode::new(SqlNode { app_state: app_state.clone() }, LimitedNode::Sql, app_state.clone()))
         .mount_dynamic("eventctl", move |rq, client_cmd_tx| {
                         eventctlnode::request_handler(rq, client_cmd_tx, app_state2.clone())
         })
+        .mount_dynamic(JOBS_SHV_PATH, move |rq, client_cmd_tx| {
+            jobsnode::request_handler(rq, client_cmd_tx, app_state3.clone())
+        })
+        .mount_dynamic(SERIES_SHV_PATH, move |rq, client_cmd_tx| {
+            league::request_handler(rq, client_cmd_tx, app_state4.clone())
+        })
+        .mount_dynamic(WEBHOOKS_SHV_PATH, move |rq, client_cmd_tx| {
+            webhooks::request_handler(rq, client_cmd_tx, app_state5.clone())
+        })
         .run_with_init(&config.client, app_tasks)
         .await;
 
@@ -237,6 +413,11 @@ async fn app_task(
     info!("app task started");
 
     let mut is_connected = false;
+    let mut startup_done = false;
+    smol::spawn(diskspace::watchdog(app_state.clone(), client_cmd_tx.clone())).detach();
+    smol::spawn(heartbeat::heartbeat(app_state.clone(), client_cmd_tx.clone())).detach();
+    smol::spawn(rules::scheduler(app_state.clone(), client_cmd_tx.clone())).detach();
+    smol::spawn(gateway::server(client_cmd_tx.clone(), app_state.clone())).detach();
     let client_cmd_tx2 = client_cmd_tx.clone();
     loop {
         select! {
@@ -247,6 +428,20 @@ async fn app_task(
                 Ok(ClientEvent::Connected(api)) => {
                     is_connected = true;
                     info!("Device connected to broker API: {:?}", api);
+                    if !startup_done {
+                        startup_done = true;
+                        let app_state = app_state.clone();
+                        let client_cmd_tx = client_cmd_tx.clone();
+                        smol::spawn(async move {
+                            match state::prune_stale_mounts(app_state.clone(), client_cmd_tx.clone()).await {
+                                Ok(pruned) => info!("Pruned {pruned} stale broker mounts"),
+                                Err(e) => warn!("Failed to prune stale broker mounts: {e}"),
+                            }
+                            if global_config().reopen_on_start {
+                                state::reopen_events(app_state, client_cmd_tx).await;
+                            }
+                        }).detach();
+                    }
                 },
                 Ok(ClientEvent::Disconnected) => {
                     is_connected = false;
@@ -266,18 +461,22 @@ async fn app_task(
 
         }
         if is_connected {
-            let _ = app_state.write().await.gc_expired_events(client_cmd_tx2.clone()).await;
+            let _ = app_state.gc_expired_events(client_cmd_tx2.clone()).await;
         }
     }
     info!("App task finished");
     Ok(())
 }
 
-fn call_rpc_error_to_anyhow(err: CallRpcMethodError) -> anyhow::Error {
-    anyhow::anyhow!("{}", err)
-}
-
 fn anyhow_to_rpc_error(err: anyhow::Error) -> RpcError {
+    if let Some(quota_exceeded) = err.downcast_ref::<quota::QuotaExceeded>() {
+        warn!("{quota_exceeded}");
+        return quota_exceeded.to_rpc_error();
+    }
+    if let Some(event_not_open) = err.downcast_ref::<state::EventNotOpen>() {
+        warn!("{event_not_open}");
+        return event_not_open.to_rpc_error();
+    }
     error!("Error: {err}\nbacktrace: {}", Backtrace::capture());
     RpcError::new(RpcErrorCode::MethodCallException, format!("Error: {err}"))
 }
@@ -316,12 +515,15 @@ fn generate_api_token() -> String {
 }
 
 fn issuer(rq: &RpcMessage) -> Option<String> {
+    if let Some(user) = gateway::forwarded_user(rq) {
+        return Some(user.to_string());
+    }
     rq.user_id().and_then(|uid| {
         let (issuer, _) = split_first_fragment(uid, ';');
         if issuer.is_empty() {
-            Some(issuer.to_string())
-        } else {
             None
+        } else {
+            Some(issuer.to_string())
         }
     })
 }
@@ -0,0 +1,122 @@
+//! Periodic maintenance of idle local event DBs. WAL file is truncated by checkpoint
+//! and free pages are released by incremental vacuum, so the files do not grow during long events.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use async_sqlite::rusqlite::Connection;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::global_config;
+use crate::state::{EventId, SharedAppState, event_db_file};
+
+/// `PRAGMA auto_vacuum` value, which enables incremental vacuum
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MaintenanceStats {
+    pub event_id: EventId,
+    pub runs: u64,
+    /// Bytes reclaimed by the last run
+    pub reclaimed_bytes: u64,
+    pub total_reclaimed_bytes: u64,
+    /// Size of DB and WAL files after the last run
+    pub db_size_bytes: u64,
+    pub last_run: Option<String>,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    maintained_at: Option<Instant>,
+}
+
+/// Maintenance statistics of events
+#[derive(Default)]
+pub(crate) struct Maintenance {
+    stats: Mutex<BTreeMap<EventId, MaintenanceStats>>,
+}
+
+impl Maintenance {
+    pub fn stats(&self) -> Vec<MaintenanceStats> {
+        self.stats.lock().unwrap().values().cloned().collect()
+    }
+    /// Event DB is idle and it was used since its last maintenance
+    fn needs_maintenance(&self, event_id: EventId, sql_called_at: Instant, now: Instant) -> bool {
+        let idle_time = global_config().maintenance.idle_time.to_std().unwrap_or_default();
+        if now.duration_since(sql_called_at) < idle_time {
+            return false;
+        }
+        self.stats.lock().unwrap().get(&event_id)
+            .and_then(|stats| stats.maintained_at)
+            .is_none_or(|maintained_at| maintained_at < sql_called_at)
+    }
+}
+
+fn files_size(db_file: &str) -> u64 {
+    [db_file.to_string(), format!("{db_file}-wal")].iter()
+        .filter_map(|file| std::fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+fn maintain_db(conn: &Connection, vacuum_pages: u32) -> Result<(), async_sqlite::rusqlite::Error> {
+    let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+    if auto_vacuum == AUTO_VACUUM_INCREMENTAL {
+        conn.execute_batch(&format!("PRAGMA incremental_vacuum({vacuum_pages})"))?;
+    } else {
+        // auto vacuum mode of existing DB can be changed by full vacuum only, it is done once
+        conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+    }
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(())
+}
+
+async fn maintain_event(app_state: &SharedAppState, event_id: EventId, db: async_sqlite::Pool) {
+    let db_file = event_db_file(event_id);
+    let size_before = files_size(&db_file);
+    let vacuum_pages = global_config().maintenance.vacuum_pages;
+    let result = db.conn(move |conn| maintain_db(conn, vacuum_pages)).await;
+    let size_after = files_size(&db_file);
+    let mut stats = app_state.maintenance.stats.lock().unwrap();
+    let stats = stats.entry(event_id).or_insert_with(|| MaintenanceStats { event_id, ..Default::default() });
+    stats.runs += 1;
+    stats.maintained_at = Some(Instant::now());
+    stats.last_run = Some(chrono::Local::now().fixed_offset().to_rfc3339());
+    stats.db_size_bytes = size_after;
+    match result {
+        Ok(()) => {
+            stats.reclaimed_bytes = size_before.saturating_sub(size_after);
+            stats.total_reclaimed_bytes += stats.reclaimed_bytes;
+            stats.last_error = None;
+            info!("Event {event_id} DB maintenance reclaimed {} bytes", stats.reclaimed_bytes);
+        }
+        Err(e) => {
+            stats.reclaimed_bytes = 0;
+            stats.last_error = Some(e.to_string());
+            error!("Event {event_id} DB maintenance error: {e}");
+        }
+    }
+}
+
+/// Run maintenance of idle open local events every `maintenance.interval`
+pub(crate) async fn scheduler(app_state: SharedAppState) {
+    let Some(interval) = global_config().maintenance.interval.to_std().ok().filter(|interval| !interval.is_zero()) else {
+        info!("Event DB maintenance is disabled");
+        return;
+    };
+    loop {
+        smol::Timer::after(interval).await;
+        let now = Instant::now();
+        let idle_events: Vec<(EventId, async_sqlite::Pool)> = app_state.open_events.read().unwrap().iter()
+            .filter_map(|(event_id, event)| {
+                let db = event.local_db.clone()?;
+                let sql_called_at = *event.sql_called_at.lock().unwrap();
+                app_state.maintenance.needs_maintenance(*event_id, sql_called_at, now).then_some((*event_id, db))
+            })
+            .collect();
+        for (event_id, db) in idle_events {
+            maintain_event(&app_state, event_id, db).await;
+        }
+    }
+}
@@ -13,6 +13,89 @@ const MIGRATION_ARRAY: &[M] = &[
     M::up(
         "ALTER TABLE events ADD COLUMN stage INTEGER NOT NULL DEFAULT 1",
     ),
+    M::up(
+        "ALTER TABLE events ADD COLUMN remote_mount TEXT",
+    ),
+    M::up(
+        "ALTER TABLE events ADD COLUMN was_open BOOLEAN NOT NULL DEFAULT 0",
+    ),
+    M::up(
+        "ALTER TABLE events ADD COLUMN sport TEXT NOT NULL DEFAULT 'foot-o'",
+    ),
+    M::up(
+        "CREATE TABLE jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            event_id INTEGER,
+            state TEXT NOT NULL,
+            error TEXT,
+            created TEXT NOT NULL,
+            started TEXT,
+            finished TEXT
+        );
+        CREATE INDEX jobs_ix1 ON jobs (event_id);",
+    ),
+    M::up(
+        "CREATE TABLE organizations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            created TEXT NOT NULL,
+            CONSTRAINT organizations_unique0 UNIQUE (name)
+        );
+        CREATE TABLE organization_members (
+            organization_id INTEGER NOT NULL REFERENCES organizations (id) ON DELETE CASCADE,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY (organization_id, user_id)
+        );
+        ALTER TABLE events ADD COLUMN organization_id INTEGER REFERENCES organizations (id);
+        CREATE INDEX events_ix1 ON events (organization_id);",
+    ),
+    M::up(
+        "ALTER TABLE events ADD COLUMN broken TEXT",
+    ),
+    M::up(
+        "CREATE TABLE series (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            points TEXT NOT NULL DEFAULT '',
+            best_count INTEGER,
+            created TEXT NOT NULL,
+            CONSTRAINT series_unique0 UNIQUE (name)
+        );
+        CREATE TABLE series_events (
+            series_id INTEGER NOT NULL REFERENCES series (id) ON DELETE CASCADE,
+            event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+            stage INTEGER NOT NULL DEFAULT 1,
+            PRIMARY KEY (series_id, event_id, stage)
+        );",
+    ),
+    M::up(
+        "CREATE TABLE personal_tokens (
+            token TEXT PRIMARY KEY,
+            registration TEXT NOT NULL,
+            birth_date TEXT,
+            created TEXT NOT NULL
+        );
+        CREATE INDEX personal_tokens_ix1 ON personal_tokens (registration);",
+    ),
+    M::up(
+        "CREATE TABLE webhooks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url TEXT NOT NULL,
+            secret TEXT,
+            events TEXT NOT NULL DEFAULT '',
+            event_id INTEGER REFERENCES events (id) ON DELETE CASCADE,
+            created TEXT NOT NULL
+        );",
+    ),
+    M::up(
+        "CREATE TABLE event_secrets (
+            event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+            skey TEXT NOT NULL,
+            svalue TEXT NOT NULL,
+            PRIMARY KEY (event_id, skey)
+        );",
+    ),
 ];
 const MIGRATIONS: Migrations = Migrations::from_slice(MIGRATION_ARRAY);
 
@@ -0,0 +1,142 @@
+//! MQTT bridge for venue display systems, which do not speak SHV. Punches at radio controls and result
+//! changes are published as JSON to topics from `mqtt` config. Single broker connection is shared by all events,
+//! messages are published with QoS 0, they are queued while the broker is not connected.
+//!
+//! Broker connection is maintained by `rumqttc` in a dedicated thread, it has its own runtime.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use qxsql::QxSqlApi;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use serde::Serialize;
+
+use crate::config::MqttConfig;
+use crate::eventsqlapi::EventSqlApi;
+use crate::global_config;
+use crate::speaker;
+use crate::state::EventId;
+use crate::views::ClassDelta;
+
+/// Messages waiting for the broker, newer messages are dropped when the queue is full
+const QUEUE_CAPACITY: usize = 1000;
+
+const DEFAULT_PORT: u16 = 1883;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Broker `host:port`, port is optional
+fn broker_address(broker: &str) -> anyhow::Result<(String, u16)> {
+    match broker.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.parse::<u16>().map_err(|e| anyhow!("Invalid MQTT broker port in {broker}: {e}"))?;
+            Ok((host.to_string(), port))
+        }
+        None => Ok((broker.to_string(), DEFAULT_PORT)),
+    }
+}
+
+fn mqtt_options(config: &MqttConfig) -> anyhow::Result<MqttOptions> {
+    let (host, port) = broker_address(&config.broker)?;
+    let mut options = MqttOptions::new(&config.client_id, host, port);
+    let keep_alive = config.keep_alive.to_std().unwrap_or(Duration::from_secs(60)).max(Duration::from_secs(1));
+    let timeout = config.timeout.to_std().unwrap_or(Duration::from_secs(10));
+    options
+        .set_keep_alive(keep_alive)
+        .set_connection_timeout(timeout.as_secs().max(1))
+        .set_clean_session(true);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    Ok(options)
+}
+
+/// Drive the broker connection, rumqttc answers pings, detects disconnects and reconnects on next poll,
+/// queued messages are kept meanwhile
+fn run_connection(mut connection: rumqttc::Connection, config: &MqttConfig) {
+    let reconnect_delay = config.reconnect_delay.to_std().unwrap_or(Duration::from_secs(5));
+    for notification in connection.iter() {
+        match notification {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => info!("MQTT bridge connected to {}", config.broker),
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT bridge connection to {} failed: {e}", config.broker);
+                std::thread::sleep(reconnect_delay);
+            }
+        }
+    }
+}
+
+/// Start MQTT bridge, if it is configured
+pub(crate) fn start() {
+    let Some(config) = &global_config().mqtt else {
+        return;
+    };
+    let options = match mqtt_options(config) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("MQTT bridge is not started: {e}");
+            return;
+        }
+    };
+    let (client, connection) = Client::new(options, QUEUE_CAPACITY);
+    if CLIENT.set(client).is_ok()
+        && let Err(e) = std::thread::Builder::new().name("mqtt".into()).spawn(move || run_connection(connection, config)) {
+        warn!("MQTT bridge thread cannot be started: {e}");
+    }
+}
+
+fn topic(template: &str, event_id: EventId, key: &str, value: i64) -> String {
+    template.replace("{event_id}", &event_id.to_string()).replace(key, &value.to_string())
+}
+
+fn publish(topic: String, payload: &impl Serialize) {
+    let Some(client) = CLIENT.get() else {
+        return;
+    };
+    let payload = match serde_json::to_vec(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("MQTT message to {topic} cannot be serialized: {e}");
+            return;
+        }
+    };
+    let retain = global_config().mqtt.as_ref().is_some_and(|config| config.retain);
+    if let Err(e) = client.try_publish(&topic, QoS::AtMostOnce, retain, payload) {
+        debug!("MQTT message to {topic} is dropped: {e}");
+    }
+}
+
+/// Punches are collected for the bridge only, when punch topic is configured
+pub(crate) fn is_punch_bridged() -> bool {
+    global_config().mqtt.as_ref().is_some_and(|config| !config.punch_topic.is_empty())
+}
+
+pub(crate) fn publish_result_deltas(event_id: EventId, deltas: &[ClassDelta]) {
+    let Some(config) = &global_config().mqtt else {
+        return;
+    };
+    if config.results_topic.is_empty() {
+        return;
+    }
+    for delta in deltas {
+        publish(topic(&config.results_topic, event_id, "{class_id}", delta.class_id), delta);
+    }
+}
+
+/// Publish new punches at radio controls with position of the runner at the control
+pub(crate) async fn publish_radio_punches(sql_api: &EventSqlApi, event_id: EventId, stage_id: i64, punch_ids: &[i64]) -> anyhow::Result<()> {
+    let Some(config) = &global_config().mqtt else {
+        return Ok(());
+    };
+    let result = sql_api.query("SELECT code FROM codes WHERE radio", None).await?;
+    let codes = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect::<Vec<_>>();
+    for punch in speaker::load_prewarnings(sql_api, stage_id, punch_ids, &codes).await? {
+        publish(topic(&config.punch_topic, event_id, "{code}", punch.code), &punch);
+    }
+    Ok(())
+}
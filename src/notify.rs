@@ -0,0 +1,371 @@
+//! Notifications of competitors about their result and splits, sent when their card is read or when results
+//! of their class are finalized (frozen). Notifications are configured per event by `notify.*` event config keys,
+//! transport is SMTP relay from the daemon config or generic webhook receiving JSON, which can forward it as SMS.
+//! Webhook secret is event secret, it is not stored in the readable event config.
+//! Every run is notified once per trigger, sent notifications are recorded in `notifications` table.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use lettre::{Message, SmtpTransport, Transport};
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use log::{debug, error, info, warn};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use smol::channel;
+
+use crate::config::SmtpTls;
+use crate::eventsqlapi::EventSqlApi;
+use crate::global_config;
+use crate::results::{RunFilter, RunStatus, format_time_ms, load_run_results};
+use crate::state::{EventId, SharedAppState};
+
+const CKEY_TRANSPORT: &str = "notify.transport";
+const CKEY_ON_CARD_READ: &str = "notify.onCardRead";
+const CKEY_ON_RESULTS_FINAL: &str = "notify.onResultsFinal";
+const CKEY_WEBHOOK_URL: &str = "notify.webhookUrl";
+/// Event secret key, it is set by `config:setValue` like other notification settings
+pub(crate) const SKEY_WEBHOOK_SECRET: &str = "notify.webhookSecret";
+
+/// Event, which makes competitors notified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NotifyTrigger {
+    /// Card with this id was read or assigned to run
+    CardRead(i64),
+    /// Results of class in stage were frozen
+    ResultsFinal { stage_id: i64, class_id: i64 },
+}
+
+impl NotifyTrigger {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NotifyTrigger::CardRead(_) => "cardRead",
+            NotifyTrigger::ResultsFinal { .. } => "resultsFinal",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NotifyTransport {
+    Smtp,
+    Webhook,
+}
+
+impl NotifyTransport {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NotifyTransport::Smtp => "smtp",
+            NotifyTransport::Webhook => "webhook",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NotifySettings {
+    transport: NotifyTransport,
+    on_card_read: bool,
+    on_results_final: bool,
+    webhook_url: String,
+    webhook_secret: String,
+}
+
+impl NotifySettings {
+    fn is_enabled(&self, trigger: &NotifyTrigger) -> bool {
+        match trigger {
+            NotifyTrigger::CardRead(_) => self.on_card_read,
+            NotifyTrigger::ResultsFinal { .. } => self.on_results_final,
+        }
+    }
+}
+
+/// Notification settings of event, competitors are not notified if `notify.transport` is not set
+async fn notify_settings(sql_api: &EventSqlApi) -> anyhow::Result<Option<NotifySettings>> {
+    let result = sql_api.query("SELECT ckey, cvalue FROM config WHERE ckey LIKE 'notify.%'", None).await?;
+    let mut transport = None;
+    let mut settings = NotifySettings {
+        transport: NotifyTransport::Webhook,
+        on_card_read: false,
+        on_results_final: false,
+        webhook_url: String::new(),
+        webhook_secret: String::new(),
+    };
+    for row in 0..result.row_count() {
+        let cvalue = result.value(row, 1).and_then(|v| v.as_str()).unwrap_or_default().trim().to_string();
+        let flag = matches!(cvalue.as_str(), "true" | "1");
+        match result.value(row, 0).and_then(|v| v.as_str()) {
+            Some(CKEY_TRANSPORT) => transport = match cvalue.as_str() {
+                "" => None,
+                "smtp" => Some(NotifyTransport::Smtp),
+                "webhook" => Some(NotifyTransport::Webhook),
+                s => bail!("Unknown notification transport: '{s}'"),
+            },
+            Some(CKEY_ON_CARD_READ) => settings.on_card_read = flag,
+            Some(CKEY_ON_RESULTS_FINAL) => settings.on_results_final = flag,
+            Some(CKEY_WEBHOOK_URL) => settings.webhook_url = cvalue,
+            _ => {}
+        }
+    }
+    let Some(transport) = transport else {
+        return Ok(None);
+    };
+    settings.transport = transport;
+    match transport {
+        NotifyTransport::Smtp if global_config().notify.smtp_from.is_empty() => bail!("Notification sender notify.smtp_from is not set in daemon config"),
+        NotifyTransport::Webhook if settings.webhook_url.is_empty() => bail!("Notification webhook {CKEY_WEBHOOK_URL} is not set"),
+        NotifyTransport::Webhook => {
+            settings.webhook_secret = sql_api.secret(SKEY_WEBHOOK_SECRET).await?.unwrap_or_default();
+            Ok(Some(settings))
+        }
+        _ => Ok(Some(settings)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Split {
+    code: i64,
+    /// Time since start
+    stp_time_ms: Option<i64>,
+    lap_time_ms: Option<i64>,
+}
+
+/// Notification of one run, it is posted as JSON to webhook
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Notification {
+    trigger: String,
+    event_name: String,
+    stage_id: i64,
+    run_id: i64,
+    name: String,
+    registration: String,
+    email: Option<String>,
+    phone: Option<String>,
+    class_name: String,
+    status: String,
+    time_ms: Option<i64>,
+    place: Option<usize>,
+    splits: Vec<Split>,
+}
+
+impl Notification {
+    fn subject(&self) -> String {
+        format!("{} - {} {}", self.event_name, self.class_name, self.result_text())
+    }
+
+    fn result_text(&self) -> String {
+        match (self.status.as_str(), self.time_ms, self.place) {
+            ("OK", Some(time_ms), Some(place)) => format!("{place}. {}", format_time_ms(time_ms)),
+            ("OK", Some(time_ms), None) => format_time_ms(time_ms),
+            (status, _, _) => status.to_string(),
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!("{}\n{}\n{}: {}\n", self.event_name, self.name, self.class_name, self.result_text());
+        if !self.splits.is_empty() {
+            text.push_str("\nSplits:\n");
+            for split in &self.splits {
+                let time = |time_ms: Option<i64>| time_ms.map(format_time_ms).unwrap_or_else(|| "-".to_string());
+                text.push_str(&format!("{:>4} {:>9} {:>9}\n", split.code, time(split.lap_time_ms), time(split.stp_time_ms)));
+            }
+        }
+        text
+    }
+}
+
+/// Runs of trigger, which were not notified yet, as `(stageId, runId)`
+async fn triggered_runs(sql_api: &EventSqlApi, trigger: &NotifyTrigger) -> anyhow::Result<Vec<(i64, i64)>> {
+    let result = match trigger {
+        NotifyTrigger::CardRead(card_id) => sql_api.query("SELECT runs.stageId, runs.id FROM cards JOIN runs ON runs.id = cards.runId WHERE cards.id = :id", Some(&record_from_slice(&[
+            ("id", (*card_id).into()),
+        ]))).await?,
+        NotifyTrigger::ResultsFinal { stage_id, class_id } => sql_api.query("SELECT runs.stageId, runs.id FROM runs \
+            JOIN competitors ON competitors.id = runs.competitorId \
+            WHERE runs.stageId = :stageId AND competitors.classId = :classId AND runs.isRunning", Some(&record_from_slice(&[
+            ("stageId", (*stage_id).into()),
+            ("classId", (*class_id).into()),
+        ]))).await?,
+    };
+    let notified = sql_api.query("SELECT runId FROM notifications WHERE triggerType = :triggerType AND error IS NULL", Some(&record_from_slice(&[
+        ("triggerType", trigger.as_str().into()),
+    ]))).await?;
+    let notified: BTreeSet<i64> = (0..notified.row_count())
+        .filter_map(|row| notified.value(row, 0).and_then(|v| v.to_int()))
+        .collect();
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    Ok((0..result.row_count())
+        .filter_map(|row| int(row, 0).zip(int(row, 1)))
+        .filter(|(_, run_id)| !notified.contains(run_id))
+        .collect())
+}
+
+async fn load_notifications(sql_api: &EventSqlApi, trigger: &NotifyTrigger, stage_id: i64, run_ids: &[i64]) -> anyhow::Result<Vec<Notification>> {
+    let result = sql_api.query("SELECT cvalue FROM config WHERE ckey = 'event.name'", None).await?;
+    let event_name = result.value(0, 0).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    // places depend on the whole class
+    let class_ids: BTreeSet<i64> = load_run_results(sql_api, stage_id, RunFilter::Runs(run_ids)).await?
+        .iter()
+        .map(|run| run.class_id)
+        .collect();
+    let mut notifications = vec![];
+    for class_id in class_ids {
+        for run in load_run_results(sql_api, stage_id, RunFilter::Class(class_id)).await? {
+            if !run_ids.contains(&run.run_id) || run.status == RunStatus::Inactive {
+                continue;
+            }
+            let contacts = sql_api.query("SELECT competitors.email, competitors.phone FROM runs \
+                JOIN competitors ON competitors.id = runs.competitorId WHERE runs.id = :runId", Some(&record_from_slice(&[
+                ("runId", run.run_id.into()),
+            ]))).await?;
+            let contact = |col| contacts.value(0, col).and_then(|v| v.as_str()).map(str::trim).filter(|s| !s.is_empty()).map(str::to_string);
+            let laps = sql_api.query("SELECT code, stpTimeMs, lapTimeMs FROM runlaps WHERE runId = :runId ORDER BY position", Some(&record_from_slice(&[
+                ("runId", run.run_id.into()),
+            ]))).await?;
+            let int = |row, col| laps.value(row, col).and_then(|v| v.to_int());
+            notifications.push(Notification {
+                trigger: trigger.as_str().to_string(),
+                event_name: event_name.clone(),
+                stage_id,
+                run_id: run.run_id,
+                name: run.name(),
+                registration: run.registration.clone(),
+                email: contact(0),
+                phone: contact(1),
+                class_name: run.class_name.clone(),
+                status: run.status.as_iof_str().to_string(),
+                time_ms: run.time_ms,
+                place: run.place,
+                splits: (0..laps.row_count())
+                    .filter_map(|row| int(row, 0).map(|code| Split { code, stp_time_ms: int(row, 1), lap_time_ms: int(row, 2) }))
+                    .collect(),
+            });
+        }
+    }
+    Ok(notifications)
+}
+
+/// Send mail through SMTP relay from the daemon config, it is blocking
+fn smtp_send(timeout: Duration, to: &str, subject: &str, text: &str) -> anyhow::Result<()> {
+    let config = &global_config().notify;
+    let message = Message::builder()
+        .from(config.smtp_from.parse().map_err(|e| anyhow!("Invalid sender address {}: {e}", config.smtp_from))?)
+        .to(to.parse().map_err(|e| anyhow!("Invalid e-mail address {to}: {e}"))?)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(text.to_string())?;
+    let (host, port) = config.smtp_relay.rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| anyhow!("Invalid SMTP relay {}, host:port expected", config.smtp_relay))?;
+    let transport = match config.smtp_tls {
+        SmtpTls::None => SmtpTransport::builder_dangerous(host),
+        SmtpTls::Starttls => SmtpTransport::starttls_relay(host)?,
+        SmtpTls::Tls => SmtpTransport::relay(host)?,
+    };
+    let mut transport = transport.port(port).timeout(Some(timeout));
+    if !config.smtp_user.is_empty() {
+        transport = transport.credentials(Credentials::new(config.smtp_user.clone(), config.smtp_password.clone()));
+    }
+    transport.build().send(&message)?;
+    Ok(())
+}
+
+/// Send notification, recipient is returned
+async fn send(settings: &NotifySettings, notification: &Notification) -> anyhow::Result<Option<String>> {
+    let config = &global_config().notify;
+    let timeout = config.timeout.to_std().unwrap_or(Duration::from_secs(10));
+    match settings.transport {
+        NotifyTransport::Smtp => {
+            let Some(to) = notification.email.clone() else {
+                return Ok(None);
+            };
+            let (subject, text) = (notification.subject(), notification.text());
+            // lettre SMTP transport is blocking
+            smol::unblock(move || smtp_send(timeout, &to, &subject, &text).map(|()| Some(to))).await
+        }
+        NotifyTransport::Webhook => {
+            let (url, secret) = (settings.webhook_url.clone(), settings.webhook_secret.clone());
+            let body = serde_json::to_string(notification)?;
+            // ureq is blocking
+            smol::unblock(move || {
+                let mut request = ureq::post(&url)
+                    .timeout(timeout)
+                    .set("Content-Type", "application/json");
+                if !secret.is_empty() {
+                    request = request.set("Authorization", &format!("Bearer {secret}"));
+                }
+                request.send_string(&body)
+                    .map(|_| Some(url))
+                    .map_err(|e| anyhow!("Notification webhook failed: {e}"))
+            }).await
+        }
+    }
+}
+
+async fn notify(sql_api: &EventSqlApi, settings: &NotifySettings, trigger: &NotifyTrigger) -> anyhow::Result<()> {
+    let mut stage_runs = BTreeMap::<i64, Vec<i64>>::new();
+    for (stage_id, run_id) in triggered_runs(sql_api, trigger).await? {
+        stage_runs.entry(stage_id).or_default().push(run_id);
+    }
+    for (stage_id, run_ids) in stage_runs {
+        for notification in load_notifications(sql_api, trigger, stage_id, &run_ids).await? {
+            let (recipient, error) = match send(settings, &notification).await {
+                Ok(None) => continue,
+                Ok(Some(recipient)) => (Some(recipient), None),
+                Err(e) => {
+                    warn!("Notification of run {} failed: {e}", notification.run_id);
+                    (None, Some(e.to_string()))
+                }
+            };
+            let record = record_from_slice(&[
+                ("runId", notification.run_id.into()),
+                ("triggerType", trigger.as_str().into()),
+                ("transport", settings.transport.as_str().into()),
+                ("recipient", recipient.map(DbValue::from).unwrap_or(DbValue::Null)),
+                ("sentAt", chrono::Local::now().fixed_offset().into()),
+                ("error", error.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ]);
+            sql_api.create_record_event("notifications", &record, None).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Notify competitors of event about triggers received from the channel.
+///
+/// Triggers are collected for `notify.delay`, so the card readout is processed before the result is sent.
+pub(crate) async fn notifier(app_state: SharedAppState, event_id: EventId, rpc_client: ClientCommandSender, triggers: channel::Receiver<NotifyTrigger>) {
+    let delay = global_config().notify.delay.to_std().unwrap_or(Duration::from_secs(10));
+    while let Ok(trigger) = triggers.recv().await {
+        let mut pending = vec![trigger];
+        smol::Timer::after(delay).await;
+        while let Ok(trigger) = triggers.try_recv() {
+            if !pending.contains(&trigger) {
+                pending.push(trigger);
+            }
+        }
+        if !app_state.is_event_open(event_id) {
+            break;
+        }
+        let sql_api = EventSqlApi::new(event_id, app_state.clone(), rpc_client.clone());
+        let settings = match notify_settings(&sql_api).await {
+            Ok(Some(settings)) => settings,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Event {event_id} notification settings error: {e}");
+                continue;
+            }
+        };
+        for trigger in pending.iter().filter(|trigger| settings.is_enabled(trigger)) {
+            debug!("Event {event_id} notifying competitors of {trigger:?}");
+            if let Err(e) = notify(&sql_api, &settings, trigger).await {
+                error!("Event {event_id} notification of {trigger:?} error: {e}");
+            }
+        }
+    }
+    info!("Event {event_id} notifier finished");
+}
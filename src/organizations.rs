@@ -0,0 +1,211 @@
+//! Organizations (clubs) sharing one daemon. Events of organization are available in its subtree
+//! `eventctl/<org>/<event_id>`, so broker access rules can grant each club its own subtree,
+//! and organization members have event owner rights on the organization events.
+
+use anyhow::bail;
+use qxsql::QxSqlApi;
+use qxsql::sql::record_from_slice;
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::RpcValue;
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::anyhow_to_rpc_error;
+use crate::appsqlapi::AppSqlApi;
+use crate::eventctlnode::{create_event_param, resolve_event_owner};
+use crate::state::{EventId, SharedAppState};
+
+const METH_CREATE_EVENT: &str = "createEvent";
+const METH_MEMBERS: &str = "members";
+const METH_ADD_MEMBER: &str = "addMember";
+const METH_REMOVE_MEMBER: &str = "removeMember";
+
+pub(crate) const ORGANIZATION_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_CREATE_EVENT, Flags::UserIDRequired, AccessLevel::Write, "s|n:owner|[s|n:owner,b:is_local,s:sport]", "[i:event_id,s:api_token]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_MEMBERS, Flags::None, AccessLevel::Service, "", "[s:user_id]", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_ADD_MEMBER, Flags::None, AccessLevel::Service, "s:user_id", "b", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_REMOVE_MEMBER, Flags::None, AccessLevel::Service, "s:user_id", "b", &[], "",
+    ),
+];
+
+/// Organization name is a path segment, it cannot be confused with event id or other `eventctl` child nodes
+pub(crate) fn is_valid_organization_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && name != crate::eventctlnode::IMPORT_NODE
+}
+
+pub(crate) async fn organization_id(app_state: &SharedAppState, name: &str) -> anyhow::Result<Option<i64>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id FROM organizations WHERE name = :name", Some(&record_from_slice(&[
+        ("name", name.into()),
+    ]))).await?;
+    Ok(result.value(0, 0).and_then(|v| v.to_int()))
+}
+
+async fn existing_organization_id(app_state: &SharedAppState, name: &str) -> anyhow::Result<i64> {
+    organization_id(app_state, name).await?
+        .ok_or_else(|| anyhow::anyhow!("Organization '{name}' not found"))
+}
+
+pub(crate) async fn create_organization(app_state: &SharedAppState, name: &str) -> anyhow::Result<i64> {
+    if !is_valid_organization_name(name) {
+        bail!("Invalid organization name: '{name}', it must start with a letter and contain letters, digits, '-' or '_' only");
+    }
+    if organization_id(app_state, name).await?.is_some() {
+        bail!("Organization '{name}' already exists");
+    }
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    qxsql.create_record("organizations", &record_from_slice(&[
+        ("name", name.into()),
+        ("created", chrono::Local::now().fixed_offset().to_rfc3339().into()),
+    ])).await
+}
+
+pub(crate) async fn list_organizations(app_state: &SharedAppState) -> anyhow::Result<Vec<String>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT name FROM organizations ORDER BY name", None).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.as_str()).map(str::to_string))
+        .collect())
+}
+
+/// Organizations `user_id` is member of, as pairs of id and name
+pub(crate) async fn user_organizations(app_state: &SharedAppState, user_id: &str) -> anyhow::Result<Vec<(i64, String)>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT organizations.id, organizations.name FROM organizations \
+        JOIN organization_members ON organization_members.organization_id = organizations.id \
+        WHERE organization_members.user_id = :user_id ORDER BY organizations.name", Some(&record_from_slice(&[
+        ("user_id", user_id.into()),
+    ]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| Some((
+            result.value(row, 0).and_then(|v| v.to_int())?,
+            result.value(row, 1).and_then(|v| v.as_str())?.to_string(),
+        )))
+        .collect())
+}
+
+async fn organization_events(app_state: &SharedAppState, organization_id: i64) -> anyhow::Result<Vec<String>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id FROM events WHERE organization_id = :organization_id ORDER BY id", Some(&record_from_slice(&[
+        ("organization_id", organization_id.into()),
+    ]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .map(|id| id.to_string())
+        .collect())
+}
+
+/// Move event to organization, event is removed from any organization if `organization` is not set
+pub(crate) async fn set_event_organization(app_state: &SharedAppState, event_id: EventId, organization: Option<&str>) -> anyhow::Result<bool> {
+    let organization_id = match organization {
+        Some(name) => Some(existing_organization_id(app_state, name).await?),
+        None => None,
+    };
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.exec("UPDATE events SET organization_id = :organization_id WHERE id = :id", Some(&record_from_slice(&[
+        ("organization_id", organization_id.map(qxsql::DbValue::from).unwrap_or(qxsql::DbValue::Null)),
+        ("id", event_id.into()),
+    ]))).await?;
+    app_state.invalidate_cached_event_record(event_id);
+    Ok(result.rows_affected > 0)
+}
+
+pub(crate) async fn is_member(app_state: &SharedAppState, organization_id: i64, user_id: &str) -> anyhow::Result<bool> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT 1 FROM organization_members WHERE organization_id = :organization_id AND user_id = :user_id", Some(&record_from_slice(&[
+        ("organization_id", organization_id.into()),
+        ("user_id", user_id.into()),
+    ]))).await?;
+    Ok(result.row_count() > 0)
+}
+
+async fn members(app_state: &SharedAppState, organization_id: i64) -> anyhow::Result<Vec<String>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT user_id FROM organization_members WHERE organization_id = :organization_id ORDER BY user_id", Some(&record_from_slice(&[
+        ("organization_id", organization_id.into()),
+    ]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.as_str()).map(str::to_string))
+        .collect())
+}
+
+async fn set_member(app_state: &SharedAppState, organization_id: i64, user_id: &str, is_member: bool) -> anyhow::Result<bool> {
+    if user_id.is_empty() {
+        bail!("User id cannot be empty");
+    }
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let query = if is_member {
+        "INSERT OR IGNORE INTO organization_members (organization_id, user_id) VALUES (:organization_id, :user_id)"
+    } else {
+        "DELETE FROM organization_members WHERE organization_id = :organization_id AND user_id = :user_id"
+    };
+    let result = qxsql.exec(query, Some(&record_from_slice(&[
+        ("organization_id", organization_id.into()),
+        ("user_id", user_id.into()),
+    ]))).await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Event belongs to the organization, events of other organizations are not visible in its subtree
+pub(crate) async fn is_organization_event(app_state: &SharedAppState, organization: &str, event_id: EventId) -> anyhow::Result<bool> {
+    let Some(organization_id) = organization_id(app_state, organization).await? else {
+        return Ok(false);
+    };
+    let event_record = app_state.cached_event_record(event_id).await?;
+    Ok(event_record.organization_id == Some(organization_id))
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+    organization: String,
+) -> RequestHandlerResult {
+    let organization_id = match organization_id(&app_state, &organization).await {
+        Ok(Some(organization_id)) => organization_id,
+        _ => return err_unresolved_request(),
+    };
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(ORGANIZATION_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(ORGANIZATION_NODE_METHODS, async move || {
+            organization_events(&app_state, organization_id).await
+                .map_err(anyhow_to_rpc_error)
+        }),
+        Method::Other(m) => {
+            match m.method() {
+                METH_CREATE_EVENT => m.resolve(ORGANIZATION_NODE_METHODS, async move || {
+                    let (owner, is_local, sport) = create_event_param(rq.param().unwrap_or_default())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let owner = resolve_event_owner(&rq, &owner)
+                        .map_err(anyhow_to_rpc_error)?;
+                    let (event_id, api_token) = app_state.create_event(owner, is_local, sport, Some(organization_id), client_cmd_tx).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(RpcValue::from(vec![RpcValue::from(event_id), RpcValue::from(api_token)]))
+                }),
+                METH_MEMBERS => m.resolve(ORGANIZATION_NODE_METHODS, async move || {
+                    members(&app_state, organization_id).await
+                        .map(|members| RpcValue::from(members.into_iter().map(RpcValue::from).collect::<Vec<_>>()))
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                METH_ADD_MEMBER | METH_REMOVE_MEMBER => m.resolve(ORGANIZATION_NODE_METHODS, async move || {
+                    let user_id = rq.param().unwrap_or_default().as_str();
+                    set_member(&app_state, organization_id, user_id, rq.method() == Some(METH_ADD_MEMBER)).await
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Minimal PDF writer for text only printouts, it uses standard Helvetica fonts,
+//! so no font files need to be embedded.
+
+use std::str::FromStr;
+
+use anyhow::bail;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum PaperSize {
+    #[default]
+    A4,
+    A5,
+    Letter,
+}
+
+impl PaperSize {
+    /// Width and height in points
+    fn size(&self) -> (f32, f32) {
+        match self {
+            PaperSize::A4 => (595.0, 842.0),
+            PaperSize::A5 => (420.0, 595.0),
+            PaperSize::Letter => (612.0, 792.0),
+        }
+    }
+}
+
+impl FromStr for PaperSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "a4" => Ok(PaperSize::A4),
+            "a5" => Ok(PaperSize::A5),
+            "letter" => Ok(PaperSize::Letter),
+            _ => bail!("Unknown paper size: '{s}'"),
+        }
+    }
+}
+
+const MARGIN: f32 = 36.0;
+const FONT_SIZE: f32 = 9.0;
+const HEADING_FONT_SIZE: f32 = 13.0;
+const LINE_HEIGHT: f32 = 12.0;
+
+/// Characters outside of WinAnsi encoding are replaced by their base letter, if known
+fn win_ansi_byte(c: char) -> u8 {
+    const FALLBACK: &[(&str, u8)] = &[
+        ("ČĆ", b'C'), ("čć", b'c'), ("Ď", b'D'), ("ď", b'd'), ("ĚĘ", b'E'), ("ěę", b'e'),
+        ("ŇŃ", b'N'), ("ňń", b'n'), ("ŘŔ", b'R'), ("řŕ", b'r'), ("ŠŚ", b'S'), ("šś", b's'),
+        ("Ť", b'T'), ("ť", b't'), ("Ů", b'U'), ("ů", b'u'), ("ŽŹŻ", b'Z'), ("žźż", b'z'),
+        ("ĹĽŁ", b'L'), ("ĺľł", b'l'), ("Ą", b'A'), ("ą", b'a'), ("Ő", b'O'), ("ő", b'o'), ("Ű", b'U'), ("ű", b'u'),
+    ];
+    match c as u32 {
+        0x20..=0x7e | 0xa0..=0xff => c as u32 as u8,
+        _ => FALLBACK.iter()
+            .find(|(chars, _)| chars.contains(c))
+            .map(|(_, b)| *b)
+            .unwrap_or(b'?'),
+    }
+}
+
+fn pdf_string(s: &str) -> Vec<u8> {
+    let mut bytes = vec![b'('];
+    for c in s.chars() {
+        let b = win_ansi_byte(c);
+        if matches!(b, b'(' | b')' | b'\\') {
+            bytes.push(b'\\');
+        }
+        bytes.push(b);
+    }
+    bytes.push(b')');
+    bytes
+}
+
+/// Paginated text document, lines are laid out from the top, new page is started when the page is full
+pub(crate) struct PdfDocument {
+    width: f32,
+    height: f32,
+    title: String,
+    pages: Vec<Vec<u8>>,
+    current: Vec<u8>,
+    y: f32,
+}
+
+impl PdfDocument {
+    /// `title` is repeated on top of every page
+    pub fn new(paper_size: PaperSize, title: &str) -> Self {
+        let (width, height) = paper_size.size();
+        let mut doc = Self { width, height, title: title.to_string(), pages: vec![], current: vec![], y: 0. };
+        doc.start_page();
+        doc
+    }
+    /// Width of printable area in points
+    pub fn text_width(&self) -> f32 {
+        self.width - 2. * MARGIN
+    }
+    fn start_page(&mut self) {
+        if !self.current.is_empty() {
+            let page = std::mem::take(&mut self.current);
+            self.pages.push(page);
+        }
+        self.y = self.height - MARGIN;
+        let title = self.title.clone();
+        let page_no = format!("{}", self.pages.len() + 1);
+        self.text(MARGIN, &title, true, FONT_SIZE);
+        self.text(self.width - MARGIN - 20., &page_no, false, FONT_SIZE);
+        self.y -= 1.5 * LINE_HEIGHT;
+    }
+    fn text(&mut self, x: f32, text: &str, bold: bool, font_size: f32) {
+        let font = if bold { "F2" } else { "F1" };
+        self.current.extend_from_slice(format!("BT /{font} {font_size} Tf {x:.1} {:.1} Td ", self.y - font_size).as_bytes());
+        self.current.extend_from_slice(&pdf_string(text));
+        self.current.extend_from_slice(b" Tj ET\n");
+    }
+    fn ensure_space(&mut self, height: f32) {
+        if self.y - height < MARGIN {
+            self.start_page();
+        }
+    }
+    /// Group heading, it is never left alone at the bottom of page
+    pub fn heading(&mut self, text: &str) {
+        self.ensure_space(HEADING_FONT_SIZE + 3. * LINE_HEIGHT);
+        self.y -= LINE_HEIGHT / 2.;
+        self.text(MARGIN, text, true, HEADING_FONT_SIZE);
+        self.y -= HEADING_FONT_SIZE + LINE_HEIGHT / 2.;
+    }
+    /// Table row, columns are `(x offset from left margin, text)`
+    pub fn row(&mut self, columns: &[(f32, &str)], bold: bool) {
+        self.ensure_space(LINE_HEIGHT);
+        for (x, text) in columns {
+            self.text(MARGIN + x, text, bold, FONT_SIZE);
+        }
+        self.y -= LINE_HEIGHT;
+    }
+    pub fn page_break(&mut self) {
+        self.start_page();
+    }
+    pub fn finish(mut self) -> Vec<u8> {
+        let page = std::mem::take(&mut self.current);
+        self.pages.push(page);
+        let page_count = self.pages.len();
+        // objects: 1 catalog, 2 pages, 3 and 4 fonts, then page and its content for every page
+        let mut objects: Vec<Vec<u8>> = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            format!("<< /Type /Pages /Kids [{}] /Count {page_count} >>",
+                (0..page_count).map(|i| format!("{} 0 R", 5 + 2 * i)).collect::<Vec<_>>().join(" ")).into_bytes(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_vec(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_vec(),
+        ];
+        for (i, content) in self.pages.iter().enumerate() {
+            objects.push(format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                self.width, self.height, 6 + 2 * i).into_bytes());
+            let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+            stream.extend_from_slice(content);
+            stream.extend_from_slice(b"\nendstream");
+            objects.push(stream);
+        }
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+            pdf.extend_from_slice(object);
+            pdf.extend_from_slice(b"\nendobj\n");
+        }
+        let xref_offset = pdf.len();
+        pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in offsets {
+            pdf.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+        }
+        pdf.extend_from_slice(format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref_offset}\n%%EOF\n", objects.len() + 1).as_bytes());
+        pdf
+    }
+}
@@ -0,0 +1,167 @@
+//! Penalty time of runs. Penalties for missed controls or assigned manually by officials are kept
+//! in `penalties` table, their sum is written to `runs.penaltyTimeMs` and it is included in `runs.timeMs`.
+
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+
+use crate::eventsqlapi::EventSqlApi;
+use crate::results::format_time_ms;
+
+/// Event config key of default penalty for missed control
+const CKEY_MISSED_CONTROL_SEC: &str = "penalty.missedControlSec";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum PenaltyKind {
+    /// Control of course was not punched or marked, the penalty is per control
+    MissedControl,
+    /// Penalty assigned by officials, reason should be given
+    Manual,
+}
+
+impl PenaltyKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PenaltyKind::MissedControl => "missedControl",
+            PenaltyKind::Manual => "manual",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Penalty {
+    pub id: i64,
+    pub run_id: i64,
+    pub kind: String,
+    pub code: Option<i64>,
+    pub time_ms: i64,
+    pub reason: Option<String>,
+    pub issuer: Option<String>,
+    pub created_at: String,
+}
+
+/// Penalties of runs in stage by run id
+pub(crate) type RunPenalties = BTreeMap<i64, Vec<Penalty>>;
+
+/// Penalty and result time of run after the change of its penalties
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RunPenalty {
+    pub run_id: i64,
+    pub penalty_time_ms: i64,
+    pub time_ms: Option<i64>,
+}
+
+pub(crate) async fn missed_control_penalty_ms(sql_api: &EventSqlApi) -> anyhow::Result<Option<i64>> {
+    let result = sql_api.query("SELECT cvalue FROM config WHERE ckey = :ckey", Some(&record_from_slice(&[
+        ("ckey", CKEY_MISSED_CONTROL_SEC.into()),
+    ]))).await?;
+    Ok(result.value(0, 0)
+        .and_then(|v| v.as_str())
+        .and_then(|cvalue| cvalue.trim().parse::<i64>().ok())
+        .filter(|sec| *sec > 0)
+        .map(|sec| sec * 1000))
+}
+
+/// Penalties of runs in stage or of single run, ordered by run and creation
+pub(crate) async fn load_penalties(sql_api: &EventSqlApi, stage_id: Option<i64>, run_id: Option<i64>) -> anyhow::Result<Vec<Penalty>> {
+    let result = sql_api.query("SELECT penalties.id, penalties.runId, penalties.kind, penalties.code, penalties.timeMs, \
+        penalties.reason, penalties.issuer, penalties.createdAt \
+        FROM penalties JOIN runs ON runs.id = penalties.runId \
+        WHERE (:stageId IS NULL OR runs.stageId = :stageId) AND (:runId IS NULL OR penalties.runId = :runId) \
+        ORDER BY penalties.runId, penalties.id",
+        Some(&record_from_slice(&[
+            ("stageId", stage_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("runId", run_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let string = |row, col| match result.value(row, col) {
+        Some(DbValue::String(s)) => Some(s.as_str().to_string()),
+        Some(DbValue::DateTime(dt)) => Some(dt.to_rfc3339()),
+        _ => None,
+    };
+    Ok((0..result.row_count())
+        .map(|row| Penalty {
+            id: int(row, 0).unwrap_or_default(),
+            run_id: int(row, 1).unwrap_or_default(),
+            kind: string(row, 2).unwrap_or_default(),
+            code: int(row, 3),
+            time_ms: int(row, 4).unwrap_or_default(),
+            reason: string(row, 5),
+            issuer: string(row, 6),
+            created_at: string(row, 7).unwrap_or_default(),
+        })
+        .collect())
+}
+
+pub(crate) async fn load_run_penalties(sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<RunPenalties> {
+    let mut run_penalties = RunPenalties::new();
+    for penalty in load_penalties(sql_api, Some(stage_id), None).await? {
+        run_penalties.entry(penalty.run_id).or_default().push(penalty);
+    }
+    Ok(run_penalties)
+}
+
+/// Penalties in printable form, like `31 01:00, 45 01:00, Wrong order 02:00`
+pub(crate) fn penalty_breakdown(penalties: &[Penalty]) -> String {
+    penalties.iter()
+        .map(|penalty| {
+            let what = match (&penalty.code, &penalty.reason) {
+                (Some(code), _) => code.to_string(),
+                (None, Some(reason)) if !reason.is_empty() => reason.clone(),
+                _ => penalty.kind.clone(),
+            };
+            format!("{what} {}", format_time_ms(penalty.time_ms))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Sum of penalties of run
+pub(crate) async fn penalty_sum(sql_api: &EventSqlApi, run_id: i64) -> anyhow::Result<i64> {
+    let result = sql_api.query("SELECT COALESCE(SUM(timeMs), 0) FROM penalties WHERE runId = :runId", Some(&record_from_slice(&[
+        ("runId", run_id.into()),
+    ]))).await?;
+    Ok(result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default())
+}
+
+/// Set penalty time of run and recompute its time, the run is written before penalties change,
+/// so the penalties are never out of sync with the run, if the run cannot be changed.
+pub(crate) async fn set_run_penalty(sql_api: &EventSqlApi, run_id: i64, penalty_time_ms: i64, issuer: Option<String>) -> anyhow::Result<RunPenalty> {
+    let (run_penalty, changed) = new_run_penalty(sql_api, run_id, penalty_time_ms).await?;
+    if changed {
+        let record = record_from_slice(&[
+            ("penaltyTimeMs", penalty_time_ms.into()),
+            ("timeMs", run_penalty.time_ms.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ]);
+        if !sql_api.update_record_event("runs", run_id, &record, issuer).await? {
+            bail!("Run id: {run_id} not found");
+        }
+    }
+    Ok(run_penalty)
+}
+
+/// Run penalty and time with new `penalty_time_ms`, and whether they differ from the stored ones
+pub(crate) async fn new_run_penalty(sql_api: &EventSqlApi, run_id: i64, penalty_time_ms: i64) -> anyhow::Result<(RunPenalty, bool)> {
+    let result = sql_api.query("SELECT startTimeMs, finishTimeMs, penaltyTimeMs, timeMs FROM runs WHERE id = :id", Some(&record_from_slice(&[
+        ("id", run_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Run id: {run_id} not found");
+    }
+    let int = |col| result.value(0, col).and_then(|v| v.to_int());
+    let old_penalty_time_ms = int(2).unwrap_or_default();
+    let old_time_ms = int(3);
+    let time_ms = match (int(0), int(1)) {
+        (Some(start_time_ms), Some(finish_time_ms)) => Some(finish_time_ms - start_time_ms + penalty_time_ms),
+        // time without start or finish was entered manually, only the penalty is replaced
+        _ => old_time_ms.map(|time_ms| time_ms - old_penalty_time_ms + penalty_time_ms),
+    };
+    let changed = (penalty_time_ms, time_ms) != (old_penalty_time_ms, old_time_ms);
+    Ok((RunPenalty { run_id, penalty_time_ms, time_ms }, changed))
+}
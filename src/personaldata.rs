@@ -0,0 +1,146 @@
+//! Export of all data stored about one person in all events, to answer data subject access requests.
+//! Person is identified by registration number, local events are read even if they are closed,
+//! remote events only when they are open, because their DB is reachable through their DB service only.
+
+use anyhow::bail;
+use qxsql::QxSqlApi;
+use qxsql::sql::{Record, record_from_slice};
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+
+use crate::appsqlapi::{AppSqlApi, query_result_to_records};
+use crate::eventdb::open_event_db_pool;
+use crate::eventsqlapi::EventSqlApi;
+use crate::state::{EventId, SharedAppState, event_db_file};
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EventPersonalData {
+    pub event_id: EventId,
+    pub event_name: String,
+    pub event_date: String,
+    pub competitors: Vec<Record>,
+    pub runs: Vec<Record>,
+    pub cards: Vec<Record>,
+    pub punches: Vec<Record>,
+    pub registrations: Vec<Record>,
+    pub pending_entries: Vec<Record>,
+}
+
+impl EventPersonalData {
+    fn is_empty(&self) -> bool {
+        self.competitors.is_empty() && self.registrations.is_empty() && self.pending_entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SkippedEvent {
+    pub event_id: EventId,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PersonalDataExport {
+    pub registration: String,
+    pub exported_at: String,
+    /// Events containing any data of the person
+    pub events: Vec<EventPersonalData>,
+    /// Events, which could not be searched, they have to be checked manually
+    pub skipped_events: Vec<SkippedEvent>,
+}
+
+fn id_list(records: &[Record], field: &str) -> String {
+    records.iter()
+        .filter_map(|record| record.get(field).and_then(|v| v.to_int()))
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+async fn table_exists(sql_api: &(impl QxSqlApi + Sync), table: &str) -> anyhow::Result<bool> {
+    let result = sql_api.query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = :name", Some(&record_from_slice(&[
+        ("name", table.into()),
+    ]))).await?;
+    Ok(result.row_count() > 0)
+}
+
+async fn collect_event_data(sql_api: &(impl QxSqlApi + Sync), registration: &str, data: &mut EventPersonalData) -> anyhow::Result<()> {
+    let params = record_from_slice(&[("registration", registration.into())]);
+    let select = async |query: String, params: Option<&Record>| -> anyhow::Result<Vec<Record>> {
+        Ok(query_result_to_records(&sql_api.query(&query, params).await?))
+    };
+    data.competitors = select("SELECT * FROM competitors WHERE registration = :registration ORDER BY id".to_string(), Some(&params)).await?;
+    data.registrations = select("SELECT * FROM registrations WHERE registration = :registration ORDER BY id".to_string(), Some(&params)).await?;
+    // pending entries are added by later schema version, closed event DB can be older
+    if table_exists(sql_api, "pending_entries").await? {
+        data.pending_entries = select("SELECT * FROM pending_entries WHERE registration = :registration ORDER BY id".to_string(), Some(&params)).await?;
+    }
+    let competitor_ids = id_list(&data.competitors, "id");
+    if competitor_ids.is_empty() {
+        return Ok(());
+    }
+    data.runs = select(format!("SELECT * FROM runs WHERE competitorId IN ({competitor_ids}) ORDER BY id"), None).await?;
+    let run_ids = id_list(&data.runs, "id");
+    if run_ids.is_empty() {
+        return Ok(());
+    }
+    data.cards = select(format!("SELECT * FROM cards WHERE runId IN ({run_ids}) ORDER BY id"), None).await?;
+    data.punches = select(format!("SELECT * FROM punches WHERE runId IN ({run_ids}) ORDER BY id"), None).await?;
+    Ok(())
+}
+
+async fn event_personal_data(app_state: &SharedAppState, event_id: EventId, registration: &str, rpc_client: ClientCommandSender) -> anyhow::Result<EventPersonalData> {
+    // event is not opened, while its closed DB is read
+    let event_lock = app_state.event_lock(event_id);
+    let _event_guard = event_lock.lock().await;
+    let event_record = app_state.event_record(event_id).await?;
+    let mut data = EventPersonalData {
+        event_id,
+        event_name: event_record.name.clone(),
+        event_date: event_record.date.to_rfc3339(),
+        ..Default::default()
+    };
+    if app_state.is_event_open(event_id) {
+        let sql_api = EventSqlApi::new(event_id, app_state.clone(), rpc_client);
+        collect_event_data(&sql_api, registration, &mut data).await?;
+    } else if event_record.is_local {
+        let db_file = event_db_file(event_id);
+        if !std::path::Path::new(&db_file).exists() {
+            return Ok(data);
+        }
+        let pool = open_event_db_pool(&db_file, Some(1)).await?;
+        let sql_api = AppSqlApi::new_without_recchng(pool);
+        collect_event_data(&sql_api, registration, &mut data).await?;
+    } else {
+        bail!("Remote event is not open");
+    }
+    Ok(data)
+}
+
+pub(crate) async fn export_personal_data(app_state: &SharedAppState, registration: &str, rpc_client: ClientCommandSender) -> anyhow::Result<PersonalDataExport> {
+    let registration = registration.trim();
+    if registration.is_empty() {
+        bail!("Registration cannot be empty");
+    }
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id FROM events ORDER BY id", None).await?;
+    let event_ids = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect::<Vec<EventId>>();
+    let mut export = PersonalDataExport {
+        registration: registration.to_string(),
+        exported_at: chrono::Local::now().fixed_offset().to_rfc3339(),
+        ..Default::default()
+    };
+    for event_id in event_ids {
+        match event_personal_data(app_state, event_id, registration, rpc_client.clone()).await {
+            Ok(data) if data.is_empty() => {}
+            Ok(data) => export.events.push(data),
+            Err(e) => export.skipped_events.push(SkippedEvent { event_id, reason: e.to_string() }),
+        }
+    }
+    log::info!("Personal data of registration {registration} exported, events: {}, skipped events: {}", export.events.len(), export.skipped_events.len());
+    Ok(export)
+}
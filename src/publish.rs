@@ -0,0 +1,224 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, FixedOffset};
+use log::{debug, error, info, warn};
+use qxsql::QxSqlApi;
+use shvclient::ClientCommandSender;
+use shvproto::RpcValue;
+use smol::channel;
+
+use crate::eventsqlapi::EventSqlApi;
+use crate::global_config;
+use crate::jobs::{JobState, run_job};
+use crate::results::{RunFilter, RunResult, load_run_results, stage_time, xml_escape};
+use crate::state::{EventId, SharedAppState};
+
+/// Results service, the event results are pushed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PublishService {
+    OResults,
+    Liveresultat,
+}
+
+/// Per event publishing settings, stored in event config `publish.*` keys, API key is event secret
+/// kept out of the readable config table. Results are not published if `publish.service` is not set
+#[derive(Debug, Clone)]
+struct PublishSettings {
+    service: PublishService,
+    api_key: String,
+    competition_id: String,
+}
+
+/// Event secret key, it is set by `config:setValue` like other publish settings
+pub(crate) const SKEY_API_KEY: &str = "publish.apiKey";
+
+async fn publish_settings(sql_api: &EventSqlApi) -> anyhow::Result<Option<PublishSettings>> {
+    let result = sql_api.query("SELECT ckey, cvalue FROM config WHERE ckey IN ('publish.service', 'publish.competitionId')", None).await?;
+    let mut service = None;
+    let mut competition_id = String::new();
+    for row in 0..result.row_count() {
+        let cvalue = result.value(row, 1).and_then(|v| v.as_str()).unwrap_or_default().trim().to_string();
+        match result.value(row, 0).and_then(|v| v.as_str()) {
+            Some("publish.service") => service = match cvalue.as_str() {
+                "" => None,
+                "oresults" => Some(PublishService::OResults),
+                "liveresultat" => Some(PublishService::Liveresultat),
+                s => bail!("Unknown results publish service: '{s}'"),
+            },
+            Some("publish.competitionId") => competition_id = cvalue,
+            _ => {}
+        }
+    }
+    let Some(service) = service else {
+        return Ok(None);
+    };
+    let api_key = sql_api.secret(SKEY_API_KEY).await?.unwrap_or_default();
+    Ok(Some(PublishSettings { service, api_key, competition_id }))
+}
+
+fn iof_date_time(stage_start: DateTime<FixedOffset>, time_ms: Option<i64>) -> Option<String> {
+    time_ms.map(|time_ms| stage_time(stage_start, time_ms).to_rfc3339())
+}
+
+/// IOF XML 3.0 result list, `Delta` status means the list contains changed runs only
+pub(crate) fn iof_result_list(event_name: &str, stage_start: DateTime<FixedOffset>, results: &[RunResult], delta: bool) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<ResultList xmlns=\"http://www.orienteering.org/datastandard/3.0\" iofVersion=\"3.0\" createTime=\"{}\" creator=\"qxeventd\" status=\"{}\">\n",
+        chrono::Local::now().fixed_offset().to_rfc3339(),
+        if delta { "Delta" } else { "Complete" },
+    ));
+    xml.push_str(&format!("<Event><Name>{}</Name><StartTime><Date>{}</Date></StartTime></Event>\n", xml_escape(event_name), stage_start.format("%Y-%m-%d")));
+    for class_results in results.chunk_by(|a, b| a.class_id == b.class_id) {
+        xml.push_str(&format!("<ClassResult><Class><Id>{}</Id><Name>{}</Name></Class>\n", class_results[0].class_id, xml_escape(&class_results[0].class_name)));
+        for run in class_results {
+            xml.push_str("<PersonResult><Person>");
+            xml.push_str(&format!("<Id>{}</Id><Name><Family>{}</Family><Given>{}</Given></Name></Person>", run.run_id, xml_escape(&run.last_name), xml_escape(&run.first_name)));
+            if !run.club.is_empty() {
+                xml.push_str(&format!("<Organisation><Name>{}</Name></Organisation>", xml_escape(&run.club)));
+            }
+            xml.push_str("<Result>");
+            if let Some(bib) = run.start_number {
+                xml.push_str(&format!("<BibNumber>{bib}</BibNumber>"));
+            }
+            if let Some(start_time) = iof_date_time(stage_start, run.start_time_ms) {
+                xml.push_str(&format!("<StartTime>{start_time}</StartTime>"));
+            }
+            if let Some(finish_time) = iof_date_time(stage_start, run.finish_time_ms) {
+                xml.push_str(&format!("<FinishTime>{finish_time}</FinishTime>"));
+            }
+            if let Some(time_ms) = run.time_ms {
+                xml.push_str(&format!("<Time>{}</Time>", time_ms as f64 / 1000.));
+            }
+            if let Some(place) = run.place {
+                xml.push_str(&format!("<Position>{place}</Position>"));
+            }
+            xml.push_str(&format!("<Status>{}</Status>", run.status.as_iof_str()));
+            if let Some(si_id) = run.si_id {
+                xml.push_str(&format!("<ControlCard>{si_id}</ControlCard>"));
+            }
+            xml.push_str("</Result></PersonResult>\n");
+        }
+        xml.push_str("</ClassResult>\n");
+    }
+    xml.push_str("</ResultList>\n");
+    xml
+}
+
+fn multipart_form(boundary: &str, fields: &[(&str, &str)], file_field: &str, file_name: &str, file: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(format!("--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n").as_bytes());
+    }
+    body.extend_from_slice(format!("--{boundary}\r\nContent-Disposition: form-data; name=\"{file_field}\"; filename=\"{file_name}\"\r\nContent-Type: application/xml\r\n\r\n").as_bytes());
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    body
+}
+
+async fn upload(settings: &PublishSettings, xml: String) -> anyhow::Result<()> {
+    let config = &global_config().publish;
+    let settings = settings.clone();
+    let (oresults_url, liveresultat_url) = (config.oresults_url.clone(), config.liveresultat_url.clone());
+    // ureq is blocking
+    smol::unblock(move || {
+        let response = match settings.service {
+            PublishService::OResults => {
+                let boundary = format!("qxeventd{}", chrono::Utc::now().timestamp_micros());
+                let body = multipart_form(&boundary, &[("apiKey", &settings.api_key)], "file", "results.xml", xml.as_bytes());
+                ureq::post(&oresults_url)
+                    .set("Content-Type", &format!("multipart/form-data; boundary={boundary}"))
+                    .send_bytes(&body)
+            }
+            PublishService::Liveresultat => {
+                ureq::post(&liveresultat_url)
+                    .query("comp", &settings.competition_id)
+                    .query("pwd", &settings.api_key)
+                    .set("Content-Type", "application/xml")
+                    .send_bytes(xml.as_bytes())
+            }
+        };
+        response.map(|_| ()).map_err(|e| anyhow!("Results upload to {:?} failed: {e}", settings.service))
+    }).await
+}
+
+async fn publish_runs(sql_api: &EventSqlApi, settings: &PublishSettings, stage_id: i64, run_ids: &[i64]) -> anyhow::Result<()> {
+    let result = sql_api.query("SELECT cvalue FROM config WHERE ckey = 'event.name'", None).await?;
+    let event_name = result.value(0, 0).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let stage_start = sql_api.stage_start(stage_id).await?;
+    // places of changed runs depend on the whole class
+    let changed = load_run_results(sql_api, stage_id, RunFilter::Runs(run_ids)).await?;
+    let class_ids: BTreeSet<i64> = changed.iter().map(|run| run.class_id).collect();
+    let mut results = vec![];
+    for class_id in class_ids {
+        results.extend(load_run_results(sql_api, stage_id, RunFilter::Class(class_id)).await?
+            .into_iter()
+            .filter(|run| run_ids.contains(&run.run_id)));
+    }
+    if results.is_empty() {
+        return Ok(());
+    }
+    let xml = iof_result_list(&event_name, stage_start, &results, true);
+    let config = &global_config().publish;
+    let retry_delay = config.retry_delay.to_std().unwrap_or(Duration::from_secs(5));
+    let mut attempt: u32 = 0;
+    loop {
+        attempt += 1;
+        match upload(settings, xml.clone()).await {
+            Ok(()) => {
+                debug!("Published {} results to {:?}", results.len(), settings.service);
+                return Ok(());
+            }
+            Err(e) if attempt <= config.retries => {
+                warn!("{e}, attempt {attempt}/{}", config.retries + 1);
+                smol::Timer::after(retry_delay * attempt).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Push changed runs results of event to results service.
+///
+/// Run ids are received from the channel, changes are collected for `publish.min_interval`
+/// and uploaded in one batch, failed upload is repeated with the next change.
+pub(crate) async fn publisher(app_state: SharedAppState, event_id: EventId, rpc_client: ClientCommandSender, changed_runs: channel::Receiver<i64>) {
+    let min_interval = global_config().publish.min_interval.to_std().unwrap_or(Duration::from_secs(10));
+    let mut pending = BTreeSet::new();
+    while let Ok(run_id) = changed_runs.recv().await {
+        pending.insert(run_id);
+        smol::Timer::after(min_interval).await;
+        while let Ok(run_id) = changed_runs.try_recv() {
+            pending.insert(run_id);
+        }
+        let Some(stage_id) = app_state.with_open_event(event_id, |e| e.current_stage) else {
+            break;
+        };
+        let sql_api = EventSqlApi::new(event_id, app_state.clone(), rpc_client.clone());
+        let settings = match publish_settings(&sql_api).await {
+            Ok(Some(settings)) => settings,
+            Ok(None) => {
+                pending.clear();
+                continue;
+            }
+            Err(e) => {
+                error!("Event {event_id} results publishing error: {e}");
+                continue;
+            }
+        };
+        let run_ids: Vec<i64> = pending.iter().copied().collect();
+        let status = run_job(app_state.clone(), rpc_client.clone(), "publish", Some(event_id), move |_progress| async move {
+            publish_runs(&sql_api, &settings, stage_id, &run_ids).await
+                .map(|()| RpcValue::null())
+        }).await;
+        match status {
+            Ok(status) if status.state == JobState::Finished => pending.clear(),
+            Ok(status) => error!("Event {event_id} results publishing {}: {}", status.state.as_str(), status.error.unwrap_or_default()),
+            Err(e) => error!("Event {event_id} results publishing error: {e}"),
+        }
+    }
+    info!("Event {event_id} results publisher finished");
+}
@@ -0,0 +1,217 @@
+//! Import of QuickEvent event DB files (.qbe). Legacy schema is converted to the initial event DB schema,
+//! the file is registered as a new local event and remaining migrations are applied when the event is opened.
+
+use anyhow::{anyhow, bail};
+use async_sqlite::rusqlite::{self, Connection, OptionalExtension};
+use log::info;
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::RpcValue;
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::encryption;
+use crate::eventctlnode::resolve_event_owner;
+use crate::eventdb::{EVENT_DB_DATA_VERSION, EVENT_DB_SCHEMA_VERSION, Sport};
+use crate::state::{EventId, EventRecordChange, SharedAppState, event_db_file};
+use crate::webhooks::{self, WebhookEvent};
+use crate::{anyhow_to_rpc_error, global_config};
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+const METH_IMPORT: &str = "import";
+
+pub(crate) const IMPORT_QBE_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_IMPORT, Flags::UserIDRequired, AccessLevel::Write, "[s|n:owner,x:data]", "[i:event_id,s:api_token]", &[], "",
+    ),
+];
+
+struct Column {
+    name: String,
+    decl_type: String,
+    not_null: bool,
+    default: Option<String>,
+}
+
+impl Column {
+    fn add_column_sql(&self, table: &str) -> String {
+        let mut sql = format!("ALTER TABLE \"{table}\" ADD COLUMN \"{}\" {}", self.name, self.decl_type);
+        // NOT NULL column can be added with default value only
+        if let Some(default) = &self.default {
+            if self.not_null {
+                sql.push_str(" NOT NULL");
+            }
+            sql.push_str(&format!(" DEFAULT {default}"));
+        }
+        sql
+    }
+}
+
+fn table_columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Column>> {
+    let mut stmt = conn.prepare("SELECT name, type, \"notnull\", dflt_value FROM pragma_table_info(?1)")?;
+    stmt.query_map([table], |row| Ok(Column {
+            name: row.get(0)?,
+            decl_type: row.get(1)?,
+            not_null: row.get(2)?,
+            default: row.get(3)?,
+        }))?
+        .collect()
+}
+
+/// Statements creating tables, columns and indexes of the initial event DB schema missing in `conn`
+fn legacy_conversion_sql(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let reference = Connection::open_in_memory()?;
+    reference.execute_batch(include_str!("create_event_db.sql"))?;
+    let mut stmt = reference.prepare("SELECT type, name, sql FROM sqlite_master \
+        WHERE type IN ('table', 'index') AND sql IS NOT NULL ORDER BY type = 'index', name")?;
+    let objects = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut statements = vec![];
+    for (kind, name, sql) in objects {
+        let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = ?1 AND name = ?2)", [&kind, &name], |row| row.get(0))?;
+        if !exists {
+            statements.push(sql);
+        } else if kind == "table" {
+            let legacy_columns = table_columns(conn, &name)?;
+            statements.extend(table_columns(&reference, &name)?.iter()
+                .filter(|column| !legacy_columns.iter().any(|legacy| legacy.name.eq_ignore_ascii_case(&column.name)))
+                .map(|column| column.add_column_sql(&name)));
+        }
+    }
+    Ok(statements)
+}
+
+/// Event name and date of converted DB
+struct LegacyEvent {
+    name: String,
+    date: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
+
+fn config_value(conn: &Connection, key: &str) -> rusqlite::Result<Option<String>> {
+    Ok(conn.query_row("SELECT cvalue FROM config WHERE ckey = ?1", [key], |row| row.get::<_, Option<String>>(0))
+        .optional()?
+        .flatten())
+}
+
+/// Convert legacy QuickEvent DB to the initial event DB schema, DB already created by qxeventd is only checked
+fn convert_legacy_db(db_file: &str) -> anyhow::Result<LegacyEvent> {
+    let mut conn = Connection::open(db_file)?;
+    conn.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0))
+        .map_err(|e| anyhow!("File is not a valid SQLite database: {e}"))
+        .and_then(|result| if result == "ok" { Ok(()) } else { Err(anyhow!("Imported database is corrupted: {result}")) })?;
+    let schema_version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if schema_version > EVENT_DB_SCHEMA_VERSION {
+        bail!("Imported DB schema version {schema_version} is newer than supported version {EVENT_DB_SCHEMA_VERSION}");
+    }
+    let has_config: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'config')", [], |row| row.get(0))?;
+    let data_version = (if has_config { config_value(&conn, "db.version")? } else { None })
+        .ok_or_else(|| anyhow!("Imported file is not a QuickEvent event database"))?;
+    let data_version: i64 = data_version.trim().parse()
+        .map_err(|e| anyhow!("Imported DB has invalid data version '{data_version}': {e}"))?;
+    if data_version > EVENT_DB_DATA_VERSION {
+        bail!("Imported DB data version {data_version} is newer than supported version {EVENT_DB_DATA_VERSION}");
+    }
+    if schema_version == 0 {
+        let tx = conn.transaction()?;
+        let statements = legacy_conversion_sql(&tx)?;
+        for sql in &statements {
+            tx.execute_batch(sql).map_err(|e| anyhow!("Conversion of imported DB failed, SQL: {sql}, error: {e}"))?;
+        }
+        tx.execute("UPDATE config SET cvalue = ?1 WHERE ckey = 'db.version'", [EVENT_DB_DATA_VERSION.to_string()])?;
+        // initial schema migration is applied
+        tx.pragma_update(None, "user_version", 1)?;
+        tx.commit()?;
+        info!("Imported DB converted from data version {data_version} by {} statements", statements.len());
+    }
+    let name = config_value(&conn, "event.name")?.unwrap_or_default();
+    let date = config_value(&conn, "event.date")?
+        .and_then(|date| chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok())
+        .map(|date| {
+            let time = config_value(&conn, "event.time").ok().flatten()
+                .and_then(|time| chrono::NaiveTime::parse_from_str(&time, "%H:%M:%S").ok())
+                .unwrap_or_default();
+            date.and_time(time)
+        })
+        .and_then(|date_time| date_time.and_local_timezone(chrono::Local).earliest())
+        .map(|date_time| date_time.fixed_offset());
+    Ok(LegacyEvent { name, date })
+}
+
+/// Convert QuickEvent DB file and register it as a new local event
+pub(crate) async fn import_qbe(app_state: SharedAppState, owner: String, data: Vec<u8>, rpc_client: ClientCommandSender) -> anyhow::Result<(EventId, String)> {
+    if owner.is_empty() {
+        bail!("Owner cannot be empty");
+    }
+    if !data.starts_with(SQLITE_HEADER) {
+        bail!("Imported file is not a SQLite database");
+    }
+    let import_dir = format!("{}/import", global_config().data_dir);
+    std::fs::create_dir_all(&import_dir)?;
+    let import_file = format!("{import_dir}/{}.qbe", chrono::Utc::now().timestamp_micros());
+    std::fs::write(&import_file, &data)?;
+    let converted = {
+        let import_file = import_file.clone();
+        smol::unblock(move || {
+            let legacy_event = convert_legacy_db(&import_file)?;
+            encryption::encrypt_file(&import_file)?;
+            Ok::<_, anyhow::Error>(legacy_event)
+        }).await
+    };
+    let legacy_event = match converted {
+        Ok(legacy_event) => legacy_event,
+        Err(e) => {
+            let _ = std::fs::remove_file(&import_file);
+            return Err(e);
+        }
+    };
+    let (event_id, api_token) = app_state.create_event(owner, Some(true), Sport::default(), None, rpc_client.clone()).await?;
+    let db_file = event_db_file(event_id);
+    let moved = std::path::Path::new(&db_file).parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::rename(&import_file, &db_file));
+    if let Err(e) = moved {
+        let _ = std::fs::remove_file(&import_file);
+        app_state.delete_event(event_id, rpc_client).await?;
+        bail!("Cannot move imported DB to {db_file}: {e}");
+    }
+    let changes = EventRecordChange {
+        name: Some(legacy_event.name.clone()),
+        date: legacy_event.date,
+        ..Default::default()
+    };
+    app_state.update_event_record(event_id, changes, rpc_client).await?;
+    info!("Imported QuickEvent DB as event {event_id}");
+    webhooks::emit(app_state.db_pool.clone(), WebhookEvent::ImportFinished, Some(event_id), serde_json::json!({
+        "kind": "qbe",
+        "eventName": legacy_event.name,
+    }));
+    Ok((event_id, api_token))
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+) -> RequestHandlerResult {
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(IMPORT_QBE_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(IMPORT_QBE_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => {
+            match m.method() {
+                METH_IMPORT => m.resolve(IMPORT_QBE_NODE_METHODS, async move || {
+                    let param = rq.param().unwrap_or_default().as_list();
+                    let owner = resolve_event_owner(&rq, param.first().map(|v| v.as_str()).unwrap_or_default())
+                        .map_err(anyhow_to_rpc_error)?;
+                    let data = param.get(1).map(|v| v.as_blob()).unwrap_or_default().to_vec();
+                    let (event_id, api_token) = import_qbe(app_state, owner, data, client_cmd_tx).await
+                        .map_err(anyhow_to_rpc_error)?;
+                    Ok(RpcValue::from(vec![RpcValue::from(event_id), RpcValue::from(api_token)]))
+                }),
+                _ => err_unresolved_request(),
+            }
+        }
+    }
+}
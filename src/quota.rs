@@ -0,0 +1,122 @@
+//! Quotas of event owners and organizations, checked when event is created or opened.
+
+use std::fmt;
+
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+
+use crate::appsqlapi::AppSqlApi;
+use crate::config::QuotaLimits;
+use crate::global_config;
+use crate::state::{EventId, EventRecord, State, event_db_file};
+
+/// Prefix of quota exceeded error message, it is followed by CPON map of error details
+pub(crate) const QUOTA_EXCEEDED: &str = "QuotaExceeded";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QuotaScope {
+    Owner,
+    Organization,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QuotaExceeded {
+    pub quota: &'static str,
+    pub scope: QuotaScope,
+    /// Owner user id or organization id
+    pub subject: String,
+    pub limit: u64,
+    pub current: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Quota {} of {:?} '{}' exceeded, limit: {}, current: {}", self.quota, self.scope, self.subject, self.limit, self.current)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl QuotaExceeded {
+    pub fn to_rpc_error(&self) -> RpcError {
+        let details = shvproto::to_rpcvalue(self).map(|v| v.to_cpon()).unwrap_or_default();
+        RpcError::new(RpcErrorCode::PermissionDenied, format!("{QUOTA_EXCEEDED}: {details}"))
+    }
+}
+
+fn check_limit(quota: &'static str, limit: Option<u64>, current: u64, scope: QuotaScope, subject: &str) -> Result<(), QuotaExceeded> {
+    match limit {
+        Some(limit) if current >= limit => Err(QuotaExceeded { quota, scope, subject: subject.to_string(), limit, current }),
+        _ => Ok(()),
+    }
+}
+
+/// Events of owner or organization
+async fn scope_events(app_state: &State, scope: QuotaScope, subject: &str) -> anyhow::Result<Vec<EventId>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let query = match scope {
+        QuotaScope::Owner => "SELECT id FROM events WHERE owner = :subject",
+        QuotaScope::Organization => "SELECT id FROM events WHERE organization_id = :subject",
+    };
+    let subject: DbValue = match scope {
+        QuotaScope::Owner => subject.into(),
+        QuotaScope::Organization => subject.parse::<i64>()?.into(),
+    };
+    let result = qxsql.query(query, Some(&record_from_slice(&[("subject", subject)]))).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect())
+}
+
+fn db_size_mb(event_ids: &[EventId]) -> u64 {
+    let bytes: u64 = event_ids.iter()
+        .flat_map(|event_id| {
+            let db_file = event_db_file(*event_id);
+            [format!("{db_file}-wal"), db_file]
+        })
+        .filter_map(|file| std::fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    bytes / (1024 * 1024)
+}
+
+async fn check_scope(app_state: &State, limits: &QuotaLimits, scope: QuotaScope, subject: &str, opening: bool) -> anyhow::Result<()> {
+    if limits.max_events.is_none() && limits.max_open_events.is_none() && limits.max_db_size_mb.is_none() {
+        return Ok(());
+    }
+    let event_ids = scope_events(app_state, scope, subject).await?;
+    if opening {
+        let open_events = event_ids.iter().filter(|event_id| app_state.is_event_open(**event_id)).count();
+        check_limit("maxOpenEvents", limits.max_open_events, open_events as u64, scope, subject)?;
+    } else {
+        check_limit("maxEvents", limits.max_events, event_ids.len() as u64, scope, subject)?;
+    }
+    if limits.max_db_size_mb.is_some() {
+        check_limit("maxDbSizeMb", limits.max_db_size_mb, db_size_mb(&event_ids), scope, subject)?;
+    }
+    Ok(())
+}
+
+/// Check quotas of owner and organization of new event
+pub(crate) async fn check_create_event(app_state: &State, owner: &str, organization_id: Option<i64>) -> anyhow::Result<()> {
+    let config = &global_config().quota;
+    check_scope(app_state, &config.owner, QuotaScope::Owner, owner, false).await?;
+    if let Some(organization_id) = organization_id {
+        check_scope(app_state, &config.organization, QuotaScope::Organization, &organization_id.to_string(), false).await?;
+    }
+    Ok(())
+}
+
+/// Check quotas of owner and organization of event being opened
+pub(crate) async fn check_open_event(app_state: &State, event_record: &EventRecord) -> anyhow::Result<()> {
+    let config = &global_config().quota;
+    check_scope(app_state, &config.owner, QuotaScope::Owner, &event_record.owner, true).await?;
+    if let Some(organization_id) = event_record.organization_id {
+        check_scope(app_state, &config.organization, QuotaScope::Organization, &organization_id.to_string(), true).await?;
+    }
+    Ok(())
+}
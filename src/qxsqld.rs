@@ -0,0 +1,216 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use shvclient::ClientCommandSender;
+use shvrpc::RpcMessage;
+use shvrpc::util::join_path;
+use smol::process::{Child, Command};
+use smol::process::unix::CommandExt;
+
+use crate::config::QxsqldConfig;
+use crate::global_config;
+use crate::state::{EventId, SharedAppState, event_db_file, remote_event_mount_point};
+use crate::traffic;
+
+pub const SIG_QXSQLD_RESTART: &str = "qxsqldrestart";
+
+fn expand_placeholders(template: &str, event_id: EventId, mount: &str, db_file: &str, broker_url: &str) -> String {
+    template
+        .replace("{event_id}", &event_id.to_string())
+        .replace("{mount}", mount)
+        .replace("{db_file}", db_file)
+        .replace("{broker_url}", broker_url)
+}
+
+pub fn spawn_qxsqld(config: &QxsqldConfig, event_id: EventId, mount: &str, db_file: &str) -> anyhow::Result<Child> {
+    let broker_url = expand_placeholders(&config.broker_url, event_id, mount, db_file, "");
+    let args = config.args.iter()
+        .map(|arg| expand_placeholders(arg, event_id, mount, db_file, &broker_url))
+        .collect::<Vec<_>>();
+    info!("Starting event {event_id} DB service: {}, mount: {mount}", config.executable);
+    if let Some(dir) = Path::new(db_file).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut command = Command::new(&config.executable);
+    command
+        .args(&args)
+        .envs(&config.env)
+        .kill_on_drop(true);
+    if let Some(working_dir) = &config.working_dir {
+        command.current_dir(working_dir);
+    }
+    set_resource_limits(&mut command, config);
+    let child = command.spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to start {}: {e}", config.executable))?;
+    info!("Event {event_id} DB service started, pid: {}", child.id());
+    Ok(child)
+}
+
+fn rlimit(value: u64) -> libc::rlimit {
+    libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t }
+}
+
+fn set_resource_limits(command: &mut Command, config: &QxsqldConfig) {
+    let address_space = config.max_address_space_mb.map(|mb| mb * 1024 * 1024);
+    let cpu_time = config.max_cpu_time_sec;
+    if address_space.is_none() && cpu_time.is_none() {
+        return;
+    }
+    // SAFETY: closure runs in the forked child before exec, it calls async-signal-safe setrlimit only
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(bytes) = address_space
+                && libc::setrlimit(libc::RLIMIT_AS, &rlimit(bytes)) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if let Some(secs) = cpu_time
+                && libc::setrlimit(libc::RLIMIT_CPU, &rlimit(secs)) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+pub async fn ping(rpc_client: &ClientCommandSender, mount: &str) -> anyhow::Result<()> {
+    let _: () = rpc_client.call_rpc_method(join_path(mount, ".app"), "ping", None, None, None, None::<fn(_)>).await
+        .map_err(|e| anyhow::anyhow!("{e}"))?;
+    Ok(())
+}
+
+/// Poll event DB service mount point until it responds to ping
+pub async fn wait_for_ready(rpc_client: &ClientCommandSender, event_id: EventId, mount: &str, timeout: Duration) -> anyhow::Result<()> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+    let started = Instant::now();
+    loop {
+        match ping(rpc_client, mount).await {
+            Ok(()) => {
+                info!("Event {event_id} DB service is ready after {} msec", started.elapsed().as_millis());
+                return Ok(());
+            }
+            Err(e) if started.elapsed() >= timeout => {
+                return Err(anyhow::anyhow!("Event {event_id} DB service did not become ready in {} sec: {e}", timeout.as_secs()));
+            }
+            Err(_) => smol::Timer::after(POLL_INTERVAL).await,
+        };
+    }
+}
+
+/// Clock ticks per second used in `/proc/<pid>/stat`
+fn clock_ticks_per_sec() -> f64 {
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 { ticks as f64 } else { 100. }
+}
+
+struct ProcessUsage {
+    rss_kb: u64,
+    cpu_ticks: u64,
+}
+
+impl ProcessUsage {
+    fn read(pid: u32) -> anyhow::Result<Self> {
+        let status = std::fs::read_to_string(format!("/proc/{pid}/status"))?;
+        let rss_kb = status.lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|val| val.split_whitespace().next())
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or_default();
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+        // process name can contain spaces, fields after it start with state (field 3)
+        let fields = stat.rsplit_once(')')
+            .map(|(_, rest)| rest.split_whitespace().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let field = |n: usize| fields.get(n - 3).and_then(|val| val.parse::<u64>().ok()).unwrap_or_default();
+        let (utime, stime) = (field(14), field(15));
+        Ok(Self { rss_kb, cpu_ticks: utime + stime })
+    }
+}
+
+/// Restarts event DB service process, when it exceeds resource limits or exits.
+///
+/// Watchdog finishes, when event is closed.
+pub(crate) async fn watchdog(app_state: SharedAppState, event_id: EventId, rpc_client: ClientCommandSender) {
+    let Some(config) = global_config().qxsqld.as_ref() else {
+        return;
+    };
+    if config.max_memory_mb.is_none() && config.max_cpu_percent.is_none() && config.max_address_space_mb.is_none() && config.max_cpu_time_sec.is_none() {
+        return;
+    }
+    let clock_ticks_per_sec = clock_ticks_per_sec();
+    let interval = config.watchdog_interval.to_std().unwrap_or(Duration::from_secs(30));
+    let mut last_cpu_ticks: Option<(u32, u64, Instant)> = None;
+    loop {
+        smol::Timer::after(interval).await;
+        let process = app_state.with_open_event_mut(event_id, |event| event.qxsqld_process.as_mut()
+            .map(|child| (child.id(), child.try_status().ok().flatten())))
+            .flatten();
+        let Some((pid, exit_status)) = process else {
+            break;
+        };
+        let exceeded = match exit_status {
+            Some(status) => Some(format!("exited with {status}")),
+            None => {
+                let usage = match ProcessUsage::read(pid) {
+                    Ok(usage) => usage,
+                    Err(e) => {
+                        warn!("Cannot read event {event_id} DB service pid: {pid} resource usage: {e}");
+                        continue;
+                    }
+                };
+                let now = Instant::now();
+                let mut exceeded = None;
+                if let Some(max_memory_mb) = config.max_memory_mb && usage.rss_kb / 1024 > max_memory_mb {
+                    exceeded = Some(format!("memory {} MB exceeds limit {max_memory_mb} MB", usage.rss_kb / 1024));
+                }
+                if let Some(max_cpu_percent) = config.max_cpu_percent
+                    && let Some((last_pid, last_ticks, last_time)) = last_cpu_ticks
+                    && last_pid == pid {
+                    let elapsed = now.duration_since(last_time).as_secs_f64();
+                    let cpu_percent = (usage.cpu_ticks.saturating_sub(last_ticks) as f64) / clock_ticks_per_sec / elapsed * 100.;
+                    if elapsed > 0. && cpu_percent > max_cpu_percent {
+                        exceeded = Some(format!("CPU usage {cpu_percent:.0}% exceeds limit {max_cpu_percent:.0}%"));
+                    }
+                }
+                last_cpu_ticks = Some((pid, usage.cpu_ticks, now));
+                exceeded
+            }
+        };
+        let Some(reason) = exceeded else {
+            continue;
+        };
+        error!("Event {event_id} DB service pid: {pid} {reason}, restarting");
+        let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}"), SIG_QXSQLD_RESTART).with_param(reason);
+        traffic::record_signal(event_id, &signal);
+        if let Err(e) = rpc_client.send_message(signal) {
+            error!("Failed to send event {event_id} {SIG_QXSQLD_RESTART} signal: {e}");
+        }
+        // old child must release the broker mount before the new one is started
+        let Some(old_child) = app_state.with_open_event_mut(event_id, |event| event.qxsqld_process.take()).flatten() else {
+            break;
+        };
+        kill_child(old_child, event_id, pid).await;
+        let child = match spawn_qxsqld(config, event_id, &remote_event_mount_point(event_id), &event_db_file(event_id)) {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to restart event {event_id} DB service: {e}");
+                break;
+            }
+        };
+        // new child is killed on drop, when the event was closed meanwhile
+        if app_state.with_open_event_mut(event_id, |event| event.qxsqld_process = Some(child)).is_none() {
+            break;
+        }
+        last_cpu_ticks = None;
+    }
+    info!("Event {event_id} DB service watchdog finished");
+}
+
+async fn kill_child(mut child: Child, event_id: EventId, pid: u32) {
+    if let Err(e) = child.kill() {
+        warn!("Failed to kill event {event_id} DB service pid: {pid}: {e}");
+    }
+    if let Err(e) = child.status().await {
+        warn!("Failed to wait for event {event_id} DB service pid: {pid} exit: {e}");
+    }
+}
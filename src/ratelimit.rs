@@ -0,0 +1,164 @@
+//! Token bucket rate limiting of calls per caller, so one flooding client cannot starve the others.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use log::warn;
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::StaticNode;
+use shvproto::RpcValue;
+use shvrpc::metamethod::{AccessLevel, MetaMethod};
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::config::{MethodRateLimits, TokenBucketConfig};
+use crate::global_config;
+use crate::state::SharedAppState;
+
+/// Buckets are pruned, when there is more of them
+const MAX_BUCKETS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum LimitedNode {
+    Sql,
+    EventCtl,
+}
+
+impl LimitedNode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LimitedNode::Sql => "sql",
+            LimitedNode::EventCtl => "eventctl",
+        }
+    }
+    fn limits(&self) -> &'static MethodRateLimits {
+        let config = &global_config().rate_limit;
+        match self {
+            LimitedNode::Sql => &config.sql,
+            LimitedNode::EventCtl => &config.eventctl,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MethodClass {
+    Read,
+    Write,
+}
+
+impl MethodClass {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MethodClass::Read => "read",
+            MethodClass::Write => "write",
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &TokenBucketConfig, now: Instant) -> Self {
+        Self { tokens: config.burst as f64, refilled_at: now }
+    }
+    fn refill(&mut self, config: &TokenBucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate).min(config.burst as f64);
+        self.refilled_at = now;
+    }
+    fn is_full(&self, config: &TokenBucketConfig) -> bool {
+        self.tokens >= config.burst as f64
+    }
+}
+
+/// Caller is identified by user id, calls without user id are identified by broker caller ids
+fn caller(rq: &RpcMessage) -> String {
+    match rq.user_id() {
+        Some(user_id) if !user_id.is_empty() => user_id.split(':').next().unwrap_or(user_id).to_string(),
+        _ => format!("{:?}", rq.caller_ids()),
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<(LimitedNode, MethodClass, String), TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Take one token from the caller bucket, `dir`, `ls` and unknown methods are not limited
+    pub fn check(&self, node: LimitedNode, rq: &RpcMessage, methods: &[MetaMethod]) -> Result<(), RpcError> {
+        let Some(mm) = rq.method().and_then(|method| methods.iter().find(|mm| mm.name == method)) else {
+            return Ok(());
+        };
+        if matches!(&*mm.name, "dir" | "ls") {
+            return Ok(());
+        }
+        let class = if matches!(mm.access, AccessLevel::Browse | AccessLevel::Read) { MethodClass::Read } else { MethodClass::Write };
+        let limits = node.limits();
+        let Some(config) = (match class {
+            MethodClass::Read => &limits.read,
+            MethodClass::Write => &limits.write,
+        }) else {
+            return Ok(());
+        };
+        let caller = caller(rq);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            // full bucket is the same as a new one
+            buckets.retain(|(node, class, _), bucket| {
+                let limits = node.limits();
+                let config = match class {
+                    MethodClass::Read => &limits.read,
+                    MethodClass::Write => &limits.write,
+                };
+                config.as_ref().is_some_and(|config| {
+                    bucket.refill(config, now);
+                    !bucket.is_full(config)
+                })
+            });
+        }
+        let bucket = buckets.entry((node, class, caller.clone()))
+            .or_insert_with(|| TokenBucket::new(config, now));
+        bucket.refill(config, now);
+        if bucket.tokens < 1.0 {
+            warn!("Rate limit of {} {} methods exceeded by {caller}", node.as_str(), class.as_str());
+            return Err(RpcError::new(RpcErrorCode::TryAgainLater,
+                format!("Rate limit of {} {} methods exceeded, try again later", node.as_str(), class.as_str())));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Static node wrapper checking the caller rate limit before the request is processed
+pub(crate) struct RateLimitedNode<N> {
+    node: N,
+    kind: LimitedNode,
+    app_state: SharedAppState,
+}
+
+impl<N> RateLimitedNode<N> {
+    pub fn new(node: N, kind: LimitedNode, app_state: SharedAppState) -> Self {
+        Self { node, kind, app_state }
+    }
+}
+
+#[async_trait]
+impl<N: StaticNode + Send + Sync> StaticNode for RateLimitedNode<N> {
+    fn methods(&self) -> &'static [MetaMethod] {
+        self.node.methods()
+    }
+
+    async fn process_request(&self, request: RpcMessage, client_command_sender: ClientCommandSender) -> Option<Result<RpcValue, RpcError>> {
+        if let Err(err) = self.app_state.rate_limiter.check(self.kind, &request, self.methods()) {
+            return Some(Err(err));
+        }
+        self.node.process_request(request, client_command_sender).await
+    }
+}
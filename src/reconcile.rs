@@ -0,0 +1,130 @@
+//! Consistency check of `events` table and event directories in `data_dir`. Events without directory
+//! are reported and optionally repaired, directories without event are only reported, they are never deleted.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::bail;
+use log::{info, warn};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+
+use crate::appsqlapi::AppSqlApi;
+use crate::config::ReconcileRepair;
+use crate::global_config;
+use crate::state::{EventId, SharedAppState, event_dir, uses_data_dir};
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReconcileReport {
+    /// Events, which directory is missing
+    pub missing_dirs: Vec<EventId>,
+    /// Directories in `data_dir` named by event id, which is not in `events` table
+    pub orphaned_dirs: Vec<String>,
+    pub recreated: Vec<EventId>,
+    pub marked_broken: Vec<EventId>,
+    /// Broken events, which directory exists again
+    pub unmarked_broken: Vec<EventId>,
+}
+
+impl ReconcileReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing_dirs.is_empty() && self.orphaned_dirs.is_empty()
+    }
+}
+
+pub(crate) fn parse_repair(s: &str) -> anyhow::Result<ReconcileRepair> {
+    match s {
+        "" => Ok(global_config().reconcile.repair),
+        "none" => Ok(ReconcileRepair::None),
+        "recreate" => Ok(ReconcileRepair::Recreate),
+        "mark" => Ok(ReconcileRepair::Mark),
+        _ => bail!("Invalid repair: '{s}', expected one of: none, recreate, mark"),
+    }
+}
+
+async fn set_event_broken(app_state: &SharedAppState, event_id: EventId, reason: Option<String>) -> anyhow::Result<()> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    qxsql.exec("UPDATE events SET broken = :broken WHERE id = :id", Some(&record_from_slice(&[
+        ("broken", reason.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("id", event_id.into()),
+    ]))).await?;
+    app_state.invalidate_cached_event_record(event_id);
+    Ok(())
+}
+
+/// Directories in `data_dir` named by event id
+fn event_dirs(data_dir: &str) -> anyhow::Result<Vec<(EventId, String)>> {
+    if !Path::new(data_dir).is_dir() {
+        return Ok(vec![]);
+    }
+    let mut dirs = vec![];
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(event_id) = entry.file_name().to_str().and_then(|name| name.parse::<EventId>().ok()) {
+            dirs.push((event_id, entry.path().to_string_lossy().into_owned()));
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+pub(crate) async fn reconcile_events(app_state: &SharedAppState, repair: ReconcileRepair) -> anyhow::Result<ReconcileReport> {
+    let data_dir = &global_config().data_dir;
+    let mut report = ReconcileReport::default();
+    if data_dir.is_empty() {
+        return Ok(report);
+    }
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id FROM events ORDER BY id", None).await?;
+    let event_ids = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect::<BTreeSet<EventId>>();
+    for event_id in &event_ids {
+        let event_id = *event_id;
+        let event_record = app_state.event_record(event_id).await?;
+        if !uses_data_dir(&event_record) {
+            continue;
+        }
+        let dir = event_dir(event_id);
+        if Path::new(&dir).is_dir() {
+            if event_record.broken.is_some() {
+                info!("Event {event_id} directory {dir} exists again, event is not broken anymore");
+                set_event_broken(app_state, event_id, None).await?;
+                report.unmarked_broken.push(event_id);
+            }
+            continue;
+        }
+        warn!("Event {event_id} directory {dir} is missing");
+        report.missing_dirs.push(event_id);
+        match repair {
+            ReconcileRepair::None => {}
+            ReconcileRepair::Recreate => {
+                std::fs::create_dir_all(&dir)?;
+                if event_record.broken.is_some() {
+                    set_event_broken(app_state, event_id, None).await?;
+                }
+                info!("Event {event_id} directory {dir} recreated");
+                report.recreated.push(event_id);
+            }
+            ReconcileRepair::Mark => {
+                if event_record.broken.is_none() {
+                    set_event_broken(app_state, event_id, Some(format!("Event directory {dir} is missing"))).await?;
+                    info!("Event {event_id} marked as broken");
+                    report.marked_broken.push(event_id);
+                }
+            }
+        }
+    }
+    for (event_id, dir) in event_dirs(data_dir)? {
+        if !event_ids.contains(&event_id) {
+            warn!("Directory {dir} does not belong to any event");
+            report.orphaned_dirs.push(dir);
+        }
+    }
+    Ok(report)
+}
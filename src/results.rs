@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::bail;
+use chrono::{DateTime, FixedOffset};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+
+use crate::discipline::load_discipline_rules;
+
+/// Competitor status in IOF XML 3.0 terms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RunStatus {
+    Ok,
+    MissingPunch,
+    Disqualified,
+    DidNotFinish,
+    DidNotStart,
+    OverTime,
+    NotCompeting,
+    /// Runner is on the course or has not started yet
+    Inactive,
+}
+
+impl RunStatus {
+    pub fn as_iof_str(&self) -> &'static str {
+        match self {
+            RunStatus::Ok => "OK",
+            RunStatus::MissingPunch => "MissingPunch",
+            RunStatus::Disqualified => "Disqualified",
+            RunStatus::DidNotFinish => "DidNotFinish",
+            RunStatus::DidNotStart => "DidNotStart",
+            RunStatus::OverTime => "OverTime",
+            RunStatus::NotCompeting => "NotCompeting",
+            RunStatus::Inactive => "Inactive",
+        }
+    }
+    /// Short status used in printed result lists
+    pub fn as_short_str(&self) -> &'static str {
+        match self {
+            RunStatus::Ok => "",
+            RunStatus::MissingPunch => "MP",
+            RunStatus::Disqualified => "DISQ",
+            RunStatus::DidNotFinish => "DNF",
+            RunStatus::DidNotStart => "DNS",
+            RunStatus::OverTime => "OT",
+            RunStatus::NotCompeting => "NC",
+            RunStatus::Inactive => "",
+        }
+    }
+}
+
+/// Result of single run in stage, times are in msec since the stage start
+#[derive(Debug, Clone)]
+pub(crate) struct RunResult {
+    pub run_id: i64,
+    pub class_id: i64,
+    pub class_name: String,
+    pub start_number: Option<i64>,
+    pub first_name: String,
+    pub last_name: String,
+    pub registration: String,
+    pub club: String,
+    pub si_id: Option<i64>,
+    pub start_time_ms: Option<i64>,
+    pub finish_time_ms: Option<i64>,
+    pub time_ms: Option<i64>,
+    /// Penalty included in `time_ms`
+    pub penalty_time_ms: Option<i64>,
+    pub status: RunStatus,
+    /// Place in class, `None` for runs without valid result
+    pub place: Option<usize>,
+    /// Sum of time adjustments of upheld protests, it is included in `time_ms`
+    pub adjustment_ms: i64,
+    /// Disqualification is cancelled by upheld protest
+    pub reinstated: bool,
+}
+
+impl RunResult {
+    pub fn name(&self) -> String {
+        format!("{} {}", self.last_name, self.first_name).trim().to_string()
+    }
+}
+
+/// Filter of runs loaded by `load_run_results`
+pub(crate) enum RunFilter<'a> {
+    Stage,
+    Class(i64),
+    Runs(&'a [i64]),
+}
+
+/// Load results of runs in stage, sorted by class, status and time.
+///
+/// Places are computed within the loaded runs, so they are valid for `RunFilter::Stage` and `RunFilter::Class` only.
+pub(crate) async fn load_run_results(sql_api: &(impl QxSqlApi + Sync), stage_id: i64, filter: RunFilter<'_>) -> anyhow::Result<Vec<RunResult>> {
+    let mut params = vec![("stageId", DbValue::from(stage_id))];
+    let condition = match filter {
+        RunFilter::Stage => String::new(),
+        RunFilter::Class(class_id) => {
+            params.push(("classId", class_id.into()));
+            " AND competitors.classId = :classId".to_string()
+        }
+        RunFilter::Runs(run_ids) => {
+            // ids are integers, they can be safely inlined
+            let ids = run_ids.iter().map(i64::to_string).collect::<Vec<_>>().join(",");
+            format!(" AND runs.id IN ({ids})")
+        }
+    };
+    let query = format!("SELECT runs.id, competitors.classId, classes.name, competitors.startNumber, competitors.firstName, competitors.lastName, \
+        competitors.registration, competitors.club, runs.siId, runs.startTimeMs, runs.finishTimeMs, runs.timeMs, \
+        runs.misPunch, runs.disqualifiedByOrganizer, runs.badCheck, runs.notStart, runs.notFinish, runs.overTime, runs.notCompeting, \
+        adjustments.adjustmentMs, adjustments.reinstate, runs.penaltyTimeMs \
+        FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+        LEFT JOIN classes ON classes.id = competitors.classId \
+        LEFT JOIN (SELECT runId, SUM(timeAdjustmentMs) AS adjustmentMs, MAX(reinstate) AS reinstate FROM protests \
+            WHERE status = 'upheld' GROUP BY runId) AS adjustments ON adjustments.runId = runs.id \
+        WHERE runs.stageId = :stageId AND runs.isRunning{condition}");
+    let result = sql_api.query(&query, Some(&record_from_slice(&params))).await?;
+    let rules = load_discipline_rules(sql_api).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let string = |row, col| result.value(row, col).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let flag = |row, col| result.value(row, col).is_some_and(DbValue::to_bool);
+    let mut results: Vec<RunResult> = (0..result.row_count())
+        .map(|row| {
+            let finish_time_ms = int(row, 10);
+            let adjustment_ms = int(row, 19).unwrap_or_default();
+            // reinstated run keeps its time, but not the disqualification
+            let reinstated = flag(row, 20);
+            let status = if flag(row, 18) {
+                RunStatus::NotCompeting
+            } else if flag(row, 15) {
+                RunStatus::DidNotStart
+            } else if flag(row, 16) {
+                RunStatus::DidNotFinish
+            } else if reinstated && finish_time_ms.is_some() {
+                RunStatus::Ok
+            } else if flag(row, 12) && !rules.penalize_missed_controls {
+                RunStatus::MissingPunch
+            } else if flag(row, 13) || flag(row, 14) {
+                RunStatus::Disqualified
+            } else if flag(row, 17) {
+                RunStatus::OverTime
+            } else if finish_time_ms.is_some() {
+                RunStatus::Ok
+            } else {
+                RunStatus::Inactive
+            };
+            RunResult {
+                run_id: int(row, 0).unwrap_or_default(),
+                class_id: int(row, 1).unwrap_or_default(),
+                class_name: string(row, 2),
+                start_number: int(row, 3),
+                first_name: string(row, 4),
+                last_name: string(row, 5),
+                registration: string(row, 6),
+                club: string(row, 7),
+                si_id: int(row, 8),
+                start_time_ms: int(row, 9),
+                finish_time_ms,
+                time_ms: int(row, 11).map(|time_ms| time_ms + adjustment_ms),
+                penalty_time_ms: int(row, 21).filter(|ms| *ms != 0),
+                status,
+                place: None,
+                adjustment_ms,
+                reinstated,
+            }
+        })
+        .collect();
+    results.sort_by(|a, b| {
+        let rank = |r: &RunResult| (r.status != RunStatus::Ok || r.time_ms.is_none(), r.status == RunStatus::Inactive);
+        (&a.class_name, rank(a), a.time_ms, &a.last_name).cmp(&(&b.class_name, rank(b), b.time_ms, &b.last_name))
+    });
+    let mut class_id = None;
+    let mut place = 0;
+    let mut prev_time_ms = None;
+    for run in results.iter_mut() {
+        if class_id != Some(run.class_id) {
+            class_id = Some(run.class_id);
+            place = 0;
+            prev_time_ms = None;
+        }
+        if run.status == RunStatus::Ok && let Some(time_ms) = run.time_ms {
+            place += 1;
+            // equal times share the place
+            if prev_time_ms.map(|(t, _)| t) != Some(time_ms) {
+                prev_time_ms = Some((time_ms, place));
+            }
+            run.place = prev_time_ms.map(|(_, p)| p);
+        }
+    }
+    Ok(results)
+}
+
+/// Club scoring scheme
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ClubScoreScheme {
+    /// Sum of places of the best runners, lower score is better
+    #[default]
+    Placement,
+    /// Sum of points for places of the best runners, higher score is better
+    Points,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ClubScoreParams {
+    /// Current stage if not set
+    #[serde(default)]
+    pub stage_id: Option<i64>,
+    #[serde(default)]
+    pub scheme: ClubScoreScheme,
+    /// Number of best runners of club counted to its score
+    #[serde(default = "default_club_score_best_count")]
+    pub best_count: usize,
+    /// Points for the first, second, ... place of `points` scheme, places not in the table get no points.
+    /// If empty, runner gets the number of runners with valid result in class minus its place plus one.
+    #[serde(default)]
+    pub points: Vec<i64>,
+    /// All classes are scored if empty
+    #[serde(default)]
+    pub class_ids: Vec<i64>,
+}
+impl_rpcvalue_conversions!(ClubScoreParams);
+
+fn default_club_score_best_count() -> usize {
+    3
+}
+
+impl Default for ClubScoreParams {
+    fn default() -> Self {
+        Self {
+            stage_id: None,
+            scheme: ClubScoreScheme::default(),
+            best_count: default_club_score_best_count(),
+            points: vec![],
+            class_ids: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ClubScoreRun {
+    pub run_id: i64,
+    pub name: String,
+    pub class_name: String,
+    pub place: usize,
+    pub points: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ClubScore {
+    pub place: usize,
+    pub club: String,
+    pub score: i64,
+    /// Runners counted to the score, clubs with less than `bestCount` runners are ranked after the complete ones
+    pub runs: Vec<ClubScoreRun>,
+}
+
+/// Club standings of stage, runners without club are not scored
+pub(crate) fn club_score(results: &[RunResult], params: &ClubScoreParams) -> anyhow::Result<Vec<ClubScore>> {
+    if params.best_count == 0 {
+        bail!("bestCount must be positive");
+    }
+    let mut class_sizes: HashMap<i64, usize> = HashMap::new();
+    for run in results.iter().filter(|run| run.place.is_some()) {
+        *class_sizes.entry(run.class_id).or_default() += 1;
+    }
+    let mut clubs: BTreeMap<&str, Vec<ClubScoreRun>> = BTreeMap::new();
+    for run in results {
+        let Some(place) = run.place else {
+            continue;
+        };
+        if run.club.trim().is_empty() || (!params.class_ids.is_empty() && !params.class_ids.contains(&run.class_id)) {
+            continue;
+        }
+        let points = match params.scheme {
+            ClubScoreScheme::Placement => place as i64,
+            ClubScoreScheme::Points if params.points.is_empty() => (class_sizes.get(&run.class_id).copied().unwrap_or_default() - place + 1) as i64,
+            ClubScoreScheme::Points => params.points.get(place - 1).copied().unwrap_or_default(),
+        };
+        clubs.entry(run.club.trim()).or_default().push(ClubScoreRun {
+            run_id: run.run_id,
+            name: run.name(),
+            class_name: run.class_name.clone(),
+            place,
+            points,
+        });
+    }
+    let mut scores: Vec<ClubScore> = clubs.into_iter()
+        .map(|(club, mut runs)| {
+            match params.scheme {
+                ClubScoreScheme::Placement => runs.sort_by_key(|run| run.points),
+                ClubScoreScheme::Points => runs.sort_by_key(|run| std::cmp::Reverse(run.points)),
+            }
+            runs.truncate(params.best_count);
+            ClubScore { place: 0, club: club.to_string(), score: runs.iter().map(|run| run.points).sum(), runs }
+        })
+        .collect();
+    let rank = |score: &ClubScore| {
+        let score_rank = match params.scheme {
+            ClubScoreScheme::Placement => score.score,
+            ClubScoreScheme::Points => -score.score,
+        };
+        (score.runs.len() < params.best_count, score_rank)
+    };
+    scores.sort_by(|a, b| rank(a).cmp(&rank(b)).then_with(|| a.club.cmp(&b.club)));
+    let mut prev_rank = None;
+    for (index, score) in scores.iter_mut().enumerate() {
+        // equal scores share the place
+        let score_rank = rank(score);
+        score.place = match prev_rank {
+            Some((rank, place)) if rank == score_rank => place,
+            _ => index + 1,
+        };
+        prev_rank = Some((score_rank, score.place));
+    }
+    Ok(scores)
+}
+
+/// Format run time as `[h:]mm:ss`
+pub(crate) fn format_time_ms(time_ms: i64) -> String {
+    let sign = if time_ms < 0 { "-" } else { "" };
+    let secs = time_ms.abs() / 1000;
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{sign}{h}:{m:02}:{s:02}")
+    } else {
+        format!("{sign}{m:02}:{s:02}")
+    }
+}
+
+/// Wall clock time of run start or finish, time since stage start is used if the stage start is unknown
+pub(crate) fn format_clock_time(stage_start: Option<DateTime<FixedOffset>>, time_ms: Option<i64>) -> String {
+    match (stage_start, time_ms) {
+        (Some(stage_start), Some(time_ms)) => stage_time(stage_start, time_ms).format("%H:%M:%S").to_string(),
+        (None, Some(time_ms)) => format_time_ms(time_ms),
+        _ => String::new(),
+    }
+}
+
+/// Absolute time of msec offset since stage start
+pub(crate) fn stage_time(stage_start: DateTime<FixedOffset>, time_ms: i64) -> DateTime<FixedOffset> {
+    stage_start + chrono::Duration::milliseconds(time_ms)
+}
+
+/// Escape text for XML and HTML output
+pub(crate) fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
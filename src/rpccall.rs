@@ -0,0 +1,46 @@
+//! Calls of methods through the broker with additional meta tags of the request, like correlation id
+//! of the request the call is made for or user forwarded by the JSON-RPC gateway.
+
+use shvclient::ClientCommandSender;
+use shvproto::RpcValue;
+use shvrpc::RpcMessage;
+use shvrpc::rpcmessage::RpcError;
+
+#[derive(Debug)]
+pub(crate) enum CallError {
+    /// Request cannot be sent or the client connection was closed before the response came
+    Transport(String),
+    /// Callee or broker responded with error
+    Rpc(RpcError),
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallError::Transport(message) => write!(f, "Transport error: {message}"),
+            CallError::Rpc(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+/// Call `path:method` with `meta` tags added to the request, delayed responses reporting progress are skipped
+pub(crate) async fn call_rpc_method_with_meta(client_cmd_tx: &ClientCommandSender, path: &str, method: &str, param: Option<RpcValue>, meta: &[(&str, RpcValue)]) -> Result<RpcValue, CallError> {
+    let mut request = RpcMessage::new_request(path, method, param);
+    for (key, value) in meta {
+        request.meta_mut().insert(*key, value.clone());
+    }
+    let responses = client_cmd_tx.do_rpc_call_message(request)
+        .map_err(|e| CallError::Transport(e.to_string()))?;
+    loop {
+        let frame = responses.recv().await
+            .map_err(|_| CallError::Transport(format!("Connection closed before response of {path}:{method}")))?;
+        let response = frame.to_rpcmesage()
+            .map_err(|e| CallError::Transport(e.to_string()))?;
+        if response.is_delay() {
+            continue;
+        }
+        return response.result().cloned().map_err(CallError::Rpc);
+    }
+}
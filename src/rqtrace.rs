@@ -0,0 +1,75 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use log::{debug, warn};
+use shvproto::RpcValue;
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+const SLOW_REQUEST_MS: u128 = 1000;
+/// Meta tag carrying correlation id of the request to the proxied calls
+pub const META_CORRELATION_ID: &str = "correlationId";
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    pub fn next() -> Self {
+        Self(NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+    /// Correlation id assigned by the previous hop, if the request comes with one
+    pub fn from_request(rq: &RpcMessage) -> Option<Self> {
+        rq.meta().get(META_CORRELATION_ID)
+            .filter(|v| v.is_int())
+            .map(|v| Self(v.as_int() as u64))
+    }
+    pub fn to_rpcvalue(self) -> RpcValue {
+        RpcValue::from(self.0 as i64)
+    }
+}
+
+impl Display for CorrelationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cid:{}", self.0)
+    }
+}
+
+/// Logs request entry on creation and exit with duration when dropped.
+///
+/// It is created once for each incoming request by the event request handler and moved to the method future,
+/// so that the whole method execution is measured.
+pub struct RequestTrace {
+    correlation_id: CorrelationId,
+    path: String,
+    method: String,
+    started: Instant,
+}
+
+impl RequestTrace {
+    pub fn new(rq: &RpcMessage) -> Self {
+        let trace = Self {
+            correlation_id: CorrelationId::from_request(rq).unwrap_or_else(CorrelationId::next),
+            path: rq.shv_path().unwrap_or_default().to_string(),
+            method: rq.method().unwrap_or_default().to_string(),
+            started: Instant::now(),
+        };
+        debug!("[{}] --> {}:{} rqid: {:?} user: {:?}", trace.correlation_id, trace.path, trace.method, rq.request_id(), rq.user_id());
+        trace
+    }
+    pub fn correlation_id(&self) -> CorrelationId {
+        self.correlation_id
+    }
+}
+
+impl Drop for RequestTrace {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed().as_millis();
+        if elapsed >= SLOW_REQUEST_MS {
+            warn!("[{}] <-- {}:{} slow request, elapsed: {elapsed} msec", self.correlation_id, self.path, self.method);
+        } else {
+            debug!("[{}] <-- {}:{} elapsed: {elapsed} msec", self.correlation_id, self.path, self.method);
+        }
+    }
+}
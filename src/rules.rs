@@ -0,0 +1,157 @@
+//! Competition rules engine, it sets run status flags, which follow from times and class rules.
+//! Finished run over the maximum time of its class gets `overTime`, started run, which has not finished
+//! within the maximum time and grace period, gets `notFinish`. Rules are evaluated for the finished run
+//! on every finish punch and for all runs of the current stage periodically, changes are signalled
+//! on `eventctl/<event_id>/rules:statuschng`.
+
+use std::collections::BTreeMap;
+
+use log::{error, info, warn};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use shvrpc::RpcMessage;
+
+use crate::eventsqlapi::EventSqlApi;
+use crate::global_config;
+use crate::state::{EventId, SharedAppState};
+use crate::traffic;
+
+pub(crate) const SIG_STATUS_CHNG: &str = "statuschng";
+
+/// Event config keys of rules
+const CKEY_ENABLED: &str = "rules.enabled";
+const CKEY_MAX_TIME_MIN: &str = "rules.maxTimeMin";
+const CKEY_NOT_FINISH_GRACE_MIN: &str = "rules.notFinishGraceMin";
+
+/// Rules of event stored in event config, missing keys have default values
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RulesConfig {
+    pub enabled: bool,
+    /// Maximum time of classes, which have no `classdefs.maxTimeMin` set
+    pub max_time_min: Option<i64>,
+    /// Started run is marked as not finished this time after its maximum time has passed
+    pub not_finish_grace_min: i64,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_time_min: None,
+            not_finish_grace_min: 0,
+        }
+    }
+}
+
+pub(crate) async fn load_rules_config(sql_api: &EventSqlApi) -> anyhow::Result<RulesConfig> {
+    let result = sql_api.query("SELECT ckey, cvalue FROM config WHERE ckey LIKE 'rules.%'", None).await?;
+    let values = (0..result.row_count())
+        .filter_map(|row| {
+            let ckey = result.value(row, 0).and_then(|v| v.as_str())?;
+            let cvalue = result.value(row, 1).and_then(|v| v.as_str())?;
+            Some((ckey.to_string(), cvalue.trim().to_string()))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let int = |ckey: &str| values.get(ckey).and_then(|cvalue| cvalue.parse::<i64>().ok());
+    let mut config = RulesConfig::default();
+    if let Some(enabled) = values.get(CKEY_ENABLED) {
+        config.enabled = matches!(enabled.as_str(), "true" | "1");
+    }
+    config.max_time_min = int(CKEY_MAX_TIME_MIN).filter(|min| *min > 0);
+    config.not_finish_grace_min = int(CKEY_NOT_FINISH_GRACE_MIN).unwrap_or_default().max(0);
+    Ok(config)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StatusChange {
+    pub run_id: i64,
+    pub over_time: bool,
+    pub not_finish: bool,
+}
+
+/// Evaluate rules of runs in stage, all runs are evaluated if `run_ids` is not set.
+/// Returns runs, which status was changed.
+pub(crate) async fn apply_rules(sql_api: &EventSqlApi, stage_id: i64, run_ids: Option<&[i64]>, issuer: Option<String>) -> anyhow::Result<Vec<StatusChange>> {
+    let config = load_rules_config(sql_api).await?;
+    if !config.enabled {
+        return Ok(vec![]);
+    }
+    let condition = match run_ids {
+        Some([]) => return Ok(vec![]),
+        // ids are integers, they can be safely inlined
+        Some(run_ids) => format!(" AND runs.id IN ({})", run_ids.iter().map(i64::to_string).collect::<Vec<_>>().join(",")),
+        None => String::new(),
+    };
+    let result = sql_api.query(&format!("SELECT runs.id, runs.startTimeMs, runs.finishTimeMs, runs.timeMs, runs.overTime, runs.notFinish, \
+        COALESCE(classdefs.maxTimeMin, :maxTimeMin) \
+        FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+        LEFT JOIN classdefs ON classdefs.classId = competitors.classId AND classdefs.stageId = runs.stageId \
+        WHERE runs.stageId = :stageId AND runs.isRunning AND NOT runs.notStart AND NOT runs.notCompeting \
+        AND NOT COALESCE(classdefs.resultsFrozen, 0){condition}"),
+        Some(&record_from_slice(&[
+            ("stageId", stage_id.into()),
+            ("maxTimeMin", config.max_time_min.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ]))).await?;
+    let now_ms = (chrono::Local::now().fixed_offset() - sql_api.stage_start(stage_id).await?).num_milliseconds();
+    let grace_ms = config.not_finish_grace_min * 60_000;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let flag = |row, col| result.value(row, col).is_some_and(DbValue::to_bool);
+    let mut changes = Vec::new();
+    for row in 0..result.row_count() {
+        let Some(max_time_ms) = int(row, 6).filter(|min| *min > 0).map(|min| min * 60_000) else {
+            continue;
+        };
+        let (over_time, not_finish) = (flag(row, 4), flag(row, 5));
+        let (new_over_time, new_not_finish) = match (int(row, 1), int(row, 2), int(row, 3)) {
+            (_, Some(_), Some(time_ms)) => (time_ms > max_time_ms, not_finish),
+            (Some(start_time_ms), None, _) if now_ms >= start_time_ms + max_time_ms + grace_ms => (over_time, true),
+            _ => (over_time, not_finish),
+        };
+        if (new_over_time, new_not_finish) == (over_time, not_finish) {
+            continue;
+        }
+        let run_id = int(row, 0).unwrap_or_default();
+        let record = record_from_slice(&[("overTime", new_over_time.into()), ("notFinish", new_not_finish.into())]);
+        if sql_api.update_record_event("runs", run_id, &record, issuer.clone()).await? {
+            changes.push(StatusChange { run_id, over_time: new_over_time, not_finish: new_not_finish });
+        }
+    }
+    Ok(changes)
+}
+
+pub(crate) fn send_status_changed(rpc_client: &ClientCommandSender, event_id: EventId, changes: &[StatusChange]) {
+    if changes.is_empty() {
+        return;
+    }
+    let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/rules"), SIG_STATUS_CHNG)
+        .with_param(shvproto::to_rpcvalue(&changes).unwrap_or_default());
+    traffic::record_signal(event_id, &signal);
+    if let Err(e) = rpc_client.send_message(signal) {
+        error!("Failed to send event {event_id} {SIG_STATUS_CHNG} signal: {e}");
+    }
+}
+
+/// Evaluate rules of current stage of open events periodically, so runs get `notFinish` when time passes
+pub(crate) async fn scheduler(app_state: SharedAppState, rpc_client: ClientCommandSender) {
+    let Some(interval) = global_config().rules_interval.to_std().ok().filter(|interval| !interval.is_zero()) else {
+        info!("Periodic evaluation of competition rules is disabled");
+        return;
+    };
+    loop {
+        smol::Timer::after(interval).await;
+        for event_id in app_state.open_event_ids() {
+            let Ok(status) = app_state.open_event_status(event_id) else {
+                continue;
+            };
+            let sql_api = EventSqlApi::new(event_id, app_state.clone(), rpc_client.clone());
+            match apply_rules(&sql_api, status.current_stage, None, None).await {
+                Ok(changes) => send_status_changed(&rpc_client, event_id, &changes),
+                Err(e) => warn!("Failed to evaluate rules of event {event_id} stage {}: {e}", status.current_stage),
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Competitor self-service, a competitor can look up its own start time, course and result in all events
+//! on the instance. Competitor is identified by personal token issued by the service, or by registration
+//! and birth date, which was recorded with the token. Events are searched like for personal data export,
+//! local events even if they are closed, remote events only when they are open.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use chrono::NaiveDate;
+use log::debug;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+
+use crate::appsqlapi::AppSqlApi;
+use crate::eventdb::open_read_only_event_db;
+use crate::eventsqlapi::EventSqlApi;
+use crate::generate_api_token;
+use crate::results::{RunFilter, load_run_results, stage_time};
+use crate::state::{EventId, SharedAppState, event_db_file};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MyRaceParams {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub registration: Option<String>,
+    /// `YYYY-MM-DD`, required with registration
+    #[serde(default)]
+    pub birth_date: Option<String>,
+}
+impl_rpcvalue_conversions!(MyRaceParams);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct IssuePersonalTokenParams {
+    pub registration: String,
+    #[serde(default)]
+    pub birth_date: Option<String>,
+}
+impl_rpcvalue_conversions!(IssuePersonalTokenParams);
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StageRace {
+    pub stage_id: i64,
+    pub class_name: String,
+    pub start_number: Option<i64>,
+    pub start_time_ms: Option<i64>,
+    /// Wall clock start time, when the stage start is known
+    pub start_time: Option<String>,
+    pub course_name: Option<String>,
+    pub course_length: Option<i64>,
+    pub course_climb: Option<i64>,
+    pub control_count: i64,
+    pub status: String,
+    pub time_ms: Option<i64>,
+    pub place: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EventRace {
+    pub event_id: EventId,
+    pub event_name: String,
+    pub event_date: String,
+    pub stages: Vec<StageRace>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MyRace {
+    pub registration: String,
+    pub events: Vec<EventRace>,
+}
+
+fn parse_birth_date(birth_date: &str) -> anyhow::Result<String> {
+    let date = birth_date.trim().parse::<NaiveDate>()
+        .map_err(|_| anyhow!("Birth date {birth_date} is not valid, YYYY-MM-DD expected"))?;
+    Ok(date.to_string())
+}
+
+/// Issue new personal token of registration, previous token of registration stops working
+pub(crate) async fn issue_personal_token(app_state: &SharedAppState, param: &IssuePersonalTokenParams) -> anyhow::Result<String> {
+    let registration = param.registration.trim();
+    if registration.is_empty() {
+        bail!("Registration cannot be empty");
+    }
+    let birth_date = param.birth_date.as_deref().map(parse_birth_date).transpose()?;
+    let token = generate_api_token();
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    qxsql.exec("DELETE FROM personal_tokens WHERE registration = :registration", Some(&record_from_slice(&[
+        ("registration", registration.into()),
+    ]))).await?;
+    qxsql.exec("INSERT INTO personal_tokens (token, registration, birth_date, created) VALUES (:token, :registration, :birth_date, :created)",
+        Some(&record_from_slice(&[
+            ("token", token.as_str().into()),
+            ("registration", registration.into()),
+            ("birth_date", birth_date.map(DbValue::from).unwrap_or(DbValue::Null)),
+            ("created", chrono::Local::now().fixed_offset().to_rfc3339().into()),
+        ]))).await?;
+    Ok(token)
+}
+
+/// Registration of competitor identified by personal token or by registration and birth date.
+///
+/// The error does not tell, which of the credentials is wrong, not to reveal registered persons.
+async fn identify_competitor(app_state: &SharedAppState, param: &MyRaceParams) -> anyhow::Result<String> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = match (&param.token, &param.registration, &param.birth_date) {
+        (Some(token), _, _) => qxsql.query("SELECT registration FROM personal_tokens WHERE token = :token", Some(&record_from_slice(&[
+            ("token", token.trim().into()),
+        ]))).await?,
+        (None, Some(registration), Some(birth_date)) => qxsql.query("SELECT registration FROM personal_tokens \
+            WHERE registration = :registration AND birth_date = :birth_date", Some(&record_from_slice(&[
+            ("registration", registration.trim().into()),
+            ("birth_date", parse_birth_date(birth_date)?.into()),
+        ]))).await?,
+        _ => bail!("Personal token or registration with birth date is required"),
+    };
+    result.value(0, 0)
+        .and_then(|v| v.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Competitor not found"))
+}
+
+async fn collect_event_races(sql_api: &(impl QxSqlApi + Sync), registration: &str) -> anyhow::Result<Vec<StageRace>> {
+    let result = sql_api.query("SELECT runs.id, runs.stageId, competitors.classId, stages.startDateTime, \
+        courses.name, courses.length, courses.climb, \
+        (SELECT COUNT(*) FROM coursecodes WHERE coursecodes.courseId = courses.id) \
+        FROM runs JOIN competitors ON competitors.id = runs.competitorId \
+        LEFT JOIN stages ON stages.id = runs.stageId \
+        LEFT JOIN classdefs ON classdefs.classId = competitors.classId AND classdefs.stageId = runs.stageId \
+        LEFT JOIN courses ON courses.id = COALESCE(runs.courseId, classdefs.courseId) \
+        WHERE competitors.registration = :registration AND runs.isRunning \
+        ORDER BY runs.stageId",
+        Some(&record_from_slice(&[
+            ("registration", registration.into()),
+        ]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let mut races = vec![];
+    for row in 0..result.row_count() {
+        let (Some(run_id), Some(stage_id), Some(class_id)) = (int(row, 0), int(row, 1), int(row, 2)) else {
+            continue;
+        };
+        // places are valid for whole class only
+        let Some(run) = load_run_results(sql_api, stage_id, RunFilter::Class(class_id)).await?
+            .into_iter()
+            .find(|run| run.run_id == run_id) else {
+            continue;
+        };
+        let stage_start = result.value(row, 3).and_then(|v| v.to_datetime());
+        races.push(StageRace {
+            stage_id,
+            class_name: run.class_name,
+            start_number: run.start_number,
+            start_time_ms: run.start_time_ms,
+            start_time: stage_start.zip(run.start_time_ms).map(|(stage_start, time_ms)| stage_time(stage_start, time_ms).to_rfc3339()),
+            course_name: result.value(row, 4).and_then(|v| v.as_str()).map(str::to_string),
+            course_length: int(row, 5),
+            course_climb: int(row, 6),
+            control_count: int(row, 7).unwrap_or_default(),
+            status: run.status.as_iof_str().to_string(),
+            time_ms: run.time_ms,
+            place: run.place,
+        });
+    }
+    Ok(races)
+}
+
+async fn event_races(app_state: &SharedAppState, event_id: EventId, registration: &str, rpc_client: ClientCommandSender) -> anyhow::Result<EventRace> {
+    let event_record = app_state.event_record(event_id).await?;
+    let mut race = EventRace {
+        event_id,
+        event_name: event_record.name.clone(),
+        event_date: event_record.date.to_rfc3339(),
+        ..Default::default()
+    };
+    if app_state.is_event_open(event_id) {
+        let sql_api = EventSqlApi::new(event_id, app_state.clone(), rpc_client);
+        race.stages = collect_event_races(&sql_api, registration).await?;
+    } else if event_record.is_local {
+        let db_file = event_db_file(event_id);
+        if !Path::new(&db_file).exists() {
+            return Ok(race);
+        }
+        // closed DB is only read, DB closed by older version is skipped rather than migrated
+        let pool = open_read_only_event_db(&db_file).await?;
+        race.stages = collect_event_races(&AppSqlApi::new_without_recchng(pool), registration).await?;
+    } else {
+        bail!("Remote event is not open");
+    }
+    Ok(race)
+}
+
+pub(crate) async fn my_race(app_state: &SharedAppState, param: &MyRaceParams, rpc_client: ClientCommandSender) -> anyhow::Result<MyRace> {
+    let registration = identify_competitor(app_state, param).await?;
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id FROM events WHERE broken IS NULL ORDER BY date DESC, id DESC", None).await?;
+    let event_ids = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect::<Vec<EventId>>();
+    let mut my_race = MyRace { registration, events: vec![] };
+    for event_id in event_ids {
+        // unreadable events are not reported to the competitor
+        match event_races(app_state, event_id, &my_race.registration, rpc_client.clone()).await {
+            Ok(race) if race.stages.is_empty() => {}
+            Ok(race) => my_race.events.push(race),
+            Err(e) => debug!("Event id: {event_id} skipped in self-service lookup, error: {e}"),
+        }
+    }
+    Ok(my_race)
+}
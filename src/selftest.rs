@@ -0,0 +1,162 @@
+//! Self test of deployed daemon, it checks DBs, spawning of event DB service and broker round trip
+//! without touching any real event.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use qxsql::QxSqlApi;
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use shvproto::RpcValue;
+use shvrpc::util::join_path;
+use smol::process::Command;
+
+use crate::appsqlapi::{AppSqlApi, with_savepoint};
+use crate::eventdb::open_memory_event_db;
+use crate::global_config;
+use crate::heartbeat::check_app_db;
+use crate::state::SharedAppState;
+
+/// Broker round trip longer than this is considered as failed
+const ROUND_TRIP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SelfTestCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    /// Check is not applicable to this deployment, skipped check is ok
+    pub skipped: bool,
+    pub duration_ms: u64,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SelfTestReport {
+    pub ok: bool,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+/// Check result, `Ok(Some(reason))` means that the check was skipped
+type CheckResult = anyhow::Result<Option<String>>;
+
+async fn run_check(name: &'static str, check: impl Future<Output = CheckResult>) -> SelfTestCheck {
+    let started = Instant::now();
+    let result = check.await;
+    let duration_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(skipped) => SelfTestCheck { name, ok: true, skipped: skipped.is_some(), duration_ms, message: skipped },
+        Err(e) => SelfTestCheck { name, ok: false, skipped: false, duration_ms, message: Some(e.to_string()) },
+    }
+}
+
+async fn check_app_db_query(app_state: &SharedAppState) -> CheckResult {
+    match check_app_db(app_state).await {
+        Some(e) => Err(anyhow!(e)),
+        None => Ok(None),
+    }
+}
+
+/// Create, read, update and delete record in temporary in-memory event DB
+async fn check_event_db_crud() -> CheckResult {
+    let qxsql = AppSqlApi::new_without_recchng(open_memory_event_db().await?);
+    let id = qxsql.create_record("classes", &record_from_slice(&[("name", "SELFTEST".into())])).await?;
+    let record = qxsql.read_record("classes", id, None).await?
+        .ok_or_else(|| anyhow!("Created record id: {id} not found"))?;
+    if record.get("name").and_then(|v| v.as_str()) != Some("SELFTEST") {
+        bail!("Created record id: {id} has unexpected content: {record:?}");
+    }
+    let params = record_from_slice(&[("id", id.into()), ("name", "SELFTEST2".into())]);
+    if qxsql.exec("UPDATE classes SET name = :name WHERE id = :id", Some(&params)).await?.rows_affected != 1 {
+        bail!("Update of record id: {id} failed");
+    }
+    if qxsql.exec("DELETE FROM classes WHERE id = :id", Some(&params)).await?.rows_affected != 1 {
+        bail!("Delete of record id: {id} failed");
+    }
+    Ok(None)
+}
+
+/// Failed nested savepoint in temporary in-memory event DB is rolled back, changes of outer savepoint are kept
+async fn check_event_db_savepoint() -> CheckResult {
+    let pool = open_memory_event_db().await?;
+    let insert = "INSERT INTO classes (name) VALUES (:name)";
+    with_savepoint(&pool, async |savepoint| {
+        savepoint.exec(insert, Some(&record_from_slice(&[("name", "SELFTEST".into())]))).await?;
+        let nested = savepoint.with_savepoint(async |nested| -> anyhow::Result<()> {
+            nested.exec(insert, Some(&record_from_slice(&[("name", "SELFTEST2".into())]))).await?;
+            bail!("Nested savepoint failed")
+        }).await;
+        if nested.is_ok() {
+            bail!("Nested savepoint did not fail");
+        }
+        Ok(())
+    }).await?;
+    let result = AppSqlApi::new_without_recchng(pool).query("SELECT name FROM classes WHERE name LIKE 'SELFTEST%'", None).await?;
+    let names = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.as_str()).map(str::to_string))
+        .collect::<Vec<_>>();
+    if names != ["SELFTEST"] {
+        bail!("Unexpected classes after savepoint rollback: {names:?}");
+    }
+    Ok(None)
+}
+
+/// Spawn configured event DB service executable and kill it immediately
+async fn check_child_spawn() -> CheckResult {
+    let Some(config) = &global_config().qxsqld else {
+        return Ok(Some("qxsqld is not configured, local events are served by in-process SQL engine".to_string()));
+    };
+    let mut command = Command::new(&config.executable);
+    command
+        .arg("--help")
+        .envs(&config.env)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true);
+    if let Some(working_dir) = &config.working_dir {
+        command.current_dir(working_dir);
+    }
+    let mut child = command.spawn()
+        .map_err(|e| anyhow!("Failed to start {}: {e}", config.executable))?;
+    // child can exit already
+    let _ = child.kill();
+    child.status().await?;
+    Ok(None)
+}
+
+/// Call `.app:ping` of the daemon itself through the broker
+async fn check_broker_round_trip(rpc_client: ClientCommandSender) -> CheckResult {
+    let Some(mount) = &global_config().client.mount else {
+        return Ok(Some("Mount point is not configured, daemon cannot call itself".to_string()));
+    };
+    let path = join_path(mount, ".app");
+    let call = async {
+        let result: Result<RpcValue, _> = rpc_client.call_rpc_method(path.clone(), "ping", None, None, None, None::<fn(f64)>).await;
+        Some(result.map_err(|e| anyhow!("{path}:ping failed: {e}")))
+    };
+    let timeout = async {
+        smol::Timer::after(ROUND_TRIP_TIMEOUT).await;
+        None
+    };
+    match smol::future::or(call, timeout).await {
+        Some(result) => result.map(|_| None),
+        None => bail!("{path}:ping timed out after {} sec", ROUND_TRIP_TIMEOUT.as_secs()),
+    }
+}
+
+pub(crate) async fn self_test(app_state: SharedAppState, rpc_client: ClientCommandSender) -> SelfTestReport {
+    let checks = vec![
+        run_check("appDb", check_app_db_query(&app_state)).await,
+        run_check("eventDbCrud", check_event_db_crud()).await,
+        run_check("eventDbSavepoint", check_event_db_savepoint()).await,
+        run_check("childSpawn", check_child_spawn()).await,
+        run_check("brokerRoundTrip", check_broker_round_trip(rpc_client)).await,
+    ];
+    let ok = checks.iter().all(|check| check.ok);
+    if !ok {
+        log::warn!("Self test failed: {:?}", checks.iter().filter(|check| !check.ok).collect::<Vec<_>>());
+    }
+    SelfTestReport { ok, checks }
+}
@@ -0,0 +1,299 @@
+//! Replay of punches and cards recorded in a past event into another event, so speaker and results
+//! pipelines can be rehearsed before race day. Records are written in order of their station times,
+//! pauses between them are kept, optionally shortened by `maxGapSec` and compressed by `speed`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use log::{error, info};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::Record;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use smol::channel;
+
+use crate::appsqlapi::{AppSqlApi, query_result_to_records};
+use crate::eventdb::open_event_db_pool;
+use crate::eventsqlapi::EventSqlApi;
+use crate::eventtimesyncnode::SI_NO_TIME;
+use crate::state::{EventId, SharedAppState, event_db_file};
+
+const MIN_SPEED: f64 = 0.01;
+const MAX_SPEED: f64 = 10000.;
+/// Card fields assigned by the target event, they are not copied from the source event
+const CARD_ASSIGN_FIELDS: &[&str] = &["runIdAssignTS", "runIdAssignError"];
+
+fn default_speed() -> f64 {
+    1.
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SimulateParams {
+    pub source_event_id: EventId,
+    /// Time compression, `2` replays the event twice as fast as it was recorded
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    /// Longer pauses between records are shortened to this length before `speed` is applied
+    #[serde(default)]
+    pub max_gap_sec: Option<u64>,
+}
+impl_rpcvalue_conversions!(SimulateParams);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SimulationState {
+    Running,
+    Finished,
+    Stopped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SimulationStatus {
+    pub source_event_id: EventId,
+    pub speed: f64,
+    pub max_gap_sec: Option<u64>,
+    pub state: SimulationState,
+    pub punches: i64,
+    pub cards: i64,
+    pub replayed: i64,
+    /// Station time of the last replayed record, msec since midnight
+    pub station_time_ms: Option<i64>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub error: Option<String>,
+}
+
+struct Simulation {
+    status: SimulationStatus,
+    /// Simulation is stopped by closing the channel
+    stop_sender: channel::Sender<()>,
+}
+
+/// Running and last finished simulation of events
+#[derive(Default)]
+pub(crate) struct Simulations {
+    simulations: Mutex<BTreeMap<EventId, Simulation>>,
+}
+
+impl Simulations {
+    pub fn status(&self, event_id: EventId) -> Option<SimulationStatus> {
+        self.simulations.lock().unwrap().get(&event_id).map(|simulation| simulation.status.clone())
+    }
+    /// Returns false, if no simulation is running in the event
+    pub fn stop(&self, event_id: EventId) -> bool {
+        let mut simulations = self.simulations.lock().unwrap();
+        let Some(simulation) = simulations.get_mut(&event_id).filter(|simulation| simulation.status.state == SimulationState::Running) else {
+            return false;
+        };
+        simulation.stop_sender.close();
+        simulation.status.state = SimulationState::Stopped;
+        simulation.status.finished_at = Some(chrono::Local::now().fixed_offset().to_rfc3339());
+        info!("Simulation in event {event_id} stopped, replayed: {}", simulation.status.replayed);
+        true
+    }
+    /// Returns false, if other simulation is already running in the event
+    fn add(&self, event_id: EventId, status: SimulationStatus, stop_sender: channel::Sender<()>) -> bool {
+        let mut simulations = self.simulations.lock().unwrap();
+        if simulations.get(&event_id).is_some_and(|simulation| simulation.status.state == SimulationState::Running) {
+            return false;
+        }
+        simulations.insert(event_id, Simulation { status, stop_sender });
+        true
+    }
+    fn update(&self, event_id: EventId, f: impl FnOnce(&mut SimulationStatus)) {
+        if let Some(simulation) = self.simulations.lock().unwrap().get_mut(&event_id) {
+            f(&mut simulation.status);
+        }
+    }
+    fn finish(&self, event_id: EventId, result: anyhow::Result<()>) {
+        self.update(event_id, |status| {
+            // stopped simulation keeps its state
+            if status.state != SimulationState::Running {
+                return;
+            }
+            match result {
+                Ok(()) => {
+                    status.state = SimulationState::Finished;
+                    info!("Simulation in event {event_id} finished, replayed: {}", status.replayed);
+                }
+                Err(e) => {
+                    status.state = SimulationState::Failed;
+                    error!("Simulation in event {event_id} failed after {} replayed records: {e}", status.replayed);
+                    status.error = Some(e.to_string());
+                }
+            }
+            status.finished_at = Some(chrono::Local::now().fixed_offset().to_rfc3339());
+        });
+    }
+}
+
+struct ReplayItem {
+    table: &'static str,
+    /// Records without station time are replayed immediately
+    station_time_ms: Option<i64>,
+    record: Record,
+}
+
+fn field(record: &Record, name: &str) -> Option<i64> {
+    record.get(name).and_then(|v| v.to_int())
+}
+
+fn punch_time_ms(record: &Record) -> Option<i64> {
+    field(record, "time").map(|time| time * 1000 + field(record, "msec").unwrap_or_default())
+}
+
+fn card_time_ms(record: &Record) -> Option<i64> {
+    ["finishTime", "checkTime"].iter()
+        .find_map(|name| field(record, name).filter(|time| *time != SI_NO_TIME))
+        .map(|time| time * 1000)
+}
+
+/// Punches and cards of the source event ordered by station time, punches go before cards read at the same time
+async fn read_items(sql_api: &(impl QxSqlApi + Sync)) -> anyhow::Result<Vec<ReplayItem>> {
+    let mut items = Vec::new();
+    for (table, time_ms) in [("punches", punch_time_ms as fn(&Record) -> Option<i64>), ("cards", card_time_ms)] {
+        let records = query_result_to_records(&sql_api.query(&format!("SELECT * FROM {table} ORDER BY id"), None).await?);
+        items.extend(records.into_iter().map(|record| ReplayItem { table, station_time_ms: time_ms(&record), record }));
+    }
+    items.sort_by_key(|item| item.station_time_ms);
+    Ok(items)
+}
+
+async fn load_items(app_state: &SharedAppState, source_event_id: EventId, rpc_client: ClientCommandSender) -> anyhow::Result<Vec<ReplayItem>> {
+    // source event is not opened, while its closed DB is read
+    let event_lock = app_state.event_lock(source_event_id);
+    let _event_guard = event_lock.lock().await;
+    if app_state.is_event_open(source_event_id) {
+        return read_items(&EventSqlApi::new(source_event_id, app_state.clone(), rpc_client)).await;
+    }
+    if !app_state.event_record(source_event_id).await?.is_local {
+        bail!("Source event id: {source_event_id} is remote, it must be open to be replayed");
+    }
+    let db_file = event_db_file(source_event_id);
+    if !Path::new(&db_file).exists() {
+        bail!("Source event id: {source_event_id} has no DB file");
+    }
+    let pool = open_event_db_pool(&db_file, Some(1)).await?;
+    read_items(&AppSqlApi::new_without_recchng(pool)).await
+}
+
+/// Run ids of source records are kept only if the run exists in the target event, which is usually a copy of the source one
+async fn prepare_items(sql_api: &EventSqlApi, items: &mut [ReplayItem]) -> anyhow::Result<()> {
+    let result = sql_api.query("SELECT id FROM runs", None).await?;
+    let run_ids = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect::<HashSet<_>>();
+    for item in items {
+        item.record.remove("id");
+        if item.table == "cards" {
+            for name in CARD_ASSIGN_FIELDS {
+                item.record.remove(*name);
+            }
+        }
+        if field(&item.record, "runId").is_some_and(|run_id| !run_ids.contains(&run_id)) {
+            item.record.insert("runId".to_string(), DbValue::Null);
+        }
+    }
+    Ok(())
+}
+
+async fn replay(
+    app_state: &SharedAppState,
+    event_id: EventId,
+    sql_api: &EventSqlApi,
+    items: Vec<ReplayItem>,
+    params: &SimulateParams,
+    stop_receiver: channel::Receiver<()>,
+    issuer: Option<String>,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let max_gap_ms = params.max_gap_sec.map(|sec| sec.saturating_mul(1000) as i64);
+    let mut delay = Duration::ZERO;
+    let mut prev_time_ms = None;
+    for item in items {
+        if let (Some(prev_time_ms), Some(time_ms)) = (prev_time_ms, item.station_time_ms) {
+            let gap_ms = max_gap_ms.map_or(time_ms - prev_time_ms, |max_gap_ms| (time_ms - prev_time_ms).min(max_gap_ms));
+            delay += Duration::from_secs_f64(gap_ms as f64 / 1000. / params.speed);
+            let stopped = smol::future::or(
+                async {
+                    smol::Timer::at(started + delay).await;
+                    false
+                },
+                async {
+                    // nothing is ever sent, recv returns when the channel is closed
+                    let _ = stop_receiver.recv().await;
+                    true
+                },
+            ).await;
+            if stopped {
+                return Ok(());
+            }
+        }
+        if stop_receiver.is_closed() {
+            return Ok(());
+        }
+        sql_api.create_record_event(item.table, &item.record, issuer.clone()).await?;
+        if item.station_time_ms.is_some() {
+            prev_time_ms = item.station_time_ms;
+        }
+        app_state.simulations.update(event_id, |status| {
+            status.replayed += 1;
+            status.station_time_ms = item.station_time_ms.or(status.station_time_ms);
+        });
+    }
+    Ok(())
+}
+
+/// Start replay of source event into open event `event_id`, the replay runs in background until it is finished or stopped
+pub(crate) async fn start_simulation(
+    app_state: &SharedAppState,
+    event_id: EventId,
+    params: SimulateParams,
+    rpc_client: ClientCommandSender,
+    issuer: Option<String>,
+) -> anyhow::Result<SimulationStatus> {
+    if params.source_event_id == event_id {
+        bail!("Event cannot replay itself");
+    }
+    if !(MIN_SPEED..=MAX_SPEED).contains(&params.speed) {
+        bail!("Simulation speed must be in range {MIN_SPEED} - {MAX_SPEED}");
+    }
+    if app_state.simulations.status(event_id).is_some_and(|status| status.state == SimulationState::Running) {
+        bail!("Simulation is already running in event id: {event_id}");
+    }
+    let mut items = load_items(app_state, params.source_event_id, rpc_client.clone()).await?;
+    let sql_api = EventSqlApi::new(event_id, app_state.clone(), rpc_client);
+    prepare_items(&sql_api, &mut items).await?;
+    let count = |table: &str| items.iter().filter(|item| item.table == table).count() as i64;
+    let status = SimulationStatus {
+        source_event_id: params.source_event_id,
+        speed: params.speed,
+        max_gap_sec: params.max_gap_sec,
+        state: SimulationState::Running,
+        punches: count("punches"),
+        cards: count("cards"),
+        replayed: 0,
+        station_time_ms: None,
+        started_at: chrono::Local::now().fixed_offset().to_rfc3339(),
+        finished_at: None,
+        error: None,
+    };
+    let (stop_sender, stop_receiver) = channel::bounded(1);
+    if !app_state.simulations.add(event_id, status.clone(), stop_sender) {
+        bail!("Simulation is already running in event id: {event_id}");
+    }
+    info!("Simulation in event {event_id} started, source event: {}, punches: {}, cards: {}, speed: {}",
+        params.source_event_id, status.punches, status.cards, params.speed);
+    let app_state = app_state.clone();
+    smol::spawn(async move {
+        let result = replay(&app_state, event_id, &sql_api, items, &params, stop_receiver, issuer).await;
+        app_state.simulations.finish(event_id, result);
+    }).detach();
+    Ok(status)
+}
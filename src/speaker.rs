@@ -0,0 +1,117 @@
+//! Announcer feeds, runners punching a radio control subscribed by `speaker:prewarning` are signalled
+//! on `eventctl/<event_id>/speaker:prewarningpunch` with their position and gap to the leader at the control.
+
+use log::error;
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use shvrpc::RpcMessage;
+
+use crate::eventsqlapi::EventSqlApi;
+use crate::state::EventId;
+use crate::traffic;
+
+const SIG_PREWARNING_PUNCH: &str = "prewarningpunch";
+
+/// Run time at control is computed from the start time, if it is not set in punch
+const PUNCH_RUN_TIME_MS: &str = "COALESCE(punches.runTimeMs, punches.timeMs - runs.startTimeMs)";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Prewarning {
+    pub code: i64,
+    pub run_id: i64,
+    pub class_id: i64,
+    pub class_name: String,
+    pub start_number: Option<i64>,
+    pub name: String,
+    pub club: String,
+    pub run_time_ms: Option<i64>,
+    /// Position in class at the control, `None` if the run time is not known
+    pub position: Option<i64>,
+    pub gap_ms: Option<i64>,
+}
+
+/// Prewarnings of punches with `punch_ids` at controls with `codes`, ordered by punch time
+pub(crate) async fn load_prewarnings(sql_api: &EventSqlApi, stage_id: i64, punch_ids: &[i64], codes: &[i64]) -> anyhow::Result<Vec<Prewarning>> {
+    if punch_ids.is_empty() || codes.is_empty() {
+        return Ok(vec![]);
+    }
+    // ids and codes are integers, they can be safely inlined
+    let join = |ids: &[i64]| ids.iter().map(i64::to_string).collect::<Vec<_>>().join(",");
+    let result = sql_api.query(&format!("SELECT punches.code, runs.id, competitors.classId, classes.name, competitors.startNumber, \
+        competitors.firstName, competitors.lastName, competitors.club, {PUNCH_RUN_TIME_MS} \
+        FROM punches JOIN runs ON runs.id = punches.runId JOIN competitors ON competitors.id = runs.competitorId \
+        LEFT JOIN classes ON classes.id = competitors.classId \
+        WHERE punches.stageId = :stageId AND punches.id IN ({}) AND punches.code IN ({}) ORDER BY punches.timeMs", join(punch_ids), join(codes)),
+        Some(&record_from_slice(&[("stageId", stage_id.into())]))).await?;
+    let int = |row, col| result.value(row, col).and_then(|v| v.to_int());
+    let string = |row, col| result.value(row, col).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let mut prewarnings = Vec::with_capacity(result.row_count());
+    for row in 0..result.row_count() {
+        let code = int(row, 0).unwrap_or_default();
+        let class_id = int(row, 2).unwrap_or_default();
+        let run_time_ms = int(row, 8);
+        let (position, gap_ms) = match run_time_ms {
+            Some(run_time_ms) => {
+                let (position, leader_time_ms) = control_position(sql_api, stage_id, code, class_id, run_time_ms).await?;
+                (Some(position), leader_time_ms.map(|leader_time_ms| run_time_ms - leader_time_ms))
+            }
+            None => (None, None),
+        };
+        prewarnings.push(Prewarning {
+            code,
+            run_id: int(row, 1).unwrap_or_default(),
+            class_id,
+            class_name: string(row, 3),
+            start_number: int(row, 4),
+            name: format!("{} {}", string(row, 6), string(row, 5)).trim().to_string(),
+            club: string(row, 7),
+            run_time_ms,
+            position,
+            gap_ms,
+        });
+    }
+    Ok(prewarnings)
+}
+
+/// Position of run time among runs of class at control and the leader time
+async fn control_position(sql_api: &EventSqlApi, stage_id: i64, code: i64, class_id: i64, run_time_ms: i64) -> anyhow::Result<(i64, Option<i64>)> {
+    let result = sql_api.query(&format!("SELECT SUM({PUNCH_RUN_TIME_MS} < :runTimeMs), MIN({PUNCH_RUN_TIME_MS}) \
+        FROM punches JOIN runs ON runs.id = punches.runId JOIN competitors ON competitors.id = runs.competitorId \
+        WHERE punches.stageId = :stageId AND punches.code = :code AND competitors.classId = :classId AND {PUNCH_RUN_TIME_MS} IS NOT NULL"),
+        Some(&record_from_slice(&[
+            ("runTimeMs", DbValue::from(run_time_ms)),
+            ("stageId", stage_id.into()),
+            ("code", code.into()),
+            ("classId", class_id.into()),
+        ]))).await?;
+    let faster = result.value(0, 0).and_then(|v| v.to_int()).unwrap_or_default();
+    Ok((faster + 1, result.value(0, 1).and_then(|v| v.to_int())))
+}
+
+/// Last `count` punches at control with `code`
+pub(crate) async fn recent_prewarnings(sql_api: &EventSqlApi, stage_id: i64, code: i64, count: i64) -> anyhow::Result<Vec<Prewarning>> {
+    let result = sql_api.query("SELECT id FROM punches WHERE stageId = :stageId AND code = :code AND runId IS NOT NULL ORDER BY timeMs DESC LIMIT :count",
+        Some(&record_from_slice(&[
+            ("stageId", stage_id.into()),
+            ("code", code.into()),
+            ("count", count.into()),
+        ]))).await?;
+    let punch_ids: Vec<i64> = (0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .collect();
+    load_prewarnings(sql_api, stage_id, &punch_ids, &[code]).await
+}
+
+pub(crate) fn send_prewarnings(rpc_client: &ClientCommandSender, event_id: EventId, prewarnings: &[Prewarning]) {
+    for prewarning in prewarnings {
+        let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/speaker"), SIG_PREWARNING_PUNCH)
+            .with_param(shvproto::to_rpcvalue(prewarning).unwrap_or_default());
+        traffic::record_signal(event_id, &signal);
+        if let Err(e) = rpc_client.send_message(signal) {
+            error!("Failed to send event {event_id} {SIG_PREWARNING_PUNCH} signal: {e}");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Statistics of SQL statements executed on local databases and slow query log.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_sqlite::rusqlite::types::Value;
+use log::warn;
+use serde::Serialize;
+
+use crate::global_config;
+
+/// Statements above this count are aggregated together
+const MAX_STATEMENTS: usize = 500;
+const OTHER_STATEMENTS: &str = "<other>";
+const MAX_STATEMENT_LEN: usize = 200;
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StatementStats {
+    pub statement: String,
+    pub count: u64,
+    pub errors: u64,
+    /// Number of executions exceeding `slow_query_threshold`
+    pub slow: u64,
+    pub total_ms: f64,
+    pub max_ms: f64,
+}
+
+static STATS: Mutex<BTreeMap<String, StatementStats>> = Mutex::new(BTreeMap::new());
+
+fn truncate(s: &str, max_len: usize) -> String {
+    match s.char_indices().nth(max_len) {
+        Some((ix, _)) => format!("{}...", &s[..ix]),
+        None => s.to_string(),
+    }
+}
+
+/// Statement with collapsed whitespace, so the same statement formatted differently is counted once
+fn normalize(query: &str) -> String {
+    truncate(&query.split_whitespace().collect::<Vec<_>>().join(" "), MAX_STATEMENT_LEN)
+}
+
+/// Parameter names with types and lengths, values are not written to the log, they can contain personal data and secrets
+fn param_summary(params: &[(String, Value)]) -> String {
+    params.iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Null => "null".to_string(),
+                Value::Integer(_) => "integer".to_string(),
+                Value::Real(_) => "real".to_string(),
+                Value::Text(s) => format!("text({})", s.chars().count()),
+                Value::Blob(b) => format!("blob({})", b.len()),
+            };
+            format!("{name}: {value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Record statement execution, statement exceeding `slow_query_threshold` is logged
+pub(crate) fn record(query: &str, params: &[(String, Value)], elapsed: Duration, ok: bool) {
+    let statement = normalize(query);
+    let threshold = global_config().slow_query_threshold.to_std().unwrap_or_default();
+    let is_slow = !threshold.is_zero() && elapsed >= threshold;
+    if is_slow {
+        warn!("Slow query {} msec: {statement}, params: [{}]", elapsed.as_millis(), param_summary(params));
+    }
+    let elapsed_ms = elapsed.as_secs_f64() * 1000.;
+    let mut stats = STATS.lock().unwrap();
+    let key = if stats.len() < MAX_STATEMENTS || stats.contains_key(&statement) { statement } else { OTHER_STATEMENTS.to_string() };
+    let entry = stats.entry(key.clone()).or_insert_with(|| StatementStats { statement: key, ..Default::default() });
+    entry.count += 1;
+    entry.errors += u64::from(!ok);
+    entry.slow += u64::from(is_slow);
+    entry.total_ms += elapsed_ms;
+    entry.max_ms = entry.max_ms.max(elapsed_ms);
+}
+
+/// Statement statistics, the most time consuming statements first
+pub(crate) fn statement_stats() -> Vec<StatementStats> {
+    let mut stats: Vec<StatementStats> = STATS.lock().unwrap().values().cloned().collect();
+    stats.sort_by(|a, b| b.total_ms.total_cmp(&a.total_ms));
+    stats
+}
+
+pub(crate) fn reset() {
+    STATS.lock().unwrap().clear();
+}
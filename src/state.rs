@@ -1,15 +1,16 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::BTreeMap, fmt, sync::{Arc, Mutex, RwLock}, time::{Duration, Instant}};
 
 use anyhow::bail;
 use anyhow::anyhow;
 use chrono::DateTime;
 use chrono::Local;
 use futures::StreamExt;
-use log::{error, info};
+use log::{debug, error, info, warn};
 use qxsql::QxSqlApiRecChng;
+use qxeventd_api::DEFAULT_STAGE;
 use qxsql::{Record};
 use qxsql::{sql::{QxSqlApi, record_from_slice}};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use shvclient::ClientCommandSender;
 
 use shvproto::RpcValue;
@@ -17,77 +18,194 @@ use shvproto::make_map;
 use shvrpc::RpcMessage;
 use shvrpc::RpcMessageMetaTags;
 use shvrpc::rpc::ShvRI;
+use shvrpc::rpcmessage::{RpcError, RpcErrorCode};
 use shvrpc::util::join_path;
-use smol::{lock::RwLock, channel};
+use smol::channel;
+
+pub(crate) use qxeventd_api::{EventId, EventRecord, EventRecordChange, EventStatus};
 
 use crate::appsqlapi::AppSqlApi;
-use crate::eventdb::migrate_db;
-use crate::eventsqlapi::EventSqlApi;
+use crate::diskspace::DiskSpace;
+use crate::eventdb::{MigrationResult, Sport, integrity_check, migrate_db, migrate_event_db_to, open_priority_db, seed_event_db};
+use crate::eventsqlapi::{CircuitBreaker, EventSqlApi};
 use crate::generate_api_token;
+use crate::jobs::Jobs;
+use crate::maintenance::Maintenance;
+use crate::notify::NotifyTrigger;
+use crate::ratelimit::RateLimiter;
+use crate::simulate::Simulations;
 use crate::global_config;
+use crate::quota;
+use crate::qxsqld::{ping, spawn_qxsqld, wait_for_ready};
 use crate::string_to_rpc_error;
+use crate::traffic;
+use crate::views::EventViews;
+use crate::webhooks::{self, WebhookEvent};
+
+pub type SharedAppState = Arc<State>;
+
+/// Event records are read on every access check, cache them for a while
+const EVENT_RECORD_CACHE_TTL: Duration = Duration::from_secs(10);
 
-pub type EventId = i64;
+/// Record change signal of event DB service
+const SIG_RECCHNG: &str = "recchng";
 
-pub type SharedAppState = Arc<RwLock<State>>;
+/// Broker mounts of API tokens, remote event DB services log in with
+const BROKER_MOUNTS_PATH: &str = ".broker/access/mounts";
 
+/// Application state shared by all request handlers.
+///
+/// There is no global lock, mutable parts have their own locks, which are never held across await points,
+/// operations on single event are serialized by `event_lock()`.
 pub(crate) struct State {
     pub db_pool: async_sqlite::Pool,
-    pub open_events: BTreeMap<EventId, OpenEventCtl>,
-    pub shutdown_sender: Option<channel::Sender<()>>,
+    pub open_events: RwLock<BTreeMap<EventId, OpenEventCtl>>,
+    pub shutdown_sender: Mutex<Option<channel::Sender<()>>>,
+    pub event_record_cache: Mutex<BTreeMap<EventId, (Instant, EventRecord)>>,
+    /// Serializes open, close and delete operations on single event
+    pub event_locks: Mutex<BTreeMap<EventId, Arc<smol::lock::Mutex<()>>>>,
+    pub jobs: Jobs,
+    pub rate_limiter: RateLimiter,
+    pub maintenance: Maintenance,
+    pub disk_space: DiskSpace,
+    pub simulations: Simulations,
 }
 
 impl State {
-    pub async fn quit_app(&mut self, client_command_sender: ClientCommandSender) -> anyhow::Result<()> {
-        let event_ids: Vec<_> = self.open_events.keys().copied().collect();
-        for event_id in event_ids {
+    pub async fn quit_app(&self, client_command_sender: ClientCommandSender) -> anyhow::Result<()> {
+        for event_id in self.open_event_ids() {
             if let Err(e) = self.close_event(event_id, client_command_sender.clone()).await {
                 bail!("Failed to close event {}: {}", event_id, e);
             }
         }
-        if let Some(sender) = self.shutdown_sender.take() {
+        let shutdown_sender = self.shutdown_sender.lock().unwrap().take();
+        if let Some(sender) = shutdown_sender {
             let _ = sender.try_send(());
         }
         Ok(())
     }
 
+    pub fn open_event_ids(&self) -> Vec<EventId> {
+        self.open_events.read().unwrap().keys().copied().collect()
+    }
+
+    pub fn is_event_open(&self, event_id: EventId) -> bool {
+        self.open_events.read().unwrap().contains_key(&event_id)
+    }
+
+    pub fn with_open_event<R>(&self, event_id: EventId, f: impl FnOnce(&OpenEventCtl) -> R) -> Option<R> {
+        self.open_events.read().unwrap().get(&event_id).map(f)
+    }
+
+    pub fn with_open_event_mut<R>(&self, event_id: EventId, f: impl FnOnce(&mut OpenEventCtl) -> R) -> Option<R> {
+        self.open_events.write().unwrap().get_mut(&event_id).map(f)
+    }
+
+    /// Notify event results publisher about changed run
+    pub fn notify_run_changed(&self, event_id: EventId, run_id: i64) {
+        self.with_open_event(event_id, |e| {
+            let _ = e.changed_runs.try_send(run_id);
+        });
+    }
+
+    /// Notify event views maintainer about changed record, read cards are passed to competitor notifier
+    pub fn notify_record_changed(&self, event_id: EventId, table: &str, id: i64) {
+        self.with_open_event(event_id, |e| {
+            let _ = e.changed_records.try_send((table.to_string(), id));
+            if table == "cards" {
+                let _ = e.notify_triggers.try_send(NotifyTrigger::CardRead(id));
+            }
+        });
+    }
+
+    /// Notify competitors of class about its final results
+    pub fn notify_results_final(&self, event_id: EventId, stage_id: i64, class_id: i64) {
+        self.with_open_event(event_id, |e| {
+            let _ = e.notify_triggers.try_send(NotifyTrigger::ResultsFinal { stage_id, class_id });
+        });
+    }
+
+    pub fn event_lock(&self, event_id: EventId) -> Arc<smol::lock::Mutex<()>> {
+        self.event_locks.lock().unwrap().entry(event_id).or_default().clone()
+    }
+
     pub async fn list_events(&self) -> anyhow::Result<Vec<Record>> {
         let qxsql = AppSqlApi::new_without_recchng(self.db_pool.clone());
-        let records = qxsql.list_records("events", Some(vec!["id", "name", "date", "owner", "is_local"]), None, None).await?;
+        let records = qxsql.list_records("events", Some(vec!["id", "name", "date", "owner", "is_local", "organization_id"]), None, None).await?;
         Ok(records)
     }
 
-    pub async fn create_event(&self, owner: String, rpc_client: ClientCommandSender) -> anyhow::Result<(EventId, String)> {
+    /// Persist event open state, so it can be reopened after the daemon restart
+    pub async fn set_event_was_open(&self, event_id: EventId, was_open: bool) -> anyhow::Result<()> {
+        let qxsql = AppSqlApi::new_without_recchng(self.db_pool.clone());
+        qxsql.exec("UPDATE events SET was_open = :was_open WHERE id = :id", Some(&record_from_slice(&[
+            ("was_open", i64::from(was_open).into()),
+            ("id", event_id.into()),
+        ]))).await?;
+        Ok(())
+    }
+
+    pub async fn was_open_event_ids(&self) -> anyhow::Result<Vec<EventId>> {
+        let qxsql = AppSqlApi::new_without_recchng(self.db_pool.clone());
+        let result = qxsql.query("SELECT id FROM events WHERE was_open", None).await?;
+        Ok(result.rows.iter()
+            .filter_map(|row| row.first().and_then(|cell| cell.to_int()))
+            .collect())
+    }
+
+    pub async fn create_event(&self, owner: String, is_local: Option<bool>, sport: Sport, organization_id: Option<i64>, rpc_client: ClientCommandSender) -> anyhow::Result<(EventId, String)> {
         if owner.is_empty() {
             return Err(anyhow::anyhow!("Owner cannot be empty"));
         }
+        quota::check_create_event(self, &owner, organization_id).await?;
         let api_token = generate_api_token();
         let event_data = EventRecord {
-            is_local: false,
+            is_local: is_local.unwrap_or(global_config().local_events),
             name: String::new(),
             date: chrono::Local::now().fixed_offset(),
             owner: owner.clone(),
             api_token: api_token.clone(),
             id: None,
-            stage: default_stage(),
+            stage: DEFAULT_STAGE,
+            remote_mount: None,
+            sport,
+            organization_id,
+            broken: None,
         };
-        let rec = event_data.to_record()?;
+        let rec = event_record_to_record(&event_data);
         let qxsql = AppSqlApi::new(self.db_pool.clone(), rpc_client.clone());
         let event_id = qxsql.create_record_with_recchng("events", &rec, Some(owner)).await?;
         info!("Created event {event_id}");
+        if uses_data_dir(&event_data) {
+            std::fs::create_dir_all(event_dir(event_id))?;
+        }
         if !event_data.is_local {
-            Self::register_event_mount_point(event_id, &api_token, rpc_client).await?;
+            Self::register_event_mount_point(&remote_event_mount_point(event_id), &api_token, rpc_client).await?;
         }
+        webhooks::emit(self.db_pool.clone(), WebhookEvent::EventCreated, Some(event_id), serde_json::json!({
+            "owner": event_data.owner,
+            "isLocal": event_data.is_local,
+            "sport": event_data.sport,
+        }));
         Ok((event_id, api_token))
     }
 
-    async fn register_event_mount_point(event_id: EventId, api_token: &str, client_cmd_tx: ClientCommandSender) -> anyhow::Result<()> {
-        let remote_mount_point = format!("{}/{}", global_config().remote_events_mount_point, event_id);
+    async fn register_event_mount_point(remote_mount_point: &str, api_token: &str, client_cmd_tx: ClientCommandSender) -> anyhow::Result<()> {
         let param: Vec<RpcValue> = vec![
             api_token.into(),
             make_map!( "mountPoint".to_string() => RpcValue::from(remote_mount_point),).into(),
         ];
-        let _res: RpcValue = client_cmd_tx.call_rpc_method(".broker/access/mounts", "setValue", Some(param.into()), None, None, None::<fn(f64)>)
+        let _res: RpcValue = client_cmd_tx.call_rpc_method(BROKER_MOUNTS_PATH, "setValue", Some(param.into()), None, None, None::<fn(f64)>)
+            .await.map_err(|e| anyhow::anyhow!("{e}"))?;
+        Ok(())
+    }
+
+    async fn unregister_event_mount_point(api_token: &str, client_cmd_tx: ClientCommandSender) -> anyhow::Result<()> {
+        let param: Vec<RpcValue> = vec![
+            api_token.into(),
+            RpcValue::null(),
+        ];
+        let _res: RpcValue = client_cmd_tx.call_rpc_method(BROKER_MOUNTS_PATH, "setValue", Some(param.into()), None, None, None::<fn(f64)>)
             .await.map_err(|e| anyhow::anyhow!("{e}"))?;
         Ok(())
     }
@@ -96,15 +214,21 @@ impl State {
         // always update the mount point, for case than shvbroker config is reloaded from file
         let event_data = self.event_record(event_id).await?;
         if !event_data.is_local {
-            Self::register_event_mount_point(event_id, &event_data.api_token, rpc_client.clone()).await?;
+            let api_token = record.api_token.clone().unwrap_or_else(|| event_data.api_token.clone());
+            if api_token != event_data.api_token {
+                // revoked token must not be able to mount the event DB service anymore
+                Self::unregister_event_mount_point(&event_data.api_token, rpc_client.clone()).await?;
+            }
+            let remote_mount_point = record.remote_mount.clone().or(event_data.remote_mount).unwrap_or_else(|| remote_event_mount_point(event_id));
+            Self::register_event_mount_point(&remote_mount_point, &api_token, rpc_client.clone()).await?;
         }
+        self.invalidate_cached_event_record(event_id);
         let qxsql = AppSqlApi::new(self.db_pool.clone(), rpc_client.clone());
-        qxsql.update_record_with_recchng("events", event_id, &record.to_record(), None).await
+        qxsql.update_record_with_recchng("events", event_id, &event_record_change_to_record(&record), None).await
     }
 
     pub fn open_event_status(&self, event_id: EventId) -> anyhow::Result<EventStatus> {
-        self.open_events.get(&event_id).ok_or_else(|| anyhow!("Invalid event id: {event_id}"))
-            .map(|ectl| {
+        self.with_open_event(event_id, |ectl| {
                 EventStatus {
                     is_local: ectl.local_db.is_some(),
                     open_at: ectl.open_at.with_timezone(&Local).fixed_offset(),
@@ -112,18 +236,48 @@ impl State {
                     current_stage: ectl.current_stage,
                 }
             })
+            .ok_or_else(|| anyhow!("Invalid event id: {event_id}"))
     }
 
     pub async fn event_record(&self, event_id: EventId) -> anyhow::Result<EventRecord> {
         let qxsql = AppSqlApi::new_without_recchng(self.db_pool.clone());
         let record = qxsql.read_record("events", event_id, None).await?
             .ok_or_else(||anyhow!("Event id: {event_id} not found"))?;
-        EventRecord::from_record(&record)
+        event_record_from_record(&record)
+    }
+
+    /// Same as `event_record()`, but the record can be up to `EVENT_RECORD_CACHE_TTL` old
+    pub async fn cached_event_record(&self, event_id: EventId) -> anyhow::Result<EventRecord> {
+        if let Some((cached_at, record)) = self.event_record_cache.lock().unwrap().get(&event_id)
+            && cached_at.elapsed() < EVENT_RECORD_CACHE_TTL {
+            return Ok(record.clone());
+        }
+        let record = self.event_record(event_id).await?;
+        self.event_record_cache.lock().unwrap().insert(event_id, (Instant::now(), record.clone()));
+        Ok(record)
+    }
+
+    /// Event is open or it has record in app DB
+    pub async fn event_exists(&self, event_id: EventId) -> anyhow::Result<bool> {
+        if self.is_event_open(event_id) {
+            return Ok(true);
+        }
+        let qxsql = AppSqlApi::new_without_recchng(self.db_pool.clone());
+        Ok(qxsql.read_record("events", event_id, None).await?.is_some())
     }
 
-    pub async fn close_event(&mut self, event_id: EventId, client_command_sender: ClientCommandSender) -> anyhow::Result<bool> {
-        if let Some(_event) = self.open_events.remove(&event_id) {
+    pub fn invalidate_cached_event_record(&self, event_id: EventId) {
+        self.event_record_cache.lock().unwrap().remove(&event_id);
+    }
+
+    pub async fn close_event(&self, event_id: EventId, client_command_sender: ClientCommandSender) -> anyhow::Result<bool> {
+        let event = self.open_events.write().unwrap().remove(&event_id);
+        if let Some(event) = event {
+            self.simulations.stop(event_id);
             // let mount_point = event_mount_point(event_id);
+            if event.remote_mount.is_some() {
+                self.unregister_event_mount(event_id, client_command_sender.clone()).await;
+            }
 
             let message = RpcMessage::new_signal("event", "lsmod").with_param(false);
             client_command_sender.send_message(message)
@@ -132,27 +286,64 @@ impl State {
             // log::info!("Sending quit RPC message to qxsql of event {event_id} mounted at {mount_point}");
             // let _: () = client_command_sender.call_rpc_method(format!("{mount_point}/.app"), "quit", None, None, None::<fn(_)>).await
             //     .map_err(|err| anyhow::anyhow!("Failed to shut down event: {}", err))?;
+            webhooks::emit(self.db_pool.clone(), WebhookEvent::EventClosed, Some(event_id), serde_json::Value::Null);
             Ok(true)
         } else {
             Ok(false)
         }
     }
-    pub async fn delete_event(&mut self, event_id: EventId, rpc_client: ClientCommandSender) -> anyhow::Result<bool> {
-        self.close_event(event_id, rpc_client.clone()).await?;
+    pub async fn delete_event(&self, event_id: EventId, rpc_client: ClientCommandSender) -> anyhow::Result<bool> {
+        if !self.close_event(event_id, rpc_client.clone()).await? {
+            self.unregister_event_mount(event_id, rpc_client.clone()).await;
+        }
         log::info!("Deleting event {}", event_id);
+        self.invalidate_cached_event_record(event_id);
         let qxsql = AppSqlApi::new(self.db_pool.clone(), rpc_client);
         let was_deleted = qxsql.delete_record_with_recchng("events", event_id, None).await?;
         Ok(was_deleted)
     }
 
-    pub async fn gc_expired_events(&mut self, client_command_sender: ClientCommandSender) -> anyhow::Result<()> {
-        let event_age_list = self.open_events.iter()
+    /// Number of master DB rows `delete_event` would remove, rows removed by cascade from the event record are included
+    pub async fn delete_event_dry_run(&self, event_id: EventId) -> anyhow::Result<i64> {
+        let rows_affected = self.db_pool.conn_mut(move |conn| {
+            let tx = conn.transaction()?;
+            let changes_before = tx.total_changes();
+            tx.execute("DELETE FROM events WHERE id = ?1", [event_id])?;
+            let rows_affected = tx.total_changes() - changes_before;
+            tx.rollback()?;
+            Ok(rows_affected)
+        }).await?;
+        Ok(rows_affected as i64)
+    }
+
+    /// Remove broker mount of remote event, failure is not fatal, stale mounts are pruned on the next start
+    async fn unregister_event_mount(&self, event_id: EventId, rpc_client: ClientCommandSender) {
+        let event_record = match self.cached_event_record(event_id).await {
+            Ok(event_record) => event_record,
+            Err(e) => {
+                warn!("Cannot unregister event {event_id} mount: {e}");
+                return;
+            }
+        };
+        if event_record.is_local {
+            return;
+        }
+        if let Err(e) = Self::unregister_event_mount_point(&event_record.api_token, rpc_client).await {
+            warn!("Failed to unregister event {event_id} mount: {e}");
+        }
+    }
+
+    pub async fn gc_expired_events(&self, client_command_sender: ClientCommandSender) -> anyhow::Result<()> {
+        let event_age_list = self.open_events.read().unwrap().iter()
             .map(|(id, event)| (*id, event.expires_at())).collect::<Vec<_>>();
         let now = chrono::Utc::now();
         for (event_id, expires_at) in event_age_list {
             if expires_at < now {
                 info!("Closing event: {event_id} as expired.");
+                let event_lock = self.event_lock(event_id);
+                let _event_guard = event_lock.lock().await;
                 self.close_event(event_id, client_command_sender.clone()).await?;
+                self.set_event_was_open(event_id, false).await?;
             }
         }
         Ok(())
@@ -171,73 +362,268 @@ impl State {
 
 }
 
+/// Close event holding its event lock, so it cannot interleave with concurrent open
+pub(crate) async fn close_event(app_state: SharedAppState, event_id: EventId, rpc_client: ClientCommandSender) -> anyhow::Result<bool> {
+    let event_lock = app_state.event_lock(event_id);
+    let _event_guard = event_lock.lock().await;
+    let was_open = app_state.close_event(event_id, rpc_client).await?;
+    app_state.set_event_was_open(event_id, false).await?;
+    Ok(was_open)
+}
+
+pub(crate) async fn delete_event(app_state: SharedAppState, event_id: EventId, rpc_client: ClientCommandSender) -> anyhow::Result<bool> {
+    let event_lock = app_state.event_lock(event_id);
+    let _event_guard = event_lock.lock().await;
+    let was_deleted = app_state.delete_event(event_id, rpc_client).await?;
+    app_state.event_locks.lock().unwrap().remove(&event_id);
+    Ok(was_deleted)
+}
+
+/// Migrate closed local event DB to schema `version`, event lock prevents the event to be opened meanwhile
+pub(crate) async fn migrate_event(app_state: SharedAppState, event_id: EventId, version: usize) -> anyhow::Result<MigrationResult> {
+    let event_lock = app_state.event_lock(event_id);
+    let _event_guard = event_lock.lock().await;
+    if app_state.is_event_open(event_id) {
+        bail!("Event id: {event_id} is open, close it before migration");
+    }
+    if !app_state.event_record(event_id).await?.is_local {
+        bail!("Event id: {event_id} DB is not local, it is migrated by its DB service");
+    }
+    migrate_event_db_to(&event_db_file(event_id), version).await
+}
+
 pub(crate) async fn open_event(app_state: SharedAppState, event_id: EventId, rpc_client: ClientCommandSender) -> anyhow::Result<String> {
     let event_shv_path = event_api_shv_path(event_id);
-    {
-        let mut state = app_state.write().await;
-        if let Some(event) = state.open_events.get_mut(&event_id) {
-            event.touched_at = chrono::Utc::now();
-            return Ok(event_shv_path);
-        }
+    // concurrent open of the same event would migrate DB and spawn qxsqld twice
+    let event_lock = app_state.event_lock(event_id);
+    let _event_guard = event_lock.lock().await;
+    if app_state.with_open_event_mut(event_id, |event| event.touched_at = chrono::Utc::now()).is_some() {
+        return Ok(event_shv_path);
     }
 
-    let event_record = app_state.read().await.event_record(event_id).await?;
-    let local_db = if event_record.is_local {
-        let db_file = format!("{}/{event_id}/event.qbe", global_config().data_dir);
+    let event_record = app_state.event_record(event_id).await?;
+    if let Some(reason) = &event_record.broken {
+        bail!("Event {event_id} is broken: {reason}");
+    }
+    quota::check_open_event(&app_state, &event_record).await?;
+    let mut open_guard = OpenEventGuard {
+        app_state: app_state.clone(),
+        event_id,
+        rpc_client: rpc_client.clone(),
+        mount_api_token: None,
+        armed: true,
+    };
+    let db_file = event_db_file(event_id);
+    let mut qxsqld_process = None;
+    let mut remote_mount = None;
+    let mut remote_signals_forwarder = None;
+    let (local_db, priority_db) = if event_record.is_local {
         let pool = migrate_db(&db_file, &event_record, rpc_client.clone()).await?;
-        Some(pool)
+        if global_config().integrity_check_on_open {
+            let report = integrity_check(&pool).await?;
+            if !report.ok {
+                error!("Event {event_id} DB integrity check failed: {}", report.summary());
+                bail!("Event {event_id} DB is corrupted: {}", report.summary());
+            }
+        }
+        let priority_pool = open_priority_db(&db_file).await?;
+        (Some(pool), Some(priority_pool))
     } else {
-        // ping child
-        let _: () = rpc_client.call_rpc_method(join_path(remote_event_mount_point(event_id), ".app"), "ping", None, None, None, None::<fn(_)>).await
-            .map_err(|_| anyhow!("Failed to ping DB service."))?;
+        let mount = event_record.remote_mount.clone().unwrap_or_else(|| remote_event_mount_point(event_id));
+        // mount is removed when event is closed
+        State::register_event_mount_point(&mount, &event_record.api_token, rpc_client.clone()).await?;
+        open_guard.mount_api_token = Some(event_record.api_token.clone());
+        if ping(&rpc_client, &mount).await.is_ok() {
+            info!("Event {event_id} DB service is already mounted at {mount}");
+        } else if let Some(qxsqld_config) = &global_config().qxsqld && event_record.remote_mount.is_none() {
+            qxsqld_process = Some(spawn_qxsqld(qxsqld_config, event_id, &mount, &db_file)?);
+            let timeout = qxsqld_config.startup_timeout.to_std().unwrap_or_default();
+            wait_for_ready(&rpc_client, event_id, &mount, timeout).await?;
+        } else {
+            return Err(anyhow!("Event {event_id} DB service is not reachable at {mount}"));
+        }
 
-        let ri = ShvRI::from_path_method_signal(&remote_event_sql_path(event_id), "", Some("recchng"))
+        let ri = ShvRI::from_path_method_signal(&join_path(&mount, "**"), "*", Some("*"))
             .map_err(|e| string_to_rpc_error(e))?;
-        info!("Subscribing SQL recchng: {ri:?}");
+        info!("Subscribing remote event signals: {ri:?}");
         let subscriber = rpc_client.subscribe(ri).await
             .map_err(|e| anyhow!("Failed to subscribe to remote event: {}", e))?;
-        smol::spawn(forward_remote_db_recchng_signals(event_id, subscriber, rpc_client.clone())).detach();
-        None
+        remote_signals_forwarder = Some(smol::spawn(forward_remote_event_signals(app_state.clone(), event_id, mount.clone(), subscriber, rpc_client.clone())));
+        remote_mount = Some(mount);
+        (None, None)
     };
 
     let now = chrono::Utc::now();
-    app_state.write().await.open_events.insert(event_id, OpenEventCtl {
+    let has_qxsqld_process = qxsqld_process.is_some();
+    let (changed_runs, changed_runs_rx) = channel::unbounded();
+    smol::spawn(crate::publish::publisher(app_state.clone(), event_id, rpc_client.clone(), changed_runs_rx)).detach();
+    let (changed_records, changed_records_rx) = channel::unbounded();
+    let (notify_triggers, notify_triggers_rx) = channel::unbounded();
+    smol::spawn(crate::notify::notifier(app_state.clone(), event_id, rpc_client.clone(), notify_triggers_rx)).detach();
+    let views = Arc::new(EventViews::default());
+    smol::spawn(crate::views::maintainer(app_state.clone(), event_id, rpc_client.clone(), views.clone(), changed_records_rx)).detach();
+    app_state.open_events.write().unwrap().insert(event_id, OpenEventCtl {
         current_stage: 1,
         local_db,
+        priority_db,
+        remote_mount,
+        circuit_breaker: Default::default(),
+        sql_called_at: Arc::new(Mutex::new(Instant::now())),
+        blob_transfers: Default::default(),
+        qxsqld_process,
+        remote_signals_forwarder,
+        changed_runs,
+        changed_records,
+        notify_triggers,
+        views,
         open_at: now,
         touched_at: now,
     });
+    if has_qxsqld_process {
+        smol::spawn(crate::qxsqld::watchdog(app_state.clone(), event_id, rpc_client.clone())).detach();
+    }
+    let event_sql = EventSqlApi::new(event_id, app_state.clone(), rpc_client.clone());
+    if let Err(e) = seed_event_db(&event_sql, event_record.sport).await {
+        warn!("Failed to seed event {event_id} DB defaults: {e}");
+    }
     let current_stage = update_event_record_from_event_config(app_state.clone(), rpc_client.clone(), event_id, &event_record).await?;
-    app_state.write().await.open_events.get_mut(&event_id).map(|e| e.current_stage = current_stage);
+    app_state.with_open_event_mut(event_id, |e| e.current_stage = current_stage);
+    if let Err(e) = app_state.set_event_was_open(event_id, true).await {
+        warn!("Failed to persist event {event_id} open state: {e}");
+    }
 
     let message = RpcMessage::new_signal("event", "lsmod").with_param(true);
     rpc_client.send_message(message)
         .map_err(|e| anyhow!("Failed to send message {}", e))?;
+    webhooks::emit(app_state.db_pool.clone(), WebhookEvent::EventOpened, Some(event_id), serde_json::json!({
+        "currentStage": current_stage,
+    }));
 
+    open_guard.armed = false;
     Ok(event_shv_path)
 }
 
-async fn forward_remote_db_recchng_signals(event_id: EventId, mut subscriber: shvclient::clientapi::Subscriber, rpc_client: ClientCommandSender) {
+/// Undo of event open failed half way, the open event entry is removed and the broker mount of remote event
+/// is unregistered, unless the guard is disarmed after the event is opened
+struct OpenEventGuard {
+    app_state: SharedAppState,
+    event_id: EventId,
+    rpc_client: ClientCommandSender,
+    mount_api_token: Option<String>,
+    armed: bool,
+}
+
+impl Drop for OpenEventGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let event_id = self.event_id;
+        warn!("Event {event_id} open failed, cleaning up");
+        // spawned qxsqld is killed and remote signals forwarder is cancelled with their OpenEventCtl
+        let _ = self.app_state.open_events.write().unwrap().remove(&event_id);
+        if let Some(api_token) = self.mount_api_token.take() {
+            let rpc_client = self.rpc_client.clone();
+            smol::spawn(async move {
+                if let Err(e) = State::unregister_event_mount_point(&api_token, rpc_client).await {
+                    error!("Failed to unregister mount point of event {event_id}: {e}");
+                }
+            }).detach();
+        }
+    }
+}
+
+/// Open events, which were open before the daemon shutdown
+pub(crate) async fn reopen_events(app_state: SharedAppState, rpc_client: ClientCommandSender) {
+    let event_ids = match app_state.was_open_event_ids().await {
+        Ok(event_ids) => event_ids,
+        Err(e) => {
+            error!("Failed to load events open before shutdown: {e}");
+            return;
+        }
+    };
+    for event_id in event_ids {
+        info!("Reopening event {event_id}");
+        if let Err(e) = open_event(app_state.clone(), event_id, rpc_client.clone()).await {
+            error!("Failed to reopen event {event_id}: {e}");
+        }
+    }
+}
+
+/// Remove broker mounts under `remote_events_mount_point`, which do not belong to any remote event,
+/// they are left by events deleted or re-tokenized while the daemon was not connected to the broker.
+pub(crate) async fn prune_stale_mounts(app_state: SharedAppState, rpc_client: ClientCommandSender) -> anyhow::Result<usize> {
+    let prefix = format!("{}/", global_config().remote_events_mount_point);
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id, api_token, remote_mount FROM events WHERE NOT is_local", None).await?;
+    let event_mounts = (0..result.row_count())
+        .filter_map(|row| {
+            let event_id = result.value(row, 0).and_then(|v| v.to_int())?;
+            let api_token = result.value(row, 1).and_then(|v| v.as_str())?.to_string();
+            let mount = result.value(row, 2).and_then(|v| v.as_str()).filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .unwrap_or_else(|| remote_event_mount_point(event_id));
+            Some((api_token, mount))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let ids: RpcValue = rpc_client.call_rpc_method(BROKER_MOUNTS_PATH, "ls", None, None, None, None::<fn(f64)>)
+        .await.map_err(|e| anyhow!("{e}"))?;
+    let mut pruned = 0;
+    for id in ids.as_list().iter().map(|id| id.as_str()) {
+        let mount: RpcValue = rpc_client.call_rpc_method(join_path(BROKER_MOUNTS_PATH, id), "value", None, None, None, None::<fn(f64)>)
+            .await.map_err(|e| anyhow!("{e}"))?;
+        let Some(mount_point) = mount.as_map().get("mountPoint").map(|v| v.as_str()) else {
+            continue;
+        };
+        if !mount_point.starts_with(&prefix) || event_mounts.get(id).is_some_and(|mount| mount == mount_point) {
+            continue;
+        }
+        info!("Pruning stale broker mount {mount_point}");
+        State::unregister_event_mount_point(id, rpc_client.clone()).await?;
+        pruned += 1;
+    }
+    Ok(pruned)
+}
+
+/// Re-emit signals from remote event mount point under `eventctl/<event_id>`,
+/// so clients subscribed on the event node get them too. Record changes are passed to the event views maintainer.
+async fn forward_remote_event_signals(app_state: SharedAppState, event_id: EventId, remote_mount_point: String, mut subscriber: shvclient::clientapi::Subscriber, rpc_client: ClientCommandSender) {
     while let Some(frame) = subscriber.next().await {
         match frame.to_rpcmesage() {
             Ok(message) => {
-                if message.is_signal() && message.method() == Some("recchng") {
-                    let remote_sql_path = remote_event_sql_path(event_id);
-                    if let Some(path) = message.shv_path() && path == &remote_sql_path {
-                        info!("Received event {event_id} subscription message: {:?}", message.shv_path());
-                        let mut signal = message;
-                        let event_sql_path = format!("eventctl/{event_id}/sql");
-                        signal.set_shvpath(&event_sql_path);
-                        if let Err(e) = rpc_client.send_message(signal) {
-                            error!("Failed to send event {event_id} recchng signal: {e}");
-                        }
+                if !message.is_signal() {
+                    continue;
+                }
+                let Some(subpath) = message.shv_path().and_then(|path| path.strip_prefix(remote_mount_point.as_str())) else {
+                    continue;
+                };
+                if message.method() == Some(SIG_RECCHNG) && let Some(param) = message.param() {
+                    let recchng = param.as_map();
+                    if let (Some(table), Some(id)) = (recchng.get("table").map(|v| v.as_str()), recchng.get("id").filter(|v| v.is_int()).map(|v| v.as_int())) {
+                        app_state.notify_record_changed(event_id, table, id);
                     }
                 }
+                let event_path = join_path(format!("eventctl/{event_id}"), subpath.trim_start_matches('/'));
+                debug!("Forwarding event {event_id} signal {:?}:{:?} to {event_path}", message.shv_path(), message.method());
+                let mut signal = message;
+                signal.set_shvpath(&event_path);
+                traffic::record_signal(event_id, &signal);
+                if let Err(e) = rpc_client.send_message(signal) {
+                    error!("Failed to send event {event_id} signal: {e}");
+                }
             },
             Err(err) => error!("Failed to decode event {event_id} subscription message: {err}"),
         }
     }
-    info!("SQL recchng {event_id} subscription stream closed");
+    info!("Remote event {event_id} subscription stream closed");
+}
+
+/// Sync event record and current stage with event config, after the config was changed
+pub(crate) async fn refresh_event_record_from_event_config(app_state: SharedAppState, client_command_sender: ClientCommandSender, event_id: EventId) -> anyhow::Result<()> {
+    let event_record = app_state.event_record(event_id).await?;
+    let current_stage = update_event_record_from_event_config(app_state.clone(), client_command_sender, event_id, &event_record).await?;
+    app_state.with_open_event_mut(event_id, |e| e.current_stage = current_stage);
+    Ok(())
 }
 
 async fn update_event_record_from_event_config(app_state: SharedAppState, client_command_sender: ClientCommandSender, event_id: i64, event_record: &EventRecord) -> anyhow::Result<i64> {
@@ -274,124 +660,180 @@ async fn update_event_record_from_event_config(app_state: SharedAppState, client
         }
     }
     if !changes.is_empty() {
-        app_state.read().await.update_event_record(event_id, changes, client_command_sender).await?;
+        app_state.update_event_record(event_id, changes, client_command_sender).await?;
         info!("Updated event record for event {event_id}");
     }
     Ok(current_stage)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct EventStatus {
-    pub current_stage: i64,
-    pub is_local: bool,
-    pub open_at: DateTime<chrono::FixedOffset>,
-    pub expires_at: DateTime<chrono::FixedOffset>,
+/// Prefix of event not open error message, it is followed by CPON map of error details
+pub(crate) const EVENT_NOT_OPEN: &str = "EventNotOpen";
+
+/// Request to event node of event, which is not open
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EventNotOpen {
+    pub event_id: EventId,
+    /// Event exists in app DB, client can open it
+    pub exists: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct EventRecord {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(default)]
-    pub id: Option<i64>,
-    pub name: String,
-    pub date: DateTime<chrono::FixedOffset>,
-    #[serde(default = "default_stage")]
-    pub stage: i64,
-    pub owner: String,
-    pub api_token: String,
-    pub is_local: bool,
+impl fmt::Display for EventNotOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.exists {
+            write!(f, "Event id: {} is not open.", self.event_id)
+        } else {
+            write!(f, "Event id: {} does not exist.", self.event_id)
+        }
+    }
 }
 
-fn default_stage() -> i64 { 1 }
-
-impl EventRecord {
-    fn from_record(record: &Record) -> anyhow::Result<Self> {
-        let get_field = |name| record.get(name).ok_or_else(||anyhow!("Cannot get field '{}'.", name));
-        Ok(Self {
-            id: get_field("id")?.to_int(),
-            name: get_field("name")?.as_str().unwrap_or_default().to_string(),
-            date: get_field("date")?.to_datetime().unwrap_or_else(|| chrono::Local::now().fixed_offset()),
-            stage: get_field("stage")?.to_int().unwrap_or(default_stage()),
-            owner: get_field("owner")?.as_str().unwrap_or_default().to_string(),
-            is_local: get_field("is_local")?.to_bool(),
-            api_token: get_field("api_token")?.as_str().unwrap_or_default().to_string(),
-        })
+impl std::error::Error for EventNotOpen {}
+
+impl EventNotOpen {
+    pub fn to_rpc_error(&self) -> RpcError {
+        let details = shvproto::to_rpcvalue(self).map(|v| v.to_cpon()).unwrap_or_default();
+        RpcError::new(RpcErrorCode::MethodCallException, format!("{EVENT_NOT_OPEN}: {details}"))
     }
-    fn to_record(&self) -> anyhow::Result<Record> {
-        let mut record = Record::new();
-        record.insert("name".to_string(), self.name.clone().into());
-        record.insert("date".to_string(), self.date.into());
-        record.insert("stage".to_string(), self.stage.into());
-        record.insert("owner".to_string(), self.owner.clone().into());
-        record.insert("api_token".to_string(), self.api_token.clone().into());
-        record.insert("is_local".to_string(), self.is_local.into());
-        Ok(record)
+}
+
+fn event_record_from_record(record: &Record) -> anyhow::Result<EventRecord> {
+    let get_field = |name| record.get(name).ok_or_else(||anyhow!("Cannot get field '{}'.", name));
+    Ok(EventRecord {
+        id: get_field("id")?.to_int(),
+        name: get_field("name")?.as_str().unwrap_or_default().to_string(),
+        date: get_field("date")?.to_datetime().unwrap_or_else(|| chrono::Local::now().fixed_offset()),
+        stage: get_field("stage")?.to_int().unwrap_or(DEFAULT_STAGE),
+        owner: get_field("owner")?.as_str().unwrap_or_default().to_string(),
+        is_local: get_field("is_local")?.to_bool(),
+        api_token: get_field("api_token")?.as_str().unwrap_or_default().to_string(),
+        remote_mount: record.get("remote_mount").and_then(|v| v.as_str()).filter(|s| !s.is_empty()).map(str::to_string),
+        sport: record.get("sport").and_then(|v| v.as_str()).and_then(|s| s.parse().ok()).unwrap_or_default(),
+        organization_id: record.get("organization_id").and_then(|v| v.to_int()),
+        broken: record.get("broken").and_then(|v| v.as_str()).filter(|s| !s.is_empty()).map(str::to_string),
+    })
+}
+
+fn event_record_to_record(event: &EventRecord) -> Record {
+    let mut record = Record::new();
+    record.insert("name".to_string(), event.name.clone().into());
+    record.insert("date".to_string(), event.date.into());
+    record.insert("stage".to_string(), event.stage.into());
+    record.insert("owner".to_string(), event.owner.clone().into());
+    record.insert("api_token".to_string(), event.api_token.clone().into());
+    record.insert("is_local".to_string(), event.is_local.into());
+    if let Some(remote_mount) = &event.remote_mount {
+        record.insert("remote_mount".to_string(), remote_mount.clone().into());
+    }
+    record.insert("sport".to_string(), event.sport.as_str().into());
+    if let Some(organization_id) = event.organization_id {
+        record.insert("organization_id".to_string(), organization_id.into());
     }
+    record
 }
 
-impl_rpcvalue_conversions!(EventStatus);
-impl_rpcvalue_conversions!(EventRecord);
-
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub(crate) struct EventRecordChange {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(default)]
-    pub name: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(default)]
-    pub date: Option<DateTime<chrono::FixedOffset>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub stage: Option<i64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(default)]
-    pub owner: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(default)]
-    pub api_token: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(default)]
-    pub is_local: Option<bool>,
+fn event_record_change_to_record(change: &EventRecordChange) -> Record {
+    let mut record = Record::new();
+    if let Some(name) = &change.name {
+        record.insert("name".to_string(), name.clone().into());
+    }
+    if let Some(date) = &change.date {
+        record.insert("date".to_string(), (*date).into());
+    }
+    if let Some(stage) = &change.stage {
+        record.insert("stage".to_string(), (*stage).into());
+    }
+    if let Some(owner) = &change.owner {
+        record.insert("owner".to_string(), owner.clone().into());
+    }
+    if let Some(api_token) = &change.api_token {
+        record.insert("api_token".to_string(), api_token.clone().into());
+    }
+    if let Some(is_local) = &change.is_local {
+        record.insert("is_local".to_string(), (*is_local).into());
+    }
+    if let Some(remote_mount) = &change.remote_mount {
+        record.insert("remote_mount".to_string(), remote_mount.clone().into());
+    }
+    record
 }
 
-impl EventRecordChange {
-    fn to_record(&self) -> Record {
-        let mut record = Record::new();
-        if let Some(name) = &self.name {
-            record.insert("name".to_string(), name.clone().into());
-        }
-        if let Some(date) = &self.date {
-            record.insert("date".to_string(), (*date).into());
-        }
-        if let Some(stage) = &self.stage {
-            record.insert("stage".to_string(), (*stage).into());
-        }
-        if let Some(owner) = &self.owner {
-            record.insert("owner".to_string(), owner.clone().into());
-        }
-        if let Some(api_token) = &self.api_token {
-            record.insert("api_token".to_string(), api_token.clone().into());
+/// Maximal chunk of blob transferred in single call, so the message fits into broker frame
+pub(crate) const MAX_BLOB_CHUNK_SIZE: usize = 1024 * 1024;
+/// Chunked transfer idle for this time is dropped
+const BLOB_TRANSFER_TTL: Duration = Duration::from_secs(10 * 60);
+/// Limits of chunked transfers of event in progress, their data are kept in memory
+const MAX_BLOB_TRANSFERS: usize = 8;
+const MAX_BLOB_TRANSFER_BYTES: usize = 128 * 1024 * 1024;
+
+pub(crate) struct BlobUpload {
+    pub table: String,
+    pub id: i64,
+    pub field: String,
+    pub data: Vec<u8>,
+    pub touched_at: Instant,
+}
+
+/// Blob result of export, which is read by chunks
+pub(crate) struct BlobDownload {
+    pub data: Vec<u8>,
+    pub touched_at: Instant,
+}
+
+/// Chunked uploads and downloads of event in progress
+#[derive(Default)]
+pub(crate) struct BlobTransfers {
+    pub uploads: BTreeMap<String, BlobUpload>,
+    pub downloads: BTreeMap<String, BlobDownload>,
+}
+
+impl BlobTransfers {
+    /// Drop transfers idle for longer than TTL, client has to start them again
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.uploads.retain(|_, upload| now.duration_since(upload.touched_at) < BLOB_TRANSFER_TTL);
+        self.downloads.retain(|_, download| now.duration_since(download.touched_at) < BLOB_TRANSFER_TTL);
+    }
+    /// Check, that new transfer or `additional_bytes` of transfer in progress fit into limits of event
+    pub fn check_capacity(&mut self, new_transfer: bool, additional_bytes: usize) -> anyhow::Result<()> {
+        self.expire();
+        if new_transfer && self.uploads.len() + self.downloads.len() >= MAX_BLOB_TRANSFERS {
+            bail!("Too many chunked transfers in progress, limit is {MAX_BLOB_TRANSFERS}");
         }
-        if let Some(is_local) = &self.is_local {
-            record.insert("is_local".to_string(), (*is_local).into());
+        let bytes: usize = self.uploads.values().map(|upload| upload.data.len())
+            .chain(self.downloads.values().map(|download| download.data.len()))
+            .sum();
+        if bytes + additional_bytes > MAX_BLOB_TRANSFER_BYTES {
+            bail!("Chunked transfers in progress exceed limit of {MAX_BLOB_TRANSFER_BYTES} bytes");
         }
-        record
-    }
-    pub fn is_empty(&self) -> bool {
-        self.name.is_none()
-            && self.date.is_none()
-            && self.stage.is_none()
-            && self.owner.is_none()
-            && self.api_token.is_none()
-            && self.is_local.is_none()
+        Ok(())
     }
 }
 
 pub(crate) struct OpenEventCtl {
     pub current_stage: i64,
     pub local_db: Option<async_sqlite::Pool>,
+    /// Dedicated connection of local event DB for latency critical writes
+    pub priority_db: Option<async_sqlite::Pool>,
+    /// Mount point of event DB service for remote events
+    pub remote_mount: Option<String>,
+    pub circuit_breaker: Arc<Mutex<CircuitBreaker>>,
+    pub blob_transfers: BlobTransfers,
+    /// Spawned event DB service, it is killed when dropped
+    pub qxsqld_process: Option<smol::process::Child>,
+    /// Forwarder of remote event DB signals, it is cancelled when dropped
+    pub remote_signals_forwarder: Option<smol::Task<()>>,
+    /// Ids of changed runs for results publisher, it finishes when the event is closed
+    pub changed_runs: channel::Sender<i64>,
+    /// Table and id of changed records for views maintainer, it finishes when the event is closed
+    pub changed_records: channel::Sender<(String, i64)>,
+    /// Triggers of competitor notifications, notifier finishes when the event is closed
+    pub notify_triggers: channel::Sender<NotifyTrigger>,
+    pub views: Arc<EventViews>,
     pub open_at: DateTime<chrono::Utc>,
     pub touched_at: DateTime<chrono::Utc>,
-
+    /// Time of last SQL call on local event DB, idle events get DB maintenance
+    pub sql_called_at: Arc<Mutex<Instant>>,
 }
 
 impl OpenEventCtl {
@@ -404,10 +846,24 @@ pub fn event_api_shv_path(event_id: EventId) -> String {
     format!("{}/eventctl/{event_id}", global_config().client.mount.as_deref().unwrap_or_default())
 }
 
+pub fn event_dir(event_id: EventId) -> String {
+    format!("{}/{event_id}", global_config().data_dir)
+}
+
+pub fn event_db_file(event_id: EventId) -> String {
+    format!("{}/event.qbe", event_dir(event_id))
+}
+
+/// Event DB is stored in the event directory, either opened locally or by spawned qxsqld
+pub(crate) fn uses_data_dir(event_record: &EventRecord) -> bool {
+    !global_config().data_dir.is_empty()
+        && (event_record.is_local || (global_config().qxsqld.is_some() && event_record.remote_mount.is_none()))
+}
+
 pub fn remote_event_mount_point(event_id: EventId) -> String {
     format!("{}/{event_id}", global_config().remote_events_mount_point)
 }
 
-pub fn remote_event_sql_path(event_id: EventId) -> String {
-    format!("{}/sql", remote_event_mount_point(event_id))
+pub fn remote_event_sql_path(remote_mount: &str) -> String {
+    format!("{remote_mount}/sql")
 }
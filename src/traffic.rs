@@ -0,0 +1,161 @@
+//! Recorder of event traffic, every request to the event nodes and every signal emitted on them is appended
+//! to `<data_dir>/<event_id>/traffic.<jsonl|cpon>` with its timestamp, one message per line. The recording is used
+//! to debug client issues after the race. Record change signals of local events are emitted by the SQL layer
+//! and are not recorded, the records themselves are in the event DB.
+//!
+//! Lines are written by single writer task off the request path, the line is dropped, when the writer falls behind.
+//! Blobs, long strings and credentials are not recorded.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::OnceLock;
+
+use log::{debug, warn};
+use serde::Serialize;
+use shvproto::RpcValue;
+use shvproto::rpcvalue::Value;
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+use smol::channel;
+
+use crate::config::TrafficFormat;
+use crate::global_config;
+use crate::state::{EventId, event_dir};
+
+const KIND_REQUEST: &str = "request";
+const KIND_SIGNAL: &str = "signal";
+
+/// Lines waiting for the writer
+const QUEUE_CAPACITY: usize = 10000;
+/// Longer strings in parameters are truncated
+const MAX_STRING_LEN: usize = 256;
+/// Values of map keys containing these words are replaced by `***`
+const REDACTED_KEYS: &[&str] = &["token", "password", "secret", "apikey"];
+
+static LINES: OnceLock<channel::Sender<(EventId, String)>> = OnceLock::new();
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TrafficEntry<'a> {
+    ts: String,
+    kind: &'static str,
+    path: &'a str,
+    /// Method of request or name of signal
+    method: &'a str,
+    request_id: Option<i64>,
+    user_id: Option<&'a str>,
+    /// Parameter in CPON, so its types are kept in JSON recording too
+    param: Option<String>,
+}
+
+fn traffic_file(event_id: EventId) -> String {
+    let extension = match global_config().traffic_recorder.format {
+        TrafficFormat::Json => "jsonl",
+        TrafficFormat::Cpon => "cpon",
+    };
+    format!("{}/traffic.{extension}", event_dir(event_id))
+}
+
+fn is_recorded(event_id: EventId) -> bool {
+    let config = &global_config().traffic_recorder;
+    config.enabled && !global_config().data_dir.is_empty() && (config.events.is_empty() || config.events.contains(&event_id))
+}
+
+fn format_entry(entry: &TrafficEntry) -> anyhow::Result<String> {
+    Ok(match global_config().traffic_recorder.format {
+        TrafficFormat::Json => serde_json::to_string(entry)?,
+        TrafficFormat::Cpon => shvproto::to_rpcvalue(entry)?.to_cpon(),
+    })
+}
+
+/// Full recording file is moved to `<file>.1`, previous rotated file is overwritten
+fn rotate(file: &str) -> std::io::Result<()> {
+    let max_size = global_config().traffic_recorder.max_file_size_mb.saturating_mul(1024 * 1024);
+    if max_size > 0 && std::fs::metadata(file).is_ok_and(|metadata| metadata.len() >= max_size) {
+        std::fs::rename(file, format!("{file}.1"))?;
+    }
+    Ok(())
+}
+
+fn append(event_id: EventId, line: &str) -> anyhow::Result<()> {
+    let file = traffic_file(event_id);
+    std::fs::create_dir_all(event_dir(event_id))?;
+    rotate(&file)?;
+    // whole line is written by single write of file opened in append mode, so concurrent lines are not interleaved
+    OpenOptions::new().create(true).append(true).open(&file)?
+        .write_all(format!("{line}\n").as_bytes())?;
+    Ok(())
+}
+
+async fn writer(lines: channel::Receiver<(EventId, String)>) {
+    while let Ok((event_id, line)) = lines.recv().await {
+        if let Err(e) = smol::unblock(move || append(event_id, &line)).await {
+            warn!("Failed to record event {event_id} traffic: {e}");
+        }
+    }
+}
+
+fn queue_line(event_id: EventId, line: String) {
+    let lines = LINES.get_or_init(|| {
+        let (sender, receiver) = channel::bounded(QUEUE_CAPACITY);
+        smol::spawn(writer(receiver)).detach();
+        sender
+    });
+    if lines.try_send((event_id, line)).is_err() {
+        debug!("Event {event_id} traffic recorder queue is full, line is dropped");
+    }
+}
+
+fn truncate(s: &str) -> String {
+    match s.char_indices().nth(MAX_STRING_LEN) {
+        Some((end, _)) => format!("{}...", &s[..end]),
+        None => s.to_string(),
+    }
+}
+
+fn is_redacted_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    REDACTED_KEYS.iter().any(|redacted| key.contains(redacted))
+}
+
+/// Parameter without blobs, credentials and long strings
+fn redact(value: &RpcValue) -> RpcValue {
+    match &value.value {
+        Value::Blob(b) => RpcValue::from(format!("<{} bytes>", b.len())),
+        Value::String(s) => RpcValue::from(truncate(s)),
+        Value::List(list) => RpcValue::from(list.iter().map(redact).collect::<Vec<_>>()),
+        Value::Map(map) => RpcValue::from(map.iter()
+            .map(|(key, val)| (key.clone(), if is_redacted_key(key) { RpcValue::from("***") } else { redact(val) }))
+            .collect::<shvproto::rpcvalue::Map>()),
+        Value::IMap(map) => RpcValue::from(map.iter()
+            .map(|(key, val)| (*key, redact(val)))
+            .collect::<shvproto::rpcvalue::IMap>()),
+        _ => value.clone(),
+    }
+}
+
+fn record(event_id: EventId, kind: &'static str, message: &RpcMessage) {
+    if !is_recorded(event_id) {
+        return;
+    }
+    let entry = TrafficEntry {
+        ts: chrono::Local::now().fixed_offset().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+        kind,
+        path: message.shv_path().unwrap_or_default(),
+        method: message.method().unwrap_or_default(),
+        request_id: message.request_id(),
+        user_id: message.user_id(),
+        param: message.param().map(|param| redact(param).to_cpon()),
+    };
+    match format_entry(&entry) {
+        Ok(line) => queue_line(event_id, line),
+        Err(e) => warn!("Failed to record event {event_id} {kind} {}:{}: {e}", entry.path, entry.method),
+    }
+}
+
+pub(crate) fn record_request(event_id: EventId, rq: &RpcMessage) {
+    record(event_id, KIND_REQUEST, rq);
+}
+
+pub(crate) fn record_signal(event_id: EventId, signal: &RpcMessage) {
+    record(event_id, KIND_SIGNAL, signal);
+}
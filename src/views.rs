@@ -0,0 +1,452 @@
+//! In-memory materialized views of open event, they back the results and speaker endpoints,
+//! so the event DB is not queried on every request.
+//!
+//! Views are maintained by a task per open event, which receives changed records. Local event
+//! changes are reported by `EventSqlApi`, remote event changes come from `recchng` signals
+//! of its DB service, so changes made by other clients of the DB service are seen too.
+//! Changed run marks its class to be reloaded, other changes reload the whole stage.
+//!
+//! Classes subscribed by `results:subscribeDelta` get `resultdelta` signal with changed rows only,
+//! when they are reloaded. Classes with changed results are signalled by `topchng` at most once
+//! per `results_top.refresh_interval`, so arena screens know which `results:top` to poll.
+//! New punches at controls subscribed by `speaker:prewarning` are signalled as prewarnings.
+//! Deltas of all classes and punches at radio controls are mirrored by the MQTT bridge, if it is configured.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, FixedOffset};
+use log::{error, info, warn};
+use serde::Serialize;
+use shvclient::ClientCommandSender;
+use shvrpc::RpcMessage;
+use smol::channel;
+
+pub(crate) use qxeventd_api::{ClassDelta, ResultDelta, ResultRow, TopRow};
+
+use crate::eventsqlapi::EventSqlApi;
+use crate::results::{RunFilter, RunResult, RunStatus, load_run_results};
+use crate::{global_config, mqtt, speaker};
+use crate::state::{EventId, SharedAppState};
+use crate::traffic;
+use crate::webhooks::{self, WebhookEvent};
+
+const SIG_RESULT_DELTA: &str = "resultdelta";
+const SIG_TOP_CHNG: &str = "topchng";
+
+/// Delta and prewarning subscriptions expire, if they are not renewed
+pub(crate) const SUBSCRIPTION_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Changes of these tables can affect any class results, changes of other tables except `runs` are ignored
+const STAGE_TABLES: &[&str] = &["competitors", "classes", "classdefs", "stages", "protests"];
+
+/// Results of current stage
+struct StageView {
+    stage_id: i64,
+    stage_start: Option<DateTime<FixedOffset>>,
+    classes: BTreeMap<i64, Vec<RunResult>>,
+    run_classes: BTreeMap<i64, i64>,
+}
+
+#[derive(Default)]
+struct ViewsData {
+    stage: Option<StageView>,
+    /// Whole stage has to be reloaded
+    stale: bool,
+    dirty_classes: BTreeSet<i64>,
+    /// Expiration of class delta subscriptions
+    delta_subscriptions: BTreeMap<i64, Instant>,
+    /// Deltas of reloaded classes waiting to be signalled
+    pending_deltas: Vec<ClassDelta>,
+    /// Deltas of all reloaded classes waiting for webhooks and MQTT bridge
+    changed_deltas: Vec<ClassDelta>,
+    /// Time of the last result change of runs, for the recent change flag
+    changed_at: BTreeMap<i64, Instant>,
+    /// Classes with changed results waiting to be signalled
+    changed_classes: BTreeSet<i64>,
+    /// Expiration of prewarning subscriptions of control codes
+    prewarning_controls: BTreeMap<i64, Instant>,
+    /// Ids of new punches, collected while there is a prewarning subscription
+    pending_punches: BTreeSet<i64>,
+    /// Ids of new punches waiting for MQTT bridge
+    bridged_punches: BTreeSet<i64>,
+}
+
+impl ViewsData {
+    fn is_delta_subscribed(&mut self, class_id: i64) -> bool {
+        self.delta_subscriptions.retain(|_, expires_at| *expires_at > Instant::now());
+        self.delta_subscriptions.contains_key(&class_id)
+    }
+
+    fn add_delta(&mut self, class_id: i64, old: Option<&Vec<RunResult>>, new: Option<&Vec<RunResult>>) {
+        let delta = class_delta(class_id, old.map(Vec::as_slice).unwrap_or_default(), new.map(Vec::as_slice).unwrap_or_default());
+        if delta.changes.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let recent_change = global_config().results_top.recent_change.to_std().unwrap_or_default();
+        self.changed_at.retain(|_, changed_at| now.duration_since(*changed_at) < recent_change);
+        self.changed_at.extend(delta.changes.iter().filter(|change| !change.removed).map(|change| (change.run_id, now)));
+        self.changed_classes.insert(class_id);
+        if self.is_delta_subscribed(class_id) {
+            self.pending_deltas.push(delta.clone());
+        }
+        self.changed_deltas.push(delta);
+    }
+
+#[derive(Default)]
+pub(crate) struct EventViews {
+    data: Mutex<ViewsData>,
+    /// Serializes reloads, so concurrent requests wait for the running one instead of seeing empty view
+    refresh_lock: smol::lock::Mutex<()>,
+}
+
+enum Reload {
+    Stage,
+    Classes(BTreeSet<i64>),
+}
+
+impl EventViews {
+    /// Mark view data affected by changed record to be reloaded
+    pub fn invalidate(&self, table: &str, id: i64) {
+        let mut data = self.data.lock().unwrap();
+        if table == "runs" {
+            match data.stage.as_ref().and_then(|stage| stage.run_classes.get(&id).copied()) {
+                Some(class_id) => { data.dirty_classes.insert(class_id); }
+                // new run or run of other stage
+                None => data.stale = true,
+            }
+        } else if STAGE_TABLES.contains(&table) {
+            data.stale = true;
+        } else if table == "punches" {
+            if !data.prewarning_controls.is_empty() {
+                data.pending_punches.insert(id);
+            }
+            if mqtt::is_punch_bridged() {
+                data.bridged_punches.insert(id);
+            }
+        }
+    }
+
+    fn take_reload(&self, stage_id: i64) -> Option<Reload> {
+        let mut data = self.data.lock().unwrap();
+        if data.stale || data.stage.as_ref().is_none_or(|stage| stage.stage_id != stage_id) {
+            data.stale = false;
+            data.dirty_classes.clear();
+            Some(Reload::Stage)
+        } else if !data.dirty_classes.is_empty() {
+            Some(Reload::Classes(std::mem::take(&mut data.dirty_classes)))
+        } else {
+            None
+        }
+    }
+
+    /// Reload stale parts of view, changes received meanwhile are reloaded next time
+    pub async fn refresh(&self, sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<()> {
+        let _refresh_guard = self.refresh_lock.lock().await;
+        match self.take_reload(stage_id) {
+            None => {}
+            Some(Reload::Stage) => {
+                let loaded = async {
+                    let stage_start = sql_api.stage_start(stage_id).await.ok();
+                    let results = load_run_results(sql_api, stage_id, RunFilter::Stage).await?;
+                    anyhow::Ok((stage_start, results))
+                }.await;
+                let (stage_start, results) = match loaded {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        self.data.lock().unwrap().stale = true;
+                        return Err(e);
+                    }
+                };
+                let mut stage = StageView { stage_id, stage_start, classes: BTreeMap::new(), run_classes: BTreeMap::new() };
+                for run in results {
+                    stage.run_classes.insert(run.run_id, run.class_id);
+                    stage.classes.entry(run.class_id).or_default().push(run);
+                }
+                let mut data = self.data.lock().unwrap();
+                // deltas of other stage have no meaning, clients have to reload results
+                if let Some(old_stage) = data.stage.take().filter(|old_stage| old_stage.stage_id == stage_id) {
+                    let class_ids: BTreeSet<i64> = old_stage.classes.keys().chain(stage.classes.keys()).copied().collect();
+                    for class_id in class_ids {
+                        data.add_delta(class_id, old_stage.classes.get(&class_id), stage.classes.get(&class_id));
+                    }
+                }
+                data.stage = Some(stage);
+            }
+            Some(Reload::Classes(class_ids)) => {
+                let mut loaded = Vec::with_capacity(class_ids.len());
+                for class_id in &class_ids {
+                    match load_run_results(sql_api, stage_id, RunFilter::Class(*class_id)).await {
+                        Ok(results) => loaded.push((*class_id, results)),
+                        Err(e) => {
+                            self.data.lock().unwrap().dirty_classes.extend(class_ids);
+                            return Err(e);
+                        }
+                    }
+                }
+                let mut data = self.data.lock().unwrap();
+                let Some(mut stage) = data.stage.take_if(|stage| stage.stage_id == stage_id) else {
+                    return Ok(());
+                };
+                for (class_id, results) in loaded {
+                    stage.run_classes.retain(|_, run_class_id| *run_class_id != class_id);
+                    stage.run_classes.extend(results.iter().map(|run| (run.run_id, class_id)));
+                    let old = stage.classes.insert(class_id, results);
+                    data.add_delta(class_id, old.as_ref(), stage.classes.get(&class_id));
+                }
+                data.stage = Some(stage);
+            }
+        }
+        Ok(())
+    }
+
+    /// Results of stage sorted the same way as `load_run_results()` does
+    pub async fn results(&self, sql_api: &EventSqlApi, stage_id: i64, class_id: Option<i64>) -> anyhow::Result<Vec<RunResult>> {
+        self.refresh(sql_api, stage_id).await?;
+        let data = self.data.lock().unwrap();
+        let Some(stage) = &data.stage else {
+            return Ok(vec![]);
+        };
+        let mut classes: Vec<&Vec<RunResult>> = stage.classes.iter()
+            .filter(|(id, _)| class_id.is_none_or(|class_id| class_id == **id))
+            .map(|(_, runs)| runs)
+            .collect();
+        classes.sort_by(|a, b| a.first().map(|run| &run.class_name).cmp(&b.first().map(|run| &run.class_name)));
+        Ok(classes.into_iter().flatten().cloned().collect())
+    }
+
+    /// Signal changes of class results for `SUBSCRIPTION_TTL`, returns current class results
+    pub async fn subscribe_delta(&self, sql_api: &EventSqlApi, stage_id: i64, class_id: i64) -> anyhow::Result<Vec<RunResult>> {
+        self.data.lock().unwrap().delta_subscriptions.insert(class_id, Instant::now() + SUBSCRIPTION_TTL);
+        self.results(sql_api, stage_id, Some(class_id)).await
+    }
+
+    fn take_deltas(&self) -> Vec<ClassDelta> {
+        std::mem::take(&mut self.data.lock().unwrap().pending_deltas)
+    }
+
+    fn take_changed_deltas(&self) -> Vec<ClassDelta> {
+        std::mem::take(&mut self.data.lock().unwrap().changed_deltas)
+    }
+
+    fn take_bridged_punches(&self) -> Vec<i64> {
+        std::mem::take(&mut self.data.lock().unwrap().bridged_punches).into_iter().collect()
+    }
+
+    fn take_changed_classes(&self) -> BTreeSet<i64> {
+        std::mem::take(&mut self.data.lock().unwrap().changed_classes)
+    }
+
+    /// Signal punches at control `code` for `SUBSCRIPTION_TTL`
+    pub fn subscribe_prewarning(&self, code: i64) {
+        self.data.lock().unwrap().prewarning_controls.insert(code, Instant::now() + SUBSCRIPTION_TTL);
+    }
+
+    /// New punches and subscribed control codes, `None` if there is nothing to signal
+    fn take_prewarning_punches(&self) -> Option<(Vec<i64>, Vec<i64>)> {
+        let mut data = self.data.lock().unwrap();
+        data.prewarning_controls.retain(|_, expires_at| *expires_at > Instant::now());
+        let punch_ids = std::mem::take(&mut data.pending_punches);
+        if punch_ids.is_empty() || data.prewarning_controls.is_empty() {
+            return None;
+        }
+        Some((punch_ids.into_iter().collect(), data.prewarning_controls.keys().copied().collect()))
+    }
+
+    /// Best `count` runs of class with valid result
+    pub async fn top(&self, sql_api: &EventSqlApi, stage_id: i64, class_id: i64, count: usize) -> anyhow::Result<Vec<TopRow>> {
+        let results = self.results(sql_api, stage_id, Some(class_id)).await?;
+        let recent_change = global_config().results_top.recent_change.to_std().unwrap_or_default();
+        let data = self.data.lock().unwrap();
+        Ok(results.iter()
+            .filter_map(|run| Some(TopRow {
+                place: run.place?,
+                name: run.name(),
+                club: run.club.clone(),
+                time_ms: run.time_ms?,
+                recent: data.changed_at.get(&run.run_id).is_some_and(|changed_at| changed_at.elapsed() < recent_change),
+            }))
+            .take(count)
+            .collect())
+    }
+
+    /// Runners on the course ordered by their expected finish time
+    pub async fn expected_finishers(&self, sql_api: &EventSqlApi, stage_id: i64) -> anyhow::Result<Vec<ExpectedFinisher>> {
+        self.refresh(sql_api, stage_id).await?;
+        let data = self.data.lock().unwrap();
+        let Some(stage) = &data.stage else {
+            return Ok(vec![]);
+        };
+        let Some(stage_start) = stage.stage_start else {
+            return Ok(vec![]);
+        };
+        let now_ms = (chrono::Local::now().fixed_offset() - stage_start).num_milliseconds();
+        let mut finishers: Vec<ExpectedFinisher> = stage.classes.values()
+            .flat_map(|runs| {
+                // results are sorted, so the first run is the leader if it has a place
+                let best_time_ms = runs.first().filter(|run| run.place.is_some()).and_then(|run| run.time_ms);
+                runs.iter()
+                    .filter(|run| run.status == RunStatus::Inactive && run.finish_time_ms.is_none())
+                    .filter_map(move |run| {
+                        let start_time_ms = run.start_time_ms.filter(|start_time_ms| *start_time_ms <= now_ms)?;
+                        Some(ExpectedFinisher {
+                            run_id: run.run_id,
+                            class_id: run.class_id,
+                            class_name: run.class_name.clone(),
+                            start_number: run.start_number,
+                            name: run.name(),
+                            club: run.club.clone(),
+                            start_time_ms,
+                            running_time_ms: now_ms - start_time_ms,
+                            expected_finish_ms: best_time_ms.map(|best_time_ms| start_time_ms + best_time_ms),
+                        })
+                    })
+            })
+            .collect();
+        finishers.sort_by_key(|finisher| (finisher.expected_finish_ms.is_none(), finisher.expected_finish_ms, finisher.start_time_ms));
+        Ok(finishers)
+    }
+}
+
+impl From<&RunResult> for ResultRow {
+    fn from(run: &RunResult) -> Self {
+        Self {
+            run_id: run.run_id,
+            class_id: run.class_id,
+            class_name: run.class_name.clone(),
+            start_number: run.start_number,
+            name: run.name(),
+            registration: run.registration.clone(),
+            club: run.club.clone(),
+            start_time_ms: run.start_time_ms,
+            finish_time_ms: run.finish_time_ms,
+            time_ms: run.time_ms,
+            status: run.status.as_iof_str().to_string(),
+            place: run.place,
+            adjustment_ms: run.adjustment_ms,
+            reinstated: run.reinstated,
+        }
+    }
+}
+
+/// Runs of class with changed place, time or status
+fn class_delta(class_id: i64, old: &[RunResult], new: &[RunResult]) -> ClassDelta {
+    let old_runs: BTreeMap<i64, &RunResult> = old.iter().map(|run| (run.run_id, run)).collect();
+    let new_run_ids: BTreeSet<i64> = new.iter().map(|run| run.run_id).collect();
+    let mut changes: Vec<ResultDelta> = new.iter()
+        .filter(|run| old_runs.get(&run.run_id).is_none_or(|old| (old.place, old.time_ms, old.status) != (run.place, run.time_ms, run.status)))
+        .map(|run| ResultDelta { run_id: run.run_id, place: run.place, time_ms: run.time_ms, status: run.status.as_iof_str().to_string(), removed: false })
+        .collect();
+    changes.extend(old.iter()
+        .filter(|run| !new_run_ids.contains(&run.run_id))
+        .map(|run| ResultDelta { run_id: run.run_id, place: None, time_ms: None, status: run.status.as_iof_str().to_string(), removed: true }));
+    ClassDelta { class_id, changes }
+}
+
+fn send_result_delta(rpc_client: &ClientCommandSender, event_id: EventId, delta: &ClassDelta) {
+    let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/results"), SIG_RESULT_DELTA)
+        .with_param(shvproto::to_rpcvalue(delta).unwrap_or_default());
+    traffic::record_signal(event_id, &signal);
+    if let Err(e) = rpc_client.send_message(signal) {
+        error!("Failed to send event {event_id} {SIG_RESULT_DELTA} signal: {e}");
+    }
+}
+
+/// Started run without finish, expected finish is estimated by the time of class leader
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExpectedFinisher {
+    pub run_id: i64,
+    pub class_id: i64,
+    pub class_name: String,
+    pub start_number: Option<i64>,
+    pub name: String,
+    pub club: String,
+    pub start_time_ms: i64,
+    pub running_time_ms: i64,
+    /// Msec since stage start, `None` if nobody has finished in class yet
+    pub expected_finish_ms: Option<i64>,
+}
+
+fn send_top_changed(rpc_client: &ClientCommandSender, event_id: EventId, class_ids: &BTreeSet<i64>) {
+    let class_ids: Vec<i64> = class_ids.iter().copied().collect();
+    let signal = RpcMessage::new_signal(&format!("eventctl/{event_id}/results"), SIG_TOP_CHNG)
+        .with_param(shvproto::to_rpcvalue(&class_ids).unwrap_or_default());
+    traffic::record_signal(event_id, &signal);
+    if let Err(e) = rpc_client.send_message(signal) {
+        error!("Failed to send event {event_id} {SIG_TOP_CHNG} signal: {e}");
+    }
+}
+
+/// Keep views of open event up to date, table and id of changed records are received from the channel,
+/// it finishes when the event is closed.
+pub(crate) async fn maintainer(app_state: SharedAppState, event_id: EventId, rpc_client: ClientCommandSender, views: Arc<EventViews>, changed_records: channel::Receiver<(String, i64)>) {
+    let refresh_interval = global_config().results_top.refresh_interval.to_std().unwrap_or_default();
+    // `topchng` is not sent before this time, classes changed meanwhile are collected
+    let mut top_deadline: Option<Instant> = None;
+    loop {
+        let received = match top_deadline {
+            Some(deadline) => smol::future::or(
+                async { Some(changed_records.recv().await) },
+                async {
+                    smol::Timer::at(deadline).await;
+                    None
+                },
+            ).await,
+            None => Some(changed_records.recv().await),
+        };
+        match received {
+            Some(Ok((table, id))) => {
+                views.invalidate(&table, id);
+                while let Ok((table, id)) = changed_records.try_recv() {
+                    views.invalidate(&table, id);
+                }
+                let Some(stage_id) = app_state.with_open_event(event_id, |e| e.current_stage) else {
+                    break;
+                };
+                let sql_api = EventSqlApi::new(event_id, app_state.clone(), rpc_client.clone());
+                if let Err(e) = views.refresh(&sql_api, stage_id).await {
+                    warn!("Failed to refresh event {event_id} views: {e}");
+                }
+                for delta in views.take_deltas() {
+                    send_result_delta(&rpc_client, event_id, &delta);
+                }
+                let deltas = views.take_changed_deltas();
+                mqtt::publish_result_deltas(event_id, &deltas);
+                if !deltas.is_empty() {
+                    match serde_json::to_value(&deltas) {
+                        Ok(classes) => webhooks::emit(app_state.db_pool.clone(), WebhookEvent::ResultsChanged, Some(event_id), serde_json::json!({
+                            "stageId": stage_id,
+                            "classes": classes,
+                        })),
+                        Err(e) => warn!("Failed to serialize event {event_id} results changes: {e}"),
+                    }
+                }
+                if let Some((punch_ids, codes)) = views.take_prewarning_punches() {
+                    match speaker::load_prewarnings(&sql_api, stage_id, &punch_ids, &codes).await {
+                        Ok(prewarnings) => speaker::send_prewarnings(&rpc_client, event_id, &prewarnings),
+                        Err(e) => warn!("Failed to load event {event_id} prewarnings: {e}"),
+                    }
+                }
+                let punch_ids = views.take_bridged_punches();
+                if !punch_ids.is_empty() && let Err(e) = mqtt::publish_radio_punches(&sql_api, event_id, stage_id, &punch_ids).await {
+                    warn!("Failed to publish event {event_id} radio punches to MQTT: {e}");
+                }
+            }
+            Some(Err(_)) => break,
+            None => {}
+        }
+        if top_deadline.is_none_or(|deadline| deadline <= Instant::now()) {
+            let changed_classes = views.take_changed_classes();
+            top_deadline = if changed_classes.is_empty() {
+                None
+            } else {
+                send_top_changed(&rpc_client, event_id, &changed_classes);
+                Some(Instant::now() + refresh_interval)
+            };
+        }
+    }
+    info!("Event {event_id} views maintainer finished");
+}
@@ -0,0 +1,300 @@
+//! Outbound webhooks. Third-party systems register URL, which gets JSON payload POSTed on event lifecycle changes,
+//! results changes and finished imports. Webhook can be limited to some event types and to single event,
+//! the secret is sent as bearer token. Webhooks are stored in master DB, delivery is retried, but not persisted.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use async_sqlite::Pool;
+use log::{debug, warn};
+use qxsql::{DbValue, QxSqlApi};
+use qxsql::sql::record_from_slice;
+use serde::{Deserialize, Serialize};
+use shvclient::ClientCommandSender;
+use shvclient::clientnode::{META_METHOD_DIR, META_METHOD_LS, Method, RequestHandlerResult, err_unresolved_request};
+use shvproto::{RpcValue, to_rpcvalue};
+use shvrpc::metamethod::{AccessLevel, Flags, MetaMethod};
+use shvrpc::{RpcMessage, RpcMessageMetaTags};
+
+use crate::appsqlapi::AppSqlApi;
+use crate::anyhow_to_rpc_error;
+use crate::state::{EventId, SharedAppState};
+
+pub(crate) const WEBHOOKS_SHV_PATH: &str = "webhooks";
+
+const METH_CREATE: &str = "create";
+const METH_CONFIG: &str = "config";
+const METH_DELETE: &str = "delete";
+
+/// Number of retries of failed delivery
+const DELIVERY_RETRIES: u32 = 3;
+/// Delay before first retry, it grows with each attempt
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const WEBHOOKS_ROOT_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_CREATE, Flags::None, AccessLevel::Service, "{s:url,s|n:secret,[s]|n:events,i|n:eventId}", "i:webhookId", &[], "",
+    ),
+];
+
+pub(crate) const WEBHOOK_NODE_METHODS: &[MetaMethod] = &[
+    META_METHOD_DIR,
+    META_METHOD_LS,
+    MetaMethod::new_static(
+        METH_CONFIG, Flags::None, AccessLevel::Service, "", "{i:id,s:url,b:hasSecret,[s]:events,i|n:eventId,s:created}", &[], "",
+    ),
+    MetaMethod::new_static(
+        METH_DELETE, Flags::None, AccessLevel::Service, "", "b", &[], "",
+    ),
+];
+
+/// Type of event delivered by webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum WebhookEvent {
+    EventCreated,
+    EventOpened,
+    EventClosed,
+    ResultsChanged,
+    ImportFinished,
+}
+
+impl WebhookEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::EventCreated => "eventCreated",
+            WebhookEvent::EventOpened => "eventOpened",
+            WebhookEvent::EventClosed => "eventClosed",
+            WebhookEvent::ResultsChanged => "resultsChanged",
+            WebhookEvent::ImportFinished => "importFinished",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateWebhookParams {
+    url: String,
+    #[serde(default)]
+    secret: Option<String>,
+    /// All event types are delivered if not set
+    #[serde(default)]
+    events: Option<Vec<WebhookEvent>>,
+    /// Events of all events are delivered if not set
+    #[serde(default)]
+    event_id: Option<EventId>,
+}
+impl_rpcvalue_conversions!(CreateWebhookParams);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookConfig {
+    id: i64,
+    url: String,
+    has_secret: bool,
+    events: Vec<WebhookEvent>,
+    event_id: Option<EventId>,
+    created: String,
+}
+
+#[derive(Debug, Clone)]
+struct Webhook {
+    url: String,
+    secret: Option<String>,
+    events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookPayload<'a> {
+    #[serde(rename = "type")]
+    event: &'static str,
+    event_id: Option<EventId>,
+    timestamp: String,
+    data: &'a serde_json::Value,
+}
+
+fn events_from_db(events: &str) -> Vec<WebhookEvent> {
+    events.split(',')
+        .map(str::trim)
+        .filter(|event| !event.is_empty())
+        .filter_map(|event| serde_json::from_value(serde_json::Value::String(event.to_string())).ok())
+        .collect()
+}
+
+async fn create_webhook(app_state: &SharedAppState, param: &CreateWebhookParams) -> anyhow::Result<i64> {
+    let url = url::Url::parse(param.url.trim())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Webhook URL must be http or https");
+    }
+    if let Some(event_id) = param.event_id {
+        // fails for not existing event
+        app_state.event_record(event_id).await?;
+    }
+    let events = param.events.iter().flatten().map(WebhookEvent::as_str).collect::<Vec<_>>().join(",");
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    qxsql.create_record("webhooks", &record_from_slice(&[
+        ("url", url.as_str().into()),
+        ("secret", param.secret.clone().filter(|secret| !secret.is_empty()).map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("events", events.into()),
+        ("event_id", param.event_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+        ("created", chrono::Local::now().fixed_offset().to_rfc3339().into()),
+    ])).await
+}
+
+async fn list_webhooks(app_state: &SharedAppState) -> anyhow::Result<Vec<String>> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT id FROM webhooks ORDER BY id", None).await?;
+    Ok((0..result.row_count())
+        .filter_map(|row| result.value(row, 0).and_then(|v| v.to_int()))
+        .map(|id| id.to_string())
+        .collect())
+}
+
+async fn webhook_config(app_state: &SharedAppState, webhook_id: i64) -> anyhow::Result<WebhookConfig> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.query("SELECT url, secret, events, event_id, created FROM webhooks WHERE id = :id", Some(&record_from_slice(&[
+        ("id", webhook_id.into()),
+    ]))).await?;
+    if result.row_count() == 0 {
+        bail!("Webhook id: {webhook_id} not found");
+    }
+    let string = |col| result.value(0, col).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    Ok(WebhookConfig {
+        id: webhook_id,
+        url: string(0),
+        has_secret: !string(1).is_empty(),
+        events: events_from_db(&string(2)),
+        event_id: result.value(0, 3).and_then(|v| v.to_int()),
+        created: string(4),
+    })
+}
+
+async fn delete_webhook(app_state: &SharedAppState, webhook_id: i64) -> anyhow::Result<bool> {
+    let qxsql = AppSqlApi::new_without_recchng(app_state.db_pool.clone());
+    let result = qxsql.exec("DELETE FROM webhooks WHERE id = :id", Some(&record_from_slice(&[
+        ("id", webhook_id.into()),
+    ]))).await?;
+    Ok(result.rows_affected > 0)
+}
+
+async fn post(webhook: &Webhook, body: String) -> anyhow::Result<()> {
+    let webhook = webhook.clone();
+    // ureq is blocking
+    smol::unblock(move || {
+        let mut request = ureq::post(&webhook.url)
+            .timeout(DELIVERY_TIMEOUT)
+            .set("Content-Type", "application/json");
+        if let Some(secret) = &webhook.secret {
+            request = request.set("Authorization", &format!("Bearer {secret}"));
+        }
+        request.send_string(&body)
+            .map(|_| ())
+            .map_err(|e| anyhow::anyhow!("Webhook {} delivery failed: {e}", webhook.url))
+    }).await
+}
+
+async fn deliver(db_pool: Pool, event: WebhookEvent, event_id: Option<EventId>, data: serde_json::Value) -> anyhow::Result<()> {
+    let qxsql = AppSqlApi::new_without_recchng(db_pool);
+    let result = qxsql.query("SELECT url, secret, events FROM webhooks WHERE event_id IS NULL OR event_id = :event_id", Some(&record_from_slice(&[
+        ("event_id", event_id.map(DbValue::from).unwrap_or(DbValue::Null)),
+    ]))).await?;
+    let string = |row, col| result.value(row, col).and_then(|v| v.as_str()).map(str::to_string);
+    let webhooks = (0..result.row_count())
+        .map(|row| Webhook {
+            url: string(row, 0).unwrap_or_default(),
+            secret: string(row, 1).filter(|secret| !secret.is_empty()),
+            events: events_from_db(&string(row, 2).unwrap_or_default()),
+        })
+        .filter(|webhook| webhook.events.is_empty() || webhook.events.contains(&event))
+        .collect::<Vec<_>>();
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+    let body = serde_json::to_string(&WebhookPayload {
+        event: event.as_str(),
+        event_id,
+        timestamp: chrono::Local::now().fixed_offset().to_rfc3339(),
+        data: &data,
+    })?;
+    for webhook in &webhooks {
+        let mut attempt: u32 = 0;
+        loop {
+            attempt += 1;
+            match post(webhook, body.clone()).await {
+                Ok(()) => {
+                    debug!("Webhook {} delivered {}", webhook.url, event.as_str());
+                    break;
+                }
+                Err(e) if attempt <= DELIVERY_RETRIES => {
+                    warn!("{e}, attempt {attempt}/{}", DELIVERY_RETRIES + 1);
+                    smol::Timer::after(RETRY_DELAY * attempt).await;
+                }
+                Err(e) => {
+                    warn!("{e}, {} is not delivered", event.as_str());
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deliver event to matching webhooks in background, the caller is not delayed by slow receivers
+pub(crate) fn emit(db_pool: Pool, event: WebhookEvent, event_id: Option<EventId>, data: serde_json::Value) {
+    smol::spawn(async move {
+        if let Err(e) = deliver(db_pool, event, event_id, data).await {
+            warn!("Webhooks of {} error: {e}", event.as_str());
+        }
+    }).detach();
+}
+
+pub(crate) async fn request_handler(
+    rq: RpcMessage,
+    _client_cmd_tx: ClientCommandSender,
+    app_state: SharedAppState,
+) -> RequestHandlerResult {
+    let shv_path = rq.shv_path().unwrap_or_default().to_string();
+    if shv_path.is_empty() {
+        return match Method::from_request(&rq) {
+            Method::Dir(dir) => dir.resolve(WEBHOOKS_ROOT_METHODS),
+            Method::Ls(ls) => ls.resolve(WEBHOOKS_ROOT_METHODS, async move || {
+                list_webhooks(&app_state).await.map_err(anyhow_to_rpc_error)
+            }),
+            Method::Other(m) => match m.method() {
+                METH_CREATE => m.resolve(WEBHOOKS_ROOT_METHODS, async move || {
+                    let param = CreateWebhookParams::try_from(rq.param())
+                        .map_err(anyhow_to_rpc_error)?;
+                    create_webhook(&app_state, &param).await
+                        .map(RpcValue::from)
+                        .map_err(anyhow_to_rpc_error)
+                }),
+                _ => err_unresolved_request(),
+            },
+        };
+    }
+    let Ok(webhook_id) = shv_path.parse::<i64>() else {
+        log::warn!("Invalid path: {shv_path}");
+        return err_unresolved_request();
+    };
+    match Method::from_request(&rq) {
+        Method::Dir(dir) => dir.resolve(WEBHOOK_NODE_METHODS),
+        Method::Ls(ls) => ls.resolve(WEBHOOK_NODE_METHODS, async move || { Ok(vec![]) }),
+        Method::Other(m) => match m.method() {
+            METH_CONFIG => m.resolve(WEBHOOK_NODE_METHODS, async move || {
+                let config = webhook_config(&app_state, webhook_id).await
+                    .map_err(anyhow_to_rpc_error)?;
+                Ok(to_rpcvalue(&config).expect("serde should work"))
+            }),
+            METH_DELETE => m.resolve(WEBHOOK_NODE_METHODS, async move || {
+                delete_webhook(&app_state, webhook_id).await
+                    .map(RpcValue::from)
+                    .map_err(anyhow_to_rpc_error)
+            }),
+            _ => err_unresolved_request(),
+        },
+    }
+}